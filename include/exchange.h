@@ -0,0 +1,83 @@
+#ifndef EXCHANGE_H
+#define EXCHANGE_H
+
+/* Generated with cbindgen --config cbindgen.toml --output include/exchange.h, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * The record was applied.
+ */
+#define TP_OK 0
+
+/**
+ * A pointer is null or a string is not valid UTF-8.
+ */
+#define TP_ERR_INVALID_ARGUMENT -1
+
+/**
+ * The processor panicked, during this call or an earlier one.
+ */
+#define TP_ERR_PANIC -2
+
+/**
+ * The balances could not be written.
+ */
+#define TP_ERR_OUTPUT -3
+
+/**
+ * Processor handed to the C callers, opaque to them.
+ */
+typedef struct TpProcessor TpProcessor;
+
+/**
+ * Creates a processor with the default settings, to be released with
+ * `tp_free`. Returns null if it could not be created.
+ */
+TpProcessor *tp_new(void);
+
+/**
+ * Processes a record, amount being null for the disputes, resolves and
+ * chargebacks. Returns `TP_OK` when it is applied, the number of its error
+ * from `ProcessingError::numeric_code` when it is rejected, or one of the
+ * negative `TP_ERR_` codes.
+ *
+ * # Safety
+ *
+ * handle must come from `tp_new` and not be freed yet, transaction_type must
+ * point to a NUL terminated string and amount be null or point to one.
+ */
+int32_t tp_process_record(TpProcessor *handle,
+                          const char *transaction_type,
+                          uint32_t client,
+                          uint64_t tx,
+                          const char *amount);
+
+/**
+ * Writes the balances of the clients as csv into buf, as the command line
+ * writes them by default, without NUL terminator. Returns the length of the
+ * csv, which is only written when it fits into the len bytes of buf, e.g.
+ * call it with a null buf and a len of 0 to size the buffer. Returns one of
+ * the negative `TP_ERR_` codes otherwise.
+ *
+ * # Safety
+ *
+ * handle must come from `tp_new` and not be freed yet, buf must be null with
+ * a len of 0 or point to len writable bytes.
+ */
+intptr_t tp_serialize_csv(TpProcessor *handle, uint8_t *buf, uintptr_t len);
+
+/**
+ * Releases a processor created with `tp_new`, null is ignored.
+ *
+ * # Safety
+ *
+ * handle must be null or come from `tp_new` and not be freed yet, it must
+ * not be used anymore.
+ */
+void tp_free(TpProcessor *handle);
+
+#endif /* EXCHANGE_H */
@@ -0,0 +1,163 @@
+//! `Arbitrary` implementations of the transaction types for the property
+//! tests written with proptest, built with the `proptest` feature.
+
+use proptest::prelude::*;
+
+use crate::type_defs::{Amount, ClientId, Transaction, TransactionId, TransactionRecord};
+
+/// Largest amount drawn, in units. The amounts are drawn from 0.0001 up to it
+/// with at most 4 fractional digits, so they are all valid.
+pub const MAX_AMOUNT: i64 = 1_000_000;
+
+impl Arbitrary for Amount {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Amount>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (1..=MAX_AMOUNT * 10_000)
+            .prop_map(|value| {
+                format!("{}.{:04}", value / 10_000, value % 10_000)
+                    .parse()
+                    .unwrap()
+            })
+            .boxed()
+    }
+}
+
+impl Arbitrary for ClientId {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<ClientId>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<u32>().prop_map(ClientId).boxed()
+    }
+}
+
+impl Arbitrary for TransactionId {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<TransactionId>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<u64>().prop_map(TransactionId).boxed()
+    }
+}
+
+/// Any transaction but the extensions, which are only recorded by the
+/// processor for the handlers of custom types.
+impl Arbitrary for Transaction {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Transaction>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        let ids = (any::<ClientId>(), any::<TransactionId>());
+        prop_oneof![
+            (ids.clone(), any::<Amount>())
+                .prop_map(|((client, tx), amount)| Transaction::Deposit { client, tx, amount }),
+            (ids.clone(), any::<Amount>())
+                .prop_map(|((client, tx), amount)| Transaction::Withdrawal { client, tx, amount }),
+            (
+                ids.clone(),
+                proptest::option::weighted(0.2, any::<Amount>())
+            )
+                .prop_map(|((client, tx), amount)| Transaction::Dispute {
+                    client,
+                    tx,
+                    amount
+                }),
+            ids.clone()
+                .prop_map(|(client, tx)| Transaction::Resolve { client, tx }),
+            ids.prop_map(|(client, tx)| Transaction::ChargeBack { client, tx }),
+        ]
+        .boxed()
+    }
+}
+
+/// Records of the arbitrary transactions, all of them valid.
+impl Arbitrary for TransactionRecord {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<TransactionRecord>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<Transaction>()
+            .prop_map(|transaction| record(&transaction))
+            .boxed()
+    }
+}
+
+fn record(transaction: &Transaction) -> TransactionRecord {
+    let amount = match *transaction {
+        Transaction::Dispute { amount, .. } => amount,
+        _ => transaction.amount(),
+    };
+    TransactionRecord {
+        transaction_type: transaction.kind().to_string(),
+        client: transaction.client_id().0,
+        tx: transaction.tx_id().0,
+        amount: amount.map(|amount| amount.to_string()),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::client::ClientView;
+    use crate::processor::{TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+
+    /// Records of one client referencing a few transaction ids from base, so
+    /// the disputes, resolves and chargebacks often hit its deposits.
+    fn client_records(client: u32, base: u64) -> impl Strategy<Value = Vec<TransactionRecord>> {
+        proptest::collection::vec(any::<TransactionRecord>(), 0..40).prop_map(move |records| {
+            records
+                .into_iter()
+                .map(|record| TransactionRecord {
+                    client,
+                    tx: base + record.tx % 20,
+                    ..record
+                })
+                .collect()
+        })
+    }
+
+    fn run(records: Vec<TransactionRecord>) -> Vec<ClientView> {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        processor.process_iter(records);
+        [ClientId(1), ClientId(2)]
+            .iter()
+            .filter_map(|client_id| processor.client_view(*client_id))
+            .collect()
+    }
+
+    proptest! {
+        // Test that the arbitrary records are valid transactions.
+        #[test]
+        fn test_valid_records(record in any::<TransactionRecord>()) {
+            prop_assert!(Transaction::try_from(&record).is_ok());
+        }
+
+        // Test that interleaving the records of two clients in any order never
+        // changes the final balances of either client.
+        #[test]
+        fn test_interleaving(
+            first in client_records(1, 0),
+            second in client_records(2, 100),
+            order in proptest::collection::vec(any::<bool>(), 0..80),
+        ) {
+            let sequential: Vec<_> = first.iter().chain(second.iter()).cloned().collect();
+            let (mut first_records, mut second_records) = (first.into_iter(), second.into_iter());
+            let mut interleaved = Vec::new();
+            for take_first in order {
+                let record = if take_first {
+                    first_records.next().or_else(|| second_records.next())
+                } else {
+                    second_records.next().or_else(|| first_records.next())
+                };
+                interleaved.extend(record);
+            }
+            interleaved.extend(first_records.chain(second_records));
+            prop_assert_eq!(run(interleaved), run(sequential));
+        }
+    }
+}
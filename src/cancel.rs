@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Number of records read between two checks of a `CancellationToken` by
+/// default.
+const DEFAULT_CHECK_INTERVAL: u64 = 1024;
+
+/// Token through which a run feeding records into a processor can be stopped
+/// from another thread or from a signal handler. The run checks it every
+/// `check_interval` records and stops reading once it is cancelled, the
+/// clones of a token share its state.
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    check_interval: u64,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            check_interval: DEFAULT_CHECK_INTERVAL,
+        }
+    }
+
+    /// Checks the token every check_interval records, at least every record.
+    pub fn with_check_interval(mut self, check_interval: u64) -> Self {
+        self.check_interval = check_interval.max(1);
+        self
+    }
+
+    pub fn check_interval(&self) -> u64 {
+        self.check_interval
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
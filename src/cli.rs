@@ -0,0 +1,808 @@
+use exchange::client::HeldPolicy;
+use exchange::config::{Config, OutputConfig, ProcessorConfig, RunConfig};
+use exchange::error::Severity;
+use exchange::malformed::MalformedRowPolicy;
+use exchange::processor::{OutputOptions, OutputPages};
+use exchange::runner::{InputFormat, RunOptions};
+use exchange::suspicious::SuspicionThresholds;
+use exchange::transaction_cache::SpillFailurePolicy;
+use exchange::type_defs::{Amount, ClientId, TransactionId, PRECISION, TRANSACTION_TYPES};
+use exchange::validation::RecordValidator;
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Command given on the command line.
+#[derive(Debug, Clone)]
+pub enum Command {
+    Process(Box<Options>),
+    InspectCache(InspectOptions),
+    /// Checks the state snapshot written by `--state-out` at the given path.
+    CheckState(String),
+    Convert(Box<ConvertOptions>),
+    /// Prints the settings resolved from the config file and the options.
+    PrintConfig(Box<Options>),
+    /// Help text requested with `help` or `--help`, printed on stdout.
+    Help(String),
+}
+
+/// Names of the commands with their description.
+const COMMANDS: [(&str, &str); 6] = [
+    (
+        "process",
+        "compute the balances of the clients, the default when the first argument is an option or a path",
+    ),
+    (
+        "inspect-cache",
+        "summarize the cache lines spilled into a cache dir",
+    ),
+    (
+        "check-state",
+        "audit the clients of a state snapshot without processing the input again",
+    ),
+    (
+        "convert",
+        "convert transactions between csv and JSON lines without applying them",
+    ),
+    (
+        "print-config",
+        "print the settings resolved from --config and the options of process",
+    ),
+    ("help", "print this help or the usage of a command"),
+];
+
+impl Command {
+    /// Parses the command line arguments, the program name is expected to be
+    /// already stripped. The first argument names the command, a bare input
+    /// path or option runs `process` as before the commands existed.
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+        let args: Vec<String> = args.collect();
+        let (command, rest) = match args.split_first() {
+            None => return Err(help()),
+            Some((first, rest)) if first == "help" => {
+                return match rest.first() {
+                    Some(command) => usage(command).map(|usage| Command::Help(usage.to_owned())),
+                    None => Ok(Command::Help(help())),
+                };
+            }
+            Some((first, _)) if first == "--help" || first == "-h" => {
+                return Ok(Command::Help(help()))
+            }
+            Some((first, rest)) if usage(first).is_ok() => (first.as_str(), rest),
+            Some((first, _)) if first.starts_with('-') || is_path(first) => ("process", &args[..]),
+            Some((first, _)) => return Err(format!("Unknown command {}\n\n{}", first, help())),
+        };
+        if rest.iter().any(|arg| arg == "--help" || arg == "-h") {
+            return usage(command).map(|usage| Command::Help(usage.to_owned()));
+        }
+        let rest = rest.iter().cloned();
+        match command {
+            "inspect-cache" => InspectOptions::parse(rest).map(Command::InspectCache),
+            "check-state" => parse_check_state(rest).map(Command::CheckState),
+            "convert" => {
+                ConvertOptions::parse(rest).map(|options| Command::Convert(Box::new(options)))
+            }
+            "print-config" => Options::parse_with(rest, false)
+                .map(|options| Command::PrintConfig(Box::new(options))),
+            _ => Options::parse(rest).map(|options| Command::Process(Box::new(options))),
+        }
+    }
+}
+
+/// Returns the help listing the commands.
+fn help() -> String {
+    let mut help = String::from(
+        "Usage: exchange [<command>] [<options>] <input>\n\n\
+         Computes the balances of the clients from their transactions.\n\nCommands:\n",
+    );
+    for (command, description) in COMMANDS.iter() {
+        help.push_str(&format!("  {:<15}{}\n", command, description));
+    }
+    help.push_str("\nRun `exchange help <command>` for the options of a command.");
+    help
+}
+
+/// Returns the usage of a command.
+fn usage(command: &str) -> Result<&'static str, String> {
+    match command {
+        "process" => Ok(USAGE),
+        "inspect-cache" => Ok(INSPECT_USAGE),
+        "check-state" => Ok(CHECK_STATE_USAGE),
+        "convert" => Ok(CONVERT_USAGE),
+        "print-config" => {
+            Ok("Usage: exchange print-config [--config <path>] [<options of process>]")
+        }
+        "help" => Ok("Usage: exchange help [<command>]"),
+        _ => Err(format!("Unknown command {}\n\n{}", command, help())),
+    }
+}
+
+/// Returns true when the argument is taken as the input of `process` rather
+/// than as an unknown command, i.e. it exists or looks like a file name.
+fn is_path(arg: &str) -> bool {
+    Path::new(arg).exists() || arg.contains(['.', '/', '\\'])
+}
+
+/// Options of `exchange inspect-cache`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InspectOptions {
+    /// Cache dir left by a run, e.g. in the `--cache-dir` of a run which did
+    /// not clean it.
+    pub cache_dir: String,
+    /// Transaction whose stored value is printed instead of the summary.
+    pub tx: Option<TransactionId>,
+}
+
+const INSPECT_USAGE: &str = "Usage: exchange inspect-cache [--tx <id>] <cache dir>";
+
+impl InspectOptions {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut cache_dir = None;
+        let mut tx = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--tx" => {
+                    let id = args
+                        .next()
+                        .ok_or_else(|| format!("Missing value for --tx\n{}", INSPECT_USAGE))?;
+                    tx = Some(id.parse()?);
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(format!("Unknown option {}\n{}", arg, INSPECT_USAGE));
+                }
+                _ if cache_dir.is_none() => cache_dir = Some(arg),
+                _ => return Err(format!("Unexpected argument {}\n{}", arg, INSPECT_USAGE)),
+            }
+        }
+        Ok(InspectOptions {
+            cache_dir: cache_dir.ok_or_else(|| INSPECT_USAGE.to_owned())?,
+            tx,
+        })
+    }
+}
+
+const CHECK_STATE_USAGE: &str = "Usage: exchange check-state <state snapshot>";
+
+/// Returns the path of the snapshot given to `exchange check-state`.
+fn parse_check_state<I: Iterator<Item = String>>(mut args: I) -> Result<String, String> {
+    let path = match args.next() {
+        Some(arg) if arg.starts_with("--") => {
+            return Err(format!("Unknown option {}\n{}", arg, CHECK_STATE_USAGE));
+        }
+        Some(path) => path,
+        None => return Err(CHECK_STATE_USAGE.to_owned()),
+    };
+    match args.next() {
+        Some(arg) => Err(format!(
+            "Unexpected argument {}\n{}",
+            arg, CHECK_STATE_USAGE
+        )),
+        None => Ok(path),
+    }
+}
+
+/// Options of `exchange convert`.
+#[derive(Debug, Clone)]
+pub struct ConvertOptions {
+    /// Formats of the input and output, guessed from their paths when unset.
+    pub from: Option<InputFormat>,
+    pub to: Option<InputFormat>,
+    pub input: String,
+    pub output: String,
+    /// Dialect, encoding and malformed row policy used to read the input.
+    pub run: RunOptions,
+}
+
+const CONVERT_USAGE: &str = "Usage: exchange convert [--from csv|jsonl] [--to csv|jsonl] [--input-encoding utf8|latin1|windows-1252] [--delimiter <char> | --tsv] [--comment-char <char>] [--map <field>=<column>...] [--amount-column <column>] [--strict-schema] [--on-malformed skip|abort|quarantine=<path>] [--fail-fast] [--log-level off|error|warn] <input> <output>";
+
+impl ConvertOptions {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut from = None;
+        let mut to = None;
+        let mut paths = Vec::new();
+        let mut run = RunOptions::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--from" => from = Some(usage_value(&arg, args.next(), CONVERT_USAGE)?.parse()?),
+                "--to" => to = Some(usage_value(&arg, args.next(), CONVERT_USAGE)?.parse()?),
+                "--input-encoding" | "--encoding" => {
+                    run.encoding = usage_value(&arg, args.next(), CONVERT_USAGE)?.parse()?
+                }
+                "--delimiter" => {
+                    let delimiter = usage_value(&arg, args.next(), CONVERT_USAGE)?;
+                    run.csv.delimiter = byte(&arg, Some(delimiter))?;
+                }
+                "--tsv" => run.csv.delimiter = b'\t',
+                "--strict-schema" => run.csv.strict_schema = true,
+                "--comment" | "--comment-char" => {
+                    let comment = usage_value(&arg, args.next(), CONVERT_USAGE)?;
+                    run.csv.comment = Some(byte(&arg, Some(comment))?);
+                }
+                "--map" => {
+                    let columns = run.csv.columns.take().unwrap_or_default();
+                    let pair = usage_value(&arg, args.next(), CONVERT_USAGE)?;
+                    run.csv.columns = Some(columns.map_pair(&pair)?);
+                }
+                "--amount-column" => {
+                    let columns = run.csv.columns.take().unwrap_or_default();
+                    let column = usage_value(&arg, args.next(), CONVERT_USAGE)?;
+                    run.csv.columns = Some(columns.map("amount", &column)?);
+                }
+                "--on-malformed" => {
+                    let policy = usage_value(&arg, args.next(), CONVERT_USAGE)?;
+                    run.on_malformed = malformed_row_policy(&policy)?;
+                }
+                "--fail-fast" => run.fail_fast = true,
+                "--log-level" => {
+                    run.log_level = usage_value(&arg, args.next(), CONVERT_USAGE)?.parse()?
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(format!("Unknown option {}\n{}", arg, CONVERT_USAGE));
+                }
+                _ if paths.len() < 2 => paths.push(arg),
+                _ => return Err(format!("Unexpected argument {}\n{}", arg, CONVERT_USAGE)),
+            }
+        }
+        let mut paths = paths.into_iter();
+        match (paths.next(), paths.next()) {
+            (Some(input), Some(output)) => Ok(ConvertOptions {
+                from,
+                to,
+                input,
+                output,
+                run,
+            }),
+            _ => Err(CONVERT_USAGE.to_owned()),
+        }
+    }
+}
+
+/// Options accepted on the command line.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Path of the csv file containing the transactions.
+    pub input: String,
+    /// Config file the settings were read from.
+    pub config: Option<String>,
+    /// Whether the balances processed so far are still written when
+    /// processing is stopped because of `fail_fast`.
+    pub partial_output: bool,
+    /// Format of the input, guessed from the input path when not given.
+    pub input_format: Option<InputFormat>,
+    /// Read the input through a memory mapping instead of buffered reads.
+    pub mmap: bool,
+    /// Split csv inputs grouped by client into chunks processed in parallel.
+    pub parallel_chunks: Option<usize>,
+    /// Print the processing stats on stderr.
+    pub stats: bool,
+    /// Check the balances against the stored transactions after the run.
+    pub verify: bool,
+    /// Exit non-zero once a record is rejected with this severity or a
+    /// higher one, processing still goes on.
+    pub fail_on: Option<Severity>,
+    /// Print the aggregate balances and stats instead of the balances of
+    /// each client.
+    pub summary_only: bool,
+    /// Directory in which the cache of each run gets its own subdirectory.
+    pub cache_dir: Option<String>,
+    /// Number of transactions each client cache holds in memory.
+    pub cache_limit: Option<u64>,
+    /// Remove the cache subdirectory of the run on exit.
+    pub clean_cache_on_exit: bool,
+    /// What to do when the caches can not spill to disk.
+    pub spill_policy: SpillFailurePolicy,
+    /// Path the balances are written to when stdout is closed early.
+    pub output_fallback: Option<String>,
+    /// Maximum number of transactions stored for each client.
+    pub max_tx_per_client: Option<u64>,
+    /// Number of transactions of a client after which a deposit can no
+    /// longer be disputed.
+    pub dispute_window: Option<u64>,
+    /// How far the withdrawals may take the available balance below zero,
+    /// they may not when unset.
+    pub overdraft_limit: Option<Amount>,
+    /// Smallest amounts of the deposits and withdrawals, any amount is
+    /// accepted when unset.
+    pub min_deposit: Option<Amount>,
+    pub min_withdrawal: Option<Amount>,
+    /// Rules checked on every record before it is converted.
+    pub validator: RecordValidator,
+    /// What to do when held would go negative.
+    pub held_policy: HeldPolicy,
+    /// Path of the csv report of the rejected records.
+    pub rejected_out: Option<String>,
+    /// Clients whose records are logged into their own file of trace_dir.
+    pub trace_clients: Vec<ClientId>,
+    pub trace_dir: String,
+    /// Number of records after which the balances are written into a new
+    /// snapshot of snapshot_dir, keeping the last snapshot_keep snapshots.
+    pub snapshot_every: Option<u64>,
+    pub snapshot_dir: String,
+    pub snapshot_keep: Option<usize>,
+    /// Path of the csv report of the deposits still under dispute.
+    pub disputes_out: Option<String>,
+    /// Path of the csv report of the disputes, resolves and chargebacks.
+    pub disputes_report: Option<String>,
+    /// Path of the csv report of the amounts held for each client.
+    pub held_detail: Option<String>,
+    /// Path of the state snapshot written at the end of the run, also when
+    /// it is interrupted.
+    pub state_out: Option<String>,
+    /// Keep the memos of the deposits and withdrawals for the reports.
+    pub keep_memos: bool,
+    /// Path of the csv report of the clients exceeding the suspicion
+    /// thresholds.
+    pub suspicious_out: Option<String>,
+    pub suspicion_thresholds: SuspicionThresholds,
+    /// Options controlling how the records are processed.
+    pub run: RunOptions,
+    /// Options controlling the output.
+    pub output: OutputOptions,
+}
+
+/// Largest number of chunks given to `--parallel-chunks`.
+const MAX_PARALLEL_CHUNKS: usize = 1024;
+
+/// Decimals of the minor unit written with `--minor-units`, cents by default.
+const DEFAULT_MINOR_UNIT_DIGITS: u32 = 2;
+
+const USAGE: &str =
+    "Usage: exchange [process] [--fail-fast] [--fail-on-conflicting-duplicate] [--fail-on info|warn|error|fatal] [--no-partial-output] [--mmap | --parallel-chunks <n>] [--with-lock-reason] [--with-dispute-flag] [--extended-output] [--excel] [--suppress-zero] [--minor-units [--minor-unit-digits <n>]] [--columns <column>[=<header>][,...]] [--sort-by client|total] [--output-shards <dir>:<n> | --page-size <n> [--page-prefix <path>]] [--clamp-held | --strict-held] [--max-tx-per-client <n>] [--dispute-window <n>] [--allow-overdraft <amount> | --deny-withdrawal-overdraft] [--min-deposit <amount>] [--min-withdrawal <amount>] [--reject-zero-client] [--reject-zero-tx] [--amount-ceiling <amount>] [--require-deposit-amount] [--client <id>[,<id>...]] [--types <type>[,<type>...]] [--currency-symbol <symbol>] [--input-format csv|jsonl] [--input-encoding utf8|latin1|windows-1252] [--delimiter <char> | --tsv] [--comment-char <char>] [--map <field>=<column>...] [--amount-column <column>] [--strict-schema] [--on-malformed skip|abort|quarantine=<path>] [--skip <n>] [--take <n>] [--chunk-size <n>] [--stats] [--verify] [--log-level off|error|warn] [--summary-only] [--rejected-out <path>] [--trace-client <id>... [--trace-dir <dir>]] [--snapshot-every <n> [--snapshot-dir <dir>] [--snapshot-keep <k>]] [--disputes-out <path> [--keep-memos]] [--disputes-report <path>] [--held-detail <path>] [--state-out <path>] [--suspicious-out <path> [--max-chargebacks <n>] [--max-charged-back <amount>]] [--cache-dir <dir> [--clean-cache-on-exit]] [--cache-limit <n>] [--strict-spill] [--output-fallback <path>] [--config <path>] <transactions.csv>";
+
+impl Options {
+    /// Parses the command line arguments, the program name is expected to be
+    /// already stripped.
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Self, String> {
+        Self::parse_with(args, true)
+    }
+
+    /// Parses the command line arguments on top of the settings of the
+    /// `--config` file, if any, which themselves override the defaults. The
+    /// input is left empty when it is not required.
+    fn parse_with<I: Iterator<Item = String>>(
+        args: I,
+        require_input: bool,
+    ) -> Result<Self, String> {
+        let args: Vec<String> = args.collect();
+        // The environment overrides the config file, the options override both.
+        let env = Config::from_env()?;
+        let mut config = Config::default();
+        config.merge(&env);
+        let options = Self::parse_over(&args, &config, require_input)?;
+        match &options.config {
+            // The options are parsed again over the settings of the file found
+            // among them, so `--config` is only taken where an option is.
+            Some(path) => {
+                let mut config = Config::load(Path::new(path))?;
+                config.merge(&env);
+                Self::parse_over(&args, &config, require_input)
+            }
+            None => Ok(options),
+        }
+    }
+
+    /// Parses the command line arguments over the given settings.
+    fn parse_over(args: &[String], config: &Config, require_input: bool) -> Result<Self, String> {
+        let (processor_config, run_config, output_config) =
+            (&config.processor, &config.run, &config.output);
+        let mut args = args.iter().cloned();
+
+        let mut input = None;
+        let mut config = None;
+        let mut partial_output = run_config.partial_output.unwrap_or(true);
+        let mut input_format = None;
+        let mut mmap = false;
+        let mut parallel_chunks = run_config.parallel_chunks.map(|chunks| chunks as usize);
+        let mut stats = run_config.stats.unwrap_or_default();
+        let mut verify = run_config.verify.unwrap_or_default();
+        let mut fail_on = run_config.fail_on.as_deref().map(str::parse).transpose()?;
+        let mut summary_only = false;
+        let mut cache_dir = processor_config.cache_dir.clone();
+        let mut cache_limit = processor_config.cache_limit;
+        let mut clean_cache_on_exit = processor_config.clean_cache_on_exit.unwrap_or_default();
+        let mut spill_policy = match processor_config.strict_spill {
+            Some(true) => SpillFailurePolicy::Abort,
+            _ => SpillFailurePolicy::default(),
+        };
+        let mut output_fallback = None;
+        let mut max_tx_per_client = processor_config.max_tx_per_client;
+        let mut dispute_window = processor_config.dispute_window;
+        let mut overdraft_limit = processor_config
+            .overdraft_limit
+            .as_deref()
+            .map(|limit| non_negative_amount(limit, "overdraft_limit"))
+            .transpose()?;
+        let mut min_deposit = processor_config
+            .min_deposit
+            .as_deref()
+            .map(|minimum| non_negative_amount(minimum, "min_deposit"))
+            .transpose()?;
+        let mut min_withdrawal = processor_config
+            .min_withdrawal
+            .as_deref()
+            .map(|minimum| non_negative_amount(minimum, "min_withdrawal"))
+            .transpose()?;
+        let mut validator = RecordValidator {
+            reject_zero_client: processor_config.reject_zero_client.unwrap_or_default(),
+            reject_zero_tx: processor_config.reject_zero_tx.unwrap_or_default(),
+            amount_ceiling: processor_config
+                .amount_ceiling
+                .as_deref()
+                .map(|ceiling| non_negative_amount(ceiling, "amount_ceiling"))
+                .transpose()?,
+            require_deposit_amount: processor_config.require_deposit_amount.unwrap_or_default(),
+        };
+        let mut held_policy = match &processor_config.held_policy {
+            Some(held_policy) => held_policy.parse()?,
+            None => HeldPolicy::default(),
+        };
+        let mut rejected_out = None;
+        let mut trace_clients = Vec::new();
+        let mut trace_dir = ".".to_owned();
+        let mut snapshot_every = None;
+        let mut snapshot_dir = ".".to_owned();
+        let mut snapshot_keep = None;
+        let mut disputes_out = None;
+        let mut disputes_report = None;
+        let mut held_detail = None;
+        let mut state_out = None;
+        let mut keep_memos = processor_config.keep_memos.unwrap_or_default();
+        let mut suspicious_out = None;
+        let mut suspicion_thresholds = SuspicionThresholds::new();
+        let mut run = RunOptions {
+            fail_fast: run_config.fail_fast.unwrap_or_default(),
+            fail_on_conflicting_duplicate: run_config
+                .fail_on_conflicting_duplicate
+                .unwrap_or_default(),
+            ..RunOptions::default()
+        };
+        if let Some(log_level) = &run_config.log_level {
+            run.log_level = log_level.parse()?;
+        }
+        let mut page_size = None;
+        let mut page_prefix = "output".to_owned();
+        let mut output = OutputOptions {
+            sort_by: match &output_config.sort_by {
+                Some(sort_by) => sort_by.parse()?,
+                None => Default::default(),
+            },
+            columns: match &output_config.columns {
+                Some(columns) => columns.parse()?,
+                None => Default::default(),
+            },
+            lock_reason: output_config.lock_reason.unwrap_or_default(),
+            dispute_flag: output_config.dispute_flag.unwrap_or_default(),
+            extended: output_config.extended.unwrap_or_default(),
+            excel: output_config.excel.unwrap_or_default(),
+            suppress_zero: output_config.suppress_zero.unwrap_or_default(),
+            ..OutputOptions::default()
+        };
+        let mut minor_units = output_config.minor_units.unwrap_or_default();
+        let mut minor_unit_digits = output_config
+            .minor_unit_digits
+            .unwrap_or(DEFAULT_MINOR_UNIT_DIGITS);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => config = Some(value(&arg, args.next())?),
+                "--fail-fast" => run.fail_fast = true,
+                "--fail-on-conflicting-duplicate" => run.fail_on_conflicting_duplicate = true,
+                "--no-partial-output" => partial_output = false,
+                "--log-level" => run.log_level = value(&arg, args.next())?.parse()?,
+                "--mmap" if cfg!(unix) => mmap = true,
+                "--parallel-chunks" => parallel_chunks = Some(count(&arg, args.next())?),
+                "--with-lock-reason" => output.lock_reason = true,
+                "--with-dispute-flag" => output.dispute_flag = true,
+                "--extended-output" => output.extended = true,
+                "--excel" => output.excel = true,
+                "--suppress-zero" => output.suppress_zero = true,
+                "--minor-units" => minor_units = true,
+                "--minor-unit-digits" => minor_unit_digits = count(&arg, args.next())?,
+                "--columns" => output.columns = value(&arg, args.next())?.parse()?,
+                "--output-shards" => output.shards = Some(value(&arg, args.next())?.parse()?),
+                "--page-size" => page_size = Some(count(&arg, args.next())?),
+                "--page-prefix" => page_prefix = value(&arg, args.next())?,
+                "--sort-by" => output.sort_by = value(&arg, args.next())?.parse()?,
+                "--stats" => stats = true,
+                "--verify" => verify = true,
+                "--fail-on" => fail_on = Some(value(&arg, args.next())?.parse()?),
+                "--summary-only" => summary_only = true,
+                "--cache-dir" => cache_dir = Some(value(&arg, args.next())?),
+                "--cache-limit" => cache_limit = Some(count(&arg, args.next())?),
+                "--clean-cache-on-exit" => clean_cache_on_exit = true,
+                "--strict-spill" => spill_policy = SpillFailurePolicy::Abort,
+                "--output-fallback" => output_fallback = Some(value(&arg, args.next())?),
+                "--max-tx-per-client" => max_tx_per_client = Some(count(&arg, args.next())?),
+                "--dispute-window" => dispute_window = Some(count(&arg, args.next())?),
+                "--allow-overdraft" => {
+                    overdraft_limit = Some(non_negative_amount(&value(&arg, args.next())?, &arg)?)
+                }
+                "--deny-withdrawal-overdraft" => overdraft_limit = None,
+                "--min-deposit" => {
+                    min_deposit = Some(non_negative_amount(&value(&arg, args.next())?, &arg)?)
+                }
+                "--min-withdrawal" => {
+                    min_withdrawal = Some(non_negative_amount(&value(&arg, args.next())?, &arg)?)
+                }
+                "--reject-zero-client" => validator.reject_zero_client = true,
+                "--reject-zero-tx" => validator.reject_zero_tx = true,
+                "--amount-ceiling" => {
+                    validator.amount_ceiling =
+                        Some(non_negative_amount(&value(&arg, args.next())?, &arg)?)
+                }
+                "--require-deposit-amount" => validator.require_deposit_amount = true,
+                "--clamp-held" => held_policy = HeldPolicy::Clamp,
+                "--strict-held" => held_policy = HeldPolicy::Strict,
+                "--rejected-out" => rejected_out = Some(value(&arg, args.next())?),
+                "--trace-client" => {
+                    trace_clients.push(value(&arg, args.next())?.trim().parse::<ClientId>()?)
+                }
+                "--trace-dir" => trace_dir = value(&arg, args.next())?,
+                "--snapshot-every" => snapshot_every = Some(count(&arg, args.next())?),
+                "--snapshot-dir" => snapshot_dir = value(&arg, args.next())?,
+                "--snapshot-keep" => snapshot_keep = Some(count(&arg, args.next())?),
+                "--disputes-out" => disputes_out = Some(value(&arg, args.next())?),
+                "--disputes-report" => disputes_report = Some(value(&arg, args.next())?),
+                "--held-detail" => held_detail = Some(value(&arg, args.next())?),
+                "--state-out" => state_out = Some(value(&arg, args.next())?),
+                "--keep-memos" => keep_memos = true,
+                "--suspicious-out" => suspicious_out = Some(value(&arg, args.next())?),
+                "--max-chargebacks" => {
+                    suspicion_thresholds =
+                        suspicion_thresholds.with_max_chargebacks(count(&arg, args.next())?);
+                }
+                "--max-charged-back" => {
+                    let amount: Amount = value(&arg, args.next())?
+                        .parse()
+                        .map_err(|err| format!("Invalid value for {}: {}", arg, err))?;
+                    suspicion_thresholds = suspicion_thresholds.with_max_charged_back(amount);
+                }
+                "--client" => {
+                    let clients = value(&arg, args.next())?
+                        .split(',')
+                        .map(|client| client.trim().parse::<ClientId>())
+                        .collect::<Result<Vec<_>, _>>()?;
+                    run.filter = run.filter.with_clients(clients);
+                }
+                "--currency-symbol" => {
+                    run.currency_symbol = Some(value(&arg, args.next())?);
+                }
+                "--input-format" => input_format = Some(value(&arg, args.next())?.parse()?),
+                "--input-encoding" | "--encoding" => {
+                    run.encoding = value(&arg, args.next())?.parse()?
+                }
+                "--delimiter" => run.csv.delimiter = byte(&arg, args.next())?,
+                "--tsv" => run.csv.delimiter = b'\t',
+                "--strict-schema" => run.csv.strict_schema = true,
+                "--comment" | "--comment-char" => run.csv.comment = Some(byte(&arg, args.next())?),
+                "--map" => {
+                    let columns = run.csv.columns.take().unwrap_or_default();
+                    run.csv.columns = Some(columns.map_pair(&value(&arg, args.next())?)?);
+                }
+                "--amount-column" => {
+                    let columns = run.csv.columns.take().unwrap_or_default();
+                    run.csv.columns = Some(columns.map("amount", &value(&arg, args.next())?)?);
+                }
+                "--on-malformed" => {
+                    run.on_malformed = malformed_row_policy(&value(&arg, args.next())?)?;
+                }
+                "--skip" => run.skip = count(&arg, args.next())?,
+                "--take" => run.take = Some(count(&arg, args.next())?),
+                "--chunk-size" => run.chunk_size = Some(count(&arg, args.next())?),
+                "--types" => {
+                    let types = value(&arg, args.next())?;
+                    let types: Vec<&str> = types.split(',').map(str::trim).collect();
+                    if let Some(unknown) =
+                        types.iter().find(|name| !TRANSACTION_TYPES.contains(name))
+                    {
+                        return Err(format!(
+                            "Unknown transaction type {} given to --types, expected one of {}",
+                            unknown,
+                            TRANSACTION_TYPES.join(", ")
+                        ));
+                    }
+                    run.filter = run.filter.with_types(types);
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(format!("Unknown option {}\n{}", arg, USAGE));
+                }
+                _ if input.is_none() => input = Some(arg),
+                _ => return Err(format!("Unexpected argument {}\n{}", arg, USAGE)),
+            }
+        }
+        if minor_unit_digits > PRECISION {
+            return Err(format!(
+                "--minor-unit-digits needs at most {} digits\n{}",
+                PRECISION, USAGE
+            ));
+        }
+        output.minor_units = Some(minor_unit_digits).filter(|_| minor_units);
+        if let Some(size) = page_size {
+            if size == 0 || output.shards.is_some() {
+                return Err(format!(
+                    "--page-size needs a positive size and no --output-shards\n{}",
+                    USAGE
+                ));
+            }
+            output.pages = Some(OutputPages {
+                prefix: PathBuf::from(page_prefix),
+                size,
+            });
+        }
+        if snapshot_every == Some(0) || snapshot_keep == Some(0) || run.chunk_size == Some(0) {
+            return Err(format!(
+                "--snapshot-every, --snapshot-keep and --chunk-size need a positive count\n{}",
+                USAGE
+            ));
+        }
+        if parallel_chunks.is_some_and(|chunks| chunks == 0 || chunks > MAX_PARALLEL_CHUNKS) {
+            return Err(format!(
+                "--parallel-chunks needs between 1 and {} chunks\n{}",
+                MAX_PARALLEL_CHUNKS, USAGE
+            ));
+        }
+
+        Ok(Options {
+            input: match input {
+                Some(input) => input,
+                None if require_input => return Err(USAGE.to_owned()),
+                None => String::new(),
+            },
+            config,
+            partial_output,
+            input_format,
+            mmap,
+            parallel_chunks,
+            stats,
+            verify,
+            fail_on,
+            summary_only,
+            cache_dir,
+            cache_limit,
+            clean_cache_on_exit,
+            spill_policy,
+            output_fallback,
+            max_tx_per_client,
+            dispute_window,
+            overdraft_limit,
+            min_deposit,
+            min_withdrawal,
+            validator,
+            held_policy,
+            rejected_out,
+            trace_clients,
+            trace_dir,
+            snapshot_every,
+            snapshot_dir,
+            snapshot_keep,
+            disputes_out,
+            disputes_report,
+            held_detail,
+            state_out,
+            keep_memos,
+            suspicious_out,
+            // Without thresholds every client with a chargeback is reported.
+            suspicion_thresholds: if suspicion_thresholds == SuspicionThresholds::new() {
+                suspicion_thresholds.with_max_chargebacks(0)
+            } else {
+                suspicion_thresholds
+            },
+            run,
+            output,
+        })
+    }
+}
+
+impl Options {
+    /// Returns every setting which can be read from a config file, as
+    /// resolved from the file and the options.
+    pub fn effective_config(&self) -> Config {
+        Config {
+            processor: ProcessorConfig {
+                held_policy: Some(self.held_policy.to_string()),
+                max_tx_per_client: self.max_tx_per_client,
+                dispute_window: self.dispute_window,
+                overdraft_limit: self.overdraft_limit.map(|limit| limit.to_string()),
+                min_deposit: self.min_deposit.map(|minimum| minimum.to_string()),
+                min_withdrawal: self.min_withdrawal.map(|minimum| minimum.to_string()),
+                reject_zero_client: Some(self.validator.reject_zero_client),
+                reject_zero_tx: Some(self.validator.reject_zero_tx),
+                amount_ceiling: self
+                    .validator
+                    .amount_ceiling
+                    .map(|ceiling| ceiling.to_string()),
+                require_deposit_amount: Some(self.validator.require_deposit_amount),
+                keep_memos: Some(self.keep_memos),
+                cache_limit: self.cache_limit,
+                cache_dir: self.cache_dir.clone(),
+                clean_cache_on_exit: Some(self.clean_cache_on_exit),
+                strict_spill: Some(self.spill_policy == SpillFailurePolicy::Abort),
+            },
+            run: RunConfig {
+                fail_fast: Some(self.run.fail_fast),
+                fail_on_conflicting_duplicate: Some(self.run.fail_on_conflicting_duplicate),
+                partial_output: Some(self.partial_output),
+                parallel_chunks: self.parallel_chunks.map(|chunks| chunks as u64),
+                stats: Some(self.stats),
+                verify: Some(self.verify),
+                fail_on: self.fail_on.map(|severity| severity.to_string()),
+                log_level: Some(self.run.log_level.to_string()),
+            },
+            output: OutputConfig {
+                sort_by: Some(self.output.sort_by.to_string()),
+                columns: Some(self.output.columns.to_string()),
+                lock_reason: Some(self.output.lock_reason),
+                dispute_flag: Some(self.output.dispute_flag),
+                extended: Some(self.output.extended),
+                excel: Some(self.output.excel),
+                suppress_zero: Some(self.output.suppress_zero),
+                minor_units: Some(self.output.minor_units.is_some()),
+                minor_unit_digits: self.output.minor_units,
+            },
+        }
+    }
+}
+
+/// Parses an overdraft limit or a minimum amount, which can not be negative.
+fn non_negative_amount(amount: &str, option: &str) -> Result<Amount, String> {
+    let amount: Amount = amount
+        .parse()
+        .map_err(|err| format!("Invalid value for {}: {}", option, err))?;
+    if amount < Amount::new() {
+        return Err(format!(
+            "Invalid value for {}: {} is negative",
+            option, amount
+        ));
+    }
+    Ok(amount)
+}
+
+/// Parses the policy given to --on-malformed, the quarantine file is created
+/// right away.
+fn malformed_row_policy(policy: &str) -> Result<MalformedRowPolicy, String> {
+    match policy.split_once('=') {
+        None if policy == "skip" => Ok(MalformedRowPolicy::Skip),
+        None if policy == "abort" => Ok(MalformedRowPolicy::Abort),
+        Some(("quarantine", path)) => {
+            let file = File::create(path)
+                .map_err(|err| format!("Could not create quarantine file {}: {}", path, err))?;
+            Ok(MalformedRowPolicy::quarantine(file))
+        }
+        _ => Err(format!(
+            "Invalid value {} for --on-malformed, expected skip, abort or quarantine=<path>",
+            policy
+        )),
+    }
+}
+
+/// Returns the number given to an option, a value out of the range of T
+/// being rejected rather than truncated.
+fn count<T>(option: &str, count: Option<String>) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let count = value(option, count)?;
+    count
+        .parse()
+        .map_err(|err| format!("Invalid value {} for {}: {}", count, option, err))
+}
+
+/// Returns the single ascii character given to an option.
+fn byte(option: &str, byte: Option<String>) -> Result<u8, String> {
+    match value(option, byte)?.as_bytes() {
+        [byte] => Ok(*byte),
+        _ => Err(format!("{} expects a single ascii character", option)),
+    }
+}
+
+/// Returns the value given to an option, failing if it is missing.
+fn value(option: &str, value: Option<String>) -> Result<String, String> {
+    usage_value(option, value, USAGE)
+}
+
+/// Returns the value given to an option of the command with the given usage.
+fn usage_value(option: &str, value: Option<String>, usage: &str) -> Result<String, String> {
+    value.ok_or_else(|| format!("Missing value for {}\n{}", option, usage))
+}
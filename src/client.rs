@@ -1,178 +1,2034 @@
-use crate::transaction_cache::TransactionCache;
+use crate::error::ProcessingError;
+use crate::metrics::CacheStats;
+use crate::money::MoneyContext;
+use crate::processor::{OutputColumn, OutputOptions};
+use crate::reports::{DisputeEvent, DisputeState};
+use crate::state::ClientState;
+use crate::store::{CacheStores, StoreFamily, TransactionStore};
+use crate::transaction_cache::SpillFailurePolicy;
 use crate::type_defs::{Amount, ClientId};
-use crate::type_defs::{Transaction, TransactionId};
+use crate::type_defs::{
+    StoredTransaction, Transaction, TransactionId, TransactionKind, TransactionNotes,
+    TransactionRecord,
+};
 use csv::Writer;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Type which holds a read-only snapshot of the client balances.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ClientView {
+    pub client_id: ClientId,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+/// Type given to the handlers of custom transaction types, it exposes the
+/// primitives to safely change the client balances. The changes are only
+/// applied to the client when the handler succeeds.
+#[derive(Debug)]
+pub struct ClientHandle {
+    view: ClientView,
+    money: MoneyContext,
+}
+
+impl ClientHandle {
+    pub fn view(&self) -> &ClientView {
+        &self.view
+    }
+
+    /// Adds funds to the available and total balances, failing with
+    /// `ProcessingError::Overflow` when they do not fit.
+    pub fn credit(&mut self, amount: Amount) -> Result<(), ProcessingError> {
+        let available = self.money.add(self.view.available, amount)?;
+        self.view.total = self.money.add(self.view.total, amount)?;
+        self.view.available = available;
+        Ok(())
+    }
+
+    /// Removes funds from the available and total balances.
+    pub fn debit(&mut self, amount: Amount) -> Result<(), ProcessingError> {
+        if amount > self.view.available {
+            return Err(ProcessingError::InsufficientFunds);
+        }
+        let available = self.money.sub(self.view.available, amount)?;
+        self.view.total = self.money.sub(self.view.total, amount)?;
+        self.view.available = available;
+        Ok(())
+    }
+
+    pub fn lock(&mut self) {
+        self.view.locked = true;
+    }
+}
+
+/// What to do when releasing a disputed amount would make the held balance
+/// negative, which only happens when the disputed transactions and the held
+/// balance got out of sync.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HeldPolicy {
+    /// Release the whole disputed amount even if held goes negative.
+    #[default]
+    Allow,
+    /// Only release what is held, so held stops at zero, and log a warning.
+    Clamp,
+    /// Reject the resolve or chargeback with `ProcessingError::NegativeHeld`.
+    Strict,
+}
+
+impl FromStr for HeldPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "allow" => Ok(HeldPolicy::Allow),
+            "clamp" => Ok(HeldPolicy::Clamp),
+            "strict" => Ok(HeldPolicy::Strict),
+            _ => Err(format!(
+                "Unknown held policy {}, expected allow, clamp or strict",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HeldPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeldPolicy::Allow => write!(f, "allow"),
+            HeldPolicy::Clamp => write!(f, "clamp"),
+            HeldPolicy::Strict => write!(f, "strict"),
+        }
+    }
+}
+
+/// Rule allowing at most `max_withdrawals` withdrawals within any window of
+/// `window` consecutive transactions applied to a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VelocityLimit {
+    pub max_withdrawals: usize,
+    pub window: usize,
+}
+
+/// Status of a client account, only active accounts process transactions.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    /// Locked by the given chargeback, or custom transaction.
+    Locked { by: TransactionId },
+    /// Closed by an admin, only reopening it makes it active again.
+    Closed,
+    /// Frozen pending a review.
+    Frozen,
+}
+
+impl AccountStatus {
+    /// Returns whether the status may change into the given one. A closed
+    /// account only changes by being reopened, i.e. made active, and a locked
+    /// account is only unlocked or closed.
+    pub fn can_change_to(&self, status: AccountStatus) -> bool {
+        match (self, status) {
+            (AccountStatus::Active, AccountStatus::Active) => false,
+            (AccountStatus::Closed, status) => status == AccountStatus::Active,
+            (AccountStatus::Locked { .. }, AccountStatus::Locked { .. }) => false,
+            (AccountStatus::Locked { .. }, AccountStatus::Frozen) => false,
+            (AccountStatus::Frozen, AccountStatus::Frozen) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountStatus::Active => write!(f, "active"),
+            AccountStatus::Locked { .. } => write!(f, "locked"),
+            AccountStatus::Closed => write!(f, "closed"),
+            AccountStatus::Frozen => write!(f, "frozen"),
+        }
+    }
+}
 
 /// Type that abstracts a Client.
 /// It keeps track of all the transactions which reference a certain client and
-/// all the disputed transactions.
+/// all the disputed transactions, in stores of the `StoreFamily` S, which
+/// spill to disk by default.
 #[derive(Debug)]
-pub struct Client<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32> {
+pub struct Client<
+    const CACHE_SIZE_LIMIT: u64,
+    const CACHE_LINE_SIZE: u32,
+    S: StoreFamily = CacheStores<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
+> {
     client_id: ClientId,
     available: Amount,
     held: Amount,
     total: Amount,
-    locked: bool,
-    processed_transactions: TransactionCache<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
-    disputed: TransactionCache<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
+    status: AccountStatus,
+    held_policy: HeldPolicy,
+    /// Context the balances are combined with, an update which overflows
+    /// them is rejected or saturates according to its overflow policy.
+    money: MoneyContext,
+    /// Maximum number of transactions stored for the client.
+    max_transactions: Option<u64>,
+    /// Number of transactions stored for the client.
+    transaction_count: u64,
+    velocity_limit: Option<VelocityLimit>,
+    /// How far the available balance may go negative through withdrawals,
+    /// withdrawals exceeding the available balance are rejected when unset.
+    overdraft_limit: Option<Amount>,
+    /// Smallest amounts of the deposits and withdrawals, smaller ones are
+    /// rejected. Any amount is accepted when unset.
+    min_deposit: Option<Amount>,
+    min_withdrawal: Option<Amount>,
+    /// Number of transactions applied to the client so far.
+    sequence: u64,
+    /// Maximum number of transactions applied to the client after a deposit
+    /// for the deposit to still be disputable.
+    dispute_window: Option<u64>,
+    /// Whether each of the last transactions applied to the client was a
+    /// withdrawal, only the ones which can still share a window with the next
+    /// withdrawal are kept.
+    recent_withdrawals: VecDeque<bool>,
+    /// Transactions charged back, the account is locked by the first one.
+    chargebacks: Vec<TransactionId>,
+    /// Cumulative amount charged back.
+    charged_back: Amount,
+    /// Cumulative change of the total made outside of the deposits and
+    /// withdrawals, by the handlers of custom types and the fees.
+    adjustments: Amount,
+    /// Number of deposits and withdrawals applied.
+    deposits_count: u64,
+    withdrawals_count: u64,
+    /// Number of deposits currently under dispute.
+    open_disputes_count: u64,
+    /// Sum of the amounts held for the deposits under dispute, maintained
+    /// with the disputed store so held can be checked against it.
+    disputed_held: Amount,
+    /// Set when held may differ from disputed_held, for the clients created
+    /// over a disputed store which already held deposits.
+    held_out_of_sync: bool,
+    /// Whether a deposit of the client was ever disputed, even if resolved
+    /// since.
+    ever_disputed: bool,
+    /// Disputes, resolves and chargebacks applied to the client in order,
+    /// only recorded when set.
+    dispute_events: Option<Vec<DisputeEvent>>,
+    processed_transactions: S::Store<TransactionId, StoredTransaction>,
+    disputed: S::Store<TransactionId, Transaction>,
+    /// Timestamps and memos of the processed transactions which had one,
+    /// created with the first of them.
+    notes: Option<S::Store<TransactionId, TransactionNotes>>,
+    /// Directory in which the caches are created, the system temporary
+    /// directory is used when unset.
+    cache_dir: Option<PathBuf>,
+    /// Set for the clients created with `in_memory`, their later stores are
+    /// kept in memory too.
+    memory_only: bool,
+    /// What the caches do when they can not spill to disk.
+    spill_policy: SpillFailurePolicy,
+    /// Number of transactions each cache holds in memory, CACHE_SIZE_LIMIT
+    /// when unset.
+    cache_limit: Option<u64>,
 }
 
-impl<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>
-    Client<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>
+impl<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32, S: StoreFamily>
+    Client<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE, S>
 {
-    pub fn new(client_id: ClientId) -> Result<Self, String> {
+    pub fn new(client_id: ClientId) -> Result<Self, ProcessingError> {
         Ok(Self::new_with_cache(
             client_id,
-            TransactionCache::new()?,
-            TransactionCache::new()?,
+            S::create(None).map_err(ProcessingError::Cache)?,
+            S::create(None).map_err(ProcessingError::Cache)?,
         ))
     }
 
+    /// Creates a client whose caches spill their transactions into new
+    /// subdirectories of cache_dir.
+    pub fn new_in(client_id: ClientId, cache_dir: &Path) -> Result<Self, ProcessingError> {
+        let mut client = Self::new_with_cache(
+            client_id,
+            S::create(Some(cache_dir)).map_err(ProcessingError::Cache)?,
+            S::create(Some(cache_dir)).map_err(ProcessingError::Cache)?,
+        );
+        client.cache_dir = Some(cache_dir.to_path_buf());
+        Ok(client)
+    }
+
+    /// Creates a client whose stores never touch the disk, e.g. when no cache
+    /// dir can be created.
+    pub fn in_memory(client_id: ClientId) -> Result<Self, ProcessingError> {
+        let mut client = Self::new_with_cache(
+            client_id,
+            S::create_in_memory().map_err(ProcessingError::Cache)?,
+            S::create_in_memory().map_err(ProcessingError::Cache)?,
+        );
+        client.memory_only = true;
+        Ok(client)
+    }
+
+    /// Creates a client over the given stores, which may already hold
+    /// transactions, e.g. caches reopened from disk. The open disputes are
+    /// counted from the disputed store.
     pub fn new_with_cache(
         client_id: ClientId,
-        processed_transactions: TransactionCache<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
-        disputed: TransactionCache<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
+        processed_transactions: S::Store<TransactionId, StoredTransaction>,
+        mut disputed: S::Store<TransactionId, Transaction>,
     ) -> Self {
+        let mut open_disputes_count = 0;
+        let mut disputed_held = Amount::new();
+        disputed.for_each(|_, transaction| {
+            open_disputes_count += 1;
+            disputed_held += transaction.amount().unwrap_or_default();
+        });
         Client {
             client_id,
             available: Amount::new(),
             held: Amount::new(),
             total: Amount::new(),
-            locked: false,
+            status: AccountStatus::Active,
+            held_policy: HeldPolicy::default(),
+            money: MoneyContext::default(),
+            max_transactions: None,
+            transaction_count: 0,
+            velocity_limit: None,
+            overdraft_limit: None,
+            min_deposit: None,
+            min_withdrawal: None,
+            sequence: 0,
+            dispute_window: None,
+            recent_withdrawals: VecDeque::new(),
+            chargebacks: Vec::new(),
+            charged_back: Amount::new(),
+            adjustments: Amount::new(),
+            deposits_count: 0,
+            withdrawals_count: 0,
+            open_disputes_count,
+            disputed_held,
+            // The balances start at zero whatever the disputes already held.
+            held_out_of_sync: open_disputes_count > 0,
+            ever_disputed: open_disputes_count > 0,
+            dispute_events: None,
             processed_transactions,
             disputed,
+            notes: None,
+            cache_dir: None,
+            memory_only: false,
+            spill_policy: SpillFailurePolicy::default(),
+            cache_limit: None,
         }
     }
 
-    pub fn can_process(&self) -> Result<(), String> {
-        if self.locked {
-            return Err("Account locked".to_owned());
+    pub fn with_held_policy(mut self, held_policy: HeldPolicy) -> Self {
+        self.held_policy = held_policy;
+        self
+    }
+
+    /// Combines the balances with money, e.g. the context of the processor.
+    pub fn with_money_context(mut self, money: MoneyContext) -> Self {
+        self.money = money;
+        self
+    }
+
+    /// Limits the number of deposits, withdrawals and custom transactions
+    /// stored for the client, further ones are rejected.
+    pub fn with_max_transactions(mut self, max_transactions: Option<u64>) -> Self {
+        self.max_transactions = max_transactions;
+        self
+    }
+
+    /// Rejects the disputes of the deposits followed by more than
+    /// dispute_window transactions of the client.
+    pub fn with_dispute_window(mut self, dispute_window: Option<u64>) -> Self {
+        self.dispute_window = dispute_window;
+        self
+    }
+
+    pub fn with_velocity_limit(mut self, velocity_limit: Option<VelocityLimit>) -> Self {
+        self.velocity_limit = velocity_limit;
+        self
+    }
+
+    /// Lets the withdrawals take the available balance down to minus
+    /// overdraft_limit.
+    pub fn with_overdraft_limit(mut self, overdraft_limit: Option<Amount>) -> Self {
+        self.overdraft_limit = overdraft_limit;
+        self
+    }
+
+    /// Rejects the deposits below min_deposit.
+    pub fn with_min_deposit(mut self, min_deposit: Option<Amount>) -> Self {
+        self.min_deposit = min_deposit;
+        self
+    }
+
+    /// Records the disputes, resolves and chargebacks of the client so they
+    /// can be reported with `dispute_events`.
+    pub fn with_dispute_events(mut self, record: bool) -> Self {
+        self.dispute_events = if record { Some(Vec::new()) } else { None };
+        self
+    }
+
+    /// Rejects the withdrawals below min_withdrawal.
+    pub fn with_min_withdrawal(mut self, min_withdrawal: Option<Amount>) -> Self {
+        self.min_withdrawal = min_withdrawal;
+        self
+    }
+
+    /// Sets what the caches of the client do when they can not spill to disk.
+    pub fn with_spill_policy(mut self, spill_policy: SpillFailurePolicy) -> Self {
+        self.spill_policy = spill_policy;
+        self.processed_transactions.set_spill_policy(spill_policy);
+        self.disputed.set_spill_policy(spill_policy);
+        if let Some(notes) = self.notes.as_mut() {
+            notes.set_spill_policy(spill_policy);
+        }
+        self
+    }
+
+    /// Sets the number of transactions each cache of the client holds in
+    /// memory before spilling, instead of CACHE_SIZE_LIMIT.
+    pub fn with_cache_limit(mut self, cache_limit: Option<u64>) -> Self {
+        self.cache_limit = cache_limit;
+        if let Some(limit) = cache_limit {
+            self.processed_transactions.set_cache_limit(limit);
+            self.disputed.set_cache_limit(limit);
+            if let Some(notes) = self.notes.as_mut() {
+                notes.set_cache_limit(limit);
+            }
         }
+        self
+    }
+
+    /// Returns why one of the caches could not spill to disk, only set with
+    /// `SpillFailurePolicy::Abort`.
+    pub fn spill_error(&self) -> Option<&str> {
+        self.processed_transactions
+            .spill_error()
+            .or_else(|| self.disputed.spill_error())
+            .or_else(|| self.notes.as_ref().and_then(|notes| notes.spill_error()))
+    }
+
+    pub fn can_process(&self) -> Result<(), ProcessingError> {
+        match self.status {
+            AccountStatus::Active => Ok(()),
+            AccountStatus::Locked { .. } => Err(ProcessingError::AccountLocked),
+            AccountStatus::Closed => Err(ProcessingError::AccountClosed),
+            AccountStatus::Frozen => Err(ProcessingError::AccountFrozen),
+        }
+    }
+
+    /// Changes the status of the account, e.g. to freeze it or for an admin
+    /// to unlock or reopen it, failing when `AccountStatus::can_change_to`
+    /// does not allow it.
+    pub fn set_status(&mut self, status: AccountStatus) -> Result<(), ProcessingError> {
+        if !self.status.can_change_to(status) {
+            return Err(ProcessingError::InvalidStatusTransition {
+                from: self.status,
+                to: status,
+            });
+        }
+        self.status = status;
         Ok(())
     }
-    pub fn deposit(&mut self, transaction: Transaction) -> Result<(), String> {
-        self.can_process()?;
-        if let Transaction::Deposit(_, transaction_id, amount) = transaction {
-            if self.processed_transactions.contains_key(&transaction_id) {
-                return Err("Transaction already processed".to_owned());
+
+    fn check_minimum(
+        kind: TransactionKind,
+        amount: Amount,
+        minimum: Option<Amount>,
+    ) -> Result<(), ProcessingError> {
+        match minimum {
+            Some(minimum) if amount < minimum => {
+                Err(ProcessingError::BelowMinimum { kind, minimum })
             }
-            self.available += amount;
-            self.total += amount;
-            self.processed_transactions
-                .insert(transaction_id, transaction);
-            return Ok(());
+            _ => Ok(()),
         }
-        Err("Wrong transaction type, expected deposit".to_owned())
     }
 
-    pub fn withdraw(&mut self, transaction: Transaction) -> Result<(), String> {
+    pub fn deposit(&mut self, tx: TransactionId, amount: Amount) -> Result<(), ProcessingError> {
         self.can_process()?;
+        self.check_duplicate(tx, TransactionKind::Deposit, amount)?;
+        Self::check_minimum(TransactionKind::Deposit, amount, self.min_deposit)?;
+        self.check_transaction_limit()?;
+        let available = self.money.add(self.available, amount)?;
+        self.total = self.money.add(self.total, amount)?;
+        self.available = available;
+        self.deposits_count += 1;
+        self.record_applied(false);
+        self.store_transaction(
+            tx,
+            Transaction::Deposit {
+                client: self.client_id,
+                tx,
+                amount,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn withdraw(&mut self, tx: TransactionId, amount: Amount) -> Result<(), ProcessingError> {
+        self.can_process()?;
+        self.check_duplicate(tx, TransactionKind::Withdrawal, amount)?;
+        Self::check_minimum(TransactionKind::Withdrawal, amount, self.min_withdrawal)?;
+        self.check_transaction_limit()?;
+        self.check_velocity()?;
+
+        let overdraft_limit = self.overdraft_limit.unwrap_or_default();
+        let available = self.money.sub(self.available, amount)?;
+        if available >= -overdraft_limit {
+            self.total = self.money.sub(self.total, amount)?;
+            self.available = available;
+            self.withdrawals_count += 1;
+            self.record_applied(true);
+            self.store_transaction(
+                tx,
+                Transaction::Withdrawal {
+                    client: self.client_id,
+                    tx,
+                    amount,
+                },
+            );
+            return Ok(());
+        }
+        Err(ProcessingError::InsufficientFunds)
+    }
+
+    /// Checks that one more transaction can be stored for the client.
+    fn check_transaction_limit(&self) -> Result<(), ProcessingError> {
+        if self
+            .max_transactions
+            .is_some_and(|max| self.transaction_count >= max)
+        {
+            return Err(ProcessingError::ClientTransactionLimitExceeded);
+        }
+        Ok(())
+    }
+
+    /// Stores a transaction applied with the current sequence number.
+    fn store_transaction(&mut self, tx: TransactionId, transaction: Transaction) {
+        self.transaction_count += 1;
+        self.processed_transactions.insert(
+            tx,
+            StoredTransaction {
+                transaction,
+                sequence: self.sequence,
+            },
+        );
+    }
+
+    /// Checks that one more withdrawal keeps the client within its velocity
+    /// limit.
+    fn check_velocity(&self) -> Result<(), ProcessingError> {
+        match self.velocity_limit {
+            Some(limit) => {
+                let withdrawals = self.recent_withdrawals.iter().filter(|w| **w).count();
+                if withdrawals >= limit.max_withdrawals {
+                    return Err(ProcessingError::VelocityLimitExceeded {
+                        limit: limit.max_withdrawals,
+                        window: limit.window,
+                    });
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
 
-        if let Transaction::Withdrawal(_, transaction_id, amount) = transaction {
-            if self.processed_transactions.contains_key(&transaction_id) {
-                return Err("Transaction already processed".to_owned());
+    /// Records an applied transaction, advancing the sequence number and the
+    /// velocity window. Only the last window - 1 transactions can share a
+    /// window with the next withdrawal.
+    fn record_applied(&mut self, withdrawal: bool) {
+        self.sequence += 1;
+        if let Some(limit) = self.velocity_limit {
+            self.recent_withdrawals.push_back(withdrawal);
+            while self.recent_withdrawals.len() > limit.window.saturating_sub(1) {
+                self.recent_withdrawals.pop_front();
             }
+        }
+    }
 
-            if amount <= self.available {
-                self.available -= amount;
-                self.total -= amount;
-                self.processed_transactions
-                    .insert(transaction_id, transaction);
-                return Ok(());
+    /// Checks that the transaction id has not been processed yet, telling apart
+    /// the replays of the same transaction from the conflicting ones.
+    fn check_duplicate(
+        &mut self,
+        tx: TransactionId,
+        kind: TransactionKind,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        match self
+            .processed_transactions
+            .get(&tx)
+            .map(|stored| stored.transaction)
+        {
+            None => Ok(()),
+            Some(stored) if stored.kind() == kind && stored.amount() == Some(amount) => {
+                Err(ProcessingError::DuplicateReplay)
             }
-            return Err("Insufficient funds".to_owned());
+            Some(stored) => Err(ProcessingError::ConflictingDuplicate {
+                stored_amount: stored.amount().filter(|_| stored.kind() == kind),
+                new_amount: amount,
+            }),
         }
+    }
 
-        Err("Wrong transaction type, expected withdraw".to_owned())
+    pub fn dispute(
+        &mut self,
+        disputed_transaction_id: &TransactionId,
+    ) -> Result<(), ProcessingError> {
+        self.dispute_portion(disputed_transaction_id, None)
     }
 
-    pub fn dispute(&mut self, disputed_transaction_id: &TransactionId) -> Result<(), String> {
+    /// Disputes the given portion of a deposit, or the whole deposit when no
+    /// portion is given. Only the disputed portion is held, then released or
+    /// charged back.
+    pub fn dispute_portion(
+        &mut self,
+        disputed_transaction_id: &TransactionId,
+        portion: Option<Amount>,
+    ) -> Result<(), ProcessingError> {
         if self.disputed.contains_key(disputed_transaction_id) {
-            return Err("Transaction already processed".to_owned());
+            return Err(ProcessingError::AlreadyDisputed);
         }
 
-        let disputed_transaction = self
+        let stored = *self
             .processed_transactions
             .get(disputed_transaction_id)
-            .ok_or("Could not find disputed transaction")?;
-        if let Transaction::Deposit(_, transaction_id, amount) = disputed_transaction {
-            self.available -= *amount;
-            self.held += *amount;
-            self.disputed.insert(*transaction_id, *disputed_transaction);
+            .ok_or(ProcessingError::TransactionNotFound)?;
+        let disputed_transaction = stored.transaction;
+        if disputed_transaction.client_id() != self.client_id {
+            return Err(ProcessingError::ClientMismatch);
+        }
+        if let Transaction::Deposit { client, tx, amount } = disputed_transaction {
+            let age = self.sequence - stored.sequence;
+            if let Some(window) = self.dispute_window.filter(|window| age > *window) {
+                return Err(ProcessingError::StaleDispute { age, window });
+            }
+            let held = match portion {
+                Some(portion) if portion <= Amount::new() => {
+                    return Err(ProcessingError::InvalidAmount(format!(
+                        "{}: disputed portion must be positive",
+                        portion
+                    )));
+                }
+                Some(portion) if portion > amount => {
+                    return Err(ProcessingError::DisputeExceedsDeposit {
+                        disputed: portion,
+                        deposited: amount,
+                    });
+                }
+                Some(portion) => portion,
+                None => amount,
+            };
+            let available = self.money.sub(self.available, held)?;
+            let total_held = self.money.add(self.held, held)?;
+            self.disputed_held = self.money.add(self.disputed_held, held)?;
+            self.available = available;
+            self.held = total_held;
+            self.open_disputes_count += 1;
+            self.ever_disputed = true;
+            // The deposit is kept with the held portion as amount so resolves
+            // and chargebacks only release that portion.
+            self.disputed.insert(
+                tx,
+                Transaction::Deposit {
+                    client,
+                    tx,
+                    amount: held,
+                },
+            );
+            self.record_dispute_event(tx, held, DisputeState::Disputed);
+            self.record_applied(false);
+            self.check_held_breakdown();
             return Ok(());
         }
 
-        Err("Wrong transaction type".to_owned())
+        Err(ProcessingError::NotDisputable)
+    }
+
+    pub fn resolve(
+        &mut self,
+        disputed_transaction_id: &TransactionId,
+    ) -> Result<(), ProcessingError> {
+        self.check_disputed(disputed_transaction_id)?;
+        self.can_process()?;
+
+        let (disputed_held, amount) = self.releasable_disputed(disputed_transaction_id)?;
+        let available = self.money.add(self.available, amount)?;
+        self.held = self.money.sub(self.held, amount)?;
+        self.available = available;
+        self.release_disputed(disputed_transaction_id, disputed_held);
+        self.record_dispute_event(*disputed_transaction_id, amount, DisputeState::Resolved);
+        self.record_applied(false);
+        self.check_held_breakdown();
+        Ok(())
     }
 
-    pub fn resolve(&mut self, disputed_transaction_id: &TransactionId) -> Result<(), String> {
+    pub fn chargeback(
+        &mut self,
+        disputed_transaction_id: &TransactionId,
+    ) -> Result<(), ProcessingError> {
+        self.check_disputed(disputed_transaction_id)?;
         self.can_process()?;
 
-        let disputed_transaction = self
+        let (disputed_held, amount) = self.releasable_disputed(disputed_transaction_id)?;
+        let total = self.money.sub(self.total, amount)?;
+        let held = self.money.sub(self.held, amount)?;
+        self.record_chargeback(*disputed_transaction_id, amount)?;
+        self.total = total;
+        self.held = held;
+        self.release_disputed(disputed_transaction_id, disputed_held);
+        self.status = AccountStatus::Locked {
+            by: *disputed_transaction_id,
+        };
+        self.record_dispute_event(*disputed_transaction_id, amount, DisputeState::ChargedBack);
+        self.record_applied(false);
+        self.check_held_breakdown();
+        Ok(())
+    }
+
+    /// Counts a chargeback of the given amount against the client.
+    pub(crate) fn record_chargeback(
+        &mut self,
+        tx: TransactionId,
+        amount: Amount,
+    ) -> Result<(), ProcessingError> {
+        self.charged_back = self.money.add(self.charged_back, amount)?;
+        self.chargebacks.push(tx);
+        Ok(())
+    }
+
+    fn record_dispute_event(&mut self, tx: TransactionId, amount: Amount, event: DisputeState) {
+        if let Some(events) = &mut self.dispute_events {
+            events.push(DisputeEvent {
+                client_id: self.client_id,
+                tx,
+                amount,
+                event,
+                final_state: event,
+            });
+        }
+    }
+
+    /// Checks that the transaction is under dispute. It is checked before the
+    /// account lock so acting twice on the same dispute, e.g. a chargeback
+    /// followed by a resolve, is always reported as not under dispute.
+    fn check_disputed(
+        &mut self,
+        disputed_transaction_id: &TransactionId,
+    ) -> Result<(), ProcessingError> {
+        if self.disputed.contains_key(disputed_transaction_id) {
+            Ok(())
+        } else {
+            Err(ProcessingError::NotDisputed)
+        }
+    }
+
+    /// Returns the sum held for the disputes once the transaction is no longer
+    /// disputed and the amount to release from held, failing if it does not
+    /// belong to this client or if the held policy rejects it. The dispute is
+    /// only closed by `release_disputed` once the balances were updated.
+    fn releasable_disputed(
+        &mut self,
+        disputed_transaction_id: &TransactionId,
+    ) -> Result<(Amount, Amount), ProcessingError> {
+        let disputed_transaction = *self
             .disputed
-            .remove(disputed_transaction_id)
-            .ok_or("Could not find disputed transaction")?;
-        if let Transaction::Deposit(_, _, amount) = disputed_transaction {
-            self.available += amount;
-            self.held -= amount;
-            return Ok(());
+            .get(disputed_transaction_id)
+            .ok_or(ProcessingError::NotDisputed)?;
+        if disputed_transaction.client_id() != self.client_id {
+            return Err(ProcessingError::ClientMismatch);
+        }
+        if let Transaction::Deposit { amount, .. } = disputed_transaction {
+            let releasable = self.releasable(amount)?;
+            return Ok((self.money.sub(self.disputed_held, amount)?, releasable));
+        }
+        Err(ProcessingError::NotDisputable)
+    }
+
+    /// Removes a transaction from the disputed ones, disputed_held being the
+    /// sum returned by `releasable_disputed`.
+    fn release_disputed(&mut self, disputed_transaction_id: &TransactionId, disputed_held: Amount) {
+        self.disputed.remove(disputed_transaction_id);
+        self.disputed_held = disputed_held;
+        self.open_disputes_count -= 1;
+    }
+
+    /// Returns the amount which can be released from held according to the
+    /// held policy.
+    fn releasable(&self, amount: Amount) -> Result<Amount, ProcessingError> {
+        if amount <= self.held {
+            return Ok(amount);
+        }
+        match self.held_policy {
+            HeldPolicy::Allow => Ok(amount),
+            HeldPolicy::Clamp => {
+                eprintln!(
+                    "Warning: clamping held of client {} at zero, releasing {} instead of {}",
+                    self.client_id, self.held, amount
+                );
+                Ok(self.held.max(Amount::new()))
+            }
+            HeldPolicy::Strict => Err(ProcessingError::NegativeHeld {
+                held: self.held,
+                amount,
+            }),
         }
-        Err("Wrong transaction type, expected resolve".to_owned())
     }
 
-    pub fn chargeback(&mut self, disputed_transaction_id: &TransactionId) -> Result<(), String> {
+    /// Applies a transaction of a custom type through the given handler, the
+    /// transaction id is recorded like for deposits and withdrawals so it can
+    /// not be applied twice.
+    pub fn apply_handler<F>(&mut self, tx: TransactionId, handler: F) -> Result<(), ProcessingError>
+    where
+        F: FnOnce(&mut ClientHandle) -> Result<(), ProcessingError>,
+    {
         self.can_process()?;
+        if self.processed_transactions.contains_key(&tx) {
+            return Err(ProcessingError::DuplicateTransaction);
+        }
+        self.check_transaction_limit()?;
 
-        let disputed_transaction = self
-            .disputed
-            .remove(disputed_transaction_id)
-            .ok_or("Could not find disputed transaction")?;
-        if let Transaction::Deposit(_, _, amount) = disputed_transaction {
-            self.locked = true;
-            self.total -= amount;
-            self.held -= amount;
-            return Ok(());
+        let mut handle = ClientHandle {
+            view: self.view(),
+            money: self.money,
+        };
+        handler(&mut handle)?;
+        let change = self.money.sub(handle.view.total, self.total)?;
+        self.adjustments = self.money.add(self.adjustments, change)?;
+        self.available = handle.view.available;
+        self.total = handle.view.total;
+        if handle.view.locked {
+            self.status = AccountStatus::Locked { by: tx };
         }
+        self.record_applied(false);
+        self.store_transaction(
+            tx,
+            Transaction::Extension {
+                client: self.client_id,
+                tx,
+            },
+        );
+        Ok(())
+    }
 
-        Err("Wrong transaction type, expected resolve".to_owned())
+    /// Deducts a fee from the available funds, outside of the transactions so
+    /// it is neither recorded nor counted. It fails when the available funds
+    /// do not cover the fee.
+    pub fn charge_fee(&mut self, fee: Amount) -> Result<(), ProcessingError> {
+        if fee > self.available {
+            return Err(ProcessingError::InsufficientFunds);
+        }
+        let available = self.money.sub(self.available, fee)?;
+        let total = self.money.sub(self.total, fee)?;
+        self.adjustments = self.money.sub(self.adjustments, fee)?;
+        self.available = available;
+        self.total = total;
+        Ok(())
     }
 
-    pub fn serialize<W: Write>(self, writer: &mut Writer<W>) -> Result<(), String> {
+    pub fn view(&self) -> ClientView {
+        ClientView {
+            client_id: self.client_id,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked(),
+        }
+    }
+
+    /// Returns true when the client has no funds at all and is active.
+    pub fn is_empty(&self) -> bool {
+        self.available.is_zero()
+            && self.held.is_zero()
+            && self.total.is_zero()
+            && self.status == AccountStatus::Active
+    }
+
+    /// Writes the row of the client, the amounts formatted with money.
+    pub fn serialize<W: Write>(
+        &self,
+        writer: &mut Writer<W>,
+        options: &OutputOptions,
+        money: &MoneyContext,
+    ) -> Result<(), String> {
+        let amount = |amount: Amount| match options.minor_units {
+            Some(digits) => amount.to_minor_units(digits).to_string(),
+            None => money.format(amount),
+        };
+        let mut record: Vec<_> = options
+            .columns
+            .columns()
+            .map(|column| match column {
+                OutputColumn::ClientId => self.client_id.to_string(),
+                OutputColumn::Available => amount(self.available),
+                OutputColumn::Held => amount(self.held),
+                OutputColumn::Total => amount(self.total),
+                OutputColumn::Locked => self.locked().to_string(),
+            })
+            .collect();
+        if options.lock_reason {
+            record.push(
+                self.lock_reason()
+                    .map(|transaction_id| transaction_id.to_string())
+                    .unwrap_or_default(),
+            );
+        }
+        if options.dispute_flag {
+            record.push(self.ever_disputed.to_string());
+        }
+        if options.extended {
+            record.push(self.deposits_count.to_string());
+            record.push(self.withdrawals_count.to_string());
+            record.push(self.open_disputes_count.to_string());
+            record.push(self.status.to_string());
+        }
         writer
-            .serialize((
-                self.client_id.0,
-                self.available.to_string(),
-                self.held.to_string(),
-                self.total.to_string(),
-                self.locked,
-            ))
+            .write_record(&record)
             .map_err(|err| format!("Could not serialize client because of: {}", err))?;
         Ok(())
     }
 
-    #[allow(dead_code)]
+    /// Creates the store of the notes if needed, so recording the notes of a
+    /// transaction can not fail once it is applied.
+    pub fn prepare_notes(&mut self) -> Result<(), ProcessingError> {
+        if self.notes.is_none() {
+            let notes = if self.memory_only {
+                S::create_in_memory()
+            } else {
+                S::create(self.cache_dir.as_deref())
+            };
+            let mut notes = notes.map_err(ProcessingError::Cache)?;
+            notes.set_spill_policy(self.spill_policy);
+            if let Some(limit) = self.cache_limit {
+                notes.set_cache_limit(limit);
+            }
+            self.notes = Some(notes);
+        }
+        Ok(())
+    }
+
+    /// Remembers the timestamp and memo of a processed transaction.
+    pub fn record_notes(
+        &mut self,
+        tx: TransactionId,
+        notes: TransactionNotes,
+    ) -> Result<(), ProcessingError> {
+        self.prepare_notes()?;
+        if let Some(store) = self.notes.as_mut() {
+            store.insert(tx, notes);
+        }
+        Ok(())
+    }
+
+    /// Returns the timestamp and memo of a processed transaction, if it had any.
+    pub fn notes(&mut self, tx: &TransactionId) -> Option<TransactionNotes> {
+        self.notes.as_mut()?.get(tx).cloned()
+    }
+
+    /// Returns the amount held for each deposit under dispute, the disputed
+    /// portion of the deposit, by increasing transaction id. The amounts sum
+    /// to the held balance unless it got out of sync, see `HeldPolicy`.
+    pub fn held_breakdown(&mut self) -> Vec<(TransactionId, Amount)> {
+        let mut breakdown = Vec::new();
+        self.disputed.for_each(|tx, transaction| {
+            if let Some(amount) = transaction.amount() {
+                breakdown.push((*tx, amount));
+            }
+        });
+        breakdown.sort_unstable_by_key(|(tx, _)| tx.0);
+        breakdown
+    }
+
+    /// Checks in debug builds that the held balance is the sum of the held
+    /// breakdown, unless it was out of sync from the start, see `HeldPolicy`.
+    fn check_held_breakdown(&self) {
+        debug_assert!(
+            self.held_out_of_sync || self.held == self.disputed_held,
+            "held of client {} is {} while its disputes hold {}",
+            self.client_id,
+            self.held,
+            self.disputed_held
+        );
+    }
+
+    /// Returns the state of the client for a state snapshot, its stored
+    /// transactions by increasing transaction id. The spilled transactions
+    /// are loaded back from disk, hence the mutable borrow.
+    pub fn state(&mut self) -> ClientState {
+        let mut transactions: Vec<_> = self.processed_transactions.entries().collect();
+        transactions.sort_unstable_by_key(|(tx, _)| tx.0);
+        let mut disputed: Vec<_> = self.disputed.entries().collect();
+        disputed.sort_unstable_by_key(|(tx, _)| tx.0);
+        let mut notes: Vec<_> = match self.notes.as_mut() {
+            Some(notes) => notes.entries().collect(),
+            None => Vec::new(),
+        };
+        notes.sort_unstable_by_key(|(tx, _)| tx.0);
+        ClientState {
+            client_id: self.client_id,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            status: self.status,
+            sequence: self.sequence,
+            deposits_count: self.deposits_count,
+            withdrawals_count: self.withdrawals_count,
+            adjustments: self.adjustments,
+            charged_back: self.charged_back,
+            chargebacks: self.chargebacks.clone(),
+            ever_disputed: self.ever_disputed,
+            transactions,
+            disputed,
+            notes,
+            checksum: 0,
+        }
+        .with_checksum()
+    }
+
+    /// Returns the disputed deposits with their memo, by increasing
+    /// transaction id.
+    pub fn open_disputes(&mut self) -> Vec<(Transaction, Option<String>)> {
+        let mut disputed = Vec::new();
+        self.disputed
+            .for_each(|_, transaction| disputed.push(*transaction));
+        disputed.sort_unstable_by_key(|transaction| transaction.tx_id().0);
+        disputed
+            .into_iter()
+            .map(|transaction| {
+                let memo = self
+                    .notes(&transaction.tx_id())
+                    .and_then(|notes| notes.memo);
+                (transaction, memo)
+            })
+            .collect()
+    }
+
+    /// Returns every transaction processed for the client, in no particular
+    /// order. The spilled transactions are loaded back from disk one cache
+    /// line at a time, hence the mutable borrow.
+    pub fn transactions(&mut self) -> impl Iterator<Item = (TransactionId, Transaction)> + '_ {
+        self.processed_transactions
+            .entries()
+            .map(|(tx, stored)| (tx, stored.transaction))
+    }
+
+    /// Recomputes the total from the stored transactions: the deposits minus
+    /// the withdrawals and the amounts charged back, plus the changes made by
+    /// handlers and fees. It differs from `total` only if the running balances
+    /// drifted.
+    pub fn recomputed_total(&mut self) -> Amount {
+        let mut total = self.adjustments - self.charged_back;
+        self.processed_transactions
+            .for_each(|_, stored| match stored.transaction {
+                Transaction::Deposit { amount, .. } => total += amount,
+                Transaction::Withdrawal { amount, .. } => total -= amount,
+                _ => {}
+            });
+        total
+    }
+
+    /// Writes every transaction processed for the client as csv records, by
+    /// increasing transaction id. The spilled transactions are loaded back
+    /// from disk, all of them are kept in memory while sorting.
+    pub fn export_transactions<W: Write>(&mut self, writer: W) -> Result<(), String> {
+        let mut transactions = Vec::new();
+        self.processed_transactions
+            .for_each(|_, stored| transactions.push(stored.transaction));
+        transactions.sort_unstable_by_key(|transaction| transaction.tx_id().0);
+
+        let mut writer = Writer::from_writer(writer);
+        for transaction in transactions.iter() {
+            let mut record = TransactionRecord::from(transaction);
+            if let Some(notes) = self.notes(&transaction.tx_id()) {
+                record.timestamp = notes.timestamp;
+                record.memo = notes.memo;
+            }
+            writer
+                .serialize(record)
+                .map_err(|err| format!("Could not export transaction because of: {}", err))?;
+        }
+        writer
+            .flush()
+            .map_err(|err| format!("Could not export transactions because of: {}", err))
+    }
+
     pub fn client_id(&self) -> ClientId {
         self.client_id
     }
 
-    #[allow(dead_code)]
     pub fn available(&self) -> Amount {
         self.available
     }
 
-    #[allow(dead_code)]
     pub fn held(&self) -> Amount {
         self.held
     }
 
-    #[allow(dead_code)]
     pub fn total(&self) -> Amount {
         self.total
     }
 
-    #[allow(dead_code)]
+    pub fn status(&self) -> AccountStatus {
+        self.status
+    }
+
+    /// Returns whether the account does not process transactions, whatever
+    /// its status, as written in the `locked` column.
     pub fn locked(&self) -> bool {
-        self.locked
+        self.status != AccountStatus::Active
+    }
+
+    /// Returns the number of deposits, withdrawals and custom transactions
+    /// stored for the client.
+    pub fn transaction_count(&self) -> u64 {
+        self.transaction_count
+    }
+
+    /// Returns the number of chargebacks of the client.
+    pub fn chargeback_count(&self) -> u64 {
+        self.chargebacks.len() as u64
+    }
+
+    /// Returns the cumulative amount charged back.
+    pub fn charged_back(&self) -> Amount {
+        self.charged_back
+    }
+
+    /// Returns the transactions charged back, in the order of the chargebacks.
+    pub fn chargebacks(&self) -> &[TransactionId] {
+        &self.chargebacks
+    }
+
+    /// Returns the disputes, resolves and chargebacks applied to the client in
+    /// order, empty unless they are recorded. Their final state is the state
+    /// the event left the dispute in.
+    pub fn dispute_events(&self) -> &[DisputeEvent] {
+        self.dispute_events.as_deref().unwrap_or_default()
+    }
+
+    /// Number of deposits applied to the client.
+    pub fn deposits_count(&self) -> u64 {
+        self.deposits_count
+    }
+
+    /// Number of withdrawals applied to the client.
+    pub fn withdrawals_count(&self) -> u64 {
+        self.withdrawals_count
+    }
+
+    /// Number of deposits of the client currently under dispute.
+    pub fn open_disputes_count(&self) -> u64 {
+        self.open_disputes_count
+    }
+
+    /// Whether a deposit of the client was ever disputed.
+    pub fn ever_disputed(&self) -> bool {
+        self.ever_disputed
+    }
+
+    /// Spills every transaction the client holds in memory to disk.
+    pub fn flush_caches(&mut self) {
+        self.processed_transactions.flush();
+        self.disputed.flush();
+        if let Some(notes) = self.notes.as_mut() {
+            notes.flush();
+        }
+    }
+
+    /// Adds the entries this client holds in memory to the cache stats.
+    pub fn add_cache_stats(&self, stats: &mut CacheStats) {
+        stats.transactions += self.processed_transactions.in_memory();
+        stats.disputes += self.disputed.in_memory();
+        stats.notes += self.notes.as_ref().map_or(0, |notes| notes.in_memory());
+    }
+
+    /// Returns the transaction which locked the account.
+    pub fn lock_reason(&self) -> Option<TransactionId> {
+        match self.status {
+            AccountStatus::Locked { by } => Some(by),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStores;
+    use crate::transaction_cache::TransactionCache;
+    use rust_decimal::Decimal;
+    use std::collections::HashMap;
+
+    type TestClient = Client<1024, 16>;
+
+    /// Builds a client whose disputed transactions contain a deposit of another client.
+    fn client_with_foreign_dispute() -> TestClient {
+        let mut processed = TransactionCache::new().unwrap();
+        let mut disputed = TransactionCache::new().unwrap();
+        let foreign = Transaction::Deposit {
+            client: ClientId(2),
+            tx: TransactionId(7),
+            amount: "1".parse().unwrap(),
+        };
+        processed.insert(
+            TransactionId(7),
+            StoredTransaction {
+                transaction: foreign,
+                sequence: 0,
+            },
+        );
+        disputed.insert(TransactionId(7), foreign);
+        Client::new_with_cache(ClientId(1), processed, disputed)
+    }
+
+    // Test that a disputed transaction of another client can not be resolved.
+    #[test]
+    fn test_resolve_client_mismatch() {
+        let mut client = client_with_foreign_dispute();
+        assert_eq!(
+            client.resolve(&TransactionId(7)),
+            Err(ProcessingError::ClientMismatch)
+        );
+        assert_eq!(client.available(), Amount::new());
+        assert_eq!(client.held(), Amount::new());
+    }
+
+    // Test that a disputed transaction of another client can not be charged back.
+    #[test]
+    fn test_chargeback_client_mismatch() {
+        let mut client = client_with_foreign_dispute();
+        assert_eq!(
+            client.chargeback(&TransactionId(7)),
+            Err(ProcessingError::ClientMismatch)
+        );
+        assert!(!client.locked());
+        assert_eq!(client.total(), Amount::new());
+    }
+
+    // Test that a processed transaction of another client can not be disputed.
+    #[test]
+    fn test_dispute_client_mismatch() {
+        let mut processed = TransactionCache::new().unwrap();
+        processed.insert(
+            TransactionId(7),
+            StoredTransaction {
+                transaction: Transaction::Deposit {
+                    client: ClientId(2),
+                    tx: TransactionId(7),
+                    amount: "1".parse().unwrap(),
+                },
+                sequence: 0,
+            },
+        );
+        let mut client: TestClient =
+            Client::new_with_cache(ClientId(1), processed, TransactionCache::new().unwrap());
+        assert_eq!(
+            client.dispute(&TransactionId(7)),
+            Err(ProcessingError::ClientMismatch)
+        );
+        assert_eq!(client.held(), Amount::new());
+    }
+
+    // Test that replays of a deposit or withdrawal are told apart from the
+    // conflicting duplicates, also once the original transaction was spilled.
+    #[test]
+    fn test_duplicates() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let mut client: TestClient = Client::new_with_cache(
+            ClientId(1),
+            TransactionCache::with_watermarks(16, 0).unwrap(),
+            TransactionCache::new().unwrap(),
+        );
+        client.deposit(TransactionId(1), amount("5")).unwrap();
+        client.withdraw(TransactionId(2), amount("1")).unwrap();
+        // Fill the cache so the first cache line gets spilled.
+        for tx in 16..40 {
+            client.deposit(TransactionId(tx), amount("1")).unwrap();
+        }
+        assert!(client.processed_transactions.cache_dir().join("0").exists());
+
+        assert_eq!(
+            client.deposit(TransactionId(1), amount("5.00")),
+            Err(ProcessingError::DuplicateReplay)
+        );
+        assert_eq!(
+            client.withdraw(TransactionId(2), amount("1")),
+            Err(ProcessingError::DuplicateReplay)
+        );
+        assert_eq!(
+            client.deposit(TransactionId(1), amount("6")),
+            Err(ProcessingError::ConflictingDuplicate {
+                stored_amount: Some(amount("5")),
+                new_amount: amount("6"),
+            })
+        );
+        assert_eq!(
+            client.withdraw(TransactionId(1), amount("5")),
+            Err(ProcessingError::ConflictingDuplicate {
+                stored_amount: None,
+                new_amount: amount("5"),
+            })
+        );
+        assert_eq!(client.available(), amount("28"));
+    }
+
+    /// Builds a client with a disputed deposit which was never moved to held.
+    fn client_with_unheld_dispute(held_policy: HeldPolicy) -> TestClient {
+        let mut processed = TransactionCache::new().unwrap();
+        let mut disputed = TransactionCache::new().unwrap();
+        let deposit = Transaction::Deposit {
+            client: ClientId(1),
+            tx: TransactionId(7),
+            amount: "3".parse().unwrap(),
+        };
+        processed.insert(
+            TransactionId(7),
+            StoredTransaction {
+                transaction: deposit,
+                sequence: 0,
+            },
+        );
+        disputed.insert(TransactionId(7), deposit);
+        let mut client: TestClient =
+            Client::new_with_cache(ClientId(1), processed, disputed).with_held_policy(held_policy);
+        client.available = "5".parse().unwrap();
+        client.held = "1".parse().unwrap();
+        client.total = "6".parse().unwrap();
+        client
+    }
+
+    // Test that by default held goes negative when it is out of sync.
+    #[test]
+    fn test_negative_held_allowed() {
+        let mut client = client_with_unheld_dispute(HeldPolicy::Allow);
+        client.resolve(&TransactionId(7)).unwrap();
+        assert_eq!(client.held(), "-2".parse().unwrap());
+        assert_eq!(client.available(), "8".parse().unwrap());
+    }
+
+    // Test that the disputes already in the store of a client created over
+    // existing caches are counted, so settling them does not underflow.
+    #[test]
+    fn test_new_with_cache_counts_open_disputes() {
+        let mut client = client_with_unheld_dispute(HeldPolicy::Allow);
+        assert_eq!(client.open_disputes_count(), 1);
+        assert!(client.ever_disputed());
+        client.chargeback(&TransactionId(7)).unwrap();
+        assert_eq!(client.open_disputes_count(), 0);
+    }
+
+    // Test that the clamp policy only releases what is held.
+    #[test]
+    fn test_negative_held_clamped() {
+        let mut client = client_with_unheld_dispute(HeldPolicy::Clamp);
+        client.resolve(&TransactionId(7)).unwrap();
+        assert_eq!(client.held(), Amount::new());
+        assert_eq!(client.available(), "6".parse().unwrap());
+        assert_eq!(client.total(), "6".parse().unwrap());
+
+        let mut client = client_with_unheld_dispute(HeldPolicy::Clamp);
+        client.chargeback(&TransactionId(7)).unwrap();
+        assert_eq!(client.held(), Amount::new());
+        assert_eq!(client.total(), "5".parse().unwrap());
+        assert!(client.locked());
+    }
+
+    // Test that the strict policy rejects the resolve and chargeback without
+    // touching the client.
+    #[test]
+    fn test_negative_held_strict() {
+        let mut client = client_with_unheld_dispute(HeldPolicy::Strict);
+        let error = ProcessingError::NegativeHeld {
+            held: "1".parse().unwrap(),
+            amount: "3".parse().unwrap(),
+        };
+        assert_eq!(client.resolve(&TransactionId(7)), Err(error.clone()));
+        assert_eq!(client.chargeback(&TransactionId(7)), Err(error));
+        assert_eq!(client.held(), "1".parse().unwrap());
+        assert!(!client.locked());
+        assert!(client.disputed.contains_key(&TransactionId(7)));
+    }
+
+    // Test that the export contains every transaction, also the spilled ones.
+    #[test]
+    fn test_export_transactions() {
+        let mut client: TestClient = Client::new_with_cache(
+            ClientId(3),
+            TransactionCache::with_watermarks(16, 0).unwrap(),
+            TransactionCache::new().unwrap(),
+        );
+        for tx in 0..40 {
+            client
+                .deposit(TransactionId(tx), "1.5".parse().unwrap())
+                .unwrap();
+        }
+        client
+            .withdraw(TransactionId(40), "2".parse().unwrap())
+            .unwrap();
+        client.dispute(&TransactionId(0)).unwrap();
+        assert!(client.processed_transactions.cache_dir().join("0").exists());
+
+        let mut output = Vec::new();
+        client.export_transactions(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 42);
+        assert_eq!(
+            lines[0],
+            "type,client,tx,amount,idempotency_key,timestamp,memo"
+        );
+        for tx in 0..40 {
+            assert_eq!(lines[tx + 1], format!("deposit,3,{},1.5,,,", tx));
+        }
+        assert_eq!(lines[41], "withdrawal,3,40,2,,,");
+    }
+
+    // Test that the transactions are iterated, spilled ones included, and that
+    // the cache does not grow past its limit while iterating.
+    #[test]
+    fn test_transactions() {
+        let mut client: TestClient = Client::new_with_cache(
+            ClientId(3),
+            TransactionCache::with_watermarks(16, 0).unwrap(),
+            TransactionCache::new().unwrap(),
+        );
+        let mut deposited = HashMap::new();
+        for tx in 0..40 {
+            let amount: Amount = format!("{}.25", tx).parse().unwrap();
+            client.deposit(TransactionId(tx), amount).unwrap();
+            deposited.insert(
+                TransactionId(tx),
+                Transaction::Deposit {
+                    client: ClientId(3),
+                    tx: TransactionId(tx),
+                    amount,
+                },
+            );
+        }
+        assert!(client.processed_transactions.cache_dir().join("0").exists());
+        assert!(client.processed_transactions.in_memory() < 40);
+
+        let transactions: HashMap<_, _> = client.transactions().collect();
+        assert_eq!(transactions, deposited);
+        assert!(client.processed_transactions.in_memory() <= 16);
+    }
+
+    /// Applies deposits, withdrawals and disputes to the client, some of them
+    /// rejected, returning which ones were applied.
+    fn apply_mixed<S: StoreFamily>(client: &mut Client<1024, 16, S>) -> Vec<bool> {
+        let mut results = Vec::new();
+        for tx in 0..30 {
+            let amount: Amount = format!("{}.5", tx % 7).parse().unwrap();
+            let result = match tx % 5 {
+                3 => client.withdraw(TransactionId(tx), amount),
+                4 => client.dispute(&TransactionId(tx - 4)),
+                _ => client.deposit(TransactionId(tx), amount),
+            };
+            results.push(result.is_ok());
+        }
+        results.push(client.resolve(&TransactionId(0)).is_ok());
+        results.push(client.chargeback(&TransactionId(5)).is_ok());
+        results.push(client.deposit(TransactionId(40), Amount::new()).is_ok());
+        results
+    }
+
+    // Test that a client keeping its transactions in memory gets the same
+    // balances and transactions as one spilling them to disk.
+    #[test]
+    fn test_memory_stores() {
+        let mut spilled: TestClient = Client::new_with_cache(
+            ClientId(4),
+            TransactionCache::with_watermarks(4, 0).unwrap(),
+            TransactionCache::with_watermarks(4, 0).unwrap(),
+        );
+        let mut in_memory = Client::<1024, 16, MemoryStores>::new(ClientId(4)).unwrap();
+        assert_eq!(apply_mixed(&mut in_memory), apply_mixed(&mut spilled));
+        assert!(spilled
+            .processed_transactions
+            .cache_dir()
+            .join("0")
+            .exists());
+
+        assert_eq!(in_memory.view(), spilled.view());
+        assert!(in_memory.locked());
+        assert_eq!(in_memory.open_disputes(), spilled.open_disputes());
+        let transactions: HashMap<_, _> = in_memory.transactions().collect();
+        assert_eq!(transactions, spilled.transactions().collect());
+        assert_eq!(transactions.len(), 24);
+    }
+
+    // Test that the total recomputed from the stored transactions matches the
+    // running total, also with fees and handlers, and flags a drift.
+    #[test]
+    fn test_recomputed_total() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let mut client: TestClient = Client::new_with_cache(
+            ClientId(4),
+            TransactionCache::with_watermarks(4, 0).unwrap(),
+            TransactionCache::with_watermarks(4, 0).unwrap(),
+        );
+        client.deposit(TransactionId(100), amount("50")).unwrap();
+        client.charge_fee(amount("1.25")).unwrap();
+        client
+            .apply_handler(TransactionId(101), |handle| handle.credit(amount("2")))
+            .unwrap();
+        apply_mixed(&mut client);
+        assert!(client.charged_back() > Amount::new());
+        assert_eq!(client.recomputed_total(), client.total());
+
+        client.total += amount("0.0001");
+        assert_ne!(client.recomputed_total(), client.total());
+    }
+
+    // Test that every update of the balances past Decimal::MAX is rejected
+    // with ProcessingError::Overflow, leaving the client untouched.
+    #[test]
+    fn test_overflow() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let max = Amount::from_decimal(Decimal::MAX);
+        let overflow =
+            |amount: Amount, change: Amount| Err(ProcessingError::Overflow { amount, change });
+        let mut client = TestClient::new(ClientId(1)).unwrap();
+        client.deposit(TransactionId(1), max).unwrap();
+        assert_eq!(
+            client.deposit(TransactionId(2), amount("1")),
+            overflow(max, amount("1"))
+        );
+        assert_eq!(
+            client.apply_handler(TransactionId(3), |handle| handle.credit(amount("1"))),
+            overflow(max, amount("1"))
+        );
+        assert_eq!(client.view().available, max);
+        assert_eq!(client.deposits_count(), 1);
+
+        // With the deposit held, available has room but total does not.
+        client.dispute(&TransactionId(1)).unwrap();
+        assert_eq!(
+            client.deposit(TransactionId(4), amount("1")),
+            overflow(max, amount("1"))
+        );
+        assert!(client.view().available.is_zero());
+        client.resolve(&TransactionId(1)).unwrap();
+        assert_eq!(client.view().available, max);
+
+        // With an overdraft of max, available stops at -max.
+        client.withdraw(TransactionId(5), max).unwrap();
+        let mut client = client.with_overdraft_limit(Some(max));
+        client.withdraw(TransactionId(6), max).unwrap();
+        assert_eq!(
+            client.withdraw(TransactionId(7), amount("1")),
+            overflow(-max, -amount("1"))
+        );
+        assert_eq!(client.view().total, -max);
+    }
+
+    // Test that with an overdraft limit a withdrawal may take the available
+    // balance below zero, down to minus the limit, and not further.
+    #[test]
+    fn test_overdraft_limit() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let mut client = TestClient::new(ClientId(1))
+            .unwrap()
+            .with_overdraft_limit(Some(amount("5")));
+        client.deposit(TransactionId(1), amount("2")).unwrap();
+        client.withdraw(TransactionId(2), amount("6")).unwrap();
+        assert_eq!(client.available(), amount("-4"));
+        assert_eq!(client.total(), amount("-4"));
+        assert_eq!(
+            client.withdraw(TransactionId(3), amount("1.0001")),
+            Err(ProcessingError::InsufficientFunds)
+        );
+        client.withdraw(TransactionId(4), amount("1")).unwrap();
+        assert_eq!(client.available(), amount("-5"));
+
+        let mut denied = TestClient::new(ClientId(2)).unwrap();
+        denied.deposit(TransactionId(1), amount("2")).unwrap();
+        assert_eq!(
+            denied.withdraw(TransactionId(2), amount("3")),
+            Err(ProcessingError::InsufficientFunds)
+        );
+    }
+
+    // Test that the deposits and withdrawals at or above their minimum are
+    // applied and the ones just below it rejected.
+    #[test]
+    fn test_minimum_amounts() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let mut client = TestClient::new(ClientId(1))
+            .unwrap()
+            .with_min_deposit(Some(amount("10")))
+            .with_min_withdrawal(Some(amount("5")));
+        assert_eq!(
+            client.deposit(TransactionId(1), amount("9.9999")),
+            Err(ProcessingError::BelowMinimum {
+                kind: TransactionKind::Deposit,
+                minimum: amount("10"),
+            })
+        );
+        client.deposit(TransactionId(2), amount("10")).unwrap();
+        client.deposit(TransactionId(3), amount("10.0001")).unwrap();
+        let err = client
+            .withdraw(TransactionId(4), amount("4.9999"))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Below minimum withdrawal of 5");
+        client.withdraw(TransactionId(5), amount("5")).unwrap();
+        client.withdraw(TransactionId(6), amount("5.0001")).unwrap();
+        assert_eq!(client.available(), amount("10"));
+
+        let mut unlimited = TestClient::new(ClientId(2)).unwrap();
+        unlimited
+            .deposit(TransactionId(1), amount("0.0001"))
+            .unwrap();
+        unlimited
+            .withdraw(TransactionId(2), amount("0.0001"))
+            .unwrap();
+    }
+
+    // Test that the held breakdown lists the amount held by each dispute and
+    // sums to held, also with partial disputes.
+    #[test]
+    fn test_held_breakdown() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let mut client = TestClient::new(ClientId(1)).unwrap();
+        for (tx, deposit) in [(3, "2.5"), (1, "4"), (2, "10")] {
+            client.deposit(TransactionId(tx), amount(deposit)).unwrap();
+        }
+        client.dispute(&TransactionId(3)).unwrap();
+        client.dispute(&TransactionId(1)).unwrap();
+        assert_eq!(
+            client.held_breakdown(),
+            vec![
+                (TransactionId(1), amount("4")),
+                (TransactionId(3), amount("2.5"))
+            ]
+        );
+        assert_eq!(client.held(), amount("6.5"));
+
+        client
+            .dispute_portion(&TransactionId(2), Some(amount("0.75")))
+            .unwrap();
+        client.resolve(&TransactionId(1)).unwrap();
+        assert_eq!(
+            client.held_breakdown(),
+            vec![
+                (TransactionId(2), amount("0.75")),
+                (TransactionId(3), amount("2.5"))
+            ]
+        );
+        assert_eq!(client.held(), amount("3.25"));
+        client.chargeback(&TransactionId(3)).unwrap();
+        assert_eq!(
+            client.held_breakdown(),
+            vec![(TransactionId(2), amount("0.75"))]
+        );
+        assert_eq!(client.held(), amount("0.75"));
+    }
+
+    // Test that debug builds catch a held balance drifting from the sum of
+    // the held breakdown.
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "while its disputes hold")]
+    fn test_held_breakdown_drift() {
+        let mut client = TestClient::new(ClientId(1)).unwrap();
+        client
+            .deposit(TransactionId(1), "2".parse().unwrap())
+            .unwrap();
+        client.dispute(&TransactionId(1)).unwrap();
+        client.held = "1".parse().unwrap();
+        client.resolve(&TransactionId(1)).unwrap();
+    }
+
+    // Test that a chargeback locks the account, which an admin may unlock,
+    // and that frozen and closed accounts reject the transactions.
+    #[test]
+    fn test_account_status() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let mut client = TestClient::new(ClientId(1)).unwrap();
+        client.deposit(TransactionId(1), amount("2")).unwrap();
+        client.dispute(&TransactionId(1)).unwrap();
+        client.chargeback(&TransactionId(1)).unwrap();
+        assert_eq!(
+            client.status(),
+            AccountStatus::Locked {
+                by: TransactionId(1)
+            }
+        );
+        assert!(client.locked());
+        assert_eq!(client.lock_reason(), Some(TransactionId(1)));
+        assert_eq!(
+            client.set_status(AccountStatus::Frozen),
+            Err(ProcessingError::InvalidStatusTransition {
+                from: AccountStatus::Locked {
+                    by: TransactionId(1)
+                },
+                to: AccountStatus::Frozen,
+            })
+        );
+
+        client.set_status(AccountStatus::Active).unwrap();
+        assert!(!client.locked());
+        assert_eq!(client.lock_reason(), None);
+        client.deposit(TransactionId(2), amount("1")).unwrap();
+
+        client.set_status(AccountStatus::Frozen).unwrap();
+        assert!(client.locked());
+        assert_eq!(
+            client.deposit(TransactionId(3), amount("1")),
+            Err(ProcessingError::AccountFrozen)
+        );
+        client.set_status(AccountStatus::Closed).unwrap();
+        assert_eq!(
+            client.withdraw(TransactionId(3), amount("1")),
+            Err(ProcessingError::AccountClosed)
+        );
+        assert_eq!(client.total(), amount("1"));
+    }
+
+    // Test that a closed account is terminal but for being reopened.
+    #[test]
+    fn test_closed_terminal() {
+        let mut client = TestClient::new(ClientId(1)).unwrap();
+        client.set_status(AccountStatus::Closed).unwrap();
+        for status in [
+            AccountStatus::Closed,
+            AccountStatus::Frozen,
+            AccountStatus::Locked {
+                by: TransactionId(1),
+            },
+        ] {
+            assert!(client.set_status(status).is_err());
+            assert_eq!(client.status(), AccountStatus::Closed);
+        }
+        client.set_status(AccountStatus::Active).unwrap();
+        assert!(client
+            .deposit(TransactionId(1), "1".parse().unwrap())
+            .is_ok());
+    }
+
+    // Test that the status is stored tagged by its name.
+    #[test]
+    fn test_account_status_serde() {
+        let locked = AccountStatus::Locked {
+            by: TransactionId(4),
+        };
+        let json = serde_json::to_string(&locked).unwrap();
+        assert_eq!(json, r#"{"status":"locked","by":4}"#);
+        assert_eq!(
+            serde_json::from_str::<AccountStatus>(&json).unwrap(),
+            locked
+        );
+        assert_eq!(
+            serde_json::from_str::<AccountStatus>(r#"{"status":"frozen"}"#).unwrap(),
+            AccountStatus::Frozen
+        );
+    }
+
+    // Test that every status is kept by the state snapshots, which are read
+    // back with the status and checksum of the client they were written with.
+    #[test]
+    fn test_account_status_snapshot() {
+        use crate::state::{StateFormatVersion, StateSnapshot};
+        use tempdir::TempDir;
+
+        let dir = TempDir::new("status_snapshot").unwrap();
+        for status in [
+            AccountStatus::Active,
+            AccountStatus::Locked {
+                by: TransactionId(u64::from(u32::MAX) + 1),
+            },
+            AccountStatus::Closed,
+            AccountStatus::Frozen,
+        ] {
+            let mut client = TestClient::new(ClientId(1)).unwrap();
+            client.status = status;
+            let snapshot = StateSnapshot {
+                version: StateFormatVersion::CURRENT,
+                partial: false,
+                clients: vec![client.state()],
+                idempotency_keys: Vec::new(),
+                idempotency_keys_checksum: 0,
+            };
+            let path = dir.path().join(format!("{}.json", status));
+            snapshot.write(&path).unwrap();
+            let read = StateSnapshot::read(&path).unwrap();
+            assert_eq!(read.clients[0].status, status);
+            assert_eq!(read.clients[0].checksum, read.clients[0].compute_checksum());
+        }
+    }
+
+    fn limited_client(max_withdrawals: usize, window: usize) -> TestClient {
+        let mut client = TestClient::new(ClientId(1))
+            .unwrap()
+            .with_velocity_limit(Some(VelocityLimit {
+                max_withdrawals,
+                window,
+            }));
+        client
+            .deposit(TransactionId(0), "1000".parse().unwrap())
+            .unwrap();
+        client
+    }
+
+    /// Applies the transactions in order, `w` for a withdrawal of 1 and `d`
+    /// for a deposit of 1, returning which withdrawals were accepted.
+    fn apply(client: &mut TestClient, first_tx: u64, transactions: &str) -> Vec<bool> {
+        let one: Amount = "1".parse().unwrap();
+        transactions
+            .chars()
+            .zip(first_tx..)
+            .filter_map(|(transaction, tx)| match transaction {
+                'w' => Some(client.withdraw(TransactionId(tx), one).is_ok()),
+                _ => {
+                    client.deposit(TransactionId(tx), one).unwrap();
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Test that at most the limit of withdrawals is accepted within any window
+    // and that the error reports the limit.
+    #[test]
+    fn test_velocity_limit() {
+        let mut client = limited_client(2, 3);
+        assert_eq!(apply(&mut client, 1, "ww"), vec![true, true]);
+        assert_eq!(
+            client.withdraw(TransactionId(3), "1".parse().unwrap()),
+            Err(ProcessingError::VelocityLimitExceeded {
+                limit: 2,
+                window: 3
+            })
+        );
+        assert_eq!(client.available(), "998".parse().unwrap());
+    }
+
+    // Test that the withdrawals are accepted again once the old ones age out
+    // of the window, deposits taking slots without counting as withdrawals.
+    #[test]
+    fn test_velocity_limit_window_slides() {
+        let mut client = limited_client(2, 3);
+        assert_eq!(apply(&mut client, 1, "ww"), vec![true, true]);
+        assert_eq!(apply(&mut client, 10, "w"), vec![false]);
+        // The deposit makes the first withdrawal age out of the window.
+        assert_eq!(apply(&mut client, 20, "dwww"), vec![true, true, false]);
+        assert_eq!(apply(&mut client, 30, "ddww"), vec![true, true]);
+    }
+
+    // Test that rejected withdrawals do not take slots in the window.
+    #[test]
+    fn test_velocity_limit_rejected_withdrawals() {
+        let mut client = limited_client(1, 2);
+        assert_eq!(
+            apply(&mut client, 1, "wwww"),
+            vec![true, false, false, false]
+        );
+        assert_eq!(apply(&mut client, 10, "dw"), vec![true]);
+        // Withdrawals rejected for insufficient funds do not count either.
+        apply(&mut client, 20, "d");
+        assert_eq!(
+            client.withdraw(TransactionId(30), "5000".parse().unwrap()),
+            Err(ProcessingError::InsufficientFunds)
+        );
+        assert_eq!(apply(&mut client, 40, "w"), vec![true]);
+    }
+
+    // Test every limit up to the window size against runs of withdrawals.
+    #[test]
+    fn test_velocity_limit_boundaries() {
+        for window in 1..6 {
+            for max_withdrawals in 0..=window {
+                let mut client = limited_client(max_withdrawals, window);
+                let accepted = apply(&mut client, 1, &"w".repeat(2 * window));
+                // Rejected withdrawals take no slot, so once the limit is
+                // reached within the window it stays reached.
+                let expected: Vec<bool> = (0..2 * window)
+                    .map(|i| i < max_withdrawals || max_withdrawals >= window)
+                    .collect();
+                assert_eq!(accepted, expected, "{} within {}", max_withdrawals, window);
+
+                let mut client = limited_client(max_withdrawals, window);
+                let transactions = "d".repeat(window - 1) + "w";
+                let accepted = apply(&mut client, 1, &transactions.repeat(3));
+                assert_eq!(accepted, vec![max_withdrawals > 0; 3]);
+            }
+        }
+    }
+
+    // Test that without a limit any number of withdrawals is accepted.
+    #[test]
+    fn test_no_velocity_limit() {
+        let mut client = TestClient::new(ClientId(1)).unwrap();
+        client
+            .deposit(TransactionId(0), "100".parse().unwrap())
+            .unwrap();
+        assert_eq!(apply(&mut client, 1, &"w".repeat(50)), vec![true; 50]);
+    }
+
+    // Test that the transactions are accepted up to the limit and rejected
+    // beyond it, while disputes of the stored ones are still processed.
+    #[test]
+    fn test_max_transactions() {
+        let mut client = TestClient::new(ClientId(1))
+            .unwrap()
+            .with_max_transactions(Some(3));
+        let one: Amount = "1".parse().unwrap();
+        client.deposit(TransactionId(1), one).unwrap();
+        client.deposit(TransactionId(2), one).unwrap();
+        client.withdraw(TransactionId(3), one).unwrap();
+        assert_eq!(client.transaction_count(), 3);
+
+        assert_eq!(
+            client.deposit(TransactionId(4), one),
+            Err(ProcessingError::ClientTransactionLimitExceeded)
+        );
+        assert_eq!(
+            client.withdraw(TransactionId(5), one),
+            Err(ProcessingError::ClientTransactionLimitExceeded)
+        );
+        assert_eq!(
+            client.apply_handler(TransactionId(6), |_| Ok(())),
+            Err(ProcessingError::ClientTransactionLimitExceeded)
+        );
+        // Replays are still reported as such.
+        assert_eq!(
+            client.deposit(TransactionId(1), one),
+            Err(ProcessingError::DuplicateReplay)
+        );
+        client.dispute(&TransactionId(1)).unwrap();
+        assert_eq!(client.transaction_count(), 3);
+        assert_eq!(client.total(), one);
+    }
+
+    // Test that a deposit can be disputed until more than the window of
+    // transactions were applied after it, also once it was spilled.
+    #[test]
+    fn test_dispute_window() {
+        let one: Amount = "1".parse().unwrap();
+        let client_with_deposits = |deposits: u64| -> TestClient {
+            let mut client: TestClient = Client::new_with_cache(
+                ClientId(1),
+                TransactionCache::with_watermarks(16, 0).unwrap(),
+                TransactionCache::new().unwrap(),
+            )
+            .with_dispute_window(Some(20));
+            for tx in 0..deposits {
+                client.deposit(TransactionId(tx), one).unwrap();
+            }
+            client
+        };
+
+        // 20 transactions after the first deposit, at the window boundary.
+        let mut client = client_with_deposits(21);
+        assert!(client.processed_transactions.cache_dir().join("0").exists());
+        client.dispute(&TransactionId(0)).unwrap();
+        assert_eq!(client.held(), one);
+
+        // One past the window.
+        let mut client = client_with_deposits(22);
+        assert_eq!(
+            client.dispute(&TransactionId(0)),
+            Err(ProcessingError::StaleDispute {
+                age: 21,
+                window: 20
+            })
+        );
+        assert_eq!(client.held(), Amount::new());
+        client.dispute(&TransactionId(1)).unwrap();
+    }
+
+    // Test that only the disputed portion of a deposit is held, then released
+    // by a resolve.
+    #[test]
+    fn test_partial_dispute_resolve() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let mut client = TestClient::new(ClientId(1)).unwrap();
+        client.deposit(TransactionId(1), amount("10")).unwrap();
+        client
+            .dispute_portion(&TransactionId(1), Some(amount("4")))
+            .unwrap();
+        assert_eq!(client.available(), amount("6"));
+        assert_eq!(client.held(), amount("4"));
+        assert_eq!(client.total(), amount("10"));
+
+        client.resolve(&TransactionId(1)).unwrap();
+        assert_eq!(client.available(), amount("10"));
+        assert_eq!(client.held(), Amount::new());
+        assert_eq!(client.total(), amount("10"));
+    }
+
+    // Test that a chargeback of a partial dispute only removes the disputed
+    // portion.
+    #[test]
+    fn test_partial_dispute_chargeback() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let mut client = TestClient::new(ClientId(1)).unwrap();
+        client.deposit(TransactionId(1), amount("10")).unwrap();
+        client
+            .dispute_portion(&TransactionId(1), Some(amount("2.5")))
+            .unwrap();
+        client.chargeback(&TransactionId(1)).unwrap();
+        assert_eq!(client.available(), amount("7.5"));
+        assert_eq!(client.held(), Amount::new());
+        assert_eq!(client.total(), amount("7.5"));
+        assert_eq!(client.charged_back(), amount("2.5"));
+        assert!(client.locked());
+    }
+
+    // Test that a disputed portion larger than the deposit, or not positive,
+    // is rejected without holding anything.
+    #[test]
+    fn test_partial_dispute_invalid() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let mut client = TestClient::new(ClientId(1)).unwrap();
+        client.deposit(TransactionId(1), amount("10")).unwrap();
+        assert_eq!(
+            client.dispute_portion(&TransactionId(1), Some(amount("10.01"))),
+            Err(ProcessingError::DisputeExceedsDeposit {
+                disputed: amount("10.01"),
+                deposited: amount("10"),
+            })
+        );
+        assert!(matches!(
+            client.dispute_portion(&TransactionId(1), Some(amount("0"))),
+            Err(ProcessingError::InvalidAmount(_))
+        ));
+        assert_eq!(client.held(), Amount::new());
+        client
+            .dispute_portion(&TransactionId(1), Some(amount("10")))
+            .unwrap();
+        assert_eq!(client.held(), amount("10"));
+    }
+
+    // Test that only the applied deposits, withdrawals and disputes are
+    // counted.
+    #[test]
+    fn test_transaction_counts() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let mut client = TestClient::new(ClientId(1)).unwrap();
+        client.deposit(TransactionId(1), amount("5")).unwrap();
+        client.deposit(TransactionId(2), amount("3")).unwrap();
+        client.withdraw(TransactionId(3), amount("1")).unwrap();
+        assert!(client.withdraw(TransactionId(4), amount("100")).is_err());
+        assert!(client.deposit(TransactionId(1), amount("5")).is_err());
+        client.dispute(&TransactionId(1)).unwrap();
+        client.dispute(&TransactionId(2)).unwrap();
+        assert!(client.dispute(&TransactionId(2)).is_err());
+        assert_eq!(client.open_disputes_count(), 2);
+        client.resolve(&TransactionId(1)).unwrap();
+        assert!(client.resolve(&TransactionId(1)).is_err());
+
+        assert_eq!(client.deposits_count(), 2);
+        assert_eq!(client.withdrawals_count(), 1);
+        assert_eq!(client.open_disputes_count(), 1);
+        client.chargeback(&TransactionId(2)).unwrap();
+        assert_eq!(client.open_disputes_count(), 0);
     }
 }
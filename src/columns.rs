@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use csv::StringRecord;
+
+/// Fields of a `TransactionRecord` as named in the header of the input.
+const FIELDS: [&str; 7] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "idempotency_key",
+    "timestamp",
+    "memo",
+];
+/// Fields read by position from the inputs without a header. The idempotency
+/// key and the timestamp are only read from a column named after them, so an
+/// extra column of a headerless input is never taken for one.
+const POSITIONAL_FIELDS: [&str; 5] = ["type", "client", "tx", "amount", "memo"];
+/// Number of fields of a record read by position.
+pub const FIELD_COUNT: usize = POSITIONAL_FIELDS.len();
+/// Fields which every input must have, the amount is only needed by deposits
+/// and withdrawals and the other fields are optional.
+const REQUIRED_FIELDS: [&str; 3] = ["type", "client", "tx"];
+/// Columns read as the amount when it is not mapped and the input has no
+/// `amount` column, as named by some exports.
+const AMOUNT_ALIASES: [&str; 2] = ["value", "money"];
+
+/// Type which maps the columns of an input with nonstandard headers onto the
+/// fields of a `TransactionRecord`, e.g. a `customer` column onto `client`.
+/// Fields which are not mapped are looked up under their own name, or one of
+/// the `AMOUNT_ALIASES` for the amount.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ColumnMapping {
+    /// Column name for each mapped field.
+    columns: HashMap<String, String>,
+}
+
+impl ColumnMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the field from the given column.
+    pub fn map(mut self, field: &str, column: &str) -> Result<Self, String> {
+        if !FIELDS.contains(&field) {
+            return Err(format!(
+                "Unknown field {}, expected one of {}",
+                field,
+                FIELDS.join(",")
+            ));
+        }
+        self.columns.insert(field.to_owned(), column.to_owned());
+        Ok(self)
+    }
+
+    /// Same as `map` for a `field=column` pair.
+    pub fn map_pair(self, pair: &str) -> Result<Self, String> {
+        let (field, column) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid column mapping {}, expected field=column", pair))?;
+        self.map(field.trim(), column.trim())
+    }
+
+    /// Renames the columns of the input header to the fields they are mapped
+    /// to, fails naming the first field whose column is missing.
+    pub fn translate_headers(&self, headers: &StringRecord) -> Result<StringRecord, String> {
+        let amount_alias = if self.columns.contains_key("amount")
+            || headers.iter().any(|header| header == "amount")
+        {
+            None
+        } else {
+            headers
+                .iter()
+                .find(|header| AMOUNT_ALIASES.contains(header))
+                .map(str::to_owned)
+        };
+        let mapping = match amount_alias {
+            Some(column) => self.clone().map("amount", &column)?,
+            None => self.clone(),
+        };
+        let translated: StringRecord = headers
+            .iter()
+            .map(|header| {
+                match mapping.columns.iter().find(|(_, column)| *column == header) {
+                    Some((field, _)) => field.as_str(),
+                    // A column named after a field mapped elsewhere is ignored.
+                    None if mapping.columns.contains_key(header) => "",
+                    None => header,
+                }
+            })
+            .collect();
+
+        for field in FIELDS.iter() {
+            let required = REQUIRED_FIELDS.contains(field) || self.columns.contains_key(*field);
+            if required && !translated.iter().any(|header| header == *field) {
+                let column = self
+                    .columns
+                    .get(*field)
+                    .map(String::as_str)
+                    .unwrap_or(field);
+                return Err(format!("Missing column {} for field {}", column, field));
+            }
+        }
+        Ok(translated)
+    }
+}
+
+/// Header under which the rows of an input without a header are read.
+pub fn positional_headers() -> StringRecord {
+    StringRecord::from(POSITIONAL_FIELDS.to_vec())
+}
+
+/// Returns the fields named by the first row of an input read without mapped
+/// columns if it is a header, i.e. it names at least the required fields.
+pub fn detect_header(row: &StringRecord) -> Option<StringRecord> {
+    ColumnMapping::new().translate_headers(row).ok()
+}
+
+/// Fails naming the first column of headers which is not read as a field once
+/// translated, e.g. a column added upstream without notice. The memo and
+/// timestamp columns are fields and the columns named after a field mapped
+/// elsewhere are known to be ignored, so neither fails.
+pub fn check_known_headers(
+    headers: &StringRecord,
+    translated: &StringRecord,
+) -> Result<(), String> {
+    match headers
+        .iter()
+        .zip(translated.iter())
+        .find(|(_, field)| !field.is_empty() && !FIELDS.contains(field))
+    {
+        Some((column, _)) => Err(format!(
+            "Unknown column {}, expected the columns of {}",
+            column,
+            FIELDS.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partner_mapping() -> ColumnMapping {
+        ColumnMapping::new()
+            .map_pair("type=txn_type")
+            .unwrap()
+            .map_pair("client=customer")
+            .unwrap()
+            .map_pair("tx=reference")
+            .unwrap()
+    }
+
+    // Test that the mapped columns are renamed to their fields.
+    #[test]
+    fn test_translate_headers() {
+        let mapping = partner_mapping().map("amount", "value").unwrap();
+        let headers = StringRecord::from(vec!["txn_type", "customer", "reference", "value"]);
+        assert_eq!(
+            mapping.translate_headers(&headers).unwrap(),
+            StringRecord::from(vec!["type", "client", "tx", "amount"])
+        );
+
+        // Columns named after a mapped field are ignored.
+        let headers = StringRecord::from(vec!["txn_type", "customer", "reference", "type"]);
+        assert_eq!(
+            partner_mapping().translate_headers(&headers).unwrap(),
+            StringRecord::from(vec!["type", "client", "tx", ""])
+        );
+    }
+
+    // Test that the amount column is only required once mapped.
+    #[test]
+    fn test_amount_optional() {
+        let headers = StringRecord::from(vec!["txn_type", "customer", "reference"]);
+        assert!(partner_mapping().translate_headers(&headers).is_ok());
+        assert_eq!(
+            partner_mapping()
+                .map("amount", "value")
+                .unwrap()
+                .translate_headers(&headers),
+            Err("Missing column value for field amount".to_owned())
+        );
+    }
+
+    // Test that an alias column is read as the amount only when the amount is
+    // not mapped and there is no amount column.
+    #[test]
+    fn test_amount_aliases() {
+        let headers = StringRecord::from(vec!["txn_type", "customer", "reference", "value"]);
+        assert_eq!(
+            partner_mapping().translate_headers(&headers).unwrap(),
+            StringRecord::from(vec!["type", "client", "tx", "amount"])
+        );
+        let headers = StringRecord::from(vec!["type", "client", "tx", "money"]);
+        assert_eq!(
+            ColumnMapping::new().translate_headers(&headers).unwrap(),
+            StringRecord::from(vec!["type", "client", "tx", "amount"])
+        );
+        let headers = StringRecord::from(vec!["type", "client", "tx", "amount", "value"]);
+        assert_eq!(
+            ColumnMapping::new().translate_headers(&headers).unwrap(),
+            headers
+        );
+        let headers = StringRecord::from(vec!["type", "client", "tx", "value", "net"]);
+        assert_eq!(
+            ColumnMapping::new()
+                .map("amount", "net")
+                .unwrap()
+                .translate_headers(&headers)
+                .unwrap(),
+            StringRecord::from(vec!["type", "client", "tx", "value", "amount"])
+        );
+    }
+
+    // Test that missing columns and unknown fields are reported.
+    #[test]
+    fn test_invalid_mapping() {
+        let headers = StringRecord::from(vec!["txn_type", "client", "reference"]);
+        assert_eq!(
+            partner_mapping().translate_headers(&headers),
+            Err("Missing column customer for field client".to_owned())
+        );
+        let headers = StringRecord::from(vec!["type", "customer"]);
+        assert_eq!(
+            ColumnMapping::new()
+                .map("client", "customer")
+                .unwrap()
+                .translate_headers(&headers),
+            Err("Missing column tx for field tx".to_owned())
+        );
+        assert!(ColumnMapping::new().map("note", "comment").is_err());
+        assert!(ColumnMapping::new().map_pair("type").is_err());
+    }
+}
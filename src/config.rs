@@ -0,0 +1,279 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::processor::TRANSACTION_SIZE;
+use crate::runner::LogLevel;
+
+/// Environment variables read by `Config::from_env`, with the setting each
+/// of them overrides.
+pub const ENV_VARS: [(&str, &str); 4] = [
+    ("TRANSACTION_CACHE_DIR", "processor.cache_dir"),
+    ("TRANSACTION_CACHE_LIMIT_BYTES", "processor.cache_limit"),
+    ("TRANSACTION_LOG_LEVEL", "run.log_level"),
+    ("TRANSACTION_STRICT", "run.fail_fast"),
+];
+
+/// Settings of the processor read from a config file, unset values keep their
+/// default.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessorConfig {
+    /// What to do when held would go negative: allow, clamp or strict.
+    pub held_policy: Option<String>,
+    pub max_tx_per_client: Option<u64>,
+    pub dispute_window: Option<u64>,
+    /// How far the withdrawals may take the available balance below zero.
+    pub overdraft_limit: Option<String>,
+    /// Smallest amounts of the deposits and withdrawals.
+    pub min_deposit: Option<String>,
+    pub min_withdrawal: Option<String>,
+    /// Rules of the `RecordValidator`, none is checked by default.
+    pub reject_zero_client: Option<bool>,
+    pub reject_zero_tx: Option<bool>,
+    pub amount_ceiling: Option<String>,
+    pub require_deposit_amount: Option<bool>,
+    pub keep_memos: Option<bool>,
+    /// Number of transactions each client cache holds in memory.
+    pub cache_limit: Option<u64>,
+    pub cache_dir: Option<String>,
+    pub clean_cache_on_exit: Option<bool>,
+    /// Stop processing when a cache can not spill to disk.
+    pub strict_spill: Option<bool>,
+}
+
+/// Settings controlling how the records are processed.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunConfig {
+    pub fail_fast: Option<bool>,
+    pub fail_on_conflicting_duplicate: Option<bool>,
+    /// Write the balances processed so far when processing stops early.
+    pub partial_output: Option<bool>,
+    pub parallel_chunks: Option<u64>,
+    pub stats: Option<bool>,
+    /// Recompute the balances from the stored transactions after the run.
+    pub verify: Option<bool>,
+    /// Lowest severity of the rejections which make the run exit non-zero,
+    /// either info, warn, error or fatal.
+    pub fail_on: Option<String>,
+    /// Either off, error or warn.
+    pub log_level: Option<String>,
+}
+
+/// Settings controlling the output.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Either client or total.
+    pub sort_by: Option<String>,
+    /// Balance columns in the format of `OutputSpec`.
+    pub columns: Option<String>,
+    pub lock_reason: Option<bool>,
+    pub dispute_flag: Option<bool>,
+    pub extended: Option<bool>,
+    pub excel: Option<bool>,
+    pub suppress_zero: Option<bool>,
+    pub minor_units: Option<bool>,
+    /// Decimals of the minor unit written with `minor_units`.
+    pub minor_unit_digits: Option<u32>,
+}
+
+/// Settings of a run read from a TOML file with a `[processor]`, `[run]`
+/// and `[output]` table.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub processor: ProcessorConfig,
+    pub run: RunConfig,
+    pub output: OutputConfig,
+}
+
+impl Config {
+    /// Reads the config file at path.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let toml = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read config {}: {}", path.display(), err))?;
+        toml.parse()
+            .map_err(|err| format!("Invalid config {}: {}", path.display(), err))
+    }
+
+    /// Reads the settings given through the `ENV_VARS` of the process.
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(std::env::vars())
+    }
+
+    /// Reads the settings given through the `ENV_VARS` among vars, the other
+    /// variables are ignored. A value which can not be parsed is reported
+    /// with the name of its variable.
+    pub fn from_vars<I: IntoIterator<Item = (String, String)>>(vars: I) -> Result<Self, String> {
+        let mut config = Config::default();
+        for (name, value) in vars {
+            let invalid = |err: String| format!("Invalid {}: {}", name, err);
+            match name.as_str() {
+                "TRANSACTION_CACHE_DIR" => config.processor.cache_dir = Some(value),
+                "TRANSACTION_CACHE_LIMIT_BYTES" => {
+                    let bytes: u64 = value
+                        .parse()
+                        .map_err(|_| invalid(format!("{} is not a number of bytes", value)))?;
+                    config.processor.cache_limit = Some(bytes / TRANSACTION_SIZE);
+                }
+                "TRANSACTION_LOG_LEVEL" => {
+                    value.parse::<LogLevel>().map_err(invalid)?;
+                    config.run.log_level = Some(value);
+                }
+                "TRANSACTION_STRICT" => {
+                    config.run.fail_fast = Some(match value.as_str() {
+                        "true" | "1" => true,
+                        "false" | "0" => false,
+                        _ => return Err(invalid(format!("{}, expected true or false", value))),
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(config)
+    }
+
+    /// Overrides the settings with those set in other.
+    pub fn merge(&mut self, other: &Config) {
+        let mut merged = serde_json::to_value(&*self).expect("Config is always serializable");
+        let other = serde_json::to_value(other).expect("Config is always serializable");
+        for (table, values) in other.as_object().into_iter().flatten() {
+            for (key, value) in values.as_object().into_iter().flatten() {
+                if !value.is_null() {
+                    merged[table][key] = value.clone();
+                }
+            }
+        }
+        *self = serde_json::from_value(merged).expect("merged settings keep their types");
+    }
+
+    /// Writes the settings which are set as TOML, in the format read by
+    /// `from_str`.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("Config is always serializable")
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = String;
+
+    /// Parses the settings, rejecting the keys which are not settings.
+    fn from_str(toml: &str) -> Result<Self, Self::Err> {
+        toml::from_str(toml).map_err(|err| err.to_string().trim_end().to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that the supported values are parsed into their table and that the
+    // settings are written back in the same format.
+    #[test]
+    fn test_parse_config() {
+        let toml = r#"
+# Nightly run
+run.fail_fast = false
+
+[processor]
+held_policy = "strict" # reject instead of going negative
+cache_limit = 1_000
+cache_dir = 'C:\cache #1'
+
+[output]
+columns = "client=id,total"
+excel = true
+"#;
+        let config: Config = toml.parse().unwrap();
+        assert_eq!(config.processor.held_policy.as_deref(), Some("strict"));
+        assert_eq!(config.processor.cache_limit, Some(1000));
+        assert_eq!(config.processor.cache_dir.as_deref(), Some("C:\\cache #1"));
+        assert_eq!(config.processor.dispute_window, None);
+        assert_eq!(config.output.columns.as_deref(), Some("client=id,total"));
+        assert_eq!(config.output.excel, Some(true));
+        assert_eq!(config.run.fail_fast, Some(false));
+        assert_eq!(config.run.stats, None);
+
+        let written = config.to_toml();
+        assert!(written.starts_with(
+            "[processor]\nheld_policy = \"strict\"\ncache_limit = 1000\ncache_dir = 'C:\\cache #1'\n"
+        ));
+        assert_eq!(written.parse::<Config>(), Ok(config));
+    }
+
+    // Test that unknown keys are reported with their line and the expected
+    // ones, and that values of the wrong type or syntax are rejected.
+    #[test]
+    fn test_invalid_config() {
+        let error = |toml: &str| toml.parse::<Config>().unwrap_err();
+        let typo = error("[processor]\ndispute_windw = 3");
+        assert!(typo.starts_with("TOML parse error at line 2, column 1\n"));
+        assert!(typo.contains("unknown field `dispute_windw`, expected one of `held_policy`"));
+        assert!(error("[proccessor]\ndispute_window = 3")
+            .ends_with("unknown field `proccessor`, expected one of `processor`, `run`, `output`"));
+        assert!(error("processor = 3")
+            .ends_with("invalid type: integer `3`, expected struct ProcessorConfig"));
+        assert!(error("[run]\nfail_fast = \"yes\"")
+            .ends_with("invalid type: string \"yes\", expected a boolean"));
+        assert!(error("[run]\nfail_fast = yes").contains("string values must be quoted"));
+        assert!(error("[run]\nstats = true\nstats = false")
+            .starts_with("TOML parse error at line 3, column 1\n"));
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // Test that the environment variables are read into their setting and
+    // only override the settings they set.
+    #[test]
+    fn test_env_config() {
+        let env = Config::from_vars(vars(&[
+            (
+                "TRANSACTION_CACHE_LIMIT_BYTES",
+                &(TRANSACTION_SIZE * 100).to_string(),
+            ),
+            ("TRANSACTION_LOG_LEVEL", "error"),
+            ("TRANSACTION_STRICT", "1"),
+            ("HOME", "/root"),
+        ]))
+        .unwrap();
+        assert_eq!(env.processor.cache_limit, Some(100));
+        assert_eq!(env.processor.cache_dir, None);
+        assert_eq!(env.run.log_level.as_deref(), Some("error"));
+        assert_eq!(env.run.fail_fast, Some(true));
+
+        let mut config: Config = "[processor]\ncache_limit = 5\ndispute_window = 3\n"
+            .parse()
+            .unwrap();
+        config.merge(&env);
+        assert_eq!(config.processor.cache_limit, Some(100));
+        assert_eq!(config.processor.dispute_window, Some(3));
+        assert_eq!(config.run.fail_fast, Some(true));
+    }
+
+    // Test that a value which can not be parsed is reported with the name of
+    // its variable.
+    #[test]
+    fn test_invalid_env_config() {
+        assert_eq!(
+            Config::from_vars(vars(&[("TRANSACTION_CACHE_LIMIT_BYTES", "2GB")])),
+            Err("Invalid TRANSACTION_CACHE_LIMIT_BYTES: 2GB is not a number of bytes".to_owned())
+        );
+        assert_eq!(
+            Config::from_vars(vars(&[("TRANSACTION_STRICT", "yes")])),
+            Err("Invalid TRANSACTION_STRICT: yes, expected true or false".to_owned())
+        );
+        assert_eq!(
+            Config::from_vars(vars(&[("TRANSACTION_LOG_LEVEL", "debug")])),
+            Err(
+                "Invalid TRANSACTION_LOG_LEVEL: Unknown log level debug, expected off, error or warn"
+                    .to_owned()
+            )
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{BufRead, Write};
+
+use serde::Serialize;
+
+use crate::malformed::MalformedRow;
+use crate::runner::{self, InputFormat, MalformedOutcome, RunOptions};
+use crate::type_defs::{Transaction, TransactionRecord};
+
+/// Counters collected while converting an input.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConversionStats {
+    /// Records written in the target format.
+    pub converted: u64,
+    /// Records which were read but are not valid transactions, e.g. of an
+    /// unknown type or with too many fractional digits.
+    pub invalid: u64,
+    /// Rows which could not be read as a record.
+    pub malformed: u64,
+    /// Malformed rows copied by `MalformedRowPolicy::Quarantine`.
+    pub quarantined: u64,
+    /// False when the conversion was stopped by a malformed row, or an invalid
+    /// record with `fail_fast`.
+    pub completed: bool,
+}
+
+impl fmt::Display for ConversionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "converted: {}, invalid: {}, malformed: {}",
+            self.converted, self.invalid, self.malformed
+        )
+    }
+}
+
+/// Record as written in JSON lines, the fields which are not set are left
+/// out as in the inputs.
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    #[serde(rename = "type")]
+    transaction_type: &'a str,
+    client: u32,
+    tx: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memo: Option<&'a str>,
+}
+
+impl<'a> From<&'a TransactionRecord> for JsonRecord<'a> {
+    fn from(record: &'a TransactionRecord) -> Self {
+        JsonRecord {
+            transaction_type: &record.transaction_type,
+            client: record.client,
+            tx: record.tx,
+            amount: record.amount.as_deref(),
+            idempotency_key: record.idempotency_key.as_deref(),
+            timestamp: record.timestamp.as_deref(),
+            memo: record.memo.as_deref(),
+        }
+    }
+}
+
+/// Writes the records in one of the input formats.
+enum RecordWriter<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Jsonl(W),
+}
+
+impl<W: Write> RecordWriter<W> {
+    fn write(&mut self, record: &TransactionRecord) -> Result<(), String> {
+        let error = |err: String| format!("Could not write converted record because of: {}", err);
+        match self {
+            RecordWriter::Csv(writer) => writer
+                .serialize(record)
+                .map_err(|err| error(err.to_string())),
+            RecordWriter::Jsonl(writer) => {
+                let line = serde_json::to_string(&JsonRecord::from(record))
+                    .map_err(|err| error(err.to_string()))?;
+                writeln!(writer, "{}", line).map_err(|err| error(err.to_string()))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        let error = |err: std::io::Error| format!("Could not write converted records: {}", err);
+        match self {
+            RecordWriter::Csv(writer) => writer.flush().map_err(error),
+            RecordWriter::Jsonl(writer) => writer.flush().map_err(error),
+        }
+    }
+}
+
+/// Converts the records of input from one format into another, in order and
+/// without applying them. Each record is only checked to be a valid
+/// transaction, the invalid ones are skipped like the rejected records of a
+/// run and the malformed rows are handled by the `MalformedRowPolicy` of the
+/// options. The csv output always has a header.
+pub fn convert<R: BufRead, W: Write>(
+    input: R,
+    from: InputFormat,
+    output: W,
+    to: InputFormat,
+    options: &RunOptions,
+) -> Result<ConversionStats, String> {
+    let records: Box<dyn Iterator<Item = Result<TransactionRecord, MalformedRow>>> = match from {
+        InputFormat::Csv => Box::new(runner::read_csv_records(input, options)?),
+        InputFormat::Jsonl => Box::new(runner::read_jsonl_records(input, options)),
+    };
+    let mut writer = match to {
+        InputFormat::Csv => RecordWriter::Csv(Box::new(csv::Writer::from_writer(output))),
+        InputFormat::Jsonl => RecordWriter::Jsonl(output),
+    };
+
+    let mut stats = ConversionStats::default();
+    for result in records {
+        match result {
+            Ok(record) => match Transaction::try_from(&record) {
+                Ok(_) => {
+                    writer.write(&record)?;
+                    stats.converted += 1;
+                }
+                Err(err) => {
+                    stats.invalid += 1;
+                    if options.fail_fast {
+                        options.error(format_args!(
+                            "Failed with error {}: {} for record: {:?}",
+                            err.code(),
+                            err,
+                            record
+                        ));
+                        return writer.flush().map(|_| stats);
+                    }
+                    options.warn(format_args!(
+                        "Ignoring error {}: {} for record: {:?}",
+                        err.code(),
+                        err,
+                        record
+                    ));
+                }
+            },
+            Err(row) => {
+                stats.malformed += 1;
+                match runner::handle_malformed(&row, options) {
+                    MalformedOutcome::Ignored => {}
+                    MalformedOutcome::Quarantined => stats.quarantined += 1,
+                    MalformedOutcome::Failed => return writer.flush().map(|_| stats),
+                }
+            }
+        }
+    }
+    writer.flush()?;
+    stats.completed = runner::flush_quarantine(options);
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::malformed::MalformedRowPolicy;
+
+    fn convert_str(input: &str, from: InputFormat, to: InputFormat) -> (String, ConversionStats) {
+        let mut output = Vec::new();
+        let stats = convert(
+            input.as_bytes(),
+            from,
+            &mut output,
+            to,
+            &RunOptions::default(),
+        )
+        .unwrap();
+        (String::from_utf8(output).unwrap(), stats)
+    }
+
+    fn csv_records(input: &str) -> Vec<TransactionRecord> {
+        runner::read_csv_records(input.as_bytes(), &RunOptions::default())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    // Test that converting csv to JSON lines and back gives the same records,
+    // in the same order.
+    #[test]
+    fn test_round_trip() {
+        let input = "type,client,tx,amount\n\
+                     deposit, 1, 1, 1.0\n\
+                     deposit,2,2,2.5\n\
+                     dispute,1,1,\n\
+                     withdrawal,1,3,0.1234\n\
+                     chargeback,1,1,\n";
+        let (jsonl, stats) = convert_str(input, InputFormat::Csv, InputFormat::Jsonl);
+        assert_eq!(stats.converted, 5);
+        assert!(stats.completed);
+        assert!(
+            jsonl.starts_with("{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.0\"}\n")
+        );
+        assert!(jsonl.contains("\n{\"type\":\"dispute\",\"client\":1,\"tx\":1}\n"));
+
+        let (csv, stats) = convert_str(&jsonl, InputFormat::Jsonl, InputFormat::Csv);
+        assert_eq!(stats.converted, 5);
+        assert!(csv.starts_with("type,client,tx,amount,idempotency_key,timestamp,memo\n"));
+        let round_trip = csv_records(&csv);
+        let original = csv_records(input);
+        assert_eq!(round_trip.len(), original.len());
+        for (converted, original) in round_trip.iter().zip(original.iter()) {
+            assert_eq!(converted.transaction_type, original.transaction_type);
+            assert_eq!(
+                (converted.client, converted.tx),
+                (original.client, original.tx)
+            );
+            assert_eq!(converted.amount, original.amount);
+        }
+    }
+
+    // Test that malformed rows and invalid records are counted and skipped in
+    // the same way from either format.
+    #[test]
+    fn test_malformed_rows() {
+        let csv = "deposit,1,1,1.0\n\
+                   deposit,two,2,3.0\n\
+                   bonus,1,3,1.0\n\
+                   deposit,1,4,1.00001\n\
+                   withdrawal,1,5,0.5\n";
+        let jsonl = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.0\"}\n\
+                     {\"type\":\"deposit\",\"client\":\"two\",\"tx\":2,\"amount\":\"3.0\"}\n\
+                     {\"type\":\"bonus\",\"client\":1,\"tx\":3,\"amount\":\"1.0\"}\n\
+                     {\"type\":\"deposit\",\"client\":1,\"tx\":4,\"amount\":\"1.00001\"}\n\
+                     {\"type\":\"withdrawal\",\"client\":1,\"tx\":5,\"amount\":\"0.5\"}\n";
+        let (to_jsonl, csv_stats) = convert_str(csv, InputFormat::Csv, InputFormat::Jsonl);
+        let (to_csv, jsonl_stats) = convert_str(jsonl, InputFormat::Jsonl, InputFormat::Csv);
+        assert_eq!(
+            csv_stats,
+            ConversionStats {
+                converted: 2,
+                invalid: 2,
+                malformed: 1,
+                quarantined: 0,
+                completed: true,
+            }
+        );
+        assert_eq!(jsonl_stats, csv_stats);
+        assert_eq!(to_jsonl.lines().count(), 2);
+        assert_eq!(to_csv.lines().count(), 3);
+
+        // Aborting on malformed rows keeps what was converted before.
+        let options = RunOptions::default().with_malformed_row_policy(MalformedRowPolicy::Abort);
+        let mut output = Vec::new();
+        let stats = convert(
+            csv.as_bytes(),
+            InputFormat::Csv,
+            &mut output,
+            InputFormat::Jsonl,
+            &options,
+        )
+        .unwrap();
+        assert!(!stats.completed);
+        assert_eq!(stats.converted, 1);
+        assert_eq!(String::from_utf8(output).unwrap().lines().count(), 1);
+    }
+}
@@ -0,0 +1,164 @@
+use encoding_rs::WINDOWS_1252;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+/// Byte order mark which Excel writes at the start of UTF-8 files.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+/// Number of bytes read at once from the input when transcoding.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Character encoding of an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// ISO-8859-1, every byte is the code point of the same value.
+    Latin1,
+    /// Windows-1252, latin-1 with printable characters such as `€` in place
+    /// of most of the control characters from 0x80 to 0x9F.
+    Windows1252,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "utf8" | "utf-8" => Ok(Encoding::Utf8),
+            "latin1" | "latin-1" | "iso-8859-1" => Ok(Encoding::Latin1),
+            "windows-1252" | "windows1252" | "cp1252" => Ok(Encoding::Windows1252),
+            _ => Err(format!("Unknown encoding {}", value)),
+        }
+    }
+}
+
+/// Type which reads an input as UTF-8, dropping the leading byte order mark of
+/// UTF-8 inputs and transcoding latin-1 inputs.
+#[derive(Debug)]
+pub struct Decoder<R> {
+    input: R,
+    encoding: Encoding,
+    /// Whether the start of the input has been checked for a byte order mark.
+    started: bool,
+    /// Decoded bytes not returned yet.
+    pending: Vec<u8>,
+    pending_pos: usize,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(input: R, encoding: Encoding) -> Self {
+        Decoder {
+            input,
+            encoding,
+            started: false,
+            pending: Vec::new(),
+            pending_pos: 0,
+        }
+    }
+
+    /// Reads the first bytes of the input, dropping the byte order mark.
+    fn start(&mut self) -> io::Result<()> {
+        let mut head = Vec::with_capacity(UTF8_BOM.len());
+        (&mut self.input)
+            .take(UTF8_BOM.len() as u64)
+            .read_to_end(&mut head)?;
+        if self.encoding == Encoding::Utf8 && head == UTF8_BOM {
+            head.clear();
+        }
+        self.decode(&head);
+        self.started = true;
+        Ok(())
+    }
+
+    fn decode(&mut self, raw: &[u8]) {
+        self.pending.clear();
+        self.pending_pos = 0;
+        match self.encoding {
+            Encoding::Utf8 => self.pending.extend_from_slice(raw),
+            Encoding::Latin1 => self
+                .pending
+                .extend_from_slice(encoding_rs::mem::decode_latin1(raw).as_bytes()),
+            // Every byte is a character on its own in windows-1252, so the
+            // chunks of the input are decoded independently.
+            Encoding::Windows1252 => {
+                let (decoded, _) = WINDOWS_1252.decode_without_bom_handling(raw);
+                self.pending.extend_from_slice(decoded.as_bytes());
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.started {
+            self.start()?;
+        }
+        if self.pending_pos == self.pending.len() {
+            if self.encoding == Encoding::Utf8 {
+                return self.input.read(buf);
+            }
+            let mut raw = [0; CHUNK_SIZE];
+            let read = self.input.read(&mut raw)?;
+            self.decode(&raw[..read]);
+        }
+        let pending = &self.pending[self.pending_pos..];
+        let len = pending.len().min(buf.len());
+        buf[..len].copy_from_slice(&pending[..len]);
+        self.pending_pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(input: &[u8], encoding: Encoding) -> String {
+        let mut output = String::new();
+        Decoder::new(input, encoding)
+            .read_to_string(&mut output)
+            .unwrap();
+        output
+    }
+
+    // Test that only a leading byte order mark is dropped.
+    #[test]
+    fn test_strip_bom() {
+        assert_eq!(
+            decode(b"\xEF\xBB\xBFtype,client", Encoding::Utf8),
+            "type,client"
+        );
+        assert_eq!(decode(b"type,client", Encoding::Utf8), "type,client");
+        assert_eq!(decode(b"ty", Encoding::Utf8), "ty");
+        assert_eq!(decode(b"", Encoding::Utf8), "");
+        assert_eq!(
+            decode("a,\u{feff}b".as_bytes(), Encoding::Utf8),
+            "a,\u{feff}b"
+        );
+    }
+
+    // Test that latin-1 bytes are transcoded to UTF-8.
+    #[test]
+    fn test_latin1() {
+        assert_eq!(decode(b"caf\xE9,\xFCber", Encoding::Latin1), "café,über");
+        let long: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| [b'a', 0xE9][i % 2]).collect();
+        assert_eq!(
+            decode(&long, Encoding::Latin1),
+            "aé".repeat(3 * CHUNK_SIZE / 2)
+        );
+        assert_eq!("ISO-8859-1".parse(), Ok(Encoding::Latin1));
+        assert!("ebcdic".parse::<Encoding>().is_err());
+    }
+
+    // Test that windows-1252 bytes are transcoded to UTF-8, the bytes from
+    // 0x80 to 0x9F unlike in latin-1.
+    #[test]
+    fn test_windows1252() {
+        assert_eq!(
+            decode(b"\x80 5,\x93caf\xE9\x94,\x81", Encoding::Windows1252),
+            "€ 5,“café”,\u{81}"
+        );
+        assert_eq!(decode(b"\x80", Encoding::Latin1), "\u{80}");
+        assert_eq!("CP1252".parse(), Ok(Encoding::Windows1252));
+    }
+}
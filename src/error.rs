@@ -0,0 +1,454 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::client::AccountStatus;
+use crate::policy::PolicyViolation;
+use crate::processor::RecordedOutcome;
+use crate::type_defs::{Amount, TransactionKind};
+use crate::validation::ValidationRule;
+
+/// Type to represent the reasons for which a transaction could not be processed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessingError {
+    /// The record has a transaction type we do not know how to process.
+    UnknownTransactionType(String),
+    /// A deposit or withdrawal record without an amount.
+    MissingAmount(TransactionKind),
+    /// The amount could not be parsed as a decimal number.
+    InvalidAmount(String),
+    /// The amount has more fractional digits than supported.
+    InvalidPrecision,
+    /// The client account is locked, no transaction is processed anymore.
+    AccountLocked,
+    /// The client account is closed, no transaction is processed until it
+    /// is reopened.
+    AccountClosed,
+    /// The client account is frozen pending a review.
+    AccountFrozen,
+    /// The account status can not change as requested, e.g. a closed account
+    /// being frozen.
+    InvalidStatusTransition {
+        from: AccountStatus,
+        to: AccountStatus,
+    },
+    /// A transaction with the same id has already been processed.
+    DuplicateTransaction,
+    /// The same deposit or withdrawal, with the same amount, has already been
+    /// processed, most likely a harmless replay.
+    DuplicateReplay,
+    /// A transaction with the same id but another kind or amount has already
+    /// been processed, which hints at corrupted data upstream.
+    ConflictingDuplicate {
+        stored_amount: Option<Amount>,
+        new_amount: Amount,
+    },
+    /// The withdrawal amount exceeds the available funds.
+    InsufficientFunds,
+    /// The amount of the deposit or withdrawal is below the minimum of the
+    /// client.
+    BelowMinimum {
+        kind: TransactionKind,
+        minimum: Amount,
+    },
+    /// Combining the amounts does not fit, with `OverflowPolicy::Reject`.
+    Overflow { amount: Amount, change: Amount },
+    /// The client already has the maximum number of transactions.
+    ClientTransactionLimitExceeded,
+    /// The withdrawal would exceed the `VelocityLimit` of the client.
+    VelocityLimitExceeded { limit: usize, window: usize },
+    /// The referenced transaction could not be found among the transactions
+    /// of the client of the record, which is also the case when its id
+    /// belongs to another client.
+    TransactionNotFound,
+    /// More transactions than the dispute window were applied to the client
+    /// after the referenced transaction.
+    StaleDispute { age: u64, window: u64 },
+    /// The disputed portion is larger than the referenced deposit.
+    DisputeExceedsDeposit { disputed: Amount, deposited: Amount },
+    /// The referenced transaction is already under dispute.
+    AlreadyDisputed,
+    /// The referenced transaction is not under dispute.
+    NotDisputed,
+    /// The referenced transaction can not be disputed.
+    NotDisputable,
+    /// The referenced transaction belongs to another client.
+    ClientMismatch,
+    /// Releasing the disputed amount would make the held balance negative.
+    NegativeHeld { held: Amount, amount: Amount },
+    /// A record with the same idempotency key has already been processed, it
+    /// carries the outcome remembered for the key.
+    IdempotentReplay(RecordedOutcome),
+    /// The transaction was vetoed by a `TransactionPolicy`.
+    PolicyViolation(PolicyViolation),
+    /// The record breaks a rule of the `RecordValidator`.
+    Validation {
+        rule: ValidationRule,
+        reason: String,
+    },
+    /// The transaction cache could not be used.
+    Cache(String),
+    /// A transaction cache could not spill to disk with
+    /// `SpillFailurePolicy::Abort`, no record is processed anymore.
+    SpillFailed(String),
+}
+
+/// How serious the rejection of a record is, from a routine replay to an
+/// error after which no record can be processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    /// Routine, e.g. a replay of an already processed transaction.
+    Info,
+    /// Business as usual, e.g. a withdrawal exceeding the available funds.
+    Warn,
+    /// Hints at corrupted data upstream, e.g. a conflicting duplicate.
+    Error,
+    /// The processor can not go on, the run is always stopped.
+    Fatal,
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "info" => Ok(Severity::Info),
+            "warn" => Ok(Severity::Warn),
+            "error" => Ok(Severity::Error),
+            "fatal" => Ok(Severity::Fatal),
+            _ => Err(format!(
+                "Unknown severity {}, expected info, warn, error or fatal",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warn => write!(f, "warn"),
+            Severity::Error => write!(f, "error"),
+            Severity::Fatal => write!(f, "fatal"),
+        }
+    }
+}
+
+impl ProcessingError {
+    /// Returns how serious the error is.
+    pub fn severity(&self) -> Severity {
+        match self {
+            ProcessingError::DuplicateReplay | ProcessingError::IdempotentReplay(_) => {
+                Severity::Info
+            }
+            ProcessingError::ConflictingDuplicate { .. } => Severity::Error,
+            ProcessingError::Cache(_) | ProcessingError::SpillFailed(_) => Severity::Fatal,
+            _ => Severity::Warn,
+        }
+    }
+
+    /// Returns the short code of the error, e.g. `E_LOCKED`, which stays the
+    /// same across releases while the messages may change.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProcessingError::UnknownTransactionType(_) => "E_UNKNOWN_TYPE",
+            ProcessingError::MissingAmount(_) => "E_MISSING_AMOUNT",
+            ProcessingError::InvalidAmount(_) => "E_INVALID_AMOUNT",
+            ProcessingError::InvalidPrecision => "E_PRECISION",
+            ProcessingError::AccountLocked => "E_LOCKED",
+            ProcessingError::AccountClosed => "E_CLOSED",
+            ProcessingError::AccountFrozen => "E_FROZEN",
+            ProcessingError::InvalidStatusTransition { .. } => "E_STATUS",
+            ProcessingError::DuplicateTransaction => "E_DUP_TX",
+            ProcessingError::DuplicateReplay => "E_DUP_REPLAY",
+            ProcessingError::ConflictingDuplicate { .. } => "E_DUP_CONFLICT",
+            ProcessingError::InsufficientFunds => "E_INSUFFICIENT",
+            ProcessingError::BelowMinimum { .. } => "E_BELOW_MINIMUM",
+            ProcessingError::Overflow { .. } => "E_OVERFLOW",
+            ProcessingError::ClientTransactionLimitExceeded => "E_TX_LIMIT",
+            ProcessingError::VelocityLimitExceeded { .. } => "E_VELOCITY",
+            ProcessingError::TransactionNotFound => "E_DISPUTE_NOT_FOUND",
+            ProcessingError::StaleDispute { .. } => "E_STALE_DISPUTE",
+            ProcessingError::DisputeExceedsDeposit { .. } => "E_DISPUTE_EXCEEDS",
+            ProcessingError::AlreadyDisputed => "E_ALREADY_DISPUTED",
+            ProcessingError::NotDisputed => "E_NOT_DISPUTED",
+            ProcessingError::NotDisputable => "E_NOT_DISPUTABLE",
+            ProcessingError::ClientMismatch => "E_CLIENT_MISMATCH",
+            ProcessingError::NegativeHeld { .. } => "E_NEGATIVE_HELD",
+            ProcessingError::IdempotentReplay(_) => "E_IDEMPOTENT_REPLAY",
+            ProcessingError::PolicyViolation(_) => "E_POLICY",
+            ProcessingError::Validation { .. } => "E_VALIDATION",
+            ProcessingError::Cache(_) => "E_IO",
+            ProcessingError::SpillFailed(_) => "E_SPILL",
+        }
+    }
+
+    /// Returns the number of the error in the table of codes, from 1 for
+    /// `E_UNKNOWN_TYPE`, for the callers which can only compare integers,
+    /// e.g. through the C interface. The numbers stay the same across
+    /// releases, new errors get the next ones.
+    pub fn numeric_code(&self) -> i32 {
+        match self {
+            ProcessingError::UnknownTransactionType(_) => 1,
+            ProcessingError::MissingAmount(_) => 2,
+            ProcessingError::InvalidAmount(_) => 3,
+            ProcessingError::InvalidPrecision => 4,
+            ProcessingError::AccountLocked => 5,
+            ProcessingError::AccountClosed => 6,
+            ProcessingError::AccountFrozen => 7,
+            ProcessingError::InvalidStatusTransition { .. } => 8,
+            ProcessingError::DuplicateTransaction => 9,
+            ProcessingError::DuplicateReplay => 10,
+            ProcessingError::ConflictingDuplicate { .. } => 11,
+            ProcessingError::InsufficientFunds => 12,
+            ProcessingError::BelowMinimum { .. } => 13,
+            ProcessingError::Overflow { .. } => 14,
+            ProcessingError::ClientTransactionLimitExceeded => 15,
+            ProcessingError::VelocityLimitExceeded { .. } => 16,
+            ProcessingError::TransactionNotFound => 17,
+            ProcessingError::StaleDispute { .. } => 18,
+            ProcessingError::DisputeExceedsDeposit { .. } => 19,
+            ProcessingError::AlreadyDisputed => 20,
+            ProcessingError::NotDisputed => 21,
+            ProcessingError::NotDisputable => 22,
+            ProcessingError::ClientMismatch => 23,
+            ProcessingError::NegativeHeld { .. } => 24,
+            ProcessingError::IdempotentReplay(_) => 25,
+            ProcessingError::PolicyViolation(_) => 26,
+            ProcessingError::Validation { .. } => 27,
+            ProcessingError::Cache(_) => 28,
+            ProcessingError::SpillFailed(_) => 29,
+        }
+    }
+}
+
+impl fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessingError::UnknownTransactionType(transaction_type) => {
+                write!(f, "Unknown transaction type {}", transaction_type)
+            }
+            ProcessingError::MissingAmount(kind) => write!(f, "Missing amount for {}", kind),
+            ProcessingError::InvalidAmount(reason) => write!(f, "Invalid amount {}", reason),
+            ProcessingError::InvalidPrecision => write!(f, "Invalid precision"),
+            ProcessingError::AccountLocked => write!(f, "Account locked"),
+            ProcessingError::AccountClosed => write!(f, "Account closed"),
+            ProcessingError::AccountFrozen => write!(f, "Account frozen"),
+            ProcessingError::InvalidStatusTransition { from, to } => {
+                write!(f, "Account status can not change from {} to {}", from, to)
+            }
+            ProcessingError::DuplicateTransaction | ProcessingError::DuplicateReplay => {
+                write!(f, "Transaction already processed")
+            }
+            ProcessingError::ConflictingDuplicate {
+                stored_amount: Some(stored_amount),
+                new_amount,
+            } => write!(
+                f,
+                "Transaction already processed with amount {} instead of {}",
+                stored_amount, new_amount
+            ),
+            ProcessingError::ConflictingDuplicate {
+                stored_amount: None,
+                new_amount,
+            } => write!(
+                f,
+                "Transaction already processed as another type, not with amount {}",
+                new_amount
+            ),
+            ProcessingError::InsufficientFunds => write!(f, "Insufficient funds"),
+            ProcessingError::BelowMinimum { kind, minimum } => {
+                write!(f, "Below minimum {} of {}", kind, minimum)
+            }
+            ProcessingError::StaleDispute { age, window } => write!(
+                f,
+                "Transaction too old to be disputed, {} transactions since while the window is {}",
+                age, window
+            ),
+            ProcessingError::DisputeExceedsDeposit {
+                disputed,
+                deposited,
+            } => write!(
+                f,
+                "Disputed amount {} exceeds the deposited amount {}",
+                disputed, deposited
+            ),
+            ProcessingError::Overflow { amount, change } => {
+                write!(f, "Amount {} overflows when changed by {}", amount, change)
+            }
+            ProcessingError::ClientTransactionLimitExceeded => {
+                write!(f, "Client transaction limit exceeded")
+            }
+            ProcessingError::VelocityLimitExceeded { limit, window } => write!(
+                f,
+                "More than {} withdrawals within {} transactions",
+                limit, window
+            ),
+            ProcessingError::IdempotentReplay(RecordedOutcome::Applied(_)) => {
+                write!(f, "Idempotent replay of an applied record")
+            }
+            ProcessingError::IdempotentReplay(RecordedOutcome::Rejected(reason)) => {
+                write!(f, "Idempotent replay of a record rejected with: {}", reason)
+            }
+            ProcessingError::NegativeHeld { held, amount } => write!(
+                f,
+                "Releasing {} would make held negative, only {} is held",
+                amount, held
+            ),
+            ProcessingError::TransactionNotFound => {
+                write!(f, "Transaction not found for this client")
+            }
+            ProcessingError::AlreadyDisputed => write!(f, "Transaction already disputed"),
+            ProcessingError::NotDisputed => write!(f, "Transaction not under dispute"),
+            ProcessingError::NotDisputable => write!(f, "Wrong transaction type"),
+            ProcessingError::ClientMismatch => write!(f, "Client mismatch"),
+            ProcessingError::PolicyViolation(violation) => {
+                write!(f, "Policy violation: {}", violation)
+            }
+            ProcessingError::Validation { rule, reason } => {
+                write!(f, "Validation rule {} failed: {}", rule, reason)
+            }
+            ProcessingError::Cache(reason) => write!(f, "Cache error: {}", reason),
+            ProcessingError::SpillFailed(reason) => write!(f, "Spill failed: {}", reason),
+        }
+    }
+}
+
+impl Error for ProcessingError {}
+
+impl From<PolicyViolation> for ProcessingError {
+    fn from(violation: PolicyViolation) -> Self {
+        ProcessingError::PolicyViolation(violation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // Test that every error has its own code and that the codes do not change,
+    // the log pipelines alert on them.
+    #[test]
+    fn test_codes() {
+        let amount: Amount = "1.0".parse().unwrap();
+        let errors = [
+            ProcessingError::UnknownTransactionType("bonus".to_owned()),
+            ProcessingError::MissingAmount(TransactionKind::Deposit),
+            ProcessingError::InvalidAmount("x".to_owned()),
+            ProcessingError::InvalidPrecision,
+            ProcessingError::AccountLocked,
+            ProcessingError::AccountClosed,
+            ProcessingError::AccountFrozen,
+            ProcessingError::InvalidStatusTransition {
+                from: AccountStatus::Closed,
+                to: AccountStatus::Frozen,
+            },
+            ProcessingError::DuplicateTransaction,
+            ProcessingError::DuplicateReplay,
+            ProcessingError::ConflictingDuplicate {
+                stored_amount: None,
+                new_amount: amount,
+            },
+            ProcessingError::InsufficientFunds,
+            ProcessingError::BelowMinimum {
+                kind: TransactionKind::Withdrawal,
+                minimum: amount,
+            },
+            ProcessingError::Overflow {
+                amount,
+                change: amount,
+            },
+            ProcessingError::ClientTransactionLimitExceeded,
+            ProcessingError::VelocityLimitExceeded {
+                limit: 1,
+                window: 2,
+            },
+            ProcessingError::TransactionNotFound,
+            ProcessingError::StaleDispute { age: 3, window: 2 },
+            ProcessingError::DisputeExceedsDeposit {
+                disputed: amount,
+                deposited: amount,
+            },
+            ProcessingError::AlreadyDisputed,
+            ProcessingError::NotDisputed,
+            ProcessingError::NotDisputable,
+            ProcessingError::ClientMismatch,
+            ProcessingError::NegativeHeld {
+                held: amount,
+                amount,
+            },
+            ProcessingError::IdempotentReplay(RecordedOutcome::Rejected("locked".to_owned())),
+            ProcessingError::PolicyViolation(PolicyViolation("limit".to_owned())),
+            ProcessingError::Validation {
+                rule: ValidationRule::ZeroClient,
+                reason: "client id 0".to_owned(),
+            },
+            ProcessingError::Cache("full".to_owned()),
+            ProcessingError::SpillFailed("full".to_owned()),
+        ];
+        let codes: Vec<_> = errors.iter().map(ProcessingError::code).collect();
+        assert_eq!(
+            codes,
+            [
+                "E_UNKNOWN_TYPE",
+                "E_MISSING_AMOUNT",
+                "E_INVALID_AMOUNT",
+                "E_PRECISION",
+                "E_LOCKED",
+                "E_CLOSED",
+                "E_FROZEN",
+                "E_STATUS",
+                "E_DUP_TX",
+                "E_DUP_REPLAY",
+                "E_DUP_CONFLICT",
+                "E_INSUFFICIENT",
+                "E_BELOW_MINIMUM",
+                "E_OVERFLOW",
+                "E_TX_LIMIT",
+                "E_VELOCITY",
+                "E_DISPUTE_NOT_FOUND",
+                "E_STALE_DISPUTE",
+                "E_DISPUTE_EXCEEDS",
+                "E_ALREADY_DISPUTED",
+                "E_NOT_DISPUTED",
+                "E_NOT_DISPUTABLE",
+                "E_CLIENT_MISMATCH",
+                "E_NEGATIVE_HELD",
+                "E_IDEMPOTENT_REPLAY",
+                "E_POLICY",
+                "E_VALIDATION",
+                "E_IO",
+                "E_SPILL",
+            ]
+        );
+        assert_eq!(codes.iter().collect::<HashSet<_>>().len(), codes.len());
+        let numeric_codes: Vec<_> = errors.iter().map(ProcessingError::numeric_code).collect();
+        assert_eq!(numeric_codes, (1..=codes.len() as i32).collect::<Vec<_>>());
+    }
+
+    // Test that the severities are ordered and parsed from their name.
+    #[test]
+    fn test_severity() {
+        assert_eq!(ProcessingError::DuplicateReplay.severity(), Severity::Info);
+        assert_eq!(
+            ProcessingError::InsufficientFunds.severity(),
+            Severity::Warn
+        );
+        assert_eq!(
+            ProcessingError::Cache("full".to_owned()).severity(),
+            Severity::Fatal
+        );
+        assert!(Severity::Info < Severity::Warn && Severity::Error < Severity::Fatal);
+        for severity in [
+            Severity::Info,
+            Severity::Warn,
+            Severity::Error,
+            Severity::Fatal,
+        ] {
+            assert_eq!(severity.to_string().parse(), Ok(severity));
+        }
+        assert!("critical".parse::<Severity>().is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! C interface of the processor, enabled with `--features ffi`, for the
+//! programs embedding it in-process. The declarations are in
+//! `include/exchange.h`, generated with `cbindgen --config cbindgen.toml`.
+//!
+//! A caller creates a processor with `tp_new`, gives it the records one by
+//! one with `tp_process_record`, reads the balances with `tp_serialize_csv`
+//! and releases it with `tp_free`. No panic crosses the interface, it is
+//! turned into `TP_ERR_PANIC` and the processor which panicked rejects every
+//! later call with the same error since its state is no longer known.
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use crate::processor::{OutputOptions, TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+use crate::type_defs::TransactionRecord;
+
+/// The record was applied.
+pub const TP_OK: i32 = 0;
+/// A pointer is null or a string is not valid UTF-8.
+pub const TP_ERR_INVALID_ARGUMENT: i32 = -1;
+/// The processor panicked, during this call or an earlier one.
+pub const TP_ERR_PANIC: i32 = -2;
+/// The balances could not be written.
+pub const TP_ERR_OUTPUT: i32 = -3;
+
+/// Processor handed to the C callers, opaque to them.
+pub struct TpProcessor {
+    processor: TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>,
+    /// Set once a call panicked.
+    poisoned: bool,
+}
+
+/// Runs f on the processor behind handle, `TP_ERR_PANIC` is returned when it
+/// panics or panicked before.
+///
+/// # Safety
+///
+/// handle must be null or come from `tp_new` and not be freed yet.
+unsafe fn with_processor<F>(handle: *mut TpProcessor, f: F) -> isize
+where
+    F: FnOnce(&mut TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>) -> isize,
+{
+    let handle = match handle.as_mut() {
+        Some(handle) => handle,
+        None => return TP_ERR_INVALID_ARGUMENT as isize,
+    };
+    if handle.poisoned {
+        return TP_ERR_PANIC as isize;
+    }
+    match panic::catch_unwind(AssertUnwindSafe(|| f(&mut handle.processor))) {
+        Ok(result) => result,
+        Err(_) => {
+            handle.poisoned = true;
+            TP_ERR_PANIC as isize
+        }
+    }
+}
+
+/// Reads a string given by the caller, None when it is null or not UTF-8.
+///
+/// # Safety
+///
+/// value must be null or point to a NUL terminated string.
+unsafe fn read_str<'a>(value: *const c_char) -> Option<&'a str> {
+    if value.is_null() {
+        return None;
+    }
+    CStr::from_ptr(value).to_str().ok()
+}
+
+/// Creates a processor with the default settings, to be released with
+/// `tp_free`. Returns null if it could not be created.
+#[no_mangle]
+pub extern "C" fn tp_new() -> *mut TpProcessor {
+    panic::catch_unwind(|| {
+        Box::into_raw(Box::new(TpProcessor {
+            processor: TransactionProcessor::new(),
+            poisoned: false,
+        }))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Processes a record, amount being null for the disputes, resolves and
+/// chargebacks. Returns `TP_OK` when it is applied, the number of its error
+/// from `ProcessingError::numeric_code` when it is rejected, or one of the
+/// negative `TP_ERR_` codes.
+///
+/// # Safety
+///
+/// handle must come from `tp_new` and not be freed yet, transaction_type must
+/// point to a NUL terminated string and amount be null or point to one.
+#[no_mangle]
+pub unsafe extern "C" fn tp_process_record(
+    handle: *mut TpProcessor,
+    transaction_type: *const c_char,
+    client: u32,
+    tx: u64,
+    amount: *const c_char,
+) -> i32 {
+    let transaction_type = match read_str(transaction_type) {
+        Some(transaction_type) => transaction_type,
+        None => return TP_ERR_INVALID_ARGUMENT,
+    };
+    let amount = match (amount.is_null(), read_str(amount)) {
+        (true, _) => None,
+        (false, Some(amount)) => Some(amount.to_owned()),
+        (false, None) => return TP_ERR_INVALID_ARGUMENT,
+    };
+    let record = TransactionRecord {
+        transaction_type: transaction_type.to_owned(),
+        client,
+        tx,
+        amount,
+        ..Default::default()
+    };
+    with_processor(handle, |processor| {
+        let code = match processor.process_transaction(record) {
+            Ok(_) => TP_OK,
+            Err(err) => err.numeric_code(),
+        };
+        code as isize
+    }) as i32
+}
+
+/// Writes the balances of the clients as csv into buf, as the command line
+/// writes them by default, without NUL terminator. Returns the length of the
+/// csv, which is only written when it fits into the len bytes of buf, e.g.
+/// call it with a null buf and a len of 0 to size the buffer. Returns one of
+/// the negative `TP_ERR_` codes otherwise.
+///
+/// # Safety
+///
+/// handle must come from `tp_new` and not be freed yet, buf must be null with
+/// a len of 0 or point to len writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn tp_serialize_csv(
+    handle: *mut TpProcessor,
+    buf: *mut u8,
+    len: usize,
+) -> isize {
+    if buf.is_null() && len != 0 {
+        return TP_ERR_INVALID_ARGUMENT as isize;
+    }
+    with_processor(handle, |processor| {
+        let mut csv = Vec::new();
+        if processor
+            .serialize_to(&mut csv, &OutputOptions::default())
+            .is_err()
+        {
+            return TP_ERR_OUTPUT as isize;
+        }
+        // buf may be null when len is 0, which only an empty csv fits in.
+        if !csv.is_empty() && csv.len() <= len {
+            slice::from_raw_parts_mut(buf, csv.len()).copy_from_slice(&csv);
+        }
+        csv.len() as isize
+    })
+}
+
+/// Releases a processor created with `tp_new`, null is ignored.
+///
+/// # Safety
+///
+/// handle must be null or come from `tp_new` and not be freed yet, it must
+/// not be used anymore.
+#[no_mangle]
+pub unsafe extern "C" fn tp_free(handle: *mut TpProcessor) {
+    if !handle.is_null() {
+        // Dropping the processor removes its caches, which may fail.
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(handle))));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProcessingError;
+    use crate::type_defs::TransactionKind;
+    use std::ffi::CString;
+    use std::fs;
+
+    // Test a run driven as a C caller would: the records given as C strings,
+    // the balances read into a buffer first too small to hold them.
+    #[test]
+    fn test_c_caller() {
+        let handle = tp_new();
+        assert!(!handle.is_null());
+        let deposit = CString::new("deposit").unwrap();
+        let withdrawal = CString::new("withdrawal").unwrap();
+        let dispute = CString::new("dispute").unwrap();
+        let deposited = CString::new("2.5").unwrap();
+        let withdrawn = CString::new("10").unwrap();
+        unsafe {
+            assert_eq!(
+                tp_process_record(handle, deposit.as_ptr(), 1, 1, deposited.as_ptr()),
+                TP_OK
+            );
+            assert_eq!(
+                tp_process_record(handle, withdrawal.as_ptr(), 1, 2, withdrawn.as_ptr()),
+                ProcessingError::InsufficientFunds.numeric_code()
+            );
+            assert_eq!(
+                tp_process_record(handle, deposit.as_ptr(), 1, 3, ptr::null()),
+                ProcessingError::MissingAmount(TransactionKind::Deposit).numeric_code()
+            );
+            assert_eq!(
+                tp_process_record(handle, dispute.as_ptr(), 1, 1, ptr::null()),
+                TP_OK
+            );
+            assert_eq!(
+                tp_process_record(handle, ptr::null(), 1, 4, ptr::null()),
+                TP_ERR_INVALID_ARGUMENT
+            );
+            let invalid = b"dep\xffosit\0";
+            assert_eq!(
+                tp_process_record(handle, invalid.as_ptr() as *const c_char, 1, 4, ptr::null()),
+                TP_ERR_INVALID_ARGUMENT
+            );
+
+            let expected = "client,available,held,total,locked\n1,0.0000,2.5,2.5,false\n";
+            let needed = tp_serialize_csv(handle, ptr::null_mut(), 0);
+            assert_eq!(needed, expected.len() as isize);
+            let mut small = vec![0u8; 10];
+            assert_eq!(
+                tp_serialize_csv(handle, small.as_mut_ptr(), small.len()),
+                needed
+            );
+            assert_eq!(small, vec![0u8; 10]);
+            let mut buf = vec![0u8; needed as usize];
+            assert_eq!(
+                tp_serialize_csv(handle, buf.as_mut_ptr(), buf.len()),
+                needed
+            );
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+            assert_eq!(
+                tp_serialize_csv(handle, ptr::null_mut(), 10),
+                TP_ERR_INVALID_ARGUMENT as isize
+            );
+
+            tp_free(handle);
+            tp_free(ptr::null_mut());
+            assert_eq!(
+                tp_serialize_csv(ptr::null_mut(), ptr::null_mut(), 0),
+                TP_ERR_INVALID_ARGUMENT as isize
+            );
+        }
+    }
+
+    // Test that a panic is turned into an error code and that the processor
+    // rejects every later call.
+    #[test]
+    fn test_panic() {
+        let handle = tp_new();
+        let deposit = CString::new("deposit").unwrap();
+        let amount = CString::new("1").unwrap();
+        unsafe {
+            assert_eq!(
+                with_processor(handle, |_| panic!("processor panicked")),
+                TP_ERR_PANIC as isize
+            );
+            assert_eq!(
+                tp_process_record(handle, deposit.as_ptr(), 1, 1, amount.as_ptr()),
+                TP_ERR_PANIC
+            );
+            assert_eq!(
+                tp_serialize_csv(handle, ptr::null_mut(), 0),
+                TP_ERR_PANIC as isize
+            );
+            tp_free(handle);
+        }
+    }
+
+    // Test that the checked-in header declares the functions and codes of
+    // this module.
+    #[test]
+    fn test_header() {
+        let header = fs::read_to_string("include/exchange.h").unwrap();
+        for declaration in [
+            "TpProcessor *tp_new(void);".to_owned(),
+            "int32_t tp_process_record(".to_owned(),
+            "intptr_t tp_serialize_csv(TpProcessor *handle, uint8_t *buf, uintptr_t len);"
+                .to_owned(),
+            "void tp_free(TpProcessor *handle);".to_owned(),
+            format!("#define TP_OK {}", TP_OK),
+            format!(
+                "#define TP_ERR_INVALID_ARGUMENT {}",
+                TP_ERR_INVALID_ARGUMENT
+            ),
+            format!("#define TP_ERR_PANIC {}", TP_ERR_PANIC),
+            format!("#define TP_ERR_OUTPUT {}", TP_ERR_OUTPUT),
+        ] {
+            assert!(header.contains(&declaration), "{}", declaration);
+        }
+    }
+}
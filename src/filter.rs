@@ -0,0 +1,105 @@
+use std::collections::HashSet;
+
+use crate::type_defs::{ClientId, TransactionRecord};
+
+/// Type which selects the records to process by client and transaction type,
+/// records which do not match are skipped before reaching the processor.
+///
+/// The filter looks at each record on its own: a dispute, resolve or chargeback
+/// is kept when its client and type match even if the transaction it references
+/// was filtered out, in which case the processor rejects it as not found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecordFilter {
+    clients: Option<HashSet<ClientId>>,
+    types: Option<HashSet<String>>,
+}
+
+impl RecordFilter {
+    /// Creates a filter which matches every record.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restricts the records to the given clients.
+    pub fn with_clients<I: IntoIterator<Item = ClientId>>(mut self, clients: I) -> Self {
+        self.clients
+            .get_or_insert_with(HashSet::new)
+            .extend(clients);
+        self
+    }
+
+    /// Restricts the records to the given transaction types, e.g. "deposit".
+    pub fn with_types<I, S>(mut self, types: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.types
+            .get_or_insert_with(HashSet::new)
+            .extend(types.into_iter().map(Into::into));
+        self
+    }
+
+    /// Returns true when the record has to be processed.
+    pub fn matches(&self, record: &TransactionRecord) -> bool {
+        let client_matches = self
+            .clients
+            .as_ref()
+            .is_none_or(|clients| clients.contains(&ClientId(record.client)));
+        let type_matches = self
+            .types
+            .as_ref()
+            .is_none_or(|types| types.contains(&record.transaction_type));
+        client_matches && type_matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(transaction_type: &str, client: u32) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type: transaction_type.to_owned(),
+            client,
+            tx: 1,
+            amount: None,
+            ..Default::default()
+        }
+    }
+
+    // Test that an empty filter matches every record.
+    #[test]
+    fn test_empty_filter() {
+        let filter = RecordFilter::new();
+        assert!(filter.matches(&record("deposit", 1)));
+        assert!(filter.matches(&record("bonus", 7)));
+    }
+
+    // Test that client and type filters must both match.
+    #[test]
+    fn test_combined_filters() {
+        let filter = RecordFilter::new()
+            .with_clients(vec![ClientId(1), ClientId(3)])
+            .with_types(vec!["deposit", "dispute"]);
+        assert!(filter.matches(&record("deposit", 1)));
+        assert!(filter.matches(&record("dispute", 3)));
+        assert!(!filter.matches(&record("deposit", 2)));
+        assert!(!filter.matches(&record("withdrawal", 1)));
+        assert!(!filter.matches(&record("withdrawal", 2)));
+    }
+
+    // Test that disputes of a matching client are kept when the type filter
+    // excludes the transactions they reference.
+    #[test]
+    fn test_dispute_kept_without_referenced_type() {
+        let filter = RecordFilter::new()
+            .with_clients(vec![ClientId(1)])
+            .with_types(vec!["dispute", "resolve", "chargeback"]);
+        assert!(!filter.matches(&record("deposit", 1)));
+        for transaction_type in &["dispute", "resolve", "chargeback"] {
+            assert!(filter.matches(&record(transaction_type, 1)));
+            assert!(!filter.matches(&record(transaction_type, 2)));
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+use crate::transaction_cache::{read_cache_file, spilled_lines};
+use crate::type_defs::{Amount, StoredTransaction, Transaction, TransactionId};
+
+/// Summary of a cache line spilled into a cache dir.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheLineSummary {
+    pub line: u64,
+    /// Number of entries of the cache line.
+    pub entries: u64,
+    /// Smallest and largest transaction ids, unset for the caches which are
+    /// not keyed by transaction id.
+    pub min_tx: Option<TransactionId>,
+    pub max_tx: Option<TransactionId>,
+    /// Sum of the deposits of the cache line.
+    pub deposited: Amount,
+}
+
+/// Returns the transaction of a spilled value, the processed transactions are
+/// stored with their sequence, the disputed ones as is and the other caches
+/// store values which are not transactions.
+fn transaction_of(value: &serde_json::Value) -> Option<Transaction> {
+    StoredTransaction::deserialize(value)
+        .map(|stored| stored.transaction)
+        .or_else(|_| Transaction::deserialize(value))
+        .ok()
+}
+
+/// Reads every cache line spilled into cache_dir and summarizes them by
+/// increasing line, failing on the first line which can not be read or was
+/// written in another format version.
+pub fn inspect_cache(cache_dir: &Path) -> Result<Vec<CacheLineSummary>, String> {
+    spilled_lines(cache_dir)?
+        .into_iter()
+        .map(|line| {
+            let entries: HashMap<String, serde_json::Value> =
+                read_cache_file(&cache_dir.join(line.to_string()))
+                    .map_err(|err| format!("Invalid cache line {}: {}", line, err))?;
+            let tx_ids: Vec<u64> = entries.keys().filter_map(|key| key.parse().ok()).collect();
+            let mut deposited = Amount::new();
+            for value in entries.values() {
+                if let Some(Transaction::Deposit { amount, .. }) = transaction_of(value) {
+                    deposited += amount;
+                }
+            }
+            Ok(CacheLineSummary {
+                line,
+                entries: entries.len() as u64,
+                min_tx: tx_ids.iter().min().copied().map(TransactionId),
+                max_tx: tx_ids.iter().max().copied().map(TransactionId),
+                deposited,
+            })
+        })
+        .collect()
+}
+
+/// Returns the value stored for the transaction in cache_dir as JSON. The
+/// size of the cache lines is not known from the files, so every line is
+/// searched.
+pub fn find_transaction(
+    cache_dir: &Path,
+    tx: TransactionId,
+) -> Result<Option<serde_json::Value>, String> {
+    let key = tx.to_string();
+    for line in spilled_lines(cache_dir)? {
+        let mut entries: HashMap<String, serde_json::Value> =
+            read_cache_file(&cache_dir.join(line.to_string()))
+                .map_err(|err| format!("Invalid cache line {}: {}", line, err))?;
+        if let Some(value) = entries.remove(&key) {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+/// Writes one line per cache line summary followed by the totals.
+pub fn write_summary<W: Write>(
+    summaries: &[CacheLineSummary],
+    mut writer: W,
+) -> Result<(), String> {
+    let error = |err: std::io::Error| format!("Could not write cache summary because of: {}", err);
+    let mut entries = 0;
+    let mut deposited = Amount::new();
+    for summary in summaries {
+        let tx_range = match (summary.min_tx, summary.max_tx) {
+            (Some(min_tx), Some(max_tx)) => format!("tx {}..={}", min_tx, max_tx),
+            _ => "no tx ids".to_owned(),
+        };
+        writeln!(
+            writer,
+            "line {}: {} entries, {}, deposited {}",
+            summary.line, summary.entries, tx_range, summary.deposited
+        )
+        .map_err(error)?;
+        entries += summary.entries;
+        deposited += summary.deposited;
+    }
+    writeln!(
+        writer,
+        "{} lines, {} entries, deposited {}",
+        summaries.len(),
+        entries,
+        deposited
+    )
+    .map_err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction_cache::TransactionCache;
+    use crate::type_defs::ClientId;
+    use tempdir::TempDir;
+
+    fn stored(transaction: Transaction, sequence: u64) -> StoredTransaction {
+        StoredTransaction {
+            transaction,
+            sequence,
+        }
+    }
+
+    // Test that the lines spilled by a cache are summarized and that a single
+    // transaction can be looked up.
+    #[test]
+    fn test_inspect_cache() {
+        let base_dir = TempDir::new("inspect_cache").unwrap();
+        // Without room in memory every insert is spilled right away.
+        let mut cache =
+            TransactionCache::<0, 10, TransactionId, StoredTransaction>::new_in(base_dir.path())
+                .unwrap();
+        for tx in 3..25 {
+            let amount = format!("{}.5", tx).parse().unwrap();
+            let transaction = if tx % 4 == 0 {
+                Transaction::Withdrawal {
+                    client: ClientId(1),
+                    tx: TransactionId(tx),
+                    amount,
+                }
+            } else {
+                Transaction::Deposit {
+                    client: ClientId(1),
+                    tx: TransactionId(tx),
+                    amount,
+                }
+            };
+            cache.insert(TransactionId(tx), stored(transaction, tx));
+        }
+        let cache_dir = cache.cache_dir().to_path_buf();
+        drop(cache);
+
+        let summaries = inspect_cache(&cache_dir).unwrap();
+        assert_eq!(summaries.len(), 3);
+        assert_eq!(
+            summaries[0],
+            CacheLineSummary {
+                line: 0,
+                entries: 7,
+                min_tx: Some(TransactionId(3)),
+                max_tx: Some(TransactionId(9)),
+                // 3.5 + 5.5 + 6.5 + 7.5 + 9.5
+                deposited: "32.5".parse().unwrap(),
+            }
+        );
+        assert_eq!(summaries[2].entries, 5);
+        assert_eq!(summaries[2].max_tx, Some(TransactionId(24)));
+
+        let mut output = Vec::new();
+        write_summary(&summaries, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("line 0: 7 entries, tx 3..=9, deposited 32.5\n"));
+        assert_eq!(
+            output.lines().last(),
+            Some("3 lines, 22 entries, deposited 221.0")
+        );
+
+        let found = find_transaction(&cache_dir, TransactionId(12))
+            .unwrap()
+            .unwrap();
+        assert_eq!(found["sequence"], 12);
+        assert_eq!(found["transaction"]["type"], "withdrawal");
+        assert_eq!(
+            find_transaction(&cache_dir, TransactionId(30)).unwrap(),
+            None
+        );
+
+        let line = cache_dir.join("2");
+        let mut content = std::fs::read(&line).unwrap();
+        content.pop();
+        std::fs::write(&line, content).unwrap();
+        assert!(inspect_cache(&cache_dir)
+            .unwrap_err()
+            .starts_with("Invalid cache line 2: Cache line checksum "));
+
+        std::fs::write(cache_dir.join("1"), "{\"version\":0}\n{}").unwrap();
+        assert_eq!(
+            inspect_cache(&cache_dir),
+            Err(
+                "Invalid cache line 1: Cache format version 0 is not supported, expected 5"
+                    .to_owned()
+            )
+        );
+    }
+}
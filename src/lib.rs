@@ -0,0 +1,40 @@
+//! Library for processing client transactions (deposits, withdrawals, disputes,
+//! resolves and chargebacks) and computing the resulting client balances.
+
+#[cfg(feature = "proptest")]
+pub mod arbitrary;
+pub mod cancel;
+pub mod checksum;
+pub mod client;
+pub mod columns;
+pub mod config;
+pub mod convert;
+pub mod encoding;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+pub mod inspect;
+pub mod malformed;
+pub mod metrics;
+pub mod money;
+pub mod observer;
+pub mod parallel;
+pub mod pipe;
+pub mod policy;
+pub mod processor;
+pub mod reports;
+pub mod runner;
+pub mod scenario;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
+pub mod state;
+pub mod store;
+pub mod suspicious;
+#[cfg(any(test, feature = "testing"))]
+pub mod test_support;
+pub mod trace;
+pub mod transaction_cache;
+pub mod type_defs;
+pub mod validation;
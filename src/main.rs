@@ -1,44 +1,434 @@
-mod client;
-mod processor;
-mod transaction_cache;
-mod type_defs;
+mod cli;
+#[cfg(unix)]
+mod mapped_file;
 
-use processor::{TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+use cli::{Command, ConvertOptions, InspectOptions, Options};
+use exchange::cancel::CancellationToken;
+use exchange::convert::{self, ConversionStats};
+use exchange::inspect;
+use exchange::parallel;
+use exchange::pipe::{self, PipeOutcome};
+use exchange::processor::{TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+use exchange::reports::{self, RejectedRecords};
+use exchange::runner::{self, InputFormat, ProcessingStats};
+use exchange::snapshot::Snapshots;
+use exchange::state::StateSnapshot;
+use exchange::suspicious;
+use exchange::trace::ClientTraces;
 use std::env;
-use type_defs::TransactionRecord;
+use std::io::{self, BufRead, BufReader, BufWriter};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+use std::sync::OnceLock;
 
 use std::fs;
 
+type Processor = TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>;
+
+/// Feeds the records read from input into the processor, the format is guessed
+/// from the input path unless given on the command line.
+fn process<R: BufRead>(
+    processor: &mut Processor,
+    path: &Path,
+    input: R,
+    options: &Options,
+) -> Result<ProcessingStats, String> {
+    let input_format = options
+        .input_format
+        .unwrap_or_else(|| InputFormat::from_path(&path.to_string_lossy()));
+    match input_format {
+        InputFormat::Csv => runner::run(processor, input, &options.run),
+        InputFormat::Jsonl => runner::process_jsonl_reader(processor, input, &options.run),
+    }
+}
+
+/// Feeds the records of the input file into the processor.
+fn process_file(
+    processor: &mut Processor,
+    path: &Path,
+    options: &Options,
+) -> Result<ProcessingStats, String> {
+    if let Some(chunks) = options.parallel_chunks {
+        let input_format = options
+            .input_format
+            .unwrap_or_else(|| InputFormat::from_path(&path.to_string_lossy()));
+        if input_format == InputFormat::Csv {
+            return parallel::run_parallel(processor, path, chunks, &options.run);
+        }
+    }
+    #[cfg(unix)]
+    if options.mmap {
+        let mapped = mapped_file::map(path)?;
+        return process(processor, path, &mapped[..], options);
+    }
+    let file = fs::File::open(path)
+        .map_err(|err| format!("Could not open input file {}: {}", path.display(), err))?;
+    process(processor, path, BufReader::new(file), options)
+}
+
+/// Creates the report file at path and writes the report into it.
+fn write_report<F>(path: &str, write: F) -> Result<(), String>
+where
+    F: FnOnce(fs::File) -> Result<(), String>,
+{
+    let file =
+        fs::File::create(path).map_err(|err| format!("Could not create {}: {}", path, err))?;
+    write(file)
+}
+
+/// Exit code of a run interrupted by ctrl-c.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Exit code of a run whose processing panicked, as for an uncaught panic.
+const PANIC_EXIT_CODE: i32 = 101;
+
+/// Exit code of a run whose output reader went away, as if killed by SIGPIPE.
+const BROKEN_PIPE_EXIT_CODE: i32 = 141;
+
+/// Exit code of a run which went on after rejecting a record with the
+/// severity given to `--fail-on` or a higher one.
+const REJECTED_EXIT_CODE: i32 = 4;
+
+/// Exit code of a run whose balances differ from the stored transactions
+/// with `--verify`, the balances are still written.
+const VERIFY_FAILED_EXIT_CODE: i32 = 3;
+
+/// Token cancelled by the SIGINT handler.
+static INTERRUPTION: OnceLock<CancellationToken> = OnceLock::new();
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    if let Some(token) = INTERRUPTION.get() {
+        token.cancel();
+    }
+}
+
+/// Cancels the token on ctrl-c instead of killing the process, so the
+/// balances processed so far can still be written.
+fn install_interrupt_handler(token: CancellationToken) {
+    let _ = INTERRUPTION.set(token);
+    #[cfg(unix)]
+    // SAFETY: the handler only stores into an atomic flag.
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+/// Puts back the default SIGINT handler once processing is over, so ctrl-c
+/// kills the process again while the output is written.
+fn restore_interrupt_handler() {
+    #[cfg(unix)]
+    // SAFETY: SIG_DFL is a valid disposition for SIGINT.
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+/// Prints the summary of the spilled cache lines of a cache dir, or the
+/// stored value of a single transaction.
+fn inspect_cache(options: &InspectOptions) -> Result<(), String> {
+    let cache_dir = Path::new(&options.cache_dir);
+    match options.tx {
+        Some(tx) => match inspect::find_transaction(cache_dir, tx)? {
+            Some(value) => {
+                println!("{}", serde_json::to_string_pretty(&value).unwrap());
+                Ok(())
+            }
+            None => Err(format!(
+                "Transaction {} not found in {}",
+                tx, options.cache_dir
+            )),
+        },
+        None => {
+            let summaries = inspect::inspect_cache(cache_dir)?;
+            inspect::write_summary(&summaries, io::stdout().lock())
+        }
+    }
+}
+
+/// Audits the clients of a state snapshot and prints the report, returning
+/// whether the snapshot passed.
+fn check_state(path: &str) -> Result<bool, String> {
+    let check = StateSnapshot::read(Path::new(path))?.check();
+    check.write_report(io::stdout().lock())?;
+    Ok(check.passed())
+}
+
+/// Converts the input file into the output file, the formats are guessed from
+/// the paths unless given on the command line.
+fn convert(options: &ConvertOptions) -> Result<ConversionStats, String> {
+    let from = options
+        .from
+        .unwrap_or_else(|| InputFormat::from_path(&options.input));
+    let to = options
+        .to
+        .unwrap_or_else(|| InputFormat::from_path(&options.output));
+    let input = fs::File::open(&options.input)
+        .map_err(|err| format!("Could not open input file {}: {}", options.input, err))?;
+    let output = fs::File::create(&options.output)
+        .map_err(|err| format!("Could not create {}: {}", options.output, err))?;
+    convert::convert(
+        BufReader::new(input),
+        from,
+        BufWriter::new(output),
+        to,
+        &options.run,
+    )
+}
+
 fn main() {
-    let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
-
-    let args: Vec<String> = env::args().collect();
-    let mut rdr = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .flexible(true)
-        .has_headers(false)
-        .from_reader(fs::File::open(args[1].clone()).expect("Could not open input file"));
-
-    for (index, result) in rdr.deserialize().enumerate() {
-        match result {
-            Ok(transaction_record) => {
-                let copy: TransactionRecord = transaction_record;
-                // Intentionally continue processing even in case of errors
-                if let Err(err) = processor.process_transaction(copy.clone()) {
-                    eprintln!("Ignoring error: {} for record: {:?}", err, copy);
+    let command = Command::parse(env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(2);
+    });
+    let mut options = match command {
+        Command::Process(options) => *options,
+        Command::PrintConfig(options) => {
+            print!("{}", options.effective_config().to_toml());
+            return;
+        }
+        Command::Help(help) => {
+            println!("{}", help);
+            return;
+        }
+        Command::Convert(options) => match convert(&options) {
+            Ok(stats) => {
+                eprintln!("{}", stats);
+                if !stats.completed {
+                    process::exit(1);
                 }
+                return;
             }
             Err(err) => {
-                // First entry might be the header, so it is expected that we might
-                // not be able to convert it into a TransactionRecord.
-                if index > 0 {
-                    eprintln!("Ignoring error {}", err);
+                eprintln!("{}", err);
+                process::exit(2);
+            }
+        },
+        Command::CheckState(path) => match check_state(&path) {
+            Ok(passed) => {
+                if !passed {
+                    process::exit(1);
                 }
+                return;
             }
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(2);
+            }
+        },
+        Command::InspectCache(options) => {
+            if let Err(err) = inspect_cache(&options) {
+                eprintln!("{}", err);
+                process::exit(2);
+            }
+            return;
         }
+    };
+    let interruption = CancellationToken::new();
+    install_interrupt_handler(interruption.clone());
+    options.run.cancellation = Some(interruption);
+
+    let mut processor = Processor::new()
+        .with_held_policy(options.held_policy)
+        .with_spill_failure_policy(options.spill_policy)
+        .with_validator(options.validator.clone());
+    if let Some(cache_limit) = options.cache_limit {
+        processor = processor.with_cache_limit(cache_limit);
+    }
+    if let Some(max_tx_per_client) = options.max_tx_per_client {
+        processor = processor.with_max_transactions_per_client(max_tx_per_client);
+    }
+    if let Some(dispute_window) = options.dispute_window {
+        processor = processor.with_dispute_window(dispute_window);
+    }
+    if let Some(overdraft_limit) = options.overdraft_limit {
+        processor = processor.with_overdraft_limit(overdraft_limit);
+    }
+    if let Some(min_deposit) = options.min_deposit {
+        processor = processor.with_min_deposit(min_deposit);
+    }
+    if let Some(min_withdrawal) = options.min_withdrawal {
+        processor = processor.with_min_withdrawal(min_withdrawal);
+    }
+    if options.keep_memos {
+        processor = processor.with_memos();
+    }
+    if options.disputes_report.is_some() {
+        processor = processor.with_dispute_events();
+    }
+    if let Some(path) = &options.rejected_out {
+        let file = fs::File::create(path).unwrap_or_else(|err| {
+            eprintln!("Could not create {}: {}", path, err);
+            process::exit(2);
+        });
+        processor = processor.with_observer(Box::new(RejectedRecords::new(file)));
+    }
+    if !options.trace_clients.is_empty() {
+        let traces = ClientTraces::create(Path::new(&options.trace_dir), &options.trace_clients)
+            .unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(2);
+            });
+        processor = processor.with_observer(Box::new(traces));
+    }
+    if let Some(every) = options.snapshot_every {
+        let snapshots = Snapshots::create(
+            Path::new(&options.snapshot_dir),
+            options.snapshot_keep,
+            &options.output,
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(2);
+        });
+        // A snapshot which can not be written does not stop the run.
+        processor = processor.with_snapshot_every(
+            every,
+            Box::new(move |processor, processed| {
+                if let Err(err) = snapshots.write(processor, processed) {
+                    eprintln!("{}", err);
+                }
+            }),
+        );
+    }
+    if let Some(cache_dir) = &options.cache_dir {
+        processor = processor
+            .with_cache_dir(Path::new(cache_dir), options.clean_cache_on_exit)
+            .unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(2);
+            });
     }
 
-    processor
-        .serialize()
-        .expect("Could not serialize processor");
+    // A directory is processed file by file, stopping at the first one which
+    // is not completely processed. A panic stops processing like an
+    // interruption so the balances processed so far are still written.
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        runner::input_files(Path::new(&options.input)).and_then(|files| {
+            let mut stats = ProcessingStats::new();
+            for file in files {
+                let file_stats = process_file(&mut processor, &file, &options)?;
+                stats.merge(&file_stats);
+                if !file_stats.completed {
+                    break;
+                }
+            }
+            Ok(stats)
+        })
+    }));
+    let panicked = outcome.is_err();
+    let stats = outcome.unwrap_or_else(|_| {
+        Ok(ProcessingStats {
+            completed: false,
+            ..ProcessingStats::new()
+        })
+    });
+    restore_interrupt_handler();
+    let stats = stats.unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(2);
+    });
+    // The rejected records report is written while processing, an error met
+    // writing it fails the run once the balances are written.
+    let observed = processor.finish_observers();
+    // The state is written whether the run processed its whole input or not,
+    // e.g. after ctrl-c.
+    let state_written = match &options.state_out {
+        Some(path) => processor
+            .state_snapshot(!stats.completed)
+            .write(Path::new(path)),
+        None => Ok(()),
+    };
+    if options.stats {
+        eprintln!("{}", stats);
+    }
+    let mut verified = true;
+    if options.verify {
+        for discrepancy in processor.verify_balances() {
+            eprintln!("{}", discrepancy);
+            verified = false;
+        }
+    }
+    let processed = stats.completed;
+    let mut broken_pipe = false;
+    options.output.partial = stats.cancelled || panicked;
+    options.output.panicked = panicked;
+    if processed || options.partial_output {
+        // A reader going away, e.g. `| head`, is not an error but still ends
+        // the run with its own exit code.
+        let fallback = options.output_fallback.as_deref().map(Path::new);
+        let serialized = match options.output.shards {
+            _ if options.summary_only => {
+                let summary = processor.balance_summary();
+                pipe::write_with_fallback(io::stdout(), fallback, |writer| {
+                    reports::write_summary(&summary, &stats, writer)
+                })
+            }
+            Some(_) => processor
+                .serialize(&options.output)
+                .map(|_| PipeOutcome::Written),
+            None if options.output.pages.is_some() => processor
+                .serialize(&options.output)
+                .map(|_| PipeOutcome::Written),
+            None => pipe::write_with_fallback(io::stdout(), fallback, |writer| {
+                processor.serialize_to(writer, &options.output)
+            }),
+        };
+        broken_pipe = serialized == Ok(PipeOutcome::Broken);
+        let mut written = serialized.map(|_| ());
+        if let (Ok(()), Some(path)) = (&written, &options.suspicious_out) {
+            let reports = processor.flag_suspicious(&options.suspicion_thresholds);
+            written = write_report(path, |file| suspicious::write_reports(&reports, file));
+        }
+        if let (Ok(()), Some(path)) = (&written, &options.disputes_out) {
+            let disputes = processor.open_disputes();
+            written = write_report(path, |file| reports::write_open_disputes(&disputes, file));
+        }
+        if let (Ok(()), Some(path)) = (&written, &options.disputes_report) {
+            let events = processor.dispute_events();
+            written = write_report(path, |file| reports::write_dispute_events(&events, file));
+        }
+        if let (Ok(()), Some(path)) = (&written, &options.held_detail) {
+            let detail = processor.held_detail();
+            written = write_report(path, |file| reports::write_held_detail(&detail, file));
+        }
+        if let Err(err) = written {
+            eprintln!("{}", err);
+            drop(processor);
+            process::exit(2);
+        }
+    }
+    if let Err(err) = observed.and(state_written) {
+        eprintln!("{}", err);
+        drop(processor);
+        process::exit(2);
+    }
+
+    // Exiting does not run the destructors, drop the processor first so its
+    // cache dir gets cleaned.
+    drop(processor);
+    if panicked {
+        process::exit(PANIC_EXIT_CODE);
+    }
+    if stats.cancelled {
+        process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    if !processed {
+        process::exit(1);
+    }
+    if broken_pipe {
+        process::exit(BROKEN_PIPE_EXIT_CODE);
+    }
+    if !verified {
+        process::exit(VERIFY_FAILED_EXIT_CODE);
+    }
+    if let Some(fail_on) = options.fail_on {
+        if stats.rejected_by_severity.range(fail_on..).next().is_some() {
+            process::exit(REJECTED_EXIT_CODE);
+        }
+    }
 }
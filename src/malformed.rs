@@ -0,0 +1,151 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+/// Number of consumed bytes kept by a `Recorder` before they are dropped.
+const RECORDER_COMPACT_SIZE: usize = 64 * 1024;
+
+/// What to do with the rows of an input which can not be read as a record.
+#[derive(Clone, Default)]
+pub enum MalformedRowPolicy {
+    /// Report the row on stderr and go on.
+    #[default]
+    Skip,
+    /// Stop processing the input.
+    Abort,
+    /// Copy the raw bytes of the row into the writer and go on, so the row can
+    /// be repaired and replayed later.
+    Quarantine {
+        writer: Arc<Mutex<dyn Write + Send>>,
+    },
+}
+
+impl MalformedRowPolicy {
+    pub fn quarantine<W: Write + Send + 'static>(writer: W) -> Self {
+        MalformedRowPolicy::Quarantine {
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+}
+
+impl fmt::Debug for MalformedRowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MalformedRowPolicy::Skip => write!(f, "Skip"),
+            MalformedRowPolicy::Abort => write!(f, "Abort"),
+            MalformedRowPolicy::Quarantine { .. } => write!(f, "Quarantine"),
+        }
+    }
+}
+
+/// A row of the input which could not be read as a record.
+#[derive(Debug)]
+pub(crate) struct MalformedRow {
+    pub error: String,
+    /// Bytes of the row as read from the input, empty when not recorded.
+    pub raw: Vec<u8>,
+}
+
+/// Bytes read by a `Recorder` which have not been dropped yet.
+#[derive(Debug, Default)]
+pub(crate) struct Recorded {
+    /// Offset in the input of the first byte kept.
+    offset: u64,
+    bytes: Vec<u8>,
+}
+
+impl Recorded {
+    /// Returns the bytes between the two offsets of the input.
+    pub fn get(&self, start: u64, end: u64) -> Vec<u8> {
+        let start = start.saturating_sub(self.offset) as usize;
+        let end = (end.saturating_sub(self.offset) as usize).min(self.bytes.len());
+        self.bytes.get(start..end).unwrap_or_default().to_vec()
+    }
+
+    /// Returns the bytes of the row between the two offsets, the csv reader
+    /// stops right after the `\r` of a `\r\n` terminator so its `\n` is
+    /// moved from the start of the next row to the end of this one.
+    pub fn get_row(&self, start: u64, end: u64) -> Vec<u8> {
+        let mut row = self.get(start, end);
+        let skipped = row.iter().take_while(|byte| **byte == b'\n').count();
+        row.drain(..skipped);
+        if row.last() == Some(&b'\r') && self.get(end, end + 1) == b"\n" {
+            row.push(b'\n');
+        }
+        row
+    }
+
+    /// Drops the bytes before the given offset once they take too much room.
+    pub fn consume(&mut self, end: u64) {
+        let consumed = end.saturating_sub(self.offset) as usize;
+        if consumed >= RECORDER_COMPACT_SIZE {
+            let consumed = consumed.min(self.bytes.len());
+            self.bytes.drain(..consumed);
+            self.offset += consumed as u64;
+        }
+    }
+}
+
+/// Reader which keeps a copy of the bytes read so the raw bytes of a row can
+/// be recovered from its offsets.
+pub(crate) struct Recorder<R> {
+    input: R,
+    recorded: Option<Rc<RefCell<Recorded>>>,
+}
+
+impl<R> Recorder<R> {
+    /// Creates a reader which records the bytes only when given where to.
+    pub fn new(input: R, recorded: Option<Rc<RefCell<Recorded>>>) -> Self {
+        Recorder { input, recorded }
+    }
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.input.read(buf)?;
+        if let Some(recorded) = &self.recorded {
+            recorded.borrow_mut().bytes.extend_from_slice(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that the recorded bytes can be recovered by offset after the
+    // consumed ones are dropped.
+    #[test]
+    fn test_recorder() {
+        let input: Vec<u8> = (0..3 * RECORDER_COMPACT_SIZE).map(|i| i as u8).collect();
+        let recorded = Rc::new(RefCell::new(Recorded::default()));
+        let mut recorder = Recorder::new(&input[..], Some(recorded.clone()));
+        let mut buf = vec![0; 2 * RECORDER_COMPACT_SIZE];
+        recorder.read_exact(&mut buf).unwrap();
+
+        recorded.borrow_mut().consume(10);
+        assert_eq!(recorded.borrow().get(10, 13), vec![10, 11, 12]);
+        let end = RECORDER_COMPACT_SIZE as u64 + 5;
+        recorded.borrow_mut().consume(end);
+        assert_eq!(recorded.borrow().offset, end);
+        assert_eq!(
+            recorded.borrow().get(end, end + 2),
+            input[end as usize..][..2]
+        );
+    }
+
+    // Test that the rows keep their own \r\n terminator.
+    #[test]
+    fn test_recorded_row() {
+        let recorded = Recorded {
+            offset: 0,
+            bytes: b"a,b\r\nc,d\r\ne,f".to_vec(),
+        };
+        assert_eq!(recorded.get_row(0, 4), b"a,b\r\n");
+        assert_eq!(recorded.get_row(4, 9), b"c,d\r\n");
+        assert_eq!(recorded.get_row(9, 13), b"e,f");
+    }
+}
@@ -0,0 +1,19 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::path::Path;
+
+/// Maps the whole input file read-only, so the csv reader can consume the
+/// input directly from the mapped pages and let the OS page the data in and
+/// out as needed.
+///
+/// Changing or truncating the file while it is mapped is not supported: the
+/// mapped bytes would change under the reader, and reading a page past the
+/// new end of the file kills the process with SIGBUS.
+pub fn map(path: &Path) -> Result<Mmap, String> {
+    let file = File::open(path)
+        .map_err(|err| format!("Could not open input file {}: {}", path.display(), err))?;
+    // SAFETY: the mapping is read-only and the input file is not changed
+    // while it is processed, which the users of --mmap are told to ensure.
+    unsafe { Mmap::map(&file) }
+        .map_err(|err| format!("Could not map input file {}: {}", path.display(), err))
+}
@@ -0,0 +1,173 @@
+use std::fmt::Write;
+
+use crate::runner::ProcessingStats;
+
+/// Number of clients and of the entries held in memory by the transaction
+/// caches of a `TransactionProcessor`, spilled entries are not counted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub clients: u64,
+    /// Deposits and withdrawals stored for the disputes.
+    pub transactions: u64,
+    /// Deposits under dispute.
+    pub disputes: u64,
+    /// Timestamps and memos of the transactions.
+    pub notes: u64,
+    /// Outcomes stored by idempotency key.
+    pub idempotency_keys: u64,
+    /// Number of times every cache was flushed to disk by
+    /// `TransactionProcessor::flush_caches`.
+    pub flushes: u64,
+}
+
+/// Type which renders the processing and cache stats in the Prometheus text
+/// exposition format.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    pub stats: ProcessingStats,
+    pub cache: CacheStats,
+}
+
+impl Metrics {
+    pub fn new(stats: ProcessingStats, cache: CacheStats) -> Self {
+        Metrics { stats, cache }
+    }
+
+    /// Renders the record counters and the cache gauges.
+    pub fn render_prometheus(&self) -> String {
+        let stats = &self.stats;
+        let cache = &self.cache;
+        let mut text = String::new();
+        metric(
+            &mut text,
+            "exchange_records_total",
+            "counter",
+            "Records seen, by outcome.",
+            "outcome",
+            &[
+                ("applied", stats.applied),
+                ("rejected", stats.rejected),
+                ("malformed", stats.malformed),
+                ("filtered", stats.filtered),
+                ("skipped", stats.skipped),
+                ("quarantined", stats.quarantined),
+                ("idempotent_replay", stats.idempotent_replays),
+            ],
+        );
+        let duplicates = stats.duplicate_replays + stats.conflicting_duplicates;
+        metric(
+            &mut text,
+            "exchange_rejected_records_total",
+            "counter",
+            "Rejected records, by reason.",
+            "reason",
+            &[
+                ("duplicate_replay", stats.duplicate_replays),
+                ("conflicting_duplicate", stats.conflicting_duplicates),
+                ("other", stats.rejected.saturating_sub(duplicates)),
+            ],
+        );
+        metric(
+            &mut text,
+            "exchange_clients",
+            "gauge",
+            "Clients seen by the processor.",
+            "",
+            &[("", cache.clients)],
+        );
+        metric(
+            &mut text,
+            "exchange_cache_entries",
+            "gauge",
+            "Entries held in memory by the transaction caches.",
+            "cache",
+            &[
+                ("transactions", cache.transactions),
+                ("disputes", cache.disputes),
+                ("notes", cache.notes),
+                ("idempotency_keys", cache.idempotency_keys),
+            ],
+        );
+        metric(
+            &mut text,
+            "exchange_cache_flushes_total",
+            "counter",
+            "Flushes of every transaction cache to disk.",
+            "",
+            &[("", cache.flushes)],
+        );
+        text
+    }
+}
+
+/// Writes a metric with one sample per label value, a metric without label
+/// has a single sample with an empty label.
+fn metric(
+    text: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    label: &str,
+    samples: &[(&str, u64)],
+) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+    for (value, sample) in samples {
+        if label.is_empty() {
+            let _ = writeln!(text, "{} {}", name, sample);
+        } else {
+            let _ = writeln!(text, "{}{{{}=\"{}\"}} {}", name, label, value, sample);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+    use crate::type_defs::TransactionRecord;
+
+    // Test that the rendered text holds the counters of the stats and the
+    // gauges of the processor caches.
+    #[test]
+    fn test_render_prometheus() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        for (transaction_type, tx) in &[("deposit", 1), ("deposit", 2), ("dispute", 1)] {
+            let record = TransactionRecord {
+                transaction_type: transaction_type.to_string(),
+                client: 3,
+                tx: *tx,
+                amount: Some("1.0".to_owned()),
+                ..Default::default()
+            };
+            processor.process_transaction(record).unwrap();
+        }
+        let stats = ProcessingStats {
+            applied: 3,
+            rejected: 4,
+            malformed: 1,
+            duplicate_replays: 2,
+            conflicting_duplicates: 1,
+            ..ProcessingStats::new()
+        };
+
+        let text = Metrics::new(stats, processor.cache_stats()).render_prometheus();
+        for line in &[
+            "# TYPE exchange_records_total counter",
+            "exchange_records_total{outcome=\"applied\"} 3",
+            "exchange_records_total{outcome=\"rejected\"} 4",
+            "exchange_records_total{outcome=\"malformed\"} 1",
+            "exchange_rejected_records_total{reason=\"duplicate_replay\"} 2",
+            "exchange_rejected_records_total{reason=\"conflicting_duplicate\"} 1",
+            "exchange_rejected_records_total{reason=\"other\"} 1",
+            "# TYPE exchange_clients gauge",
+            "exchange_clients 1",
+            "exchange_cache_entries{cache=\"transactions\"} 2",
+            "exchange_cache_entries{cache=\"disputes\"} 1",
+            "exchange_cache_entries{cache=\"idempotency_keys\"} 0",
+            "exchange_cache_flushes_total 0",
+        ] {
+            assert!(text.lines().any(|text_line| text_line == *line), "{}", line);
+        }
+    }
+}
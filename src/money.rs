@@ -0,0 +1,285 @@
+use rust_decimal::prelude::RoundingStrategy;
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+use crate::error::ProcessingError;
+use crate::type_defs::{strip_currency_symbol, Amount, PRECISION};
+
+/// Largest number of fractional digits of the decimals backing `Amount`.
+const MAX_PRECISION: u32 = 28;
+
+/// What parsing does with the digits beyond the precision of a
+/// `MoneyContext`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// Reject the amount with `ProcessingError::InvalidPrecision`.
+    #[default]
+    Reject,
+    /// Round to the nearest, the midpoints to the even digit.
+    HalfEven,
+    /// Round to the nearest, the midpoints away from zero.
+    HalfAwayFromZero,
+    /// Drop the extra digits.
+    TowardZero,
+}
+
+impl Rounding {
+    fn strategy(self) -> Option<RoundingStrategy> {
+        match self {
+            Rounding::Reject => None,
+            Rounding::HalfEven => Some(RoundingStrategy::MidpointNearestEven),
+            Rounding::HalfAwayFromZero => Some(RoundingStrategy::MidpointAwayFromZero),
+            Rounding::TowardZero => Some(RoundingStrategy::ToZero),
+        }
+    }
+}
+
+/// What combining two amounts does when the result does not fit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Fail with `ProcessingError::Overflow`.
+    #[default]
+    Reject,
+    /// Give the largest or smallest amount instead.
+    Saturate,
+}
+
+/// Precision, rounding and overflow decisions for the amounts of a run, in
+/// one place. `Amount` is only a value, the amounts are parsed, combined and
+/// formatted through the context of the processor, e.g.
+/// `TransactionProcessor::with_money_context(MoneyContext::new(8))` for
+/// amounts with 8 decimals.
+///
+/// The default context is the one `Amount::from_str` parses with: 4 decimals,
+/// more digits and overflows being rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MoneyContext {
+    /// Number of fractional digits kept.
+    pub precision: u32,
+    pub rounding: Rounding,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for MoneyContext {
+    fn default() -> Self {
+        MoneyContext::new(PRECISION)
+    }
+}
+
+impl MoneyContext {
+    /// Context with the given precision, at most 28 digits, rejecting the
+    /// amounts with more digits and the overflows.
+    pub fn new(precision: u32) -> Self {
+        assert!(
+            precision <= MAX_PRECISION,
+            "a precision of at most {} digits is supported",
+            MAX_PRECISION
+        );
+        MoneyContext {
+            precision,
+            rounding: Rounding::default(),
+            overflow: OverflowPolicy::default(),
+        }
+    }
+
+    pub fn with_rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    pub fn with_overflow(mut self, overflow: OverflowPolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Parses a fixed point amount, scientific notation such as `1e2` is
+    /// rejected whatever the decimal library accepts since financial inputs
+    /// are not expected to use it. The digits beyond the precision are
+    /// handled according to the rounding.
+    pub fn parse(&self, value: &str) -> Result<Amount, ProcessingError> {
+        if value.contains(['e', 'E']) {
+            return Err(ProcessingError::InvalidAmount(format!(
+                "{}: scientific notation is not supported",
+                value
+            )));
+        }
+        let decimal = Decimal::from_str(value)
+            .map_err(|err| ProcessingError::InvalidAmount(format!("{}: {}", value, err)))?;
+        Ok(Amount::from_decimal(self.round(decimal)?))
+    }
+
+    /// Parses an amount which may carry the given currency symbol right before
+    /// or right after the digits, e.g. "$10.00" or "10.00$".
+    pub fn parse_with_symbol(&self, value: &str, symbol: &str) -> Result<Amount, ProcessingError> {
+        self.parse(strip_currency_symbol(value, symbol))
+    }
+
+    pub fn add(&self, left: Amount, right: Amount) -> Result<Amount, ProcessingError> {
+        self.combine(left.decimal().checked_add(right.decimal()), left, right)
+    }
+
+    pub fn sub(&self, left: Amount, right: Amount) -> Result<Amount, ProcessingError> {
+        self.combine(left.decimal().checked_sub(right.decimal()), left, -right)
+    }
+
+    /// Writes the amount with at most the precision of the context, an
+    /// amount built with more digits is rounded with the rounding of the
+    /// context, half away from zero when it rejects them.
+    pub fn format(&self, amount: Amount) -> String {
+        let decimal = amount.decimal();
+        if decimal.scale() > self.precision {
+            let strategy = self
+                .rounding
+                .strategy()
+                .unwrap_or(RoundingStrategy::MidpointAwayFromZero);
+            Amount::from_decimal(decimal.round_dp_with_strategy(self.precision, strategy))
+                .to_string()
+        } else {
+            amount.to_string()
+        }
+    }
+
+    /// Writes the amount as an integer of 10^-precision units, e.g.
+    /// `105000` for `10.50` with 4 decimals.
+    pub fn format_minor_units(&self, amount: Amount) -> String {
+        amount.to_minor_units(self.precision).to_string()
+    }
+
+    fn round(&self, decimal: Decimal) -> Result<Decimal, ProcessingError> {
+        if decimal.scale() <= self.precision {
+            return Ok(decimal);
+        }
+        match self.rounding.strategy() {
+            Some(strategy) => Ok(decimal.round_dp_with_strategy(self.precision, strategy)),
+            None => Err(ProcessingError::InvalidPrecision),
+        }
+    }
+
+    /// Wraps the result of combining left with right, which is positive when
+    /// the combination grows the amount.
+    fn combine(
+        &self,
+        result: Option<Decimal>,
+        left: Amount,
+        right: Amount,
+    ) -> Result<Amount, ProcessingError> {
+        match (result, self.overflow) {
+            (Some(decimal), _) => Ok(Amount::canonical(decimal)),
+            (None, OverflowPolicy::Reject) => Err(ProcessingError::Overflow {
+                amount: left,
+                change: right,
+            }),
+            (None, OverflowPolicy::Saturate) if right > Amount::new() => {
+                Ok(Amount::from_decimal(Decimal::MAX))
+            }
+            (None, OverflowPolicy::Saturate) => Ok(Amount::from_decimal(Decimal::MIN)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test every combination of precision, rounding and input: None is a
+    // rejected amount, Some the formatted amount.
+    #[test]
+    fn test_parse_matrix() {
+        let cases: &[(u32, Rounding, &str, Option<&str>)] = &[
+            (4, Rounding::Reject, "1.5", Some("1.5")),
+            (4, Rounding::Reject, "1.2345", Some("1.2345")),
+            (4, Rounding::Reject, "1.23456", None),
+            (4, Rounding::HalfEven, "1.23455", Some("1.2346")),
+            (4, Rounding::HalfEven, "1.23445", Some("1.2344")),
+            (4, Rounding::HalfAwayFromZero, "1.23445", Some("1.2345")),
+            (4, Rounding::HalfAwayFromZero, "-1.23445", Some("-1.2345")),
+            (4, Rounding::TowardZero, "1.23459", Some("1.2345")),
+            (4, Rounding::TowardZero, "-1.23459", Some("-1.2345")),
+            (8, Rounding::Reject, "1.23456789", Some("1.23456789")),
+            (8, Rounding::Reject, "1.234567891", None),
+            (8, Rounding::HalfEven, "0.000000005", Some("0.00000000")),
+            (
+                8,
+                Rounding::HalfAwayFromZero,
+                "0.000000005",
+                Some("0.00000001"),
+            ),
+            (2, Rounding::Reject, "10.001", None),
+            (2, Rounding::HalfAwayFromZero, "10.005", Some("10.01")),
+            (0, Rounding::TowardZero, "10.99", Some("10")),
+            (4, Rounding::HalfEven, "1e2", None),
+            (4, Rounding::HalfEven, "abc", None),
+            (8, Rounding::TowardZero, "", None),
+        ];
+        for (precision, rounding, input, expected) in cases {
+            let ctx = MoneyContext::new(*precision).with_rounding(*rounding);
+            let parsed = ctx.parse(input).map(|amount| ctx.format(amount));
+            assert_eq!(
+                parsed.as_deref().ok(),
+                *expected,
+                "precision {}, {:?}, {:?}",
+                precision,
+                rounding,
+                input
+            );
+        }
+        assert_eq!(
+            MoneyContext::default().parse("1.23456"),
+            Err(ProcessingError::InvalidPrecision)
+        );
+    }
+
+    // Test that the amounts are written with the precision of the context,
+    // also as minor units.
+    #[test]
+    fn test_format() {
+        let amount: Amount = "10.5".parse().unwrap();
+        let cases = [
+            (4, "10.5", "105000"),
+            (8, "10.5", "1050000000"),
+            (2, "10.5", "1050"),
+        ];
+        for (precision, formatted, minor_units) in cases {
+            let ctx = MoneyContext::new(precision);
+            assert_eq!(ctx.format(amount), formatted);
+            assert_eq!(ctx.format_minor_units(amount), minor_units);
+        }
+        let precise = MoneyContext::new(8).parse("1.00005").unwrap();
+        assert_eq!(MoneyContext::default().format(precise), "1.0001");
+        assert_eq!(
+            MoneyContext::default()
+                .with_rounding(Rounding::TowardZero)
+                .format(precise),
+            "1.0000"
+        );
+    }
+
+    // Test that the amounts are combined exactly and the overflows rejected
+    // or saturated.
+    #[test]
+    fn test_add_sub() {
+        let ctx = MoneyContext::default();
+        let amount = |value: &str| ctx.parse(value).unwrap();
+        assert_eq!(ctx.add(amount("1.5"), amount("2.25")), Ok(amount("3.75")));
+        assert_eq!(ctx.sub(amount("1.5"), amount("2.25")), Ok(amount("-0.75")));
+        assert_eq!(
+            ctx.format(ctx.sub(amount("1.5"), amount("1.5")).unwrap()),
+            "0.0000"
+        );
+
+        let max = Amount::from_decimal(Decimal::MAX);
+        let min = Amount::from_decimal(Decimal::MIN);
+        assert_eq!(
+            ctx.add(max, amount("1")),
+            Err(ProcessingError::Overflow {
+                amount: max,
+                change: amount("1")
+            })
+        );
+        assert!(ctx.sub(min, amount("1")).is_err());
+        let saturating = ctx.with_overflow(OverflowPolicy::Saturate);
+        assert_eq!(saturating.add(max, amount("1")), Ok(max));
+        assert_eq!(saturating.sub(min, amount("1")), Ok(min));
+        assert_eq!(saturating.add(min, amount("-1")), Ok(min));
+    }
+}
@@ -0,0 +1,70 @@
+use crate::error::ProcessingError;
+use crate::processor::Applied;
+use crate::type_defs::{Amount, ClientId, Transaction, TransactionId, TransactionRecord};
+
+/// Trait implemented by types which want to be notified about the transactions
+/// applied by a `TransactionProcessor`, e.g. to forward chargebacks to a fraud
+/// detection system. Every method does nothing by default so implementors only
+/// need to override the events they are interested in.
+///
+/// The methods for applied transactions are called after the client state has
+/// been updated.
+///
+/// Closures taking the processed record and its outcome implement it as well,
+/// they are called through `on_processed`.
+pub trait ProcessorObserver {
+    fn on_deposit(&mut self, _client_id: ClientId, _tx: TransactionId, _amount: Amount) {}
+
+    fn on_withdrawal(&mut self, _client_id: ClientId, _tx: TransactionId, _amount: Amount) {}
+
+    fn on_dispute_opened(&mut self, _client_id: ClientId, _tx: TransactionId) {}
+
+    fn on_resolved(&mut self, _client_id: ClientId, _tx: TransactionId) {}
+
+    fn on_chargeback(&mut self, _client_id: ClientId, _tx: TransactionId) {}
+
+    /// Called when a record could not be processed.
+    fn on_rejected(&mut self, _record: &TransactionRecord, _error: &ProcessingError) {}
+
+    /// Called once for every processed record, whatever the outcome.
+    fn on_processed(
+        &mut self,
+        _record: &TransactionRecord,
+        _result: &Result<Applied, ProcessingError>,
+    ) {
+    }
+
+    /// Called once the run is over, e.g. to flush a report. Returns the first
+    /// error the observer met, if any.
+    fn finish(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl<F> ProcessorObserver for F
+where
+    F: FnMut(&TransactionRecord, &Result<Applied, ProcessingError>),
+{
+    fn on_processed(
+        &mut self,
+        record: &TransactionRecord,
+        result: &Result<Applied, ProcessingError>,
+    ) {
+        self(record, result)
+    }
+}
+
+/// Calls the observer method matching the applied transaction.
+pub(crate) fn notify_applied(observer: &mut dyn ProcessorObserver, transaction: &Transaction) {
+    match *transaction {
+        Transaction::Deposit { client, tx, amount } => observer.on_deposit(client, tx, amount),
+        Transaction::Withdrawal { client, tx, amount } => {
+            observer.on_withdrawal(client, tx, amount)
+        }
+        Transaction::Dispute { client, tx, .. } => observer.on_dispute_opened(client, tx),
+        Transaction::Resolve { client, tx } => observer.on_resolved(client, tx),
+        Transaction::ChargeBack { client, tx } => observer.on_chargeback(client, tx),
+        // Custom transactions are only reported through on_processed.
+        Transaction::Extension { .. } => {}
+    }
+}
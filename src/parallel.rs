@@ -0,0 +1,508 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+
+use crate::client::Client;
+use crate::columns;
+use crate::malformed::MalformedRowPolicy;
+use crate::processor::{ProcessorSettings, TransactionProcessor};
+use crate::runner::{self, ProcessingStats, RunOptions};
+use crate::type_defs::ClientId;
+
+/// Number of bytes read after a candidate split point to find where the
+/// client changes, the candidate is dropped when the client does not change
+/// within them.
+const PEEK_SIZE: u64 = 1024 * 1024;
+
+/// Smallest number of bytes of a chunk, an input is split into at most its
+/// size divided by it whatever number of chunks is requested.
+const MIN_CHUNK_SIZE: u64 = 1024;
+
+/// Byte ranges of a csv input, each of them starting at a row whose client
+/// differs from the one of the previous row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ChunkPlan {
+    /// Header row prepended to every chunk, empty for headerless inputs.
+    header: Vec<u8>,
+    /// Offsets of the chunks, the last chunk ends at the end of the input.
+    starts: Vec<u64>,
+    len: u64,
+    /// Position of the client id in the rows.
+    client_column: usize,
+}
+
+/// Processes a csv file grouped by client, i.e. where all the rows of a client
+/// are next to each other, as up to `chunks` byte ranges and merges the
+/// resulting clients into the processor. The chunks are shared out between at
+/// most as many threads as `thread::available_parallelism`, and an input is
+/// split into chunks of at least `MIN_CHUNK_SIZE` bytes.
+///
+/// The file is processed on the current thread when it can not be split: the
+/// processor can not be forked (see `TransactionProcessor::fork_settings`),
+/// the options depend on the order of the records (skip, take, fail fast,
+/// failing on conflicting duplicates, aborting at or quarantining the
+/// malformed rows), the input has idempotency keys, which are deduplicated
+/// across the whole input, no row where the client changes was found around
+/// the split points, or a client appears in more than one chunk. The input is
+/// read once to check the latter before any chunk is processed, so nothing is
+/// processed twice. Quoted fields spanning several lines are not supported
+/// when splitting. The record positions of the stats are relative to each
+/// chunk.
+pub fn run_parallel<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>(
+    processor: &mut TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
+    path: &Path,
+    chunks: usize,
+    options: &RunOptions,
+) -> Result<ProcessingStats, String> {
+    let settings = processor.fork_settings();
+    let order_dependent = options.skip > 0
+        || options.take.is_some()
+        || options.fail_fast
+        || options.fail_on_conflicting_duplicate
+        || !matches!(options.on_malformed, MalformedRowPolicy::Skip);
+    let plan = match settings {
+        Some(_) if chunks > 1 && !order_dependent => plan_chunks(path, chunks, options)?,
+        _ => None,
+    };
+    let (settings, plan) = match (settings, plan) {
+        (Some(settings), Some(plan)) => (settings, plan),
+        _ => return run_sequential(processor, path, options),
+    };
+    // A client in several chunks means the input is not grouped by client.
+    let known: HashSet<ClientId> = processor.client_ids().collect();
+    if !grouped(path, &plan, known, options)? {
+        return run_sequential(processor, path, options);
+    }
+
+    let ranges: Vec<(u64, u64)> = plan
+        .starts
+        .iter()
+        .enumerate()
+        .map(|(chunk, start)| {
+            let end = plan.starts.get(chunk + 1).copied().unwrap_or(plan.len);
+            (*start, end)
+        })
+        .collect();
+    let threads = thread::available_parallelism()
+        .map_or(1, usize::from)
+        .min(ranges.len());
+    // Worker i processes the chunks i, i + threads... so the results are put
+    // back in the order of the chunks.
+    let mut results: Vec<(usize, Result<_, String>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|worker| {
+                let (settings, header, ranges) = (&settings, &plan.header, &ranges);
+                scope.spawn(move || {
+                    ranges
+                        .iter()
+                        .enumerate()
+                        .skip(worker)
+                        .step_by(threads)
+                        .map(|(chunk, range)| {
+                            (chunk, run_chunk(path, header, *range, settings, options))
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Chunk processing panicked"))
+            .collect()
+    });
+    results.sort_unstable_by_key(|(chunk, _)| *chunk);
+    let results = results
+        .into_iter()
+        .map(|(_, result)| result)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut stats = ProcessingStats::new();
+    for (chunk_stats, clients) in results {
+        stats.merge(&chunk_stats);
+        processor.merge_clients(clients)?;
+    }
+    Ok(stats)
+}
+
+/// Processes the byte range of the input, after the header, with a processor
+/// forked from settings.
+fn run_chunk<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>(
+    path: &Path,
+    header: &[u8],
+    (start, end): (u64, u64),
+    settings: &ProcessorSettings,
+    options: &RunOptions,
+) -> Result<
+    (
+        ProcessingStats,
+        Vec<Client<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>>,
+    ),
+    String,
+> {
+    let mut file = open(path)?;
+    file.seek(SeekFrom::Start(start))
+        .map_err(|err| format!("Could not seek {}: {}", path.display(), err))?;
+    let input = BufReader::new(header.chain(file.take(end - start)));
+    let mut chunk_processor = TransactionProcessor::from_settings(settings);
+    let stats = runner::run(&mut chunk_processor, input, options)?;
+    Ok((stats, chunk_processor.into_clients()))
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    File::open(path).map_err(|err| format!("Could not open input file {}: {}", path.display(), err))
+}
+
+fn run_sequential<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>(
+    processor: &mut TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
+    path: &Path,
+    options: &RunOptions,
+) -> Result<ProcessingStats, String> {
+    runner::run(processor, BufReader::new(open(path)?), options)
+}
+
+/// Splits the input into at most `chunks` chunks, returns None when no split
+/// point could be found.
+fn plan_chunks(
+    path: &Path,
+    chunks: usize,
+    options: &RunOptions,
+) -> Result<Option<ChunkPlan>, String> {
+    let mut file = open(path)?;
+    let len = file
+        .metadata()
+        .map_err(|err| format!("Could not read {}: {}", path.display(), err))?
+        .len();
+
+    // The header, with the comment lines before it, is read again at the start
+    // of every chunk.
+    let mut header = Vec::new();
+    let mut reader = BufReader::new(&mut file);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        reader
+            .read_until(b'\n', &mut line)
+            .map_err(|err| format!("Could not read {}: {}", path.display(), err))?;
+        header.extend_from_slice(&line);
+        let comment = options
+            .csv
+            .comment
+            .is_some_and(|comment| line.first() == Some(&comment));
+        if !comment {
+            break;
+        }
+    }
+    let row = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .delimiter(options.csv.delimiter)
+        .trim(csv::Trim::All)
+        .from_reader(line.as_slice())
+        .records()
+        .next()
+        .and_then(Result::ok)
+        .unwrap_or_default();
+    let translated = match &options.csv.columns {
+        Some(columns) => Some(columns.translate_headers(&row)?),
+        None => columns::detect_header(&row),
+    };
+    let client_column = match translated {
+        // The idempotency keys of a chunk would only be checked against the
+        // keys of the same chunk.
+        Some(translated) if translated.iter().any(|field| field == "idempotency_key") => {
+            return Ok(None)
+        }
+        Some(translated) => match translated.iter().position(|field| field == "client") {
+            Some(column) => column,
+            None => return Ok(None),
+        },
+        // Headerless inputs are read by position from their first row.
+        None => {
+            header.clear();
+            1
+        }
+    };
+
+    let data_start = header.len() as u64;
+    let chunks = chunks.min(((len - data_start) / MIN_CHUNK_SIZE) as usize);
+    let mut starts = vec![data_start];
+    for chunk in 1..chunks as u64 {
+        let candidate = data_start + (len - data_start) * chunk / chunks as u64;
+        if let Some(start) = find_split(&mut file, candidate, client_column, options)? {
+            starts.push(start);
+        }
+    }
+    starts.sort_unstable();
+    starts.dedup();
+    if starts.len() < 2 {
+        return Ok(None);
+    }
+    Ok(Some(ChunkPlan {
+        header,
+        starts,
+        len,
+        client_column,
+    }))
+}
+
+/// Reads the client of every row of the chunks, returns whether none of them
+/// is in more than one chunk nor already known.
+fn grouped(
+    path: &Path,
+    plan: &ChunkPlan,
+    mut known: HashSet<ClientId>,
+    options: &RunOptions,
+) -> Result<bool, String> {
+    let read_error = |err: std::io::Error| format!("Could not read {}: {}", path.display(), err);
+    let mut file = BufReader::new(open(path)?);
+    file.seek(SeekFrom::Start(plan.starts[0]))
+        .map_err(read_error)?;
+    let mut line = Vec::new();
+    let mut position = plan.starts[0];
+    for (chunk, start) in plan.starts.iter().enumerate() {
+        let end = plan.starts.get(chunk + 1).copied().unwrap_or(plan.len);
+        debug_assert_eq!(position, *start);
+        let mut clients = HashSet::new();
+        while position < end {
+            line.clear();
+            let read = file.read_until(b'\n', &mut line).map_err(read_error)?;
+            if read == 0 {
+                break;
+            }
+            position += read as u64;
+            if let Some(client) = client_of(&line, plan.client_column, options) {
+                clients.insert(ClientId(client));
+            }
+        }
+        if clients.iter().any(|client| known.contains(client)) {
+            return Ok(false);
+        }
+        known.extend(clients);
+    }
+    Ok(true)
+}
+
+/// Returns the offset of the first row after the candidate offset whose
+/// client differs from the one of the row before it.
+fn find_split(
+    file: &mut File,
+    candidate: u64,
+    client_column: usize,
+    options: &RunOptions,
+) -> Result<Option<u64>, String> {
+    file.seek(SeekFrom::Start(candidate))
+        .map_err(|err| format!("Could not seek input because of: {}", err))?;
+    let mut peeked = Vec::new();
+    file.take(PEEK_SIZE)
+        .read_to_end(&mut peeked)
+        .map_err(|err| format!("Could not read input because of: {}", err))?;
+
+    // The candidate usually falls in the middle of a row, start at the next one.
+    let mut offset = match peeked.iter().position(|byte| *byte == b'\n') {
+        Some(end) => end + 1,
+        None => return Ok(None),
+    };
+    let mut previous = None;
+    while let Some(len) = peeked[offset..].iter().position(|byte| *byte == b'\n') {
+        let line = &peeked[offset..offset + len];
+        if let Some(client) = client_of(line, client_column, options) {
+            if previous.is_some_and(|previous| previous != client) {
+                return Ok(Some(candidate + offset as u64));
+            }
+            previous = Some(client);
+        }
+        offset += len + 1;
+    }
+    Ok(None)
+}
+
+/// Reads the client id of a row, comments and rows without a valid client
+/// id give None.
+fn client_of(line: &[u8], client_column: usize, options: &RunOptions) -> Option<u32> {
+    if options
+        .csv
+        .comment
+        .is_some_and(|comment| line.first() == Some(&comment))
+    {
+        return None;
+    }
+    let field = line
+        .split(|byte| *byte == options.csv.delimiter)
+        .nth(client_column)?;
+    std::str::from_utf8(field).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::columns::ColumnMapping;
+    use crate::processor::{OutputOptions, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+    use std::fs;
+    use tempdir::TempDir;
+
+    type Processor = TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>;
+
+    /// Writes an input grouped by client, each client getting the given
+    /// number of deposits, a withdrawal, a dispute and a resolve.
+    fn grouped_input(dir: &TempDir, rows_per_client: &[(u32, u32)]) -> std::path::PathBuf {
+        let mut input = String::from("type,client,tx,amount\n");
+        let mut tx = 0;
+        for (client, deposits) in rows_per_client {
+            let first = tx + 1;
+            for _ in 0..*deposits {
+                tx += 1;
+                input.push_str(&format!("deposit,{},{},1.5\n", client, tx));
+            }
+            tx += 1;
+            input.push_str(&format!("withdrawal,{},{},2.0\n", client, tx));
+            input.push_str(&format!("dispute,{},{},\n", client, first));
+            if client % 2 == 0 {
+                input.push_str(&format!("resolve,{},{},\n", client, first));
+            }
+        }
+        let path = dir.path().join("grouped.csv");
+        fs::write(&path, input).unwrap();
+        path
+    }
+
+    fn balances(processor: &Processor) -> String {
+        let mut output = Vec::new();
+        processor
+            .serialize_to(&mut output, &OutputOptions::default())
+            .unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    fn compare(path: &Path, chunks: usize, options: &RunOptions) -> ProcessingStats {
+        let mut sequential = Processor::new();
+        let sequential_stats = run_sequential(&mut sequential, path, options).unwrap();
+        let mut parallel = Processor::new();
+        let stats = run_parallel(&mut parallel, path, chunks, options).unwrap();
+        assert_eq!(balances(&parallel), balances(&sequential));
+        assert_eq!(stats.applied, sequential_stats.applied);
+        assert_eq!(stats.rejected, sequential_stats.rejected);
+        stats
+    }
+
+    // Test that the chunks of a grouped input give the same balances as a
+    // sequential run.
+    #[test]
+    fn test_parallel_matches_sequential() {
+        let dir = TempDir::new("parallel").unwrap();
+        let clients: Vec<(u32, u32)> = (1..=80).map(|client| (client, client % 7)).collect();
+        let path = grouped_input(&dir, &clients);
+
+        let plan = plan_chunks(&path, 4, &RunOptions::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(plan.starts.len(), 4);
+        compare(&path, 4, &RunOptions::default());
+
+        // The number of chunks is bounded by the size of the input.
+        let len = fs::metadata(&path).unwrap().len();
+        let plan = plan_chunks(&path, usize::MAX, &RunOptions::default())
+            .unwrap()
+            .unwrap();
+        assert!(plan.starts.len() as u64 <= len / MIN_CHUNK_SIZE);
+        compare(&path, usize::MAX, &RunOptions::default());
+
+        // With a header every chunk starts with it.
+        let options = RunOptions {
+            csv: crate::runner::CsvOptions {
+                columns: Some(ColumnMapping::new()),
+                ..Default::default()
+            },
+            ..RunOptions::default()
+        };
+        let plan = plan_chunks(&path, 3, &options).unwrap().unwrap();
+        assert_eq!(plan.header, b"type,client,tx,amount\n");
+        let stats = compare(&path, 3, &options);
+        assert_eq!(stats.malformed, 0);
+    }
+
+    // Test that a client spanning a candidate split point is kept in a single
+    // chunk, and that an input of a single client is not split.
+    #[test]
+    fn test_client_spanning_split_point() {
+        let dir = TempDir::new("parallel_span").unwrap();
+        let path = grouped_input(&dir, &[(1, 3), (2, 200), (3, 4)]);
+        let plan = plan_chunks(&path, 2, &RunOptions::default())
+            .unwrap()
+            .unwrap();
+        let input = fs::read(&path).unwrap();
+        let split = plan.starts[1] as usize;
+        assert!(input[split..].starts_with(b"deposit,3,"));
+        compare(&path, 2, &RunOptions::default());
+
+        let path = grouped_input(&dir, &[(5, 100)]);
+        assert_eq!(plan_chunks(&path, 4, &RunOptions::default()).unwrap(), None);
+        compare(&path, 4, &RunOptions::default());
+    }
+
+    // Test that an input which is not grouped by client falls back to a
+    // sequential run.
+    #[test]
+    fn test_ungrouped_input() {
+        let dir = TempDir::new("parallel_ungrouped").unwrap();
+        let mut input = String::new();
+        for tx in 1..=200 {
+            input.push_str(&format!("deposit,{},{},1.0\n", tx % 3 + 1, tx));
+        }
+        let path = dir.path().join("ungrouped.csv");
+        fs::write(&path, input).unwrap();
+        let plan = plan_chunks(&path, 4, &RunOptions::default())
+            .unwrap()
+            .unwrap();
+        assert!(!grouped(&path, &plan, HashSet::new(), &RunOptions::default()).unwrap());
+        compare(&path, 4, &RunOptions::default());
+    }
+
+    // Test that the options stopping at a row in the middle of the input give
+    // the balances of a sequential run.
+    #[test]
+    fn test_order_dependent_options() {
+        let dir = TempDir::new("parallel_order").unwrap();
+        let clients: Vec<(u32, u32)> = (1..=40).map(|client| (client, 2)).collect();
+        let path = grouped_input(&dir, &clients);
+        let input = fs::read_to_string(&path).unwrap();
+
+        // Client 2 deposits tx 4 and 5.
+        let conflicting =
+            input.replacen("deposit,2,5,1.5\n", "deposit,2,5,1.5\ndeposit,2,4,9.0\n", 1);
+        fs::write(&path, conflicting).unwrap();
+        let options = RunOptions {
+            fail_on_conflicting_duplicate: true,
+            ..RunOptions::default()
+        };
+        let stats = compare(&path, 4, &options);
+        assert!(!stats.completed);
+
+        let malformed = input.replacen("deposit,2,5,1.5\n", "deposit,2,5,1.5\ndeposit,x\n", 1);
+        fs::write(&path, malformed).unwrap();
+        let options = RunOptions::default().with_malformed_row_policy(MalformedRowPolicy::Abort);
+        let stats = compare(&path, 4, &options);
+        assert!(!stats.completed);
+    }
+
+    // Test that an input with idempotency keys is not split, a key replayed
+    // by a client of another chunk being still recognized.
+    #[test]
+    fn test_idempotency_keys() {
+        let dir = TempDir::new("parallel_keys").unwrap();
+        let mut input = String::from("type,client,tx,amount,idempotency_key\n");
+        for client in 1..=40 {
+            input.push_str(&format!(
+                "deposit,{},{},1.0,key-{}\n",
+                client,
+                client,
+                client % 39
+            ));
+        }
+        let path = dir.path().join("keys.csv");
+        fs::write(&path, input).unwrap();
+        assert_eq!(plan_chunks(&path, 4, &RunOptions::default()).unwrap(), None);
+        let stats = compare(&path, 4, &RunOptions::default());
+        assert_eq!(stats.idempotent_replays, 1);
+    }
+}
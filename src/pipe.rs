@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Writer which remembers whether the reader of the output went away, so a
+/// closed pipe, e.g. `exchange input.csv | head`, can be told apart from the
+/// other write errors once they have been turned into messages.
+#[derive(Debug)]
+pub struct PipeWriter<W> {
+    inner: W,
+    broken: bool,
+}
+
+impl<W: Write> PipeWriter<W> {
+    pub fn new(inner: W) -> Self {
+        PipeWriter {
+            inner,
+            broken: false,
+        }
+    }
+
+    /// Returns true once a write or flush failed with a broken pipe.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    fn check<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(err) = &result {
+            self.broken |= err.kind() == io::ErrorKind::BrokenPipe;
+        }
+        result
+    }
+}
+
+impl<W: Write> Write for PipeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.inner.write(buf);
+        self.check(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.inner.flush();
+        self.check(result)
+    }
+}
+
+/// How the output was written by `write_with_fallback`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeOutcome {
+    /// The whole output was written.
+    Written,
+    /// The reader went away before the end of the output.
+    Broken,
+}
+
+/// Writes the output through a `PipeWriter`. When the reader goes away the
+/// whole output is written again into the fallback file, if any, since what
+/// was written before the pipe broke may not have been read. Errors other
+/// than a broken pipe are returned.
+pub fn write_with_fallback<W, F>(
+    inner: W,
+    fallback: Option<&Path>,
+    mut write: F,
+) -> Result<PipeOutcome, String>
+where
+    W: Write,
+    F: FnMut(&mut dyn Write) -> Result<(), String>,
+{
+    let mut writer = PipeWriter::new(inner);
+    match write(&mut writer) {
+        Ok(()) => Ok(PipeOutcome::Written),
+        Err(_) if writer.is_broken() => {
+            if let Some(path) = fallback {
+                let file = File::create(path)
+                    .map_err(|err| format!("Could not create {}: {}", path.display(), err))?;
+                let mut file = BufWriter::new(file);
+                write(&mut file)?;
+                file.flush()
+                    .map_err(|err| format!("Could not write {}: {}", path.display(), err))?;
+            }
+            Ok(PipeOutcome::Broken)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{
+        OutputOptions, TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE,
+    };
+
+    /// Writer failing every write with the given error kind.
+    struct FailingWriter(io::ErrorKind);
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(self.0, "closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Test that only broken pipes are recorded when serializing the balances.
+    #[test]
+    fn test_broken_pipe() {
+        let processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let mut writer = PipeWriter::new(FailingWriter(io::ErrorKind::BrokenPipe));
+        assert!(processor
+            .serialize_to(&mut writer, &OutputOptions::default())
+            .is_err());
+        assert!(writer.is_broken());
+
+        let mut writer = PipeWriter::new(FailingWriter(io::ErrorKind::Other));
+        assert!(processor
+            .serialize_to(&mut writer, &OutputOptions::default())
+            .is_err());
+        assert!(!writer.is_broken());
+
+        let mut writer = PipeWriter::new(Vec::new());
+        processor
+            .serialize_to(&mut writer, &OutputOptions::default())
+            .unwrap();
+        assert!(!writer.is_broken());
+    }
+
+    // Test that the whole output is written into the fallback file once the
+    // pipe is broken, and that other errors are returned.
+    #[test]
+    fn test_output_fallback() {
+        let dir = tempdir::TempDir::new("fallback").unwrap();
+        let fallback = dir.path().join("balances.csv");
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        processor
+            .process_transaction(crate::type_defs::TransactionRecord {
+                transaction_type: "deposit".to_owned(),
+                client: 1,
+                tx: 1,
+                amount: Some("2.5".to_owned()),
+                ..Default::default()
+            })
+            .unwrap();
+        let serialize =
+            |writer: &mut dyn Write| processor.serialize_to(writer, &OutputOptions::default());
+
+        let outcome = write_with_fallback(
+            FailingWriter(io::ErrorKind::BrokenPipe),
+            Some(&fallback),
+            serialize,
+        );
+        assert_eq!(outcome, Ok(PipeOutcome::Broken));
+        assert_eq!(
+            std::fs::read_to_string(&fallback).unwrap(),
+            "client,available,held,total,locked\n1,2.5,0.0000,2.5,false\n"
+        );
+
+        let outcome =
+            write_with_fallback(FailingWriter(io::ErrorKind::BrokenPipe), None, serialize);
+        assert_eq!(outcome, Ok(PipeOutcome::Broken));
+        assert!(write_with_fallback(FailingWriter(io::ErrorKind::Other), None, serialize).is_err());
+
+        let mut output = Vec::new();
+        let outcome = write_with_fallback(&mut output, Some(&dir.path().join("unused")), serialize);
+        assert_eq!(outcome, Ok(PipeOutcome::Written));
+        assert!(!dir.path().join("unused").exists());
+        assert!(output.starts_with(b"client,available"));
+    }
+}
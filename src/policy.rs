@@ -0,0 +1,53 @@
+use std::fmt;
+
+use crate::client::ClientView;
+use crate::type_defs::{Amount, Transaction};
+
+/// Type which holds the reason for which a policy rejected a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyViolation(pub String);
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Trait implemented by business rules which can veto a transaction before it
+/// is applied to the client, e.g. deployment specific limits that should not be
+/// hard-coded into the processor.
+///
+/// Closures taking the transaction and the current client state implement it
+/// as well.
+pub trait TransactionPolicy {
+    fn check(&self, transaction: &Transaction, client: &ClientView) -> Result<(), PolicyViolation>;
+}
+
+impl<F> TransactionPolicy for F
+where
+    F: Fn(&Transaction, &ClientView) -> Result<(), PolicyViolation>,
+{
+    fn check(&self, transaction: &Transaction, client: &ClientView) -> Result<(), PolicyViolation> {
+        self(transaction, client)
+    }
+}
+
+/// Policy which rejects deposits and withdrawals above a certain amount.
+#[derive(Debug, Copy, Clone)]
+pub struct MaxAmount(pub Amount);
+
+impl TransactionPolicy for MaxAmount {
+    fn check(
+        &self,
+        transaction: &Transaction,
+        _client: &ClientView,
+    ) -> Result<(), PolicyViolation> {
+        match transaction.amount() {
+            Some(amount) if amount > self.0 => Err(PolicyViolation(format!(
+                "Amount {} exceeds the maximum of {}",
+                amount, self.0
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
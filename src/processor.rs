@@ -1,18 +1,417 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::io;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::num::NonZeroU16;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tempdir::TempDir;
 
-use crate::client::Client;
-use crate::type_defs::{ClientId, Transaction, TransactionRecord};
+use crate::client::{Client, ClientHandle, ClientView, HeldPolicy, VelocityLimit};
+use crate::error::ProcessingError;
+use crate::metrics::CacheStats;
+use crate::money::MoneyContext;
+use crate::observer::{self, ProcessorObserver};
+use crate::policy::TransactionPolicy;
+use crate::reports::{BalanceDiscrepancy, BalanceSummary, DisputeEvent, HeldDetail, OpenDispute};
+use crate::state::{ClientState, StateFormatVersion, StateSnapshot};
+use crate::suspicious::{SuspicionReport, SuspicionThresholds};
+use crate::transaction_cache::{SpillFailurePolicy, TransactionCache};
+use crate::type_defs::{
+    Amount, ClientId, StoredTransaction, Transaction, TransactionId, TransactionKind,
+    TransactionNotes, TransactionRecord,
+};
+use crate::validation::RecordValidator;
 
 /// Assume we have at least 2GiB available to store transactions in memory.
 pub const CACHE_SIZE_LIMIT: u64 = 2 * 1024 * 1024 * 1024;
 /// Each cache line could have 4 MiB.
 pub const CACHE_SIZE_LINE: u32 = 4 * 1024 * 1024;
+/// Approximate number of bytes a processed transaction takes in the cache of
+/// a client, used to turn a memory budget into a cache limit.
+pub const TRANSACTION_SIZE: u64 =
+    (std::mem::size_of::<TransactionId>() + std::mem::size_of::<StoredTransaction>()) as u64;
+
+/// Order in which the clients are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    /// Increasing client id.
+    #[default]
+    ClientId,
+    /// Decreasing total balance.
+    Total,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "client" => Ok(SortKey::ClientId),
+            "total" => Ok(SortKey::Total),
+            _ => Err(format!("Unknown sort key {}", value)),
+        }
+    }
+}
+
+impl fmt::Display for SortKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortKey::ClientId => write!(f, "client"),
+            SortKey::Total => write!(f, "total"),
+        }
+    }
+}
+
+/// Balance column of the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputColumn {
+    ClientId,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+impl FromStr for OutputColumn {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "client" | "client_id" => Ok(OutputColumn::ClientId),
+            "available" => Ok(OutputColumn::Available),
+            "held" => Ok(OutputColumn::Held),
+            "total" => Ok(OutputColumn::Total),
+            "locked" => Ok(OutputColumn::Locked),
+            _ => Err(format!(
+                "Unknown column {}, expected one of client_id,available,held,total,locked",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for OutputColumn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputColumn::ClientId => "client_id",
+            OutputColumn::Available => "available",
+            OutputColumn::Held => "held",
+            OutputColumn::Total => "total",
+            OutputColumn::Locked => "locked",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Type which selects the balance columns of the output, in order, with the
+/// header written for each of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputSpec {
+    columns: Vec<(OutputColumn, String)>,
+}
+
+impl OutputSpec {
+    pub fn columns(&self) -> impl Iterator<Item = OutputColumn> + '_ {
+        self.columns.iter().map(|(column, _)| *column)
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = &str> {
+        self.columns.iter().map(|(_, header)| header.as_str())
+    }
+}
+
+impl Default for OutputSpec {
+    /// The client, available, held, total and locked columns.
+    fn default() -> Self {
+        "client,available,held,total,locked".parse().unwrap()
+    }
+}
+
+impl FromStr for OutputSpec {
+    type Err = String;
+
+    /// Parses a comma separated list of columns, each of them written under
+    /// its own name unless renamed with `column=header`, e.g.
+    /// `client=id,total,locked`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let columns = value
+            .split(',')
+            .map(|column| {
+                let (name, header) = column.split_once('=').unwrap_or((column, column));
+                Ok((name.trim().parse()?, header.trim().to_owned()))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(OutputSpec { columns })
+    }
+}
+
+impl fmt::Display for OutputSpec {
+    /// Writes the columns in the format parsed by `from_str`, the headers
+    /// which differ from the column name being written after it.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let columns: Vec<String> = self
+            .columns
+            .iter()
+            .map(|(column, header)| {
+                let name = column.to_string();
+                if *header == name {
+                    name
+                } else {
+                    format!("{}={}", name, header)
+                }
+            })
+            .collect();
+        write!(f, "{}", columns.join(","))
+    }
+}
+
+/// Directory and number of the shards the balances are split into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputShards {
+    pub dir: PathBuf,
+    pub count: NonZeroU16,
+}
+
+impl OutputShards {
+    /// Name of the file of the given shard.
+    pub fn file_name(shard: u16) -> String {
+        format!("part-{:05}.csv", shard)
+    }
+}
+
+impl FromStr for OutputShards {
+    type Err = String;
+
+    /// Parses `<dir>:<n>`, the number of shards being after the last colon.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid output shards {}, expected <dir>:<n>", value);
+        let (dir, count) = value.rsplit_once(':').ok_or_else(invalid)?;
+        let count: NonZeroU16 = count.parse().map_err(|_| invalid())?;
+        if dir.is_empty() {
+            return Err(invalid());
+        }
+        Ok(OutputShards {
+            dir: PathBuf::from(dir),
+            count,
+        })
+    }
+}
+
+/// Prefix and number of clients of the pages the balances are split into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputPages {
+    pub prefix: PathBuf,
+    pub size: u64,
+}
+
+impl OutputPages {
+    /// Path of the file of the given page, e.g. `output.0.csv`.
+    pub fn path(&self, page: usize) -> PathBuf {
+        let mut path = self.prefix.clone().into_os_string();
+        path.push(format!(".{}.csv", page));
+        PathBuf::from(path)
+    }
+}
+
+/// Options which control the output written by `TransactionProcessor::serialize`.
+#[derive(Debug, Default, Clone)]
+pub struct OutputOptions {
+    /// Order in which the clients are written, clients which compare equal
+    /// are always written by increasing client id.
+    pub sort_by: SortKey,
+    /// Balance columns written, in order.
+    pub columns: OutputSpec,
+    /// Append a lock_reason column with the chargeback that locked the account.
+    pub lock_reason: bool,
+    /// Append an ever_disputed column telling whether a deposit of the client
+    /// was ever disputed.
+    pub dispute_flag: bool,
+    /// Append the deposits_count, withdrawals_count, open_disputes_count and
+    /// status columns.
+    pub extended: bool,
+    /// Prepend a UTF-8 BOM and terminate lines with CRLF, as preferred by Excel.
+    pub excel: bool,
+    /// Skip the clients which have zero balances and are not locked.
+    pub suppress_zero: bool,
+    /// Write the amounts as integers of the minor unit with the given number
+    /// of decimals, e.g. cents with 2.
+    pub minor_units: Option<u32>,
+    /// Split the balances into shards instead of writing them to stdout.
+    pub shards: Option<OutputShards>,
+    /// Split the sorted balances into pages instead of writing them to
+    /// stdout, it is not combined with the shards.
+    pub pages: Option<OutputPages>,
+    /// Mark the balances as partial, processing having been interrupted. A
+    /// trailing `PARTIAL_MARKER` comment line is written after the balances,
+    /// or a `_PARTIAL` file next to the shards.
+    pub partial: bool,
+    /// Mark the partial balances as cut short by a panic rather than an
+    /// interruption, with `PANIC_MARKER` instead of `PARTIAL_MARKER`.
+    pub panicked: bool,
+}
+
+impl OutputOptions {
+    /// Returns the comment line written after partial balances.
+    fn partial_marker(&self) -> &'static str {
+        if self.panicked {
+            PANIC_MARKER
+        } else {
+            PARTIAL_MARKER
+        }
+    }
+}
+
+/// Comment line written after the balances when they are partial.
+pub const PARTIAL_MARKER: &str = "# partial output, processing was interrupted";
+
+/// Comment line written after the balances when processing panicked.
+pub const PANIC_MARKER: &str = "# partial output, processing panicked";
+
+/// Byte order mark written at the start of the output in excel mode.
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Type which describes the outcome of a successfully processed transaction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Applied {
+    pub kind: TransactionKind,
+    pub client_id: ClientId,
+    /// Balances of the client after the transaction was applied.
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+/// Outcome of a record remembered under its idempotency key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RecordedOutcome {
+    Applied(Applied),
+    /// The record was rejected with the given reason.
+    Rejected(String),
+}
+
+impl From<&Result<Applied, ProcessingError>> for RecordedOutcome {
+    fn from(result: &Result<Applied, ProcessingError>) -> Self {
+        match result {
+            Ok(applied) => RecordedOutcome::Applied(*applied),
+            Err(err) => RecordedOutcome::Rejected(err.to_string()),
+        }
+    }
+}
+
+/// Store of the outcomes by idempotency key, spilled to disk like the client
+/// transactions.
+type IdempotencyKeys<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32> =
+    TransactionCache<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE, String, RecordedOutcome>;
+
+/// Type of the handlers which apply a custom transaction type to a client.
+pub type TransactionHandler =
+    dyn Fn(&TransactionRecord, &mut ClientHandle) -> Result<(), ProcessingError>;
+
+/// Type of the callbacks called when the total of a watched client exceeds
+/// the threshold of the watch, with the transaction which made it exceed it.
+pub type WatchCallback = dyn FnMut(&Applied);
+
+/// Threshold on the total of a client registered with
+/// `TransactionProcessor::add_watch`.
+struct Watch {
+    threshold: Amount,
+    /// Whether the total of the client is above the threshold.
+    above: bool,
+    callback: Box<WatchCallback>,
+}
+
+/// Type of the callbacks called every few records with the processor and the
+/// number of records processed so far.
+pub type SnapshotCallback<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32> =
+    dyn FnMut(&TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>, u64);
+
+/// Settings shared by a `TransactionProcessor` with the processors it is
+/// forked into, e.g. to process the chunks of an input on other threads.
+#[derive(Debug, Clone)]
+pub struct ProcessorSettings {
+    cache_dir: Option<PathBuf>,
+    held_policy: HeldPolicy,
+    max_transactions_per_client: Option<u64>,
+    dispute_window: Option<u64>,
+    keep_memos: bool,
+    record_disputes: bool,
+    velocity_limit: Option<VelocityLimit>,
+    overdraft_limit: Option<Amount>,
+    min_deposit: Option<Amount>,
+    min_withdrawal: Option<Amount>,
+    spill_policy: SpillFailurePolicy,
+    cache_limit: Option<u64>,
+    validator: RecordValidator,
+    money: MoneyContext,
+}
 
 /// Type that abstracts an transaction processor, it is the entry point for processing
 /// any transaction.
 pub struct TransactionProcessor<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32> {
     clients: HashMap<ClientId, Client<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>>,
+    observers: Vec<Box<dyn ProcessorObserver>>,
+    policies: Vec<Box<dyn TransactionPolicy>>,
+    handlers: HashMap<String, Box<TransactionHandler>>,
+    /// Directory of this run in which the client caches are created, the
+    /// system temporary directory is used when unset.
+    cache_dir: Option<PathBuf>,
+    /// What the clients do when held would go negative.
+    held_policy: HeldPolicy,
+    /// Maximum number of transactions stored for each client.
+    max_transactions_per_client: Option<u64>,
+    /// Number of transactions of a client after which its deposits can no
+    /// longer be disputed.
+    dispute_window: Option<u64>,
+    /// Keep the memos of the deposits and withdrawals for the reports.
+    keep_memos: bool,
+    /// Record the disputes, resolves and chargebacks for the disputes report.
+    record_disputes: bool,
+    /// Withdrawal velocity limit of every client.
+    velocity_limit: Option<VelocityLimit>,
+    /// How far the available balance of every client may go negative.
+    overdraft_limit: Option<Amount>,
+    /// Smallest amounts of the deposits and withdrawals of every client.
+    min_deposit: Option<Amount>,
+    min_withdrawal: Option<Amount>,
+    /// Outcomes of the records by idempotency key, created with the first
+    /// record which has one.
+    idempotency_keys: Option<IdempotencyKeys<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>>,
+    /// What the caches do when they can not spill to disk.
+    spill_policy: SpillFailurePolicy,
+    /// Number of transactions each client cache holds in memory,
+    /// CACHE_SIZE_LIMIT when unset.
+    cache_limit: Option<u64>,
+    /// Rules checked on every record before it is converted.
+    validator: RecordValidator,
+    /// Precision, rounding and overflow of the amounts parsed from the
+    /// records and written in the output.
+    money: MoneyContext,
+    /// Why a cache could not spill to disk with `SpillFailurePolicy::Abort`,
+    /// no record is processed anymore once set.
+    spill_error: Option<String>,
+    /// Set once the caches could not be created on disk, e.g. on a read-only
+    /// temporary filesystem, the caches created from then on are kept in
+    /// memory only.
+    memory_only: bool,
+    /// Warning of the switch to memory, until taken by the caller to log it.
+    memory_warning: Option<String>,
+    /// Watches on the totals of the clients.
+    watches: HashMap<ClientId, Vec<Watch>>,
+    /// Number of calls to `flush_caches`.
+    cache_flushes: u64,
+    /// Number of records given to `process_transaction`.
+    processed_records: u64,
+    /// Callback called every that many records.
+    snapshot: Option<(
+        u64,
+        Box<SnapshotCallback<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>>,
+    )>,
+    /// Removes the directory of this run when the processor is dropped.
+    _cache_run_dir: Option<TempDir>,
 }
 
 impl<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>
@@ -21,335 +420,2552 @@ impl<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>
     pub fn new() -> Self {
         TransactionProcessor {
             clients: HashMap::new(),
+            observers: Vec::new(),
+            policies: Vec::new(),
+            handlers: HashMap::new(),
+            cache_dir: None,
+            held_policy: HeldPolicy::default(),
+            max_transactions_per_client: None,
+            dispute_window: None,
+            keep_memos: false,
+            record_disputes: false,
+            velocity_limit: None,
+            overdraft_limit: None,
+            min_deposit: None,
+            min_withdrawal: None,
+            idempotency_keys: None,
+            spill_policy: SpillFailurePolicy::default(),
+            cache_limit: None,
+            validator: RecordValidator::default(),
+            money: MoneyContext::default(),
+            spill_error: None,
+            memory_only: false,
+            memory_warning: None,
+            watches: HashMap::new(),
+            cache_flushes: 0,
+            processed_records: 0,
+            snapshot: None,
+            _cache_run_dir: None,
+        }
+    }
+
+    /// Spills the client caches into a new uniquely named subdirectory of
+    /// base_dir, so concurrent runs sharing the same base_dir never collide.
+    /// The subdirectory is removed when the processor is dropped only if
+    /// clean_on_exit is set.
+    pub fn with_cache_dir(
+        mut self,
+        base_dir: &Path,
+        clean_on_exit: bool,
+    ) -> Result<Self, ProcessingError> {
+        let run_dir = TempDir::new_in(base_dir, "run").map_err(|err| {
+            ProcessingError::Cache(format!(
+                "Could not create cache dir in {} because of: {}",
+                base_dir.display(),
+                err
+            ))
+        })?;
+        if clean_on_exit {
+            self.cache_dir = Some(run_dir.path().to_path_buf());
+            self._cache_run_dir = Some(run_dir);
+        } else {
+            self.cache_dir = Some(run_dir.into_path());
+            self._cache_run_dir = None;
+        }
+        Ok(self)
+    }
+
+    /// Directory of this run in which the client caches are created, if any.
+    pub fn cache_dir(&self) -> Option<&Path> {
+        self.cache_dir.as_deref()
+    }
+
+    /// Sets what the clients do when releasing a disputed amount would make
+    /// their held balance negative.
+    pub fn with_held_policy(mut self, held_policy: HeldPolicy) -> Self {
+        self.held_policy = held_policy;
+        self
+    }
+
+    /// Limits the number of deposits, withdrawals and custom transactions
+    /// stored for each client, further ones are rejected.
+    pub fn with_max_transactions_per_client(mut self, max_transactions: u64) -> Self {
+        self.max_transactions_per_client = Some(max_transactions);
+        self
+    }
+
+    /// Rejects the disputes of the deposits followed by more than
+    /// dispute_window transactions of the same client.
+    pub fn with_dispute_window(mut self, dispute_window: u64) -> Self {
+        self.dispute_window = Some(dispute_window);
+        self
+    }
+
+    /// Keeps the memos of the deposits and withdrawals so they can be written
+    /// in the open disputes report, at the cost of storing them.
+    pub fn with_memos(mut self) -> Self {
+        self.keep_memos = true;
+        self
+    }
+
+    /// Records the disputes, resolves and chargebacks of every client so they
+    /// can be reported with `dispute_events`.
+    pub fn with_dispute_events(mut self) -> Self {
+        self.record_disputes = true;
+        self
+    }
+
+    /// Limits the number of withdrawals of each client within any window of
+    /// consecutive transactions of that client.
+    pub fn with_velocity_limit(mut self, velocity_limit: VelocityLimit) -> Self {
+        self.velocity_limit = Some(velocity_limit);
+        self
+    }
+
+    /// Checks every record against the rules of validator before it is
+    /// converted into a transaction, see `RecordValidator`.
+    pub fn with_validator(mut self, validator: RecordValidator) -> Self {
+        self.validator = validator;
+        self
+    }
+
+    /// Parses the amounts of the records and writes the balances with money,
+    /// e.g. `MoneyContext::new(8)` for amounts with 8 decimals.
+    pub fn with_money_context(mut self, money: MoneyContext) -> Self {
+        self.money = money;
+        self
+    }
+
+    pub fn money_context(&self) -> &MoneyContext {
+        &self.money
+    }
+
+    /// Lets the withdrawals of every client take its available balance down
+    /// to minus overdraft_limit instead of rejecting the withdrawals which
+    /// exceed it.
+    pub fn with_overdraft_limit(mut self, overdraft_limit: Amount) -> Self {
+        self.overdraft_limit = Some(overdraft_limit);
+        self
+    }
+
+    /// Rejects the deposits below min_deposit with
+    /// `ProcessingError::BelowMinimum`.
+    pub fn with_min_deposit(mut self, min_deposit: Amount) -> Self {
+        self.min_deposit = Some(min_deposit);
+        self
+    }
+
+    /// Rejects the withdrawals below min_withdrawal with
+    /// `ProcessingError::BelowMinimum`.
+    pub fn with_min_withdrawal(mut self, min_withdrawal: Amount) -> Self {
+        self.min_withdrawal = Some(min_withdrawal);
+        self
+    }
+
+    /// Sets what the caches do when they can not spill to disk, e.g. because
+    /// the disk is full. By default they warn and keep everything in memory,
+    /// with `SpillFailurePolicy::Abort` the records following the failure
+    /// are rejected with `ProcessingError::SpillFailed`.
+    pub fn with_spill_failure_policy(mut self, spill_policy: SpillFailurePolicy) -> Self {
+        self.spill_policy = spill_policy;
+        self
+    }
+
+    /// Sets the number of transactions each client cache holds in memory
+    /// before spilling, instead of CACHE_SIZE_LIMIT, so it can be tuned
+    /// without rebuilding.
+    pub fn with_cache_limit(mut self, cache_limit: u64) -> Self {
+        self.cache_limit = Some(cache_limit);
+        self
+    }
+
+    /// Returns why a cache could not spill to disk, only set with
+    /// `SpillFailurePolicy::Abort`.
+    pub fn spill_error(&self) -> Option<&str> {
+        self.spill_error.as_deref()
+    }
+
+    /// Registers an observer which is notified about every applied or
+    /// rejected transaction.
+    pub fn with_observer(mut self, observer: Box<dyn ProcessorObserver>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Tells the observers the run is over, returns the first error one of
+    /// them met, e.g. a report which could not be written.
+    pub fn finish_observers(&mut self) -> Result<(), String> {
+        self.observers
+            .iter_mut()
+            .try_for_each(|observer| observer.finish())
+    }
+
+    /// Registers a policy which is checked before any transaction is applied,
+    /// policies are checked in the order they were registered and the first
+    /// violation rejects the transaction.
+    pub fn with_policy(mut self, policy: Box<dyn TransactionPolicy>) -> Self {
+        self.policies.push(policy);
+        self
+    }
+
+    /// Calls callback after every `every` records given to
+    /// `process_transaction`, applied or rejected, with the processor and the
+    /// number of records processed so far. The callback runs between two
+    /// records, so the balances it sees are always consistent, e.g. to write
+    /// snapshots of them during a long run.
+    pub fn with_snapshot_every(
+        mut self,
+        every: u64,
+        callback: Box<SnapshotCallback<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>>,
+    ) -> Self {
+        self.snapshot = Some((every.max(1), callback));
+        self
+    }
+
+    /// Calls callback every time a transaction makes the total of the client
+    /// go above threshold, having been at or below it. The callback is called
+    /// again when the total goes back down and then exceeds the threshold
+    /// again.
+    pub fn add_watch(
+        &mut self,
+        client_id: ClientId,
+        threshold: Amount,
+        callback: Box<WatchCallback>,
+    ) {
+        let above = self
+            .clients
+            .get(&client_id)
+            .is_some_and(|client| client.total() > threshold);
+        self.watches.entry(client_id).or_default().push(Watch {
+            threshold,
+            above,
+            callback,
+        });
+    }
+
+    /// Returns the settings of the processor so more processors can be
+    /// created with them on other threads. Observers, policies, handlers,
+    /// watches and snapshot callbacks can not be shared, and the outcomes stored by
+    /// idempotency key would not be seen by the other processors, so none is
+    /// returned when the processor has any of them.
+    pub fn fork_settings(&self) -> Option<ProcessorSettings> {
+        if !self.observers.is_empty()
+            || !self.policies.is_empty()
+            || !self.handlers.is_empty()
+            || self.idempotency_keys.is_some()
+            || !self.watches.is_empty()
+            || self.snapshot.is_some()
+        {
+            return None;
+        }
+        Some(ProcessorSettings {
+            cache_dir: self.cache_dir.clone(),
+            held_policy: self.held_policy,
+            max_transactions_per_client: self.max_transactions_per_client,
+            dispute_window: self.dispute_window,
+            keep_memos: self.keep_memos,
+            record_disputes: self.record_disputes,
+            velocity_limit: self.velocity_limit,
+            overdraft_limit: self.overdraft_limit,
+            min_deposit: self.min_deposit,
+            min_withdrawal: self.min_withdrawal,
+            spill_policy: self.spill_policy,
+            cache_limit: self.cache_limit,
+            validator: self.validator.clone(),
+            money: self.money,
+        })
+    }
+
+    /// Creates a processor from the settings of another one, its client
+    /// caches are created in the cache dir of that processor.
+    pub fn from_settings(settings: &ProcessorSettings) -> Self {
+        TransactionProcessor {
+            cache_dir: settings.cache_dir.clone(),
+            held_policy: settings.held_policy,
+            max_transactions_per_client: settings.max_transactions_per_client,
+            dispute_window: settings.dispute_window,
+            keep_memos: settings.keep_memos,
+            record_disputes: settings.record_disputes,
+            velocity_limit: settings.velocity_limit,
+            overdraft_limit: settings.overdraft_limit,
+            min_deposit: settings.min_deposit,
+            min_withdrawal: settings.min_withdrawal,
+            spill_policy: settings.spill_policy,
+            cache_limit: settings.cache_limit,
+            validator: settings.validator.clone(),
+            money: settings.money,
+            ..Self::new()
+        }
+    }
+
+    /// Returns the ids of the clients seen by the processor.
+    pub fn client_ids(&self) -> impl Iterator<Item = ClientId> + '_ {
+        self.clients.keys().copied()
+    }
+
+    /// Returns the balances of a client, if the processor has seen it.
+    pub fn client_view(&self, client_id: ClientId) -> Option<ClientView> {
+        self.clients.get(&client_id).map(|client| client.view())
+    }
+
+    /// Consumes the processor and returns its clients, e.g. to merge them into
+    /// another processor with `merge_clients`.
+    pub fn into_clients(mut self) -> Vec<Client<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>> {
+        self.clients.drain().map(|(_, client)| client).collect()
+    }
+
+    /// Adds the clients of another processor, which must not have any client
+    /// in common with this one. Nothing is added when one of them is already
+    /// known.
+    pub fn merge_clients(
+        &mut self,
+        clients: Vec<Client<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>>,
+    ) -> Result<(), String> {
+        if let Some(client) = clients
+            .iter()
+            .find(|client| self.clients.contains_key(&client.client_id()))
+        {
+            return Err(format!(
+                "Could not merge client {}, it is already known",
+                client.client_id()
+            ));
         }
+        self.clients.extend(
+            clients
+                .into_iter()
+                .map(|client| (client.client_id(), client)),
+        );
+        Ok(())
+    }
+
+    /// Registers the handler applying records whose type is not one of the
+    /// known transaction types. Records of types without a handler are rejected.
+    pub fn register_handler(&mut self, transaction_type: &str, handler: Box<TransactionHandler>) {
+        self.handlers.insert(transaction_type.to_owned(), handler);
     }
 
     /// Processes a transaction and reports in case any erros is encountered.
-    pub fn process_transaction(&mut self, record: TransactionRecord) -> Result<(), String> {
-        let transaction = Transaction::from_record(record)?;
-        match transaction {
-            Transaction::Deposit(client_id, _, _) => self
-                .clients
-                .entry(client_id)
-                .or_insert(Client::new(client_id)?)
-                .deposit(transaction),
-            Transaction::Withdrawal(client_id, _, _) => self
-                .clients
-                .entry(client_id)
-                .or_insert(Client::new(client_id)?)
-                .withdraw(transaction),
-
-            Transaction::Dispute(client_id, transaction_id) => self
-                .clients
-                .entry(client_id)
-                .or_insert(Client::new(client_id)?)
-                .dispute(&transaction_id),
-
-            Transaction::Resolve(client_id, transaction_id) => self
-                .clients
-                .entry(client_id)
-                .or_insert(Client::new(client_id)?)
-                .resolve(&transaction_id),
-            Transaction::ChargeBack(client_id, transaction_id) => self
-                .clients
-                .entry(client_id)
-                .or_insert(Client::new(client_id)?)
-                .chargeback(&transaction_id),
-            Transaction::Unknown => Err("Transaction::Unknown".to_owned()),
-        }
-    }
-
-    /// Serializes the balance acounts for all the clients.
-    pub fn serialize(self) -> Result<(), String> {
-        let mut wtr = csv::Writer::from_writer(io::stdout());
-        wtr.write_record(&["client", "available", "held", "total", "locked"])
-            .map_err(|err| format!("Could not serialize header because of: {}", err))?;
-
-        for client in self.clients {
-            client.1.serialize(&mut wtr)?;
+    /// A record whose idempotency key was already seen is not applied again,
+    /// it is rejected with the outcome remembered for the key.
+    pub fn process_transaction(
+        &mut self,
+        record: TransactionRecord,
+    ) -> Result<Applied, ProcessingError> {
+        self.process_transaction_ref(&record)
+    }
+
+    /// Same as `process_transaction` for a borrowed record, e.g. one still
+    /// needed to report why it was rejected.
+    pub fn process_transaction_ref(
+        &mut self,
+        record: &TransactionRecord,
+    ) -> Result<Applied, ProcessingError> {
+        if let Some(err) = &self.spill_error {
+            return Err(ProcessingError::SpillFailed(err.clone()));
+        }
+        let key = record
+            .idempotency_key
+            .as_ref()
+            .filter(|key| !key.is_empty());
+        let result = match key {
+            Some(key) => match self.idempotency_keys().map(|keys| keys.get(key).cloned()) {
+                Ok(Some(outcome)) => Err(ProcessingError::IdempotentReplay(outcome)),
+                Ok(None) => {
+                    let result = self.process_record(record);
+                    if let Ok(keys) = self.idempotency_keys() {
+                        keys.insert(key.clone(), RecordedOutcome::from(&result));
+                        if let Some(err) = keys.spill_error() {
+                            self.spill_error = Some(err.to_owned());
+                        }
+                    }
+                    result
+                }
+                Err(err) => Err(err),
+            },
+            None => self.process_record(record),
+        };
+        for observer in self.observers.iter_mut() {
+            if let Err(err) = &result {
+                observer.on_rejected(record, err);
+            }
+            observer.on_processed(record, &result);
+        }
+        if let Ok(applied) = &result {
+            for watch in self
+                .watches
+                .get_mut(&applied.client_id)
+                .into_iter()
+                .flatten()
+            {
+                let above = applied.total > watch.threshold;
+                if above && !watch.above {
+                    (watch.callback)(applied);
+                }
+                watch.above = above;
+            }
+        }
+        self.processed_records += 1;
+        if let Some((every, mut callback)) = self.snapshot.take() {
+            if self.processed_records.is_multiple_of(every) {
+                callback(self, self.processed_records);
+            }
+            self.snapshot = Some((every, callback));
+        }
+        result
+    }
+
+    /// Returns the outcomes by idempotency key, creating the store next to
+    /// the client caches on first use.
+    fn idempotency_keys(
+        &mut self,
+    ) -> Result<&mut IdempotencyKeys<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>, ProcessingError> {
+        if self.idempotency_keys.is_none() {
+            let created = match &self.cache_dir {
+                _ if self.memory_only => Ok(TransactionCache::in_memory_only()),
+                Some(cache_dir) => TransactionCache::new_in(cache_dir),
+                None => TransactionCache::new(),
+            };
+            let mut keys = match created {
+                Ok(keys) => keys,
+                Err(err) if self.spill_policy == SpillFailurePolicy::KeepInMemory => {
+                    self.fall_back_to_memory(&err);
+                    TransactionCache::in_memory_only()
+                }
+                Err(err) => return Err(ProcessingError::Cache(err)),
+            };
+            keys.set_spill_policy(self.spill_policy);
+            if let Some(limit) = self.cache_limit {
+                keys.set_cache_limit(limit);
+            }
+            self.idempotency_keys = Some(keys);
+        }
+        Ok(self.idempotency_keys.as_mut().unwrap())
+    }
+
+    /// Creates a client with its caches on disk, or in memory once the caches
+    /// could not be created on disk. Unless the spill policy is
+    /// `SpillFailurePolicy::Abort`, the first failure only warns and switches
+    /// every following cache to memory rather than rejecting every record.
+    fn create_client(
+        &mut self,
+        client_id: ClientId,
+    ) -> Result<Client<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>, ProcessingError> {
+        if !self.memory_only {
+            let created = match &self.cache_dir {
+                Some(cache_dir) => Client::new_in(client_id, cache_dir),
+                None => Client::new(client_id),
+            };
+            match created {
+                Err(ProcessingError::Cache(err))
+                    if self.spill_policy == SpillFailurePolicy::KeepInMemory =>
+                {
+                    self.fall_back_to_memory(&err)
+                }
+                created => return created,
+            }
+        }
+        Client::in_memory(client_id)
+    }
+
+    fn fall_back_to_memory(&mut self, err: &str) {
+        self.memory_warning = Some(format!(
+            "Warning: {}, the transactions are kept in memory only from now on",
+            err
+        ));
+        self.memory_only = true;
+    }
+
+    /// Returns the warning of the switch of the caches to memory once, so the
+    /// caller logs it according to its log level.
+    pub fn take_memory_warning(&mut self) -> Option<String> {
+        self.memory_warning.take()
+    }
+
+    /// Returns whether the caches are kept in memory only since they could
+    /// not be created on disk.
+    pub fn memory_only(&self) -> bool {
+        self.memory_only
+    }
+
+    fn process_record(&mut self, record: &TransactionRecord) -> Result<Applied, ProcessingError> {
+        self.validator.validate(record)?;
+        match Transaction::from_record(record, &self.money) {
+            Ok(transaction) => self.apply(transaction, record),
+            Err(ProcessingError::UnknownTransactionType(_))
+                if self.handlers.contains_key(&record.transaction_type) =>
+            {
+                let transaction = Transaction::Extension {
+                    client: ClientId(record.client),
+                    tx: TransactionId(record.tx),
+                };
+                self.apply(transaction, record)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Processes a batch of records in order, the outcome of each record is
+    /// reported at the same position, a failing record does not stop the batch.
+    pub fn process_batch(
+        &mut self,
+        records: Vec<TransactionRecord>,
+    ) -> Vec<Result<Applied, ProcessingError>> {
+        self.process_iter(records)
+    }
+
+    /// Same as `process_batch` for any iterator of records.
+    pub fn process_iter<I>(&mut self, records: I) -> Vec<Result<Applied, ProcessingError>>
+    where
+        I: IntoIterator<Item = TransactionRecord>,
+    {
+        records
+            .into_iter()
+            .map(|record| self.process_transaction(record))
+            .collect()
+    }
+
+    fn apply(
+        &mut self,
+        transaction: Transaction,
+        record: &TransactionRecord,
+    ) -> Result<Applied, ProcessingError> {
+        let client_id = transaction.client_id();
+        if !self.clients.contains_key(&client_id) {
+            let client = self
+                .create_client(client_id)?
+                .with_held_policy(self.held_policy)
+                .with_money_context(self.money)
+                .with_max_transactions(self.max_transactions_per_client)
+                .with_dispute_window(self.dispute_window)
+                .with_velocity_limit(self.velocity_limit)
+                .with_overdraft_limit(self.overdraft_limit)
+                .with_min_deposit(self.min_deposit)
+                .with_min_withdrawal(self.min_withdrawal)
+                .with_dispute_events(self.record_disputes)
+                .with_spill_policy(self.spill_policy)
+                .with_cache_limit(self.cache_limit);
+            self.clients.insert(client_id, client);
+        }
+        let client = self.clients.get_mut(&client_id).unwrap();
+
+        let view = client.view();
+        for policy in self.policies.iter() {
+            policy.check(&transaction, &view)?;
+        }
+
+        let keep_memos = self.keep_memos;
+        let non_empty = |value: &Option<String>| value.clone().filter(|value| !value.is_empty());
+        let notes = TransactionNotes {
+            timestamp: non_empty(&record.timestamp),
+            memo: non_empty(&record.memo).filter(|_| keep_memos),
+        };
+        if !notes.is_empty() {
+            client.prepare_notes()?;
+        }
+
+        let result = match transaction {
+            Transaction::Deposit { tx, amount, .. } => client.deposit(tx, amount),
+            Transaction::Withdrawal { tx, amount, .. } => client.withdraw(tx, amount),
+            Transaction::Dispute { tx, amount, .. } => client.dispute_portion(&tx, amount),
+            Transaction::Resolve { tx, .. } => client.resolve(&tx),
+            Transaction::ChargeBack { tx, .. } => client.chargeback(&tx),
+            Transaction::Extension { tx, .. } => {
+                let handler = &self.handlers[&record.transaction_type];
+                client.apply_handler(tx, |handle| handler(record, handle))
+            }
+        };
+        // The transaction is applied even though its cache could not spill,
+        // the following ones are rejected.
+        if let Some(err) = client.spill_error() {
+            self.spill_error = Some(err.to_owned());
+        }
+        result?;
+        if let Transaction::Deposit { tx, .. }
+        | Transaction::Withdrawal { tx, .. }
+        | Transaction::Extension { tx, .. } = transaction
+        {
+            if !notes.is_empty() {
+                client.record_notes(tx, notes)?;
+            }
+        }
+        let applied = Applied {
+            kind: transaction.kind(),
+            client_id,
+            available: client.available(),
+            held: client.held(),
+            total: client.total(),
+            locked: client.locked(),
+        };
+
+        for observer in self.observers.iter_mut() {
+            observer::notify_applied(observer.as_mut(), &transaction);
+        }
+        Ok(applied)
+    }
+
+    /// Returns the state of every client, by increasing client id, to be
+    /// written with `StateSnapshot::write`. partial tells whether the run was
+    /// interrupted before the end of its input.
+    pub fn state_snapshot(&mut self, partial: bool) -> StateSnapshot {
+        let mut clients: Vec<ClientState> = self
+            .clients
+            .values_mut()
+            .map(|client| client.state())
+            .collect();
+        clients.sort_unstable_by_key(|client| client.client_id.0);
+        let mut idempotency_keys: Vec<(String, RecordedOutcome)> = match &mut self.idempotency_keys
+        {
+            Some(keys) => keys.entries().collect(),
+            None => Vec::new(),
+        };
+        idempotency_keys.sort_unstable_by(|(key, _), (other, _)| key.cmp(other));
+        let mut snapshot = StateSnapshot {
+            version: StateFormatVersion::CURRENT,
+            partial,
+            clients,
+            idempotency_keys,
+            idempotency_keys_checksum: 0,
+        };
+        snapshot.idempotency_keys_checksum = snapshot.keys_checksum();
+        snapshot
+    }
+
+    /// Returns the deposits still under dispute, by increasing client and
+    /// transaction id. Their memo is only known when the memos are kept.
+    pub fn open_disputes(&mut self) -> Vec<OpenDispute> {
+        let mut client_ids: Vec<ClientId> = self.clients.keys().copied().collect();
+        client_ids.sort_by_key(|client_id| client_id.0);
+        let mut disputes = Vec::new();
+        for client_id in client_ids {
+            let client = self.clients.get_mut(&client_id).unwrap();
+            for (tx, amount) in client.held_breakdown() {
+                let memo = client.notes(&tx).and_then(|notes| notes.memo);
+                disputes.push(OpenDispute {
+                    client_id,
+                    tx,
+                    amount,
+                    memo,
+                });
+            }
+        }
+        disputes
+    }
+
+    /// Returns the disputes, resolves and chargebacks recorded with
+    /// `with_dispute_events`, by increasing client id then in the order they
+    /// were applied. The final state of each event is the state its deposit
+    /// was left in by the last event of that deposit.
+    pub fn dispute_events(&self) -> Vec<DisputeEvent> {
+        let mut client_ids: Vec<ClientId> = self.clients.keys().copied().collect();
+        client_ids.sort_by_key(|client_id| client_id.0);
+        let mut events = Vec::new();
+        for client_id in client_ids {
+            let client_events = self.clients[&client_id].dispute_events();
+            let mut final_states = HashMap::new();
+            for event in client_events {
+                final_states.insert(event.tx, event.event);
+            }
+            events.extend(client_events.iter().map(|event| DisputeEvent {
+                final_state: final_states[&event.tx],
+                ..event.clone()
+            }));
+        }
+        events
+    }
+
+    /// Returns the amounts held for each client with a held balance, by
+    /// increasing client id: one `HeldDetail` for each dispute and, when the
+    /// disputes do not sum to the held balance, one without transaction for
+    /// the rest.
+    pub fn held_detail(&mut self) -> Vec<HeldDetail> {
+        let mut client_ids: Vec<ClientId> = self.clients.keys().copied().collect();
+        client_ids.sort_by_key(|client_id| client_id.0);
+        let mut detail = Vec::new();
+        for client_id in client_ids {
+            let client = self.clients.get_mut(&client_id).unwrap();
+            let mut unexplained = client.held();
+            for (tx, held) in client.held_breakdown() {
+                unexplained -= held;
+                detail.push(HeldDetail {
+                    client_id,
+                    tx: Some(tx),
+                    held,
+                });
+            }
+            if !unexplained.is_zero() {
+                detail.push(HeldDetail {
+                    client_id,
+                    tx: None,
+                    held: unexplained,
+                });
+            }
+        }
+        detail
+    }
+
+    /// Calls apply with every client by increasing client id, e.g. to charge
+    /// fees with `Client::charge_fee`. It is never called while processing
+    /// the transactions, the host decides when fees are due.
+    pub fn apply_fee<F>(&mut self, mut apply: F)
+    where
+        F: FnMut(&mut Client<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>),
+    {
+        let mut clients: Vec<_> = self.clients.values_mut().collect();
+        clients.sort_unstable_by_key(|client| client.client_id().0);
+        for client in clients {
+            apply(client);
+        }
+    }
+
+    /// Returns the number of clients, of locked clients and the sums of their
+    /// balances. The clients are summed by increasing client id so the scale
+    /// of the sums does not depend on the order of the clients in memory.
+    pub fn balance_summary(&self) -> BalanceSummary {
+        let mut clients: Vec<_> = self.clients.values().collect();
+        clients.sort_unstable_by_key(|client| client.client_id().0);
+        let mut summary = BalanceSummary::default();
+        for client in clients {
+            summary.clients += 1;
+            summary.locked += client.locked() as u64;
+            summary.available += client.available();
+            summary.held += client.held();
+            summary.total += client.total();
+        }
+        summary
+    }
+
+    /// Returns the n clients with the highest total by decreasing total, the
+    /// clients with the same total by increasing client id.
+    pub fn top_clients(&self, n: usize) -> Vec<(ClientId, Amount)> {
+        let mut clients: Vec<(ClientId, Amount)> = self
+            .clients
+            .values()
+            .map(|client| (client.client_id(), client.total()))
+            .collect();
+        let order = |a: &(ClientId, Amount), b: &(ClientId, Amount)| {
+            b.1.cmp(&a.1).then(a.0 .0.cmp(&b.0 .0))
+        };
+        if n < clients.len() {
+            if n > 0 {
+                clients.select_nth_unstable_by(n - 1, order);
+            }
+            clients.truncate(n);
+        }
+        clients.sort_unstable_by(order);
+        clients
+    }
+
+    /// Recomputes the total of every client from its stored transactions and
+    /// returns the clients whose running total differs, by increasing client
+    /// id.
+    pub fn verify_balances(&mut self) -> Vec<BalanceDiscrepancy> {
+        let mut discrepancies: Vec<BalanceDiscrepancy> = self
+            .clients
+            .values_mut()
+            .filter_map(|client| {
+                let recomputed = client.recomputed_total();
+                (recomputed != client.total()).then(|| BalanceDiscrepancy {
+                    client_id: client.client_id(),
+                    total: client.total(),
+                    recomputed,
+                })
+            })
+            .collect();
+        discrepancies.sort_by_key(|discrepancy| discrepancy.client_id.0);
+        discrepancies
+    }
+
+    /// Returns the clients whose chargebacks exceed the thresholds, by
+    /// increasing client id.
+    pub fn flag_suspicious(&self, thresholds: &SuspicionThresholds) -> Vec<SuspicionReport> {
+        let mut reports: Vec<SuspicionReport> = self
+            .clients
+            .values()
+            .filter(|client| thresholds.exceeded(client.chargeback_count(), client.charged_back()))
+            .map(|client| SuspicionReport {
+                client_id: client.client_id(),
+                chargebacks: client.chargeback_count(),
+                charged_back: client.charged_back(),
+                transactions: client.chargebacks().to_vec(),
+            })
+            .collect();
+        reports.sort_by_key(|report| report.client_id.0);
+        reports
+    }
+
+    /// Spills every client cache, and the outcomes stored by idempotency
+    /// key, to disk, so the memory they hold only grows with the records
+    /// processed since. A cache which can not spill stops the processor as
+    /// while processing.
+    pub fn flush_caches(&mut self) {
+        for client in self.clients.values_mut() {
+            client.flush_caches();
+            if let Some(err) = client.spill_error() {
+                self.spill_error.get_or_insert_with(|| err.to_owned());
+            }
+        }
+        if let Some(keys) = self.idempotency_keys.as_mut() {
+            keys.flush();
+            if let Some(err) = keys.spill_error() {
+                self.spill_error.get_or_insert_with(|| err.to_owned());
+            }
+        }
+        self.cache_flushes += 1;
+    }
+
+    /// Returns the number of clients and of the entries held in memory by the
+    /// transaction caches.
+    pub fn cache_stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            clients: self.clients.len() as u64,
+            flushes: self.cache_flushes,
+            idempotency_keys: self
+                .idempotency_keys
+                .as_ref()
+                .map_or(0, |keys| keys.in_memory()),
+            ..CacheStats::default()
+        };
+        for client in self.clients.values() {
+            client.add_cache_stats(&mut stats);
+        }
+        stats
+    }
+
+    /// Serializes the balance accounts for all the clients, into the shards
+    /// or pages when the options have some.
+    pub fn serialize(&self, options: &OutputOptions) -> Result<(), String> {
+        match (&options.shards, &options.pages) {
+            (Some(shards), _) => self.serialize_shards(shards, options),
+            (None, Some(pages)) => self.serialize_pages(pages, options),
+            (None, None) => self.serialize_to(io::stdout(), options),
+        }
+    }
+
+    /// Serializes the balance accounts for all the clients into the given writer.
+    pub fn serialize_to<W: Write>(&self, writer: W, options: &OutputOptions) -> Result<(), String> {
+        let mut writers = vec![balances_writer(writer, options)?];
+        self.write_balances(&mut writers, |_| 0, options)?;
+        if options.partial {
+            let terminator = if options.excel { "\r\n" } else { "\n" };
+            let mut writer = writers
+                .pop()
+                .expect("One writer")
+                .into_inner()
+                .map_err(|err| format!("Could not flush output because of: {}", err))?;
+            write!(writer, "{}{}", options.partial_marker(), terminator)
+                .and_then(|_| writer.flush())
+                .map_err(|err| format!("Could not write partial marker because of: {}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Serializes the balance accounts into the `part-NNNNN.csv` files of the
+    /// shards dir, each client going to the shard of its id modulo the number
+    /// of shards. Every shard gets a header, even without clients, and
+    /// `_manifest.json` lists the number of clients written into each shard.
+    pub fn serialize_shards(
+        &self,
+        shards: &OutputShards,
+        options: &OutputOptions,
+    ) -> Result<(), String> {
+        fs::create_dir_all(&shards.dir).map_err(|err| {
+            format!(
+                "Could not create shards dir {} because of: {}",
+                shards.dir.display(),
+                err
+            )
+        })?;
+        let count = shards.count.get();
+        let mut writers = Vec::with_capacity(count as usize);
+        for shard in 0..count {
+            let path = shards.dir.join(OutputShards::file_name(shard));
+            let file = File::create(&path)
+                .map_err(|err| format!("Could not create {}: {}", path.display(), err))?;
+            writers.push(balances_writer(io::BufWriter::new(file), options)?);
+        }
+        let counts = self.write_balances(
+            &mut writers,
+            |client_id| (client_id.0 % count as u32) as usize,
+            options,
+        )?;
+
+        let manifest = ShardManifest {
+            shards: counts
+                .into_iter()
+                .enumerate()
+                .map(|(shard, clients)| ShardEntry {
+                    file: OutputShards::file_name(shard as u16),
+                    clients,
+                })
+                .collect(),
+        };
+        if options.partial {
+            let path = shards.dir.join("_PARTIAL");
+            fs::write(&path, format!("{}\n", options.partial_marker()))
+                .map_err(|err| format!("Could not create {}: {}", path.display(), err))?;
+        }
+        let path = shards.dir.join("_manifest.json");
+        let file = File::create(&path)
+            .map_err(|err| format!("Could not create {}: {}", path.display(), err))?;
+        serde_json::to_writer_pretty(file, &manifest)
+            .map_err(|err| format!("Could not write {} because of: {}", path.display(), err))
+    }
+
+    /// Serializes the balance accounts into the pages, in the order of the
+    /// options, each page holding at most the page size of clients after its
+    /// header. The first page is always written, even without clients, and
+    /// the partial marker goes after the last page.
+    pub fn serialize_pages(
+        &self,
+        pages: &OutputPages,
+        options: &OutputOptions,
+    ) -> Result<(), String> {
+        let clients = self.sorted_clients(options);
+        let size = pages.size.max(1) as usize;
+        let page_count = clients.len().div_ceil(size).max(1);
+        for page in 0..page_count {
+            let path = pages.path(page);
+            let file = File::create(&path)
+                .map_err(|err| format!("Could not create {}: {}", path.display(), err))?;
+            let mut writer = balances_writer(io::BufWriter::new(file), options)?;
+            for client in clients.iter().skip(page * size).take(size) {
+                client.serialize(&mut writer, options, &self.money)?;
+            }
+            let mut writer = writer
+                .into_inner()
+                .map_err(|err| format!("Could not flush output because of: {}", err))?;
+            if options.partial && page + 1 == page_count {
+                let terminator = if options.excel { "\r\n" } else { "\n" };
+                write!(writer, "{}{}", options.partial_marker(), terminator)
+                    .map_err(|err| format!("Could not write partial marker because of: {}", err))?;
+            }
+            writer
+                .flush()
+                .map_err(|err| format!("Could not flush output because of: {}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the clients to write, in the order of the options.
+    fn sorted_clients(
+        &self,
+        options: &OutputOptions,
+    ) -> Vec<&Client<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>> {
+        let mut clients: Vec<_> = self
+            .clients
+            .values()
+            .filter(|client| !(options.suppress_zero && client.is_empty()))
+            .collect();
+        clients.sort_by(|left, right| {
+            let primary = match options.sort_by {
+                SortKey::ClientId => Ordering::Equal,
+                SortKey::Total => right.total().cmp(&left.total()),
+            };
+            primary.then_with(|| left.client_id().0.cmp(&right.client_id().0))
+        });
+        clients
+    }
+
+    /// Writes the row of each client into the writer picked by route and
+    /// returns how many clients were written into each writer.
+    fn write_balances<W: Write, F: Fn(ClientId) -> usize>(
+        &self,
+        writers: &mut [csv::Writer<W>],
+        route: F,
+        options: &OutputOptions,
+    ) -> Result<Vec<u64>, String> {
+        let mut counts = vec![0; writers.len()];
+        for client in self.sorted_clients(options) {
+            let writer = route(client.client_id());
+            client.serialize(&mut writers[writer], options, &self.money)?;
+            counts[writer] += 1;
+        }
+        for wtr in writers {
+            wtr.flush()
+                .map_err(|err| format!("Could not flush output because of: {}", err))?;
+        }
+        Ok(counts)
+    }
+}
+
+/// Creates a csv writer for the balances and writes the header.
+fn balances_writer<W: Write>(
+    mut writer: W,
+    options: &OutputOptions,
+) -> Result<csv::Writer<W>, String> {
+    let mut builder = csv::WriterBuilder::new();
+    if options.excel {
+        writer
+            .write_all(UTF8_BOM)
+            .map_err(|err| format!("Could not serialize BOM because of: {}", err))?;
+        builder.terminator(csv::Terminator::CRLF);
+    }
+    let mut wtr = builder.from_writer(writer);
+
+    let mut header: Vec<_> = options.columns.headers().collect();
+    if options.lock_reason {
+        header.push("lock_reason");
+    }
+    if options.dispute_flag {
+        header.push("ever_disputed");
+    }
+    if options.extended {
+        header.extend([
+            "deposits_count",
+            "withdrawals_count",
+            "open_disputes_count",
+            "status",
+        ]);
+    }
+    wtr.write_record(&header)
+        .map_err(|err| format!("Could not serialize header because of: {}", err))?;
+    Ok(wtr)
+}
+
+/// Number of clients written into each shard, as written to `_manifest.json`.
+#[derive(Debug, Serialize, Deserialize)]
+struct ShardManifest {
+    shards: Vec<ShardEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ShardEntry {
+    file: String,
+    clients: u64,
+}
+
+impl<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32> Default
+    for TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{MaxAmount, PolicyViolation};
+    use crate::reports::DisputeState;
+    use crate::test_support::{Record, Scenario};
+    use crate::type_defs::{Amount, ClientId};
+    use rust_decimal::Decimal;
+    use std::cell::RefCell;
+    use std::fs;
+    use std::rc::Rc;
+    use std::str::FromStr;
+
+    // Test deposit transactions in a loop
+    #[test]
+    fn test_deposit_loop() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let num_iterations = 1024;
+        let expected_amount = num_iterations.to_string();
+        for i in 0..num_iterations {
+            let transaction = TransactionRecord {
+                transaction_type: "deposit".to_owned(),
+                client: 1,
+                tx: i,
+                amount: Some("1".to_owned()),
+                ..Default::default()
+            };
+            assert!(processor.process_transaction(transaction).is_ok());
+        }
+        assert_eq!(processor.clients.len(), 1);
+        for client in processor.clients.into_values() {
+            assert_eq!(client.client_id(), ClientId(1));
+            assert_eq!(client.total(), Amount::from_str(&expected_amount).unwrap());
+            assert_eq!(
+                client.available(),
+                Amount::from_str(&expected_amount).unwrap()
+            );
+            assert!(!client.locked());
+            assert_eq!(client.held(), Amount::from_str("0.0").unwrap());
+        }
+    }
+
+    // Test deposit follow by the same amount of withdraws.
+    #[test]
+
+    fn test_deposit_withdraw_loop() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let num_iterations = 8 * 1024;
+        for i in 0..num_iterations {
+            let transaction = TransactionRecord {
+                transaction_type: "deposit".to_owned(),
+                client: 1,
+                tx: i * 2,
+                amount: Some("1".to_owned()),
+                ..Default::default()
+            };
+
+            assert!(processor.process_transaction(transaction).is_ok());
+
+            let transaction = TransactionRecord {
+                transaction_type: "withdrawal".to_owned(),
+                client: 1,
+                tx: i * 2 + 1,
+                amount: Some("1".to_owned()),
+                ..Default::default()
+            };
+            assert!(processor.process_transaction(transaction).is_ok());
+        }
+
+        assert_eq!(processor.clients.len(), 1);
+        for client in processor.clients.into_values() {
+            assert_eq!(client.client_id(), ClientId(1));
+            assert_eq!(client.total(), Amount::from_str("0.0").unwrap());
+            assert_eq!(client.available(), Amount::from_str("0.0").unwrap());
+            assert!(!client.locked());
+            assert_eq!(client.held(), Amount::from_str("0.0").unwrap());
+        }
+    }
+
+    // Test duplicate transaction do nothing.
+    #[test]
+
+    fn test_duplicate_transactions_do_nothing() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let num_iterations = 8 * 1024;
+        for i in 0..num_iterations {
+            let transaction = TransactionRecord {
+                transaction_type: "deposit".to_owned(),
+                client: 1,
+                tx: i * 2,
+                amount: Some("1".to_owned()),
+                ..Default::default()
+            };
+
+            assert!(processor.process_transaction(transaction.clone()).is_ok());
+            assert!(processor.process_transaction(transaction).is_err());
+
+            let transaction = TransactionRecord {
+                transaction_type: "withdrawal".to_owned(),
+                client: 1,
+                tx: i * 2 + 1,
+                amount: Some("1".to_owned()),
+                ..Default::default()
+            };
+            assert!(processor.process_transaction(transaction.clone()).is_ok());
+            assert!(processor.process_transaction(transaction).is_err());
+        }
+
+        assert_eq!(processor.clients.len(), 1);
+        for client in processor.clients.into_values() {
+            assert_eq!(client.client_id(), ClientId(1));
+            assert_eq!(client.total(), Amount::from_str("0.0").unwrap());
+            assert_eq!(client.available(), Amount::from_str("0.0").unwrap());
+            assert!(!client.locked());
+            assert_eq!(client.held(), Amount::from_str("0.0").unwrap());
+        }
+    }
+
+    // Test a sequence of dispute, withdraw, resolve and make sure the
+    // account balance is correct.
+    #[test]
+    fn test_deposit_dispute_withdraw_resolve_withdraw() {
+        let deposit_transaction_id = 8 * 1024;
+        let (outcomes, clients) = Scenario::new()
+            .then(Record::deposit(1, deposit_transaction_id, "1"))
+            .then(Record::dispute(1, deposit_transaction_id))
+            .then(Record::withdrawal(1, deposit_transaction_id + 1, "1"))
+            .then(Record::resolve(1, deposit_transaction_id))
+            .then(Record::resolve(1, deposit_transaction_id))
+            .then(Record::withdrawal(1, deposit_transaction_id + 1, "1"))
+            .run_with_outcomes();
+        assert_eq!(
+            outcomes.iter().map(Result::is_ok).collect::<Vec<_>>(),
+            vec![true, true, false, true, false, true]
+        );
+        assert_eq!(clients.len(), 1);
+        let client = &clients[0];
+        assert_eq!(client.client_id, ClientId(1));
+        assert_eq!(client.total, Amount::from_str("0.0").unwrap());
+        assert_eq!(client.available, Amount::from_str("0.0").unwrap());
+        assert!(!client.locked);
+        assert_eq!(client.held, Amount::from_str("0.0").unwrap());
+    }
+
+    // Test that disputing the same transaction twice or resolving
+    // twice do not have any impact.
+    #[test]
+    fn test_deposit_dispute_twice_resolve_twice() {
+        let deposit_transaction_id = 8 * 1024;
+        let (outcomes, clients) = Scenario::new()
+            .then(Record::deposit(1, deposit_transaction_id, "1"))
+            .then(Record::dispute(1, deposit_transaction_id))
+            .then(Record::dispute(1, deposit_transaction_id))
+            .then(Record::resolve(1, deposit_transaction_id))
+            .then(Record::resolve(1, deposit_transaction_id))
+            .run_with_outcomes();
+        assert_eq!(
+            outcomes.iter().map(Result::is_ok).collect::<Vec<_>>(),
+            vec![true, true, false, true, false]
+        );
+        assert_eq!(clients.len(), 1);
+        let client = &clients[0];
+        assert_eq!(client.client_id, ClientId(1));
+        assert_eq!(client.total, Amount::from_str("1.0").unwrap());
+        assert_eq!(client.available, Amount::from_str("1.0").unwrap());
+        assert!(!client.locked);
+        assert_eq!(client.held, Amount::from_str("0.0").unwrap());
+    }
+
+    // Test that withdraw after chargeback is not processed
+    #[test]
+    fn test_deposit_dispute_withdraw_chargeback_withdraw() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let deposit_transaction_id = 8 * 1024;
+        let transaction = TransactionRecord {
+            transaction_type: "deposit".to_owned(),
+            client: 1,
+            tx: deposit_transaction_id,
+            amount: Some("1".to_owned()),
+            ..Default::default()
+        };
+
+        assert!(processor.process_transaction(transaction).is_ok());
+        let transaction = TransactionRecord {
+            transaction_type: "dispute".to_owned(),
+            client: 1,
+            tx: deposit_transaction_id,
+            amount: None,
+            ..Default::default()
+        };
+        assert!(processor.process_transaction(transaction).is_ok());
+
+        let transaction = TransactionRecord {
+            transaction_type: "withdrawal".to_owned(),
+            client: 1,
+            tx: deposit_transaction_id + 1,
+            amount: Some("1".to_owned()),
+            ..Default::default()
+        };
+        assert!(processor.process_transaction(transaction).is_err());
+
+        let transaction = TransactionRecord {
+            transaction_type: "chargeback".to_owned(),
+            client: 1,
+            tx: deposit_transaction_id,
+            amount: None,
+            ..Default::default()
+        };
+
+        assert!(processor.process_transaction(transaction.clone()).is_ok());
+
+        assert!(processor.process_transaction(transaction).is_err());
+
+        let transaction = TransactionRecord {
+            transaction_type: "withdrawal".to_owned(),
+            client: 1,
+            tx: deposit_transaction_id + 1,
+            amount: Some("1".to_owned()),
+            ..Default::default()
+        };
+        assert!(processor.process_transaction(transaction).is_err());
+
+        for client in processor.clients.into_values() {
+            assert_eq!(client.client_id(), ClientId(1));
+            assert_eq!(client.total(), Amount::from_str("0.0").unwrap());
+            assert_eq!(client.available(), Amount::from_str("0.0").unwrap());
+            assert!(client.locked());
+            assert_eq!(client.held(), Amount::from_str("0.0").unwrap());
+        }
+    }
+
+    // Test that processing borrowed records gives the same outcomes and
+    // balances as processing them by value.
+    #[test]
+    fn test_process_transaction_ref() {
+        let records: Vec<TransactionRecord> = crate::scenario::Generator::new(5)
+            .with_clients(10)
+            .take(5_000)
+            .collect();
+        let mut by_value = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let mut by_ref = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        for record in &records {
+            assert_eq!(
+                by_ref.process_transaction_ref(record),
+                by_value.process_transaction(record.clone())
+            );
+        }
+        for client in 1..=10 {
+            assert_eq!(
+                by_ref.client_view(ClientId(client)),
+                by_value.client_view(ClientId(client))
+            );
+        }
+    }
+
+    // Test that the balances going back to zero after a dispute and its
+    // resolve are written as the balances of a fresh account.
+    #[test]
+    fn test_canonical_zero() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let (_, clients) = Scenario::new()
+            .then(Record::deposit(1, 1, "5.0"))
+            .then(Record::dispute(1, 1))
+            .then(Record::resolve(1, 1))
+            .then(Record::withdrawal(1, 2, "5.0"))
+            .then(Record::deposit(2, 3, "1.0"))
+            .run_on(&mut processor);
+        let fresh = Amount::new().to_string();
+        assert_eq!(clients[0].held.to_string(), fresh);
+        assert_eq!(clients[0].held.to_string(), clients[1].held.to_string());
+        assert_eq!(clients[0].available.to_string(), fresh);
+        assert_eq!(clients[0].total.to_string(), fresh);
+
+        let mut output = Vec::new();
+        processor
+            .serialize_to(&mut output, &OutputOptions::default())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,0.0000,0.0000,0.0000,false\n\
+             2,1.0,0.0000,1.0,false\n"
+        );
+    }
+
+    // Test that the amounts of the records are parsed and written with the
+    // money context of the processor.
+    #[test]
+    fn test_money_context() {
+        use crate::money::Rounding;
+
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_money_context(MoneyContext::new(8));
+        let (outcomes, clients) = Scenario::new()
+            .then(Record::deposit(1, 1, "0.00000001"))
+            .then(Record::deposit(1, 2, "0.000000001"))
+            .run_on(&mut processor);
+        assert!(outcomes[0].is_ok());
+        assert_eq!(outcomes[1], Err(ProcessingError::InvalidPrecision));
+        assert_eq!(clients[0].total.to_string(), "0.00000001");
+        let mut output = Vec::new();
+        let options = OutputOptions {
+            minor_units: Some(8),
+            ..OutputOptions::default()
+        };
+        processor.serialize_to(&mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1,0,1,false\n"
+        );
+
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_money_context(MoneyContext::default().with_rounding(Rounding::HalfEven));
+        let (_, clients) = Scenario::new()
+            .then(Record::deposit(1, 1, "1.00005"))
+            .then(Record::deposit(1, 2, "1.00015"))
+            .run_on(&mut processor);
+        assert_eq!(clients[0].total.to_string(), "2.0002");
+    }
+
+    // Test that a deposit taking the balances past Decimal::MAX is rejected
+    // with the balances left untouched, or saturates with the saturating
+    // money context.
+    #[test]
+    fn test_balance_overflow() {
+        use crate::money::OverflowPolicy;
+
+        let max = Decimal::MAX.to_string();
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let (outcomes, clients) = Scenario::new()
+            .then(Record::deposit(1, 1, &max))
+            .then(Record::deposit(1, 2, "1"))
+            .run_on(&mut processor);
+        assert!(outcomes[0].is_ok());
+        assert_eq!(
+            outcomes[1],
+            Err(ProcessingError::Overflow {
+                amount: max.parse().unwrap(),
+                change: "1".parse().unwrap(),
+            })
+        );
+        assert_eq!(clients[0].total.to_string(), max);
+
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_money_context(MoneyContext::default().with_overflow(OverflowPolicy::Saturate));
+        let (outcomes, clients) = Scenario::new()
+            .then(Record::deposit(1, 1, &max))
+            .then(Record::deposit(1, 2, "1"))
+            .run_on(&mut processor);
+        assert!(outcomes.iter().all(|outcome| outcome.is_ok()));
+        assert_eq!(clients[0].total.to_string(), max);
+    }
+
+    // Test that the top clients are the ones with the highest totals, the ties
+    // going to the lowest client ids.
+    #[test]
+    fn test_top_clients() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        Scenario::new()
+            .then(Record::deposit(1, 1, "5.0"))
+            .then(Record::deposit(2, 2, "9.0"))
+            .then(Record::deposit(3, 3, "7.0"))
+            .then(Record::deposit(4, 4, "9.0"))
+            .then(Record::deposit(5, 5, "12.0"))
+            .then(Record::withdrawal(5, 6, "4.0"))
+            .run_on(&mut processor);
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        assert_eq!(
+            processor.top_clients(2),
+            vec![(ClientId(2), amount("9")), (ClientId(4), amount("9"))]
+        );
+        assert_eq!(
+            processor.top_clients(3),
+            vec![
+                (ClientId(2), amount("9")),
+                (ClientId(4), amount("9")),
+                (ClientId(5), amount("8"))
+            ]
+        );
+        assert_eq!(processor.top_clients(10).len(), 5);
+        assert!(processor.top_clients(0).is_empty());
+    }
+
+    // Test that a client whose deposit was disputed then resolved is still
+    // flagged as ever disputed in the output.
+    #[test]
+    fn test_serialize_dispute_flag() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        processor.process_iter(vec![
+            Record::deposit(1, 1, "2.0").build(),
+            Record::dispute(1, 1).build(),
+            Record::resolve(1, 1).build(),
+            Record::deposit(2, 2, "1.0").build(),
+        ]);
+        let options = OutputOptions {
+            dispute_flag: true,
+            ..OutputOptions::default()
+        };
+        let mut output = Vec::new();
+        processor.serialize_to(&mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,ever_disputed\n\
+             1,2.0,0.0000,2.0,false,true\n\
+             2,1.0,0.0000,1.0,false,false\n"
+        );
+    }
+
+    // Test that the chargeback which locked the account is recorded as the
+    // lock reason.
+    #[test]
+    fn test_chargeback_sets_lock_reason() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        for tx in 1..=3 {
+            let transaction = TransactionRecord {
+                transaction_type: "deposit".to_owned(),
+                client: 1,
+                tx,
+                amount: Some("1".to_owned()),
+                ..Default::default()
+            };
+            assert!(processor.process_transaction(transaction).is_ok());
+        }
+        assert_eq!(processor.clients[&ClientId(1)].lock_reason(), None);
+
+        for transaction_type in &["dispute", "chargeback"] {
+            let transaction = TransactionRecord {
+                transaction_type: (*transaction_type).to_owned(),
+                client: 1,
+                tx: 2,
+                amount: None,
+                ..Default::default()
+            };
+            assert!(processor.process_transaction(transaction).is_ok());
+        }
+
+        let client = &processor.clients[&ClientId(1)];
+        assert!(client.locked());
+        assert_eq!(client.lock_reason(), Some(TransactionId(2)));
+    }
+
+    fn dispute_records(transaction_types: &[&str]) -> Vec<TransactionRecord> {
+        let mut records = vec![TransactionRecord {
+            transaction_type: "deposit".to_owned(),
+            client: 1,
+            tx: 1,
+            amount: Some("1".to_owned()),
+            ..Default::default()
+        }];
+        records.extend(
+            transaction_types
+                .iter()
+                .map(|transaction_type| TransactionRecord {
+                    transaction_type: (*transaction_type).to_owned(),
+                    client: 1,
+                    tx: 1,
+                    amount: None,
+                    ..Default::default()
+                }),
+        );
+        records
+    }
+
+    // Test that the snapshot callback is called every few records, and the
+    // balances it sees while a dispute is open are consistent.
+    #[test]
+    fn test_snapshot_every() {
+        let snapshots = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&snapshots);
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_snapshot_every(
+                2,
+                Box::new(move |processor, processed| {
+                    let mut output = Vec::new();
+                    processor
+                        .serialize_to(&mut output, &OutputOptions::default())
+                        .unwrap();
+                    seen.borrow_mut()
+                        .push((processed, String::from_utf8(output).unwrap()));
+                }),
+            );
+        assert!(processor.fork_settings().is_none());
+        processor.process_iter(dispute_records(&[
+            "dispute",
+            "resolve",
+            "dispute",
+            "chargeback",
+        ]));
+
+        let snapshots = snapshots.borrow();
+        assert_eq!(
+            snapshots
+                .iter()
+                .map(|(processed, _)| *processed)
+                .collect::<Vec<_>>(),
+            vec![2, 4]
+        );
+        for (_, output) in snapshots.iter() {
+            let line = output.lines().nth(1).unwrap();
+            let fields: Vec<&str> = line.split(',').collect();
+            let amount = |index: usize| Amount::from_str(fields[index]).unwrap();
+            // Both snapshots are taken with the deposit under dispute.
+            assert_eq!(amount(2), Amount::from_str("1").unwrap());
+            assert_eq!(amount(3) - amount(2), amount(1));
+        }
+    }
+
+    // Test that a watch fires each time the total of its client goes above
+    // the threshold, and not for the other clients.
+    #[test]
+    fn test_add_watch() {
+        let fired = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::clone(&fired);
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        processor.add_watch(
+            ClientId(5),
+            Amount::from_str("10").unwrap(),
+            Box::new(move |applied| seen.borrow_mut().push((applied.kind, applied.total))),
+        );
+        assert!(processor.fork_settings().is_none());
+        let record = |transaction_type: &str, client: u32, tx: u64, amount: Option<&str>| {
+            TransactionRecord {
+                transaction_type: transaction_type.to_owned(),
+                client,
+                tx,
+                amount: amount.map(str::to_owned),
+                ..Default::default()
+            }
+        };
+        processor.process_iter(vec![
+            record("deposit", 5, 1, Some("6")),
+            record("deposit", 6, 2, Some("20")),
+            record("deposit", 5, 3, Some("4")),
+            record("deposit", 5, 4, Some("0.0001")),
+            record("deposit", 5, 5, Some("3")),
+            record("withdrawal", 5, 6, Some("100")),
+            record("withdrawal", 5, 7, Some("5")),
+            record("deposit", 5, 8, Some("5")),
+            record("dispute", 5, 8, None),
+            record("resolve", 5, 8, None),
+        ]);
+        assert_eq!(
+            *fired.borrow(),
+            vec![
+                (
+                    TransactionKind::Deposit,
+                    Amount::from_str("10.0001").unwrap()
+                ),
+                (
+                    TransactionKind::Deposit,
+                    Amount::from_str("13.0001").unwrap()
+                ),
+            ]
+        );
+    }
+
+    // Test that acting twice on the same dispute reports that the transaction
+    // is not under dispute anymore, even once the account is locked.
+    #[test]
+    fn test_double_dispute_actions() {
+        for actions in [
+            ["chargeback", "chargeback"],
+            ["chargeback", "resolve"],
+            ["resolve", "resolve"],
+            ["resolve", "chargeback"],
+        ] {
+            let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+            let records = dispute_records(&["dispute", actions[0], actions[1]]);
+            let results = processor.process_batch(records);
+            assert!(results[..3].iter().all(Result::is_ok), "{:?}", actions);
+            assert_eq!(results[3], Err(ProcessingError::NotDisputed));
+        }
+        assert_eq!(
+            ProcessingError::NotDisputed.to_string(),
+            "Transaction not under dispute"
+        );
+    }
+
+    // Test that a client referencing the transaction of another client can
+    // neither dispute it nor move the funds of either client.
+    #[test]
+    fn test_cross_client_dispute() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let record = |transaction_type: &str, client, tx, amount: Option<&str>| TransactionRecord {
+            transaction_type: transaction_type.to_owned(),
+            client,
+            tx,
+            amount: amount.map(str::to_owned),
+            ..Default::default()
+        };
+        let results = processor.process_batch(vec![
+            record("deposit", 1, 1, Some("5.0")),
+            record("deposit", 2, 2, Some("3.0")),
+            record("dispute", 2, 1, None),
+            record("resolve", 2, 1, None),
+            record("chargeback", 2, 1, None),
+        ]);
+        assert!(results[..2].iter().all(Result::is_ok));
+        assert_eq!(results[2], Err(ProcessingError::TransactionNotFound));
+        assert_eq!(results[3], Err(ProcessingError::NotDisputed));
+        assert_eq!(results[4], Err(ProcessingError::NotDisputed));
+        assert_eq!(
+            ProcessingError::TransactionNotFound.to_string(),
+            "Transaction not found for this client"
+        );
+
+        for (client_id, available) in [(1, "5.0"), (2, "3.0")] {
+            let client = &processor.clients[&ClientId(client_id)];
+            assert_eq!(client.available(), Amount::from_str(available).unwrap());
+            assert_eq!(client.held(), Amount::new());
+            assert!(!client.locked());
+        }
+
+        // The owner of the transaction can still dispute it.
+        assert!(processor
+            .process_transaction(record("dispute", 1, 1, None))
+            .is_ok());
+        assert_eq!(
+            processor.clients[&ClientId(1)].held(),
+            Amount::from_str("5.0").unwrap()
+        );
+    }
+
+    // Test that deposits and withdrawals written with different scales balance
+    // out exactly.
+    #[test]
+    fn test_mixed_scale_deposit_withdraw() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let records = [
+            ("deposit", 1, "1"),
+            ("withdrawal", 2, "1.0000"),
+            ("deposit", 3, "0.5"),
+            ("deposit", 4, "0.25"),
+            ("withdrawal", 5, "0.7500"),
+            ("deposit", 6, "2.10"),
+            ("withdrawal", 7, "2.1001"),
+        ];
+        for (transaction_type, tx, amount) in records.iter() {
+            let transaction = TransactionRecord {
+                transaction_type: (*transaction_type).to_owned(),
+                client: 1,
+                tx: *tx,
+                amount: Some((*amount).to_owned()),
+                ..Default::default()
+            };
+            let result = processor.process_transaction(transaction);
+            if *tx == 7 {
+                assert_eq!(result, Err(ProcessingError::InsufficientFunds));
+            } else {
+                assert!(result.is_ok());
+            }
         }
-        Ok(())
+
+        let client = &processor.clients[&ClientId(1)];
+        assert_eq!(client.available(), Amount::from_str("2.1").unwrap());
+        assert_eq!(client.total(), Amount::from_str("2.1000").unwrap());
+        assert_eq!(client.held(), Amount::from_str("0").unwrap());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::type_defs::{Amount, ClientId};
+    // Test that the excel output starts with a BOM and uses CRLF terminators,
+    // while the default output does not.
+    #[test]
+    fn test_serialize_excel() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let transaction = TransactionRecord {
+            transaction_type: "deposit".to_owned(),
+            client: 1,
+            tx: 1,
+            amount: Some("1.5".to_owned()),
+            ..Default::default()
+        };
+        assert!(processor.process_transaction(transaction).is_ok());
 
-    // Test deposit transactions in a loop
+        let mut output = Vec::new();
+        processor
+            .serialize_to(&mut output, &OutputOptions::default())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5,0.0000,1.5,false\n"
+        );
+
+        let options = OutputOptions {
+            excel: true,
+            ..OutputOptions::default()
+        };
+        let mut output = Vec::new();
+        processor.serialize_to(&mut output, &options).unwrap();
+        assert_eq!(&output[..3], &[0xEF, 0xBB, 0xBF]);
+        assert_eq!(
+            String::from_utf8(output[3..].to_vec()).unwrap(),
+            "client,available,held,total,locked\r\n1,1.5,0.0000,1.5,false\r\n"
+        );
+    }
+
+    // Test that the sorted clients are split into pages of at most the page
+    // size, each with a header.
     #[test]
-    fn test_deposit_loop() {
+    fn test_serialize_pages() {
         let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
-        let num_iterations = 1024;
-        let expected_amount = num_iterations.to_string();
-        for i in 0..num_iterations {
+        for client in [5, 3, 1, 4, 2] {
             let transaction = TransactionRecord {
                 transaction_type: "deposit".to_owned(),
-                client: 1,
-                tx: i,
+                client,
+                tx: client as u64,
                 amount: Some("1".to_owned()),
+                ..Default::default()
             };
-            assert_eq!(processor.process_transaction(transaction), Ok(()));
+            assert!(processor.process_transaction(transaction).is_ok());
         }
-        assert_eq!(processor.clients.len(), 1);
-        for client in processor.clients.into_values() {
-            assert_eq!(client.client_id(), ClientId(1));
-            assert_eq!(
-                client.total(),
-                Amount::from_str(expected_amount.clone()).unwrap()
-            );
-            assert_eq!(
-                client.available(),
-                Amount::from_str(expected_amount.clone()).unwrap()
-            );
-            assert_eq!(client.locked(), false);
-            assert_eq!(client.held(), Amount::from_str("0.0".to_owned()).unwrap());
+        let dir = TempDir::new("pages").unwrap();
+        let pages = OutputPages {
+            prefix: dir.path().join("output"),
+            size: 2,
+        };
+        let options = OutputOptions {
+            pages: Some(pages.clone()),
+            ..OutputOptions::default()
+        };
+        processor.serialize(&options).unwrap();
+
+        let header = "client,available,held,total,locked";
+        for (page, clients) in [(0, vec![1, 2]), (1, vec![3, 4]), (2, vec![5])] {
+            let path = pages.path(page);
+            assert!(path.ends_with(format!("output.{}.csv", page)));
+            let content = fs::read_to_string(path).unwrap();
+            let mut lines = content.lines();
+            assert_eq!(lines.next(), Some(header));
+            let ids: Vec<u32> = lines
+                .map(|row| row.split(',').next().unwrap().parse().unwrap())
+                .collect();
+            assert_eq!(ids, clients);
         }
+        assert!(!pages.path(3).exists());
     }
 
-    // Test deposit follow by the same amount of withdraws.
+    // Test that the clients are split into the shards by id modulo the number
+    // of shards, with a header in every shard and the counts in the manifest.
     #[test]
-
-    fn test_deposit_withdraw_loop() {
+    fn test_serialize_shards() {
         let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
-        let num_iterations = 8 * 1024;
-        for i in 0..num_iterations {
+        for client in 1..=10 {
             let transaction = TransactionRecord {
                 transaction_type: "deposit".to_owned(),
-                client: 1,
-                tx: i * 2,
+                client,
+                tx: client as u64,
                 amount: Some("1".to_owned()),
+                ..Default::default()
             };
+            assert!(processor.process_transaction(transaction).is_ok());
+        }
+        let dir = TempDir::new("shards").unwrap();
+        let shards: OutputShards = format!("{}:4", dir.path().join("out").display())
+            .parse()
+            .unwrap();
+        let options = OutputOptions {
+            shards: Some(shards.clone()),
+            ..OutputOptions::default()
+        };
+        processor.serialize(&options).unwrap();
 
-            assert_eq!(processor.process_transaction(transaction), Ok(()));
+        let header = "client,available,held,total,locked";
+        let mut sharded_rows = Vec::new();
+        for (shard, clients) in [
+            (0, vec![4, 8]),
+            (1, vec![1, 5, 9]),
+            (2, vec![2, 6, 10]),
+            (3, vec![3, 7]),
+        ] {
+            let content =
+                fs::read_to_string(shards.dir.join(OutputShards::file_name(shard))).unwrap();
+            let mut lines = content.lines();
+            assert_eq!(lines.next(), Some(header));
+            let rows: Vec<String> = lines.map(str::to_owned).collect();
+            let ids: Vec<u32> = rows
+                .iter()
+                .map(|row| row.split(',').next().unwrap().parse().unwrap())
+                .collect();
+            assert_eq!(ids, clients);
+            sharded_rows.extend(rows);
+        }
+        let manifest: ShardManifest =
+            serde_json::from_reader(File::open(shards.dir.join("_manifest.json")).unwrap())
+                .unwrap();
+        let counts: Vec<(&str, u64)> = manifest
+            .shards
+            .iter()
+            .map(|entry| (entry.file.as_str(), entry.clients))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("part-00000.csv", 2),
+                ("part-00001.csv", 3),
+                ("part-00002.csv", 3),
+                ("part-00003.csv", 2)
+            ]
+        );
 
-            let transaction = TransactionRecord {
-                transaction_type: "withdrawal".to_owned(),
-                client: 1,
-                tx: i * 2 + 1,
-                amount: Some("1".to_owned()),
+        let mut output = Vec::new();
+        processor
+            .serialize_to(&mut output, &OutputOptions::default())
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let mut rows: Vec<&str> = output.lines().skip(1).collect();
+        rows.sort_unstable();
+        sharded_rows.sort_unstable();
+        assert_eq!(rows, sharded_rows);
+
+        // Empty shards still get a header.
+        let empty = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let shards = OutputShards {
+            dir: dir.path().join("empty"),
+            count: NonZeroU16::new(2).unwrap(),
+        };
+        empty.serialize_shards(&shards, &options).unwrap();
+        assert_eq!(
+            fs::read_to_string(shards.dir.join("part-00001.csv")).unwrap(),
+            format!("{}\n", header)
+        );
+        assert!("out:0".parse::<OutputShards>().is_err());
+        assert!("out".parse::<OutputShards>().is_err());
+    }
+
+    // Test that a flat fee is charged to every client which can afford it,
+    // held funds left untouched.
+    #[test]
+    fn test_apply_fee() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        for (transaction_type, client, tx, amount) in &[
+            ("deposit", 1, 1, "10"),
+            ("deposit", 1, 2, "5"),
+            ("dispute", 1, 2, ""),
+            ("deposit", 2, 3, "0.5"),
+        ] {
+            let record = TransactionRecord {
+                transaction_type: transaction_type.to_string(),
+                client: *client,
+                tx: *tx,
+                amount: Some(amount.to_string()),
+                ..Default::default()
             };
-            assert_eq!(processor.process_transaction(transaction), Ok(()));
+            processor.process_transaction(record).unwrap();
         }
+        let fee = Amount::from_str("1.25").unwrap();
+        let mut charged = Vec::new();
+        processor.apply_fee(|client| {
+            if client.charge_fee(fee).is_ok() {
+                charged.push(client.client_id());
+            }
+        });
+        assert_eq!(charged, vec![ClientId(1)]);
 
-        assert_eq!(processor.clients.len(), 1);
-        for client in processor.clients.into_values() {
-            assert_eq!(client.client_id(), ClientId(1));
-            assert_eq!(client.total(), Amount::from_str("0.0".to_owned()).unwrap());
-            assert_eq!(
-                client.available(),
-                Amount::from_str("0.0".to_owned()).unwrap()
-            );
-            assert_eq!(client.locked(), false);
-            assert_eq!(client.held(), Amount::from_str("0.0".to_owned()).unwrap());
+        let mut output = Vec::new();
+        processor
+            .serialize_to(&mut output, &OutputOptions::default())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,8.75,5,13.75,false\n2,0.5,0.0000,0.5,false\n"
+        );
+    }
+
+    // Test that the output spec selects, orders and renames the columns, and
+    // that unknown columns are rejected.
+    #[test]
+    fn test_output_spec() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let transaction = TransactionRecord {
+            transaction_type: "deposit".to_owned(),
+            client: 1,
+            tx: 1,
+            amount: Some("1.5".to_owned()),
+            ..Default::default()
+        };
+        assert!(processor.process_transaction(transaction).is_ok());
+
+        let options = OutputOptions {
+            columns: "locked,client_id=id,total".parse().unwrap(),
+            ..OutputOptions::default()
+        };
+        let mut output = Vec::new();
+        processor.serialize_to(&mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "locked,id,total\nfalse,1,1.5\n"
+        );
+
+        assert_eq!(
+            "client,totl".parse::<OutputSpec>(),
+            Err(
+                "Unknown column totl, expected one of client_id,available,held,total,locked"
+                    .to_owned()
+            )
+        );
+    }
+
+    /// Observer which records every event it is notified about.
+    struct EventCollector(Rc<RefCell<Vec<String>>>);
+
+    impl ProcessorObserver for EventCollector {
+        fn on_deposit(&mut self, client_id: ClientId, tx: TransactionId, amount: Amount) {
+            self.0
+                .borrow_mut()
+                .push(format!("deposit {} {} {}", client_id, tx, amount));
+        }
+
+        fn on_dispute_opened(&mut self, client_id: ClientId, tx: TransactionId) {
+            self.0
+                .borrow_mut()
+                .push(format!("dispute {} {}", client_id, tx));
+        }
+
+        fn on_chargeback(&mut self, client_id: ClientId, tx: TransactionId) {
+            self.0
+                .borrow_mut()
+                .push(format!("chargeback {} {}", client_id, tx));
+        }
+
+        fn on_rejected(&mut self, record: &TransactionRecord, error: &ProcessingError) {
+            self.0.borrow_mut().push(format!(
+                "rejected {} {}: {}",
+                record.transaction_type, record.tx, error
+            ));
         }
     }
 
-    // Test duplicate transaction do nothing.
+    // Test that observers are notified of each applied and rejected transaction
+    // in order.
     #[test]
+    fn test_observer_events() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_observer(Box::new(EventCollector(events.clone())));
 
-    fn test_duplicate_transactions_do_nothing() {
-        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
-        let num_iterations = 8 * 1024;
-        for i in 0..num_iterations {
+        for transaction_type in &["deposit", "dispute", "chargeback", "deposit"] {
             let transaction = TransactionRecord {
-                transaction_type: "deposit".to_owned(),
+                transaction_type: (*transaction_type).to_owned(),
                 client: 1,
-                tx: i * 2,
-                amount: Some("1".to_owned()),
+                tx: 7,
+                amount: Some("1.5".to_owned()),
+                ..Default::default()
             };
+            let _ = processor.process_transaction(transaction);
+        }
 
-            assert_eq!(processor.process_transaction(transaction.clone()), Ok(()));
-            assert!(processor.process_transaction(transaction).is_err());
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                "deposit 1 7 1.5",
+                "dispute 1 7",
+                "chargeback 1 7",
+                "rejected deposit 7: Account locked",
+            ]
+        );
+    }
 
+    // Test that clients with zero balances are only written when not suppressed.
+    #[test]
+    fn test_serialize_suppress_zero() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let records = [("deposit", 1, 1), ("withdrawal", 1, 2), ("deposit", 2, 3)];
+        for (transaction_type, client, tx) in records.iter() {
             let transaction = TransactionRecord {
-                transaction_type: "withdrawal".to_owned(),
-                client: 1,
-                tx: i * 2 + 1,
-                amount: Some("1".to_owned()),
+                transaction_type: (*transaction_type).to_owned(),
+                client: *client,
+                tx: *tx,
+                amount: Some("2.5".to_owned()),
+                ..Default::default()
             };
-            assert_eq!(processor.process_transaction(transaction.clone()), Ok(()));
-            assert!(processor.process_transaction(transaction).is_err());
+            assert!(processor.process_transaction(transaction).is_ok());
         }
 
-        assert_eq!(processor.clients.len(), 1);
-        for client in processor.clients.into_values() {
-            assert_eq!(client.client_id(), ClientId(1));
-            assert_eq!(client.total(), Amount::from_str("0.0".to_owned()).unwrap());
+        let mut output = Vec::new();
+        processor
+            .serialize_to(&mut output, &OutputOptions::default())
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\n1,0.0000,0.0000,0.0000,false\n"));
+        assert!(output.contains("\n2,2.5,0.0000,2.5,false\n"));
+
+        let options = OutputOptions {
+            suppress_zero: true,
+            ..OutputOptions::default()
+        };
+        let mut output = Vec::new();
+        processor.serialize_to(&mut output, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n2,2.5,0.0000,2.5,false\n"
+        );
+    }
+
+    // Test the outcome reported for each transaction kind.
+    #[test]
+    fn test_applied_payload() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let records = [
+            (
+                "deposit",
+                1,
+                Some("5"),
+                TransactionKind::Deposit,
+                "5",
+                "0",
+                false,
+            ),
+            (
+                "withdrawal",
+                2,
+                Some("1"),
+                TransactionKind::Withdrawal,
+                "4",
+                "0",
+                false,
+            ),
+            (
+                "dispute",
+                1,
+                None,
+                TransactionKind::Dispute,
+                "-1",
+                "5",
+                false,
+            ),
+            (
+                "resolve",
+                1,
+                None,
+                TransactionKind::Resolve,
+                "4",
+                "0",
+                false,
+            ),
+            (
+                "dispute",
+                1,
+                None,
+                TransactionKind::Dispute,
+                "-1",
+                "5",
+                false,
+            ),
+            (
+                "chargeback",
+                1,
+                None,
+                TransactionKind::ChargeBack,
+                "-1",
+                "0",
+                true,
+            ),
+        ];
+        for (transaction_type, tx, amount, kind, available, held, locked) in records.iter() {
+            let transaction = TransactionRecord {
+                transaction_type: (*transaction_type).to_owned(),
+                client: 3,
+                tx: *tx,
+                amount: amount.map(str::to_owned),
+                ..Default::default()
+            };
+            let available = Amount::from_str(available).unwrap();
+            let held = Amount::from_str(held).unwrap();
+            let mut total = available;
+            total += held;
             assert_eq!(
-                client.available(),
-                Amount::from_str("0.0".to_owned()).unwrap()
+                processor.process_transaction(transaction),
+                Ok(Applied {
+                    kind: *kind,
+                    client_id: ClientId(3),
+                    available,
+                    held,
+                    total,
+                    locked: *locked,
+                })
             );
-            assert_eq!(client.locked(), false);
-            assert_eq!(client.held(), Amount::from_str("0.0".to_owned()).unwrap());
         }
     }
 
-    // Test a sequence of dispute, withdraw, resolve and make sure the
-    // account balance is correct.
+    // Test that policies veto transactions before they are applied.
     #[test]
-    fn test_deposit_dispute_withdraw_resolve_withdraw() {
-        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
-        let deposit_transaction_id = 8 * 1024;
-        let transaction = TransactionRecord {
-            transaction_type: "deposit".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id,
-            amount: Some("1".to_owned()),
+    fn test_policies() {
+        let no_withdrawals_for_client_2 = |transaction: &Transaction, client: &ClientView| {
+            if transaction.kind() == TransactionKind::Withdrawal && client.client_id == ClientId(2)
+            {
+                return Err(PolicyViolation("Withdrawals blocked".to_owned()));
+            }
+            Ok(())
         };
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_policy(Box::new(no_withdrawals_for_client_2))
+            .with_policy(Box::new(MaxAmount(Amount::from_str("100").unwrap())));
 
-        assert_eq!(processor.process_transaction(transaction), Ok(()));
-        let transaction = TransactionRecord {
-            transaction_type: "dispute".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id,
-            amount: None,
-        };
-        assert_eq!(processor.process_transaction(transaction), Ok(()));
+        let records = [
+            ("deposit", 1, 1, "10", true),
+            ("deposit", 2, 2, "10", true),
+            ("withdrawal", 1, 3, "5", true),
+            ("withdrawal", 2, 4, "5", false),
+            ("deposit", 1, 5, "100.0001", false),
+        ];
+        for (transaction_type, client, tx, amount, accepted) in records.iter() {
+            let transaction = TransactionRecord {
+                transaction_type: (*transaction_type).to_owned(),
+                client: *client,
+                tx: *tx,
+                amount: Some((*amount).to_owned()),
+                ..Default::default()
+            };
+            assert_eq!(
+                processor.process_transaction(transaction).is_ok(),
+                *accepted
+            );
+        }
 
         let transaction = TransactionRecord {
             transaction_type: "withdrawal".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id + 1,
+            client: 2,
+            tx: 6,
             amount: Some("1".to_owned()),
+            ..Default::default()
         };
-        assert!(processor.process_transaction(transaction).is_err());
-
-        let transaction = TransactionRecord {
-            transaction_type: "resolve".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id,
-            amount: None,
-        };
+        assert_eq!(
+            processor
+                .process_transaction(transaction)
+                .unwrap_err()
+                .to_string(),
+            "Policy violation: Withdrawals blocked"
+        );
 
-        assert_eq!(processor.process_transaction(transaction.clone()), Ok(()));
-
-        assert!(processor.process_transaction(transaction).is_err());
+        assert_eq!(
+            processor.clients[&ClientId(1)].available(),
+            Amount::from_str("5").unwrap()
+        );
+        assert_eq!(
+            processor.clients[&ClientId(2)].available(),
+            Amount::from_str("10").unwrap()
+        );
+    }
 
-        let transaction = TransactionRecord {
-            transaction_type: "withdrawal".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id + 1,
-            amount: Some("1".to_owned()),
+    // Test that closure observers are called once per processed record with
+    // its outcome.
+    #[test]
+    fn test_closure_observer() {
+        let outcomes = Rc::new(RefCell::new(Vec::new()));
+        let collected = outcomes.clone();
+        let observer = move |record: &TransactionRecord,
+                             result: &Result<Applied, ProcessingError>| {
+            collected.borrow_mut().push((
+                record.tx,
+                result.as_ref().map(|applied| applied.available).ok(),
+            ));
         };
-        assert_eq!(processor.process_transaction(transaction), Ok(()));
-        for client in processor.clients.into_values() {
-            assert_eq!(client.client_id(), ClientId(1));
-            assert_eq!(client.total(), Amount::from_str("0.0".to_owned()).unwrap());
-            assert_eq!(
-                client.available(),
-                Amount::from_str("0.0".to_owned()).unwrap()
-            );
-            assert_eq!(client.locked(), false);
-            assert_eq!(client.held(), Amount::from_str("0.0".to_owned()).unwrap());
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_observer(Box::new(observer));
+
+        let records = [
+            ("deposit", 1, "2"),
+            ("withdrawal", 2, "3"),
+            ("withdrawal", 3, "0.5"),
+        ];
+        for (transaction_type, tx, amount) in records.iter() {
+            let transaction = TransactionRecord {
+                transaction_type: (*transaction_type).to_owned(),
+                client: 1,
+                tx: *tx,
+                amount: Some((*amount).to_owned()),
+                ..Default::default()
+            };
+            let _ = processor.process_transaction(transaction);
         }
+
+        assert_eq!(
+            *outcomes.borrow(),
+            vec![
+                (1, Some(Amount::from_str("2").unwrap())),
+                (2, None),
+                (3, Some(Amount::from_str("1.5").unwrap())),
+            ]
+        );
     }
 
-    // Test that disputing the same transaction twice or resolving
-    // twice do not have any impact.
+    // Test that a batch reports the outcome of every record in order.
     #[test]
-    fn test_deposit_dispute_twice_resolve_twice() {
+    fn test_process_batch() {
         let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
-        let deposit_transaction_id = 8 * 1024;
-        let transaction = TransactionRecord {
-            transaction_type: "deposit".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id,
-            amount: Some("1".to_owned()),
-        };
+        let records = vec![
+            ("deposit", 1, Some("2")),
+            ("deposit", 1, Some("2")),
+            ("withdrawal", 2, Some("3")),
+            ("dispute", 1, None),
+            ("withdrawal", 3, Some("1")),
+            ("resolve", 1, None),
+        ];
+        let records = records
+            .into_iter()
+            .map(|(transaction_type, tx, amount)| TransactionRecord {
+                transaction_type: transaction_type.to_owned(),
+                client: 1,
+                tx,
+                amount: amount.map(str::to_owned),
+                ..Default::default()
+            })
+            .collect();
 
-        assert_eq!(processor.process_transaction(transaction), Ok(()));
-        let transaction = TransactionRecord {
-            transaction_type: "dispute".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id,
-            amount: None,
+        let results = processor.process_batch(records);
+        let balances: Vec<Result<(Amount, Amount), ProcessingError>> = results
+            .into_iter()
+            .map(|result| result.map(|applied| (applied.available, applied.held)))
+            .collect();
+        let balance = |available: &str, held: &str| {
+            Ok((
+                Amount::from_str(available).unwrap(),
+                Amount::from_str(held).unwrap(),
+            ))
         };
+        assert_eq!(
+            balances,
+            vec![
+                balance("2", "0"),
+                Err(ProcessingError::DuplicateReplay),
+                Err(ProcessingError::InsufficientFunds),
+                balance("0", "2"),
+                Err(ProcessingError::InsufficientFunds),
+                balance("2", "0"),
+            ]
+        );
+    }
 
-        assert_eq!(processor.process_transaction(transaction.clone()), Ok(()));
-        assert!(processor.process_transaction(transaction).is_err());
-
-        let transaction = TransactionRecord {
-            transaction_type: "resolve".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id,
-            amount: None,
+    // Test that replaying a batch with the same idempotency keys, even under
+    // new transaction ids, leaves the balances untouched and reports every
+    // record as a replay of its first outcome.
+    #[test]
+    fn test_idempotency_keys() {
+        let batch = |first_tx: u64| -> Vec<TransactionRecord> {
+            vec![
+                ("deposit", Some("5"), "a"),
+                ("withdrawal", Some("2"), "b"),
+                ("withdrawal", Some("9"), "c"),
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (transaction_type, amount, key))| TransactionRecord {
+                transaction_type: transaction_type.to_owned(),
+                client: 1,
+                tx: first_tx + i as u64,
+                amount: amount.map(str::to_owned),
+                idempotency_key: Some(key.to_owned()),
+                ..Default::default()
+            })
+            .collect()
         };
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let first = processor.process_batch(batch(1));
+        let view = processor.clients[&ClientId(1)].view();
+        assert_eq!(view.available, Amount::from_str("3").unwrap());
 
-        assert_eq!(processor.process_transaction(transaction.clone()), Ok(()));
-
-        assert!(processor.process_transaction(transaction).is_err());
+        let second = processor.process_batch(batch(10));
+        assert_eq!(processor.clients[&ClientId(1)].view(), view);
+        assert_eq!(first[2], Err(ProcessingError::InsufficientFunds));
+        let replays: Vec<Result<Applied, ProcessingError>> = first
+            .iter()
+            .map(|result| Err(ProcessingError::IdempotentReplay(result.into())))
+            .collect();
+        assert_eq!(second, replays);
+    }
 
-        for client in processor.clients.into_values() {
-            assert_eq!(client.client_id(), ClientId(1));
-            assert_eq!(client.total(), Amount::from_str("1.0".to_owned()).unwrap());
-            assert_eq!(
-                client.available(),
-                Amount::from_str("1.0".to_owned()).unwrap()
-            );
-            assert_eq!(client.locked(), false);
-            assert_eq!(client.held(), Amount::from_str("0.0".to_owned()).unwrap());
+    // Test that only the clients with more chargebacks than the threshold are
+    // flagged, with the transactions they charged back.
+    #[test]
+    fn test_flag_suspicious() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let records = vec![
+            ("deposit", 1, 1, Some("1")),
+            ("deposit", 2, 2, Some("2")),
+            ("dispute", 2, 2, None),
+            ("chargeback", 2, 2, None),
+            ("deposit", 3, 3, Some("3")),
+            ("dispute", 3, 3, None),
+            ("chargeback", 3, 3, None),
+        ];
+        for (transaction_type, client, tx, amount) in records {
+            processor
+                .process_transaction(TransactionRecord {
+                    transaction_type: transaction_type.to_owned(),
+                    client,
+                    tx,
+                    amount: amount.map(str::to_owned),
+                    ..Default::default()
+                })
+                .unwrap();
         }
+        // The first chargeback locks the account, the following ones can only
+        // be recorded directly.
+        let client = processor.clients.get_mut(&ClientId(3)).unwrap();
+        client
+            .record_chargeback(TransactionId(4), Amount::from_str("4").unwrap())
+            .unwrap();
+        client
+            .record_chargeback(TransactionId(5), Amount::from_str("5").unwrap())
+            .unwrap();
+
+        let reports =
+            processor.flag_suspicious(&SuspicionThresholds::new().with_max_chargebacks(2));
+        assert_eq!(
+            reports,
+            vec![SuspicionReport {
+                client_id: ClientId(3),
+                chargebacks: 3,
+                charged_back: Amount::from_str("12").unwrap(),
+                transactions: vec![TransactionId(3), TransactionId(4), TransactionId(5)],
+            }]
+        );
+        let by_amount =
+            SuspicionThresholds::new().with_max_charged_back(Amount::from_str("1").unwrap());
+        let flagged: Vec<ClientId> = processor
+            .flag_suspicious(&by_amount)
+            .iter()
+            .map(|report| report.client_id)
+            .collect();
+        assert_eq!(flagged, vec![ClientId(2), ClientId(3)]);
     }
 
-    // Test that withdraw after chargeback is not processed
+    // Test that the timestamps of an input are kept with the deposits and
+    // withdrawals and written back by the export.
     #[test]
-    fn test_deposit_dispute_withdraw_chargeback_withdraw() {
+    fn test_timestamp_round_trip() {
+        let input = "type,client,tx,amount,timestamp
+deposit,1,1,2.5,2024-03-01T10:00:00Z
+deposit,1,2,1,
+withdrawal,1,3,0.5,2024-03-01T11:30:00Z
+dispute,1,1,,2024-03-02T08:00:00Z
+";
         let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
-        let deposit_transaction_id = 8 * 1024;
-        let transaction = TransactionRecord {
-            transaction_type: "deposit".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id,
-            amount: Some("1".to_owned()),
-        };
+        for record in csv::Reader::from_reader(input.as_bytes()).deserialize() {
+            processor.process_transaction(record.unwrap()).unwrap();
+        }
 
-        assert_eq!(processor.process_transaction(transaction), Ok(()));
-        let transaction = TransactionRecord {
-            transaction_type: "dispute".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id,
-            amount: None,
-        };
-        assert_eq!(processor.process_transaction(transaction), Ok(()));
+        let mut output = Vec::new();
+        processor
+            .clients
+            .get_mut(&ClientId(1))
+            .unwrap()
+            .export_transactions(&mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "type,client,tx,amount,idempotency_key,timestamp,memo
+deposit,1,1,2.5,,2024-03-01T10:00:00Z,
+deposit,1,2,1,,,
+withdrawal,1,3,0.5,,2024-03-01T11:30:00Z,
+"
+        );
+    }
 
-        let transaction = TransactionRecord {
-            transaction_type: "withdrawal".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id + 1,
-            amount: Some("1".to_owned()),
+    // Test that the memos of the deposits are reported with the open disputes
+    // only once enabled, without changing any balance.
+    #[test]
+    fn test_memos() {
+        let records = || {
+            vec![
+                ("deposit", 1, Some("2"), Some("salary")),
+                ("deposit", 2, Some("3"), Some("refund")),
+                ("deposit", 3, Some("1"), None),
+                ("withdrawal", 4, Some("10"), Some("too much")),
+                ("dispute", 1, None, Some("customer call")),
+                ("dispute", 3, None, None),
+            ]
+            .into_iter()
+            .map(|(transaction_type, tx, amount, memo)| TransactionRecord {
+                transaction_type: transaction_type.to_owned(),
+                client: 1,
+                tx,
+                amount: amount.map(str::to_owned),
+                memo: memo.map(str::to_owned),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>()
         };
-        assert!(processor.process_transaction(transaction).is_err());
+        let mut without_memos = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let mut with_memos =
+            TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new().with_memos();
+        assert_eq!(
+            without_memos.process_batch(records()),
+            with_memos.process_batch(records())
+        );
+        let mut balances = Vec::new();
+        without_memos
+            .serialize_to(&mut balances, &OutputOptions::default())
+            .unwrap();
+        let mut balances_with_memos = Vec::new();
+        with_memos
+            .serialize_to(&mut balances_with_memos, &OutputOptions::default())
+            .unwrap();
+        assert_eq!(balances, balances_with_memos);
 
-        let transaction = TransactionRecord {
-            transaction_type: "chargeback".to_owned(),
-            client: 1,
-            tx: deposit_transaction_id,
-            amount: None,
+        let dispute = |tx: u64, amount: &str, memo: Option<&str>| OpenDispute {
+            client_id: ClientId(1),
+            tx: TransactionId(tx),
+            amount: Amount::from_str(amount).unwrap(),
+            memo: memo.map(str::to_owned),
         };
+        assert_eq!(
+            with_memos.open_disputes(),
+            vec![dispute(1, "2", Some("salary")), dispute(3, "1", None)]
+        );
+        assert_eq!(
+            without_memos.open_disputes(),
+            vec![dispute(1, "2", None), dispute(3, "1", None)]
+        );
+    }
 
-        assert_eq!(processor.process_transaction(transaction.clone()), Ok(()));
+    // Test that the dispute events are reported in order with the final state
+    // of their deposit once enabled.
+    #[test]
+    fn test_dispute_events() {
+        let scenario = || {
+            Scenario::new()
+                .then(Record::deposit(2, 1, "2"))
+                .then(Record::deposit(1, 2, "3"))
+                .then(Record::deposit(1, 3, "1"))
+                .then(Record::dispute(2, 1))
+                .then(Record::resolve(2, 1))
+                .then(Record::dispute(1, 3))
+                .then(Record::dispute(1, 2))
+                .then(Record::chargeback(1, 2))
+                .then(Record::resolve(1, 2))
+        };
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        scenario().run_on(&mut processor);
+        assert!(processor.dispute_events().is_empty());
 
-        assert!(processor.process_transaction(transaction).is_err());
+        let mut processor =
+            TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new().with_dispute_events();
+        scenario().run_on(&mut processor);
+        let event = |client: u32, tx: u64, amount: &str, event, final_state| DisputeEvent {
+            client_id: ClientId(client),
+            tx: TransactionId(tx),
+            amount: Amount::from_str(amount).unwrap(),
+            event,
+            final_state,
+        };
+        assert_eq!(
+            processor.dispute_events(),
+            vec![
+                event(1, 3, "1", DisputeState::Disputed, DisputeState::Disputed),
+                event(1, 2, "3", DisputeState::Disputed, DisputeState::ChargedBack),
+                event(
+                    1,
+                    2,
+                    "3",
+                    DisputeState::ChargedBack,
+                    DisputeState::ChargedBack
+                ),
+                event(2, 1, "2", DisputeState::Disputed, DisputeState::Resolved),
+                event(2, 1, "2", DisputeState::Resolved, DisputeState::Resolved),
+            ]
+        );
+    }
 
-        let transaction = TransactionRecord {
-            transaction_type: "withdrawal".to_owned(),
+    // Test that the client caches are created in a per-run subdirectory of the
+    // configured dir which is only removed when asked to.
+    #[test]
+    fn test_cache_dir() {
+        let base_dir = TempDir::new("processor_cache_dir").unwrap();
+        let deposit = || TransactionRecord {
+            transaction_type: "deposit".to_owned(),
             client: 1,
-            tx: deposit_transaction_id + 1,
+            tx: 1,
             amount: Some("1".to_owned()),
+            ..Default::default()
         };
-        assert!(processor.process_transaction(transaction).is_err());
 
-        for client in processor.clients.into_values() {
-            assert_eq!(client.client_id(), ClientId(1));
-            assert_eq!(client.total(), Amount::from_str("0.0".to_owned()).unwrap());
-            assert_eq!(
-                client.available(),
-                Amount::from_str("0.0".to_owned()).unwrap()
-            );
-            assert_eq!(client.locked(), true);
-            assert_eq!(client.held(), Amount::from_str("0.0".to_owned()).unwrap());
+        let mut kept = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_cache_dir(base_dir.path(), false)
+            .unwrap();
+        let mut cleaned = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_cache_dir(base_dir.path(), true)
+            .unwrap();
+        assert!(kept.process_transaction(deposit()).is_ok());
+        assert!(cleaned.process_transaction(deposit()).is_ok());
+
+        let kept_dir = kept.cache_dir().unwrap().to_path_buf();
+        let cleaned_dir = cleaned.cache_dir().unwrap().to_path_buf();
+        assert_ne!(kept_dir, cleaned_dir);
+        assert_eq!(kept_dir.parent(), Some(base_dir.path()));
+        // One subdirectory for each of the client caches.
+        assert_eq!(fs::read_dir(&cleaned_dir).unwrap().count(), 2);
+
+        drop(kept);
+        drop(cleaned);
+        assert!(kept_dir.exists());
+        assert!(!cleaned_dir.exists());
+    }
+
+    // Test that the clients are written by client id, also when sorted by
+    // total and their totals are equal.
+    #[test]
+    fn test_serialize_sort_tie_break() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        for (client, tx, amount) in [(9, 1, "2.0"), (3, 2, "5"), (4, 3, "2"), (1, 4, "2.00")] {
+            let record = TransactionRecord {
+                transaction_type: "deposit".to_owned(),
+                client,
+                tx,
+                amount: Some(amount.to_owned()),
+                ..Default::default()
+            };
+            assert!(processor.process_transaction(record).is_ok());
         }
+        let clients = |sort_by: SortKey| {
+            let mut output = Vec::new();
+            let options = OutputOptions {
+                sort_by,
+                ..OutputOptions::default()
+            };
+            processor.serialize_to(&mut output, &options).unwrap();
+            String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').next().unwrap().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(clients(SortKey::ClientId), vec!["1", "3", "4", "9"]);
+        assert_eq!(clients(SortKey::Total), vec!["3", "1", "4", "9"]);
     }
 }
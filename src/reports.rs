@@ -0,0 +1,334 @@
+use std::fmt;
+use std::io::Write;
+
+use crate::error::ProcessingError;
+use crate::observer::ProcessorObserver;
+use crate::runner::ProcessingStats;
+use crate::type_defs::{Amount, ClientId, TransactionId, TransactionRecord};
+
+/// Observer which writes the records rejected by a `TransactionProcessor` as
+/// csv, together with their memo, the code of their error and the reason they
+/// were rejected.
+pub struct RejectedRecords<W: Write> {
+    writer: csv::Writer<W>,
+    /// First error met while writing, the following records are not written.
+    error: Option<String>,
+}
+
+impl<W: Write> RejectedRecords<W> {
+    pub fn new(writer: W) -> Self {
+        let mut report = RejectedRecords {
+            writer: csv::Writer::from_writer(writer),
+            error: None,
+        };
+        report.write(&["type", "client", "tx", "amount", "memo", "code", "error"]);
+        report
+    }
+
+    fn write(&mut self, record: &[&str]) {
+        if self.error.is_none() {
+            if let Err(err) = self.writer.write_record(record) {
+                self.error = Some(format!(
+                    "Could not write rejected record because of: {}",
+                    err
+                ));
+            }
+        }
+    }
+
+    /// Flushes the report and returns the underlying writer, or the first
+    /// error met while writing.
+    pub fn into_inner(mut self) -> Result<W, String> {
+        self.finish()?;
+        self.writer
+            .into_inner()
+            .map_err(|err| format!("Could not write rejected records because of: {}", err))
+    }
+}
+
+impl<W: Write> ProcessorObserver for RejectedRecords<W> {
+    fn on_rejected(&mut self, record: &TransactionRecord, error: &ProcessingError) {
+        let client = record.client.to_string();
+        let tx = record.tx.to_string();
+        let error_code = error.code();
+        let error = error.to_string();
+        self.write(&[
+            &record.transaction_type,
+            &client,
+            &tx,
+            record.amount.as_deref().unwrap_or_default(),
+            record.memo.as_deref().unwrap_or_default(),
+            error_code,
+            &error,
+        ]);
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        if let Some(err) = &self.error {
+            return Err(err.clone());
+        }
+        self.writer
+            .flush()
+            .map_err(|err| format!("Could not write rejected records because of: {}", err))
+    }
+}
+
+/// Type which describes a deposit still under dispute at the end of a run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenDispute {
+    pub client_id: ClientId,
+    pub tx: TransactionId,
+    /// Amount held for the dispute, the disputed portion of the deposit.
+    pub amount: Amount,
+    /// Memo of the deposit, only kept when the processor keeps the memos.
+    pub memo: Option<String>,
+}
+
+/// Writes the open disputes as csv.
+pub fn write_open_disputes<W: Write>(disputes: &[OpenDispute], writer: W) -> Result<(), String> {
+    let mut writer = csv::Writer::from_writer(writer);
+    let error = |err: csv::Error| format!("Could not write open disputes because of: {}", err);
+    writer
+        .write_record(["client", "tx", "amount", "memo"])
+        .map_err(error)?;
+    for dispute in disputes {
+        writer
+            .write_record(&[
+                dispute.client_id.to_string(),
+                dispute.tx.to_string(),
+                dispute.amount.to_string(),
+                dispute.memo.clone().unwrap_or_default(),
+            ])
+            .map_err(error)?;
+    }
+    writer
+        .flush()
+        .map_err(|err| format!("Could not write open disputes because of: {}", err))
+}
+
+/// State a dispute is left in by one of its events.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DisputeState {
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl fmt::Display for DisputeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DisputeState::Disputed => "disputed",
+            DisputeState::Resolved => "resolved",
+            DisputeState::ChargedBack => "charged_back",
+        })
+    }
+}
+
+/// Type which describes a dispute, resolve or chargeback of a deposit,
+/// together with the state the deposit was left in at the end of the run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisputeEvent {
+    pub client_id: ClientId,
+    pub tx: TransactionId,
+    /// Disputed portion of the deposit, held by a dispute and released or
+    /// charged back by the following resolve or chargeback.
+    pub amount: Amount,
+    pub event: DisputeState,
+    pub final_state: DisputeState,
+}
+
+/// Writes the dispute events as csv.
+pub fn write_dispute_events<W: Write>(events: &[DisputeEvent], writer: W) -> Result<(), String> {
+    let mut writer = csv::Writer::from_writer(writer);
+    let error = |err: csv::Error| format!("Could not write disputes report because of: {}", err);
+    writer
+        .write_record(["client", "tx", "amount", "event", "final_state"])
+        .map_err(error)?;
+    for event in events {
+        writer
+            .write_record(&[
+                event.client_id.to_string(),
+                event.tx.to_string(),
+                event.amount.to_string(),
+                event.event.to_string(),
+                event.final_state.to_string(),
+            ])
+            .map_err(error)?;
+    }
+    writer
+        .flush()
+        .map_err(|err| format!("Could not write disputes report because of: {}", err))
+}
+
+/// Type which describes an amount held for a client at the end of a run, by
+/// a dispute or, without transaction, the part of the held balance which no
+/// dispute explains.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeldDetail {
+    pub client_id: ClientId,
+    pub tx: Option<TransactionId>,
+    pub held: Amount,
+}
+
+/// Writes the held detail as csv, the tx column is empty for the held amounts
+/// which no dispute explains.
+pub fn write_held_detail<W: Write>(detail: &[HeldDetail], writer: W) -> Result<(), String> {
+    let mut writer = csv::Writer::from_writer(writer);
+    let error = |err: csv::Error| format!("Could not write held detail because of: {}", err);
+    writer
+        .write_record(["client", "tx", "held"])
+        .map_err(error)?;
+    for held in detail {
+        writer
+            .write_record(&[
+                held.client_id.to_string(),
+                held.tx.map(|tx| tx.to_string()).unwrap_or_default(),
+                held.held.to_string(),
+            ])
+            .map_err(error)?;
+    }
+    writer
+        .flush()
+        .map_err(|err| format!("Could not write held detail because of: {}", err))
+}
+
+/// Type which describes a client whose running total differs from the total
+/// recomputed from its stored transactions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BalanceDiscrepancy {
+    pub client_id: ClientId,
+    pub total: Amount,
+    pub recomputed: Amount,
+}
+
+impl fmt::Display for BalanceDiscrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Balance discrepancy for client {}: total {} but the transactions sum to {}",
+            self.client_id, self.total, self.recomputed
+        )
+    }
+}
+
+/// Aggregate balances of the clients of a processor.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BalanceSummary {
+    pub clients: u64,
+    pub locked: u64,
+    pub available: Amount,
+    pub held: Amount,
+    /// Net position of the clients.
+    pub total: Amount,
+}
+
+/// Writes the aggregate balances followed by the stats of the run, one
+/// `name: value` line each, the applied records being broken down by kind and
+/// the rejected ones by severity and error code.
+pub fn write_summary<W: Write>(
+    balances: &BalanceSummary,
+    stats: &ProcessingStats,
+    mut writer: W,
+) -> Result<(), String> {
+    let mut lines = vec![
+        format!("clients: {}", balances.clients),
+        format!("locked clients: {}", balances.locked),
+        format!("available: {}", balances.available),
+        format!("held: {}", balances.held),
+        format!("total: {}", balances.total),
+        format!("applied: {}", stats.applied),
+    ];
+    lines.extend(
+        stats
+            .applied_by_kind
+            .iter()
+            .map(|(kind, count)| format!("  {}: {}", kind, count)),
+    );
+    lines.push(format!("rejected: {}", stats.rejected));
+    lines.extend(
+        stats
+            .rejected_by_severity
+            .iter()
+            .map(|(severity, count)| format!("  severity {}: {}", severity, count)),
+    );
+    lines.extend(
+        stats
+            .rejected_by_code
+            .iter()
+            .map(|(code, count)| format!("  {}: {}", code, count)),
+    );
+    lines.extend([
+        format!("  duplicate replays: {}", stats.duplicate_replays),
+        format!("  conflicting duplicates: {}", stats.conflicting_duplicates),
+        format!("malformed: {}", stats.malformed),
+        format!("filtered: {}", stats.filtered),
+        format!("skipped: {}", stats.skipped),
+        format!("idempotent replays: {}", stats.idempotent_replays),
+    ]);
+    for line in lines {
+        writeln!(writer, "{}", line)
+            .map_err(|err| format!("Could not write summary because of: {}", err))?;
+    }
+    writer
+        .flush()
+        .map_err(|err| format!("Could not write summary because of: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that the rejected records are written with their memo and error.
+    #[test]
+    fn test_rejected_records() {
+        let mut report = RejectedRecords::new(Vec::new());
+        let record = TransactionRecord {
+            transaction_type: "withdrawal".to_owned(),
+            client: 2,
+            tx: 7,
+            amount: Some("5.0".to_owned()),
+            memo: Some("rent, march".to_owned()),
+            ..Default::default()
+        };
+        report.on_rejected(&record, &ProcessingError::InsufficientFunds);
+        let output = String::from_utf8(report.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "type,client,tx,amount,memo,code,error\n\
+             withdrawal,2,7,5.0,\"rent, march\",E_INSUFFICIENT,Insufficient funds\n"
+        );
+    }
+
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Test that a report which can not be written returns the error once the
+    // run is over instead of dropping it.
+    #[test]
+    fn test_rejected_records_write_error() {
+        let mut report = RejectedRecords::new(FullDisk);
+        let record = TransactionRecord {
+            transaction_type: "withdrawal".to_owned(),
+            client: 2,
+            tx: 7,
+            ..Default::default()
+        };
+        report.on_rejected(&record, &ProcessingError::InsufficientFunds);
+        let err = report.finish().unwrap_err();
+        assert!(
+            err.starts_with("Could not write rejected records"),
+            "{}",
+            err
+        );
+        assert!(report.into_inner().is_err());
+    }
+}
@@ -0,0 +1,1370 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+
+use crate::cancel::CancellationToken;
+use crate::columns::{self, ColumnMapping};
+use crate::encoding::{Decoder, Encoding};
+use crate::error::{ProcessingError, Severity};
+use crate::filter::RecordFilter;
+use crate::malformed::{MalformedRow, MalformedRowPolicy, Recorded, Recorder};
+use crate::processor::TransactionProcessor;
+use crate::type_defs::{self, TransactionRecord};
+
+/// Options describing the csv dialect of the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvOptions {
+    /// Field delimiter, a comma by default.
+    pub delimiter: u8,
+    /// Lines starting with this byte are ignored.
+    pub comment: Option<u8>,
+    /// When set the input must start with a header and the columns are
+    /// looked up by name through the mapping instead of by position.
+    pub columns: Option<ColumnMapping>,
+    /// Rejects the columns which are not read as a field instead of ignoring
+    /// them: an unknown header name fails the run up front and a row with
+    /// more fields than the header, or than a record without a header, is
+    /// malformed.
+    pub strict_schema: bool,
+}
+
+impl CsvOptions {
+    /// Options for tab separated files.
+    pub fn tsv() -> Self {
+        CsvOptions {
+            delimiter: b'\t',
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: b',',
+            comment: None,
+            columns: None,
+            strict_schema: false,
+        }
+    }
+}
+
+/// Which of the messages about the records of a run are written on stderr.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// No message at all.
+    Off,
+    /// Only the error which stopped the run.
+    Error,
+    /// The errors and the records which were ignored, quarantined or
+    /// cancelled.
+    #[default]
+    Warn,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            _ => Err(format!(
+                "Unknown log level {}, expected off, error or warn",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogLevel::Off => write!(f, "off"),
+            LogLevel::Error => write!(f, "error"),
+            LogLevel::Warn => write!(f, "warn"),
+        }
+    }
+}
+
+/// Options which control how the records of an input are fed into a
+/// `TransactionProcessor`.
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    /// Dialect of the csv input.
+    pub csv: CsvOptions,
+    /// Encoding of the input, a leading UTF-8 byte order mark is always dropped.
+    pub encoding: Encoding,
+    /// Stop processing at the first record which could not be processed.
+    pub fail_fast: bool,
+    /// Stop processing at the first transaction which reuses the id of
+    /// another one with a different type or amount, replays are still ignored.
+    pub fail_on_conflicting_duplicate: bool,
+    /// Records which do not match the filter are skipped.
+    pub filter: RecordFilter,
+    /// Currency symbol stripped from the amounts before they are parsed.
+    pub currency_symbol: Option<String>,
+    /// Number of data records ignored at the start of the input.
+    pub skip: u64,
+    /// Maximum number of data records processed after the skipped ones.
+    pub take: Option<u64>,
+    /// What to do with the rows which can not be read as a record, `fail_fast`
+    /// also stops at them unless they are quarantined.
+    pub on_malformed: MalformedRowPolicy,
+    /// Stops reading the input once cancelled, e.g. on ctrl-c.
+    pub cancellation: Option<CancellationToken>,
+    /// Messages written on stderr while processing.
+    pub log_level: LogLevel,
+    /// Number of data records read between two flushes of the processor
+    /// caches, see `TransactionProcessor::flush_caches`.
+    pub chunk_size: Option<u64>,
+}
+
+impl RunOptions {
+    pub fn with_malformed_row_policy(mut self, on_malformed: MalformedRowPolicy) -> Self {
+        self.on_malformed = on_malformed;
+        self
+    }
+
+    fn log(&self, level: LogLevel, message: fmt::Arguments<'_>) {
+        if level <= self.log_level {
+            eprintln!("{}", message);
+        }
+    }
+
+    pub(crate) fn error(&self, message: fmt::Arguments<'_>) {
+        self.log(LogLevel::Error, message);
+    }
+
+    pub(crate) fn warn(&self, message: fmt::Arguments<'_>) {
+        self.log(LogLevel::Warn, message);
+    }
+}
+
+/// Counters collected while processing an input.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProcessingStats {
+    /// Records successfully applied.
+    pub applied: u64,
+    /// Records successfully applied by transaction kind, e.g. "deposit".
+    pub applied_by_kind: BTreeMap<String, u64>,
+    /// Records rejected by the processor.
+    pub rejected: u64,
+    /// Records rejected by the processor by error code, e.g. "E_LOCKED".
+    pub rejected_by_code: BTreeMap<String, u64>,
+    /// Records rejected by the processor by severity of their error.
+    pub rejected_by_severity: BTreeMap<Severity, u64>,
+    /// Rows which could not be read as a record.
+    pub malformed: u64,
+    /// Records skipped by the filter.
+    pub filtered: u64,
+    /// Records ignored because of `RunOptions::skip`.
+    pub skipped: u64,
+    /// Malformed rows copied by `MalformedRowPolicy::Quarantine`.
+    pub quarantined: u64,
+    /// Records whose idempotency key was already processed, they are neither
+    /// applied nor rejected.
+    pub idempotent_replays: u64,
+    /// Rejected records replaying an already processed transaction.
+    pub duplicate_replays: u64,
+    /// Rejected records reusing the id of a transaction with another type or
+    /// amount.
+    pub conflicting_duplicates: u64,
+    /// Position of the first and last data records covered by the run, the
+    /// first data record of the input is at position 1 and the header does
+    /// not count.
+    pub first_record: Option<u64>,
+    pub last_record: Option<u64>,
+    /// False when processing was stopped because of `fail_fast`, a
+    /// cancellation or a cache which could not spill.
+    pub completed: bool,
+    /// True when processing was stopped by the `CancellationToken`.
+    pub cancelled: bool,
+}
+
+impl ProcessingStats {
+    /// Stats of a run which has not seen any record yet.
+    pub fn new() -> Self {
+        ProcessingStats {
+            completed: true,
+            ..Self::default()
+        }
+    }
+
+    /// Adds the stats of a following run, e.g. for the next file of a
+    /// directory. The record positions are relative to each run.
+    pub fn merge(&mut self, other: &ProcessingStats) {
+        self.applied += other.applied;
+        for (kind, count) in &other.applied_by_kind {
+            *self.applied_by_kind.entry(kind.clone()).or_default() += count;
+        }
+        self.rejected += other.rejected;
+        for (code, count) in &other.rejected_by_code {
+            *self.rejected_by_code.entry(code.clone()).or_default() += count;
+        }
+        for (severity, count) in &other.rejected_by_severity {
+            *self.rejected_by_severity.entry(*severity).or_default() += count;
+        }
+        self.malformed += other.malformed;
+        self.filtered += other.filtered;
+        self.skipped += other.skipped;
+        self.quarantined += other.quarantined;
+        self.idempotent_replays += other.idempotent_replays;
+        self.duplicate_replays += other.duplicate_replays;
+        self.conflicting_duplicates += other.conflicting_duplicates;
+        self.first_record = self.first_record.or(other.first_record);
+        self.last_record = other.last_record.or(self.last_record);
+        self.completed &= other.completed;
+        self.cancelled |= other.cancelled;
+    }
+}
+
+impl fmt::Display for ProcessingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "applied: {}, rejected: {}, malformed: {}, filtered: {}, skipped: {}",
+            self.applied, self.rejected, self.malformed, self.filtered, self.skipped
+        )?;
+        if self.quarantined > 0 {
+            write!(f, ", quarantined: {}", self.quarantined)?;
+        }
+        if self.idempotent_replays > 0 {
+            write!(f, ", idempotent replays: {}", self.idempotent_replays)?;
+        }
+        if self.duplicate_replays > 0 || self.conflicting_duplicates > 0 {
+            write!(
+                f,
+                ", duplicate replays: {}, conflicting duplicates: {}",
+                self.duplicate_replays, self.conflicting_duplicates
+            )?;
+        }
+        if let (Some(first), Some(last)) = (self.first_record, self.last_record) {
+            write!(f, ", records: {}-{}", first, last)?;
+        }
+        if self.cancelled {
+            write!(f, ", cancelled")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the files to process for the input path: the path itself for a
+/// file, or every `*.csv` file of a directory in lexical order.
+pub fn input_files(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let entries = fs::read_dir(path)
+        .map_err(|err| format!("Could not read input dir {}: {}", path.display(), err))?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry =
+            entry.map_err(|err| format!("Could not read input dir {}: {}", path.display(), err))?;
+        let file = entry.path();
+        if file.is_file() && file.extension().is_some_and(|extension| extension == "csv") {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Format of the records of an input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Csv,
+    /// One JSON object per line.
+    Jsonl,
+}
+
+impl InputFormat {
+    /// Guesses the format from the extension of the input path, csv is
+    /// assumed unless the extension is jsonl or ndjson.
+    pub fn from_path(path: &str) -> Self {
+        match Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("jsonl") | Some("ndjson") => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" => Ok(InputFormat::Jsonl),
+            _ => Err(format!("Unknown input format {}", value)),
+        }
+    }
+}
+
+/// Feeds all the csv records read from input into the processor, errors are
+/// reported on stderr and processing goes on unless asked to fail fast. It
+/// only fails when the input can not be read at all, e.g. when a mapped
+/// column is missing from the header.
+pub fn run<R: Read, const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>(
+    processor: &mut TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
+    input: R,
+    options: &RunOptions,
+) -> Result<ProcessingStats, String> {
+    let records = read_csv_records(input, options)?;
+    Ok(run_records(processor, records, options))
+}
+
+/// Reads the csv records of input in the dialect and encoding of the options,
+/// the rows which can not be read as a record are returned as `MalformedRow`s
+/// carrying their raw bytes when they are to be quarantined. Without mapped
+/// columns a first row naming the fields is read as the header, the rows are
+/// read by position otherwise and a first row which can not be read is taken
+/// for a header of another shape.
+pub(crate) fn read_csv_records<R: Read>(
+    input: R,
+    options: &RunOptions,
+) -> Result<impl Iterator<Item = Result<TransactionRecord, MalformedRow>>, String> {
+    // The raw bytes are only needed to quarantine the malformed rows.
+    let recorded = match options.on_malformed {
+        MalformedRowPolicy::Quarantine { .. } => Some(Rc::new(RefCell::new(Recorded::default()))),
+        _ => None,
+    };
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .has_headers(options.csv.columns.is_some())
+        .delimiter(options.csv.delimiter)
+        .comment(options.csv.comment)
+        .from_reader(Recorder::new(
+            Decoder::new(input, options.encoding),
+            recorded.clone(),
+        ));
+
+    let mut record = csv::StringRecord::new();
+    // Set when the first row, read ahead to look for a header, is a record.
+    let mut pending = false;
+    let mut failed = false;
+    let headers = match &options.csv.columns {
+        Some(columns) => {
+            let headers = rdr
+                .headers()
+                .map_err(|err| format!("Could not read header because of: {}", err))?;
+            let translated = columns.translate_headers(headers)?;
+            if options.csv.strict_schema {
+                columns::check_known_headers(headers, &translated)?;
+            }
+            rdr.set_headers(translated.clone());
+            translated
+        }
+        None => {
+            let positional = columns::positional_headers();
+            let first = rdr.read_record(&mut record);
+            let headers = match &first {
+                Ok(true) => match columns::detect_header(&record) {
+                    Some(translated) => {
+                        if options.csv.strict_schema {
+                            columns::check_known_headers(&record, &translated)?;
+                        }
+                        translated
+                    }
+                    None => {
+                        pending = record
+                            .deserialize::<TransactionRecord>(Some(&positional))
+                            .is_ok();
+                        positional
+                    }
+                },
+                Ok(false) => positional,
+                Err(err) => {
+                    failed = matches!(err.kind(), csv::ErrorKind::Io(_));
+                    positional
+                }
+            };
+            if let (false, Some(recorded)) = (pending, &recorded) {
+                recorded.borrow_mut().consume(rdr.position().byte());
+            }
+            headers
+        }
+    };
+
+    let strict_fields = if options.csv.strict_schema {
+        Some(headers.len())
+    } else {
+        None
+    };
+    let records = std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let start = rdr.position().byte();
+        let read = if pending {
+            pending = false;
+            Ok(true)
+        } else {
+            rdr.read_record(&mut record)
+        };
+        let result = match read {
+            Ok(false) => return None,
+            Ok(true) => match strict_fields {
+                Some(fields) if record.len() > fields => Err(format!(
+                    "Row has {} fields, at most {} are expected",
+                    record.len(),
+                    fields
+                )),
+                _ => record
+                    .deserialize::<TransactionRecord>(Some(&headers))
+                    .map_err(describe_csv_error),
+            },
+            Err(err) => {
+                // The input can not be read anymore.
+                failed = matches!(err.kind(), csv::ErrorKind::Io(_));
+                Err(describe_csv_error(err))
+            }
+        };
+        let end = rdr.position().byte();
+        let raw = match (&result, &recorded) {
+            (Err(_), Some(recorded)) => {
+                let start = record.position().map_or(start, |position| position.byte());
+                recorded.borrow().get_row(start, end)
+            }
+            _ => Vec::new(),
+        };
+        if let Some(recorded) = &recorded {
+            recorded.borrow_mut().consume(end);
+        }
+        Some(result.map_err(|error| MalformedRow { error, raw }))
+    });
+    Ok(records)
+}
+
+/// Feeds all the JSON records read from input, one per line, into the
+/// processor. Unknown fields are ignored, blank lines are skipped and lines
+/// which can not be read are reported with their line number.
+pub fn process_jsonl_reader<R: BufRead, const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>(
+    processor: &mut TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
+    input: R,
+    options: &RunOptions,
+) -> Result<ProcessingStats, String> {
+    let records = read_jsonl_records(input, options);
+    Ok(run_records(processor, records, options))
+}
+
+/// Reads the JSON records of input, one per line, in the encoding of the
+/// options. The lines which can not be read are returned as `MalformedRow`s
+/// with their line number.
+pub(crate) fn read_jsonl_records<R: BufRead>(
+    input: R,
+    options: &RunOptions,
+) -> impl Iterator<Item = Result<TransactionRecord, MalformedRow>> {
+    let mut input = BufReader::new(Decoder::new(input, options.encoding));
+    let mut line_number = 0;
+    let mut failed = false;
+    std::iter::from_fn(move || loop {
+        if failed {
+            return None;
+        }
+        let mut raw = Vec::new();
+        line_number += 1;
+        let result = match input.read_until(b'\n', &mut raw) {
+            Ok(0) => return None,
+            Ok(_) => match std::str::from_utf8(&raw) {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => parse_json_record(line).map_err(|err| err.to_string()),
+                Err(err) => Err(err.to_string()),
+            },
+            Err(err) => {
+                failed = true;
+                Err(err.to_string())
+            }
+        };
+        return Some(result.map_err(|err| MalformedRow {
+            error: format!("line {}: {}", line_number, err),
+            raw,
+        }));
+    })
+}
+
+/// Parses a JSON record. An amount given as a JSON number is read from its
+/// text as written, e.g. `2.10`, by quoting it in the line, so it is never
+/// rounded through a float.
+fn parse_json_record(line: &str) -> serde_json::Result<TransactionRecord> {
+    #[derive(serde::Deserialize)]
+    struct RawAmount<'a> {
+        #[serde(borrow, default)]
+        amount: Option<&'a serde_json::value::RawValue>,
+    }
+
+    let number = serde_json::from_str::<RawAmount>(line)?
+        .amount
+        .map(|amount| amount.get())
+        .filter(|text| text.starts_with(|c: char| c == '-' || c.is_ascii_digit()));
+    match number {
+        Some(number) => {
+            // The raw value borrows its text from the line.
+            let start = number.as_ptr() as usize - line.as_ptr() as usize;
+            let end = start + number.len();
+            let quoted = format!("{}\"{}\"{}", &line[..start], number, &line[end..]);
+            serde_json::from_str(&quoted)
+        }
+        None => serde_json::from_str(line),
+    }
+}
+
+/// Describes a csv error, hinting at the encoding when the input is not UTF-8.
+fn describe_csv_error(err: csv::Error) -> String {
+    match err.kind() {
+        csv::ErrorKind::Utf8 { .. } => format!(
+            "{}, the input might not be UTF-8 encoded, e.g. latin-1 inputs need --input-encoding latin1",
+            err
+        ),
+        _ => err.to_string(),
+    }
+}
+
+/// Feeds the data records of an input into the processor, the iterator is only
+/// advanced until the last record to take.
+fn run_records<I, const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>(
+    processor: &mut TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
+    mut records: I,
+    options: &RunOptions,
+) -> ProcessingStats
+where
+    I: Iterator<Item = Result<TransactionRecord, MalformedRow>>,
+{
+    let mut stats = ProcessingStats::default();
+    // A take reaching past the largest position is the same as no take.
+    let end = options.take.map(|take| options.skip.saturating_add(take));
+    let mut record_position = 0;
+    // Stop as soon as the last record to take is processed instead of reading
+    // the rest of the input.
+    while end.is_none_or(|end| record_position < end) {
+        if let Some(token) = &options.cancellation {
+            if record_position % token.check_interval() == 0 && token.is_cancelled() {
+                options.warn(format_args!("Cancelled after {} records", record_position));
+                stats.cancelled = true;
+                return stats;
+            }
+        }
+        if let Some(chunk_size) = options.chunk_size {
+            if record_position > 0 && record_position % chunk_size == 0 {
+                processor.flush_caches();
+                if let Some(err) = processor.spill_error() {
+                    options.error(format_args!("Failed with error: {}", err));
+                    return stats;
+                }
+            }
+        }
+        let result = match records.next() {
+            Some(result) => result,
+            None => break,
+        };
+        record_position += 1;
+        if record_position <= options.skip {
+            stats.skipped += 1;
+            continue;
+        }
+        stats.first_record.get_or_insert(record_position);
+        stats.last_record = Some(record_position);
+
+        match result {
+            Ok(transaction_record) => {
+                let mut copy: TransactionRecord = transaction_record;
+                if let (Some(symbol), Some(amount)) = (&options.currency_symbol, &copy.amount) {
+                    copy.amount = Some(type_defs::strip_currency_symbol(amount, symbol).to_owned());
+                }
+                if !options.filter.matches(&copy) {
+                    stats.filtered += 1;
+                    continue;
+                }
+                // Intentionally continue processing even in case of errors,
+                // unless asked to fail fast.
+                match processor.process_transaction_ref(&copy) {
+                    Ok(applied) => {
+                        stats.applied += 1;
+                        *stats
+                            .applied_by_kind
+                            .entry(applied.kind.to_string())
+                            .or_default() += 1;
+                    }
+                    Err(ProcessingError::IdempotentReplay(_)) => stats.idempotent_replays += 1,
+                    Err(err) => {
+                        stats.rejected += 1;
+                        *stats
+                            .rejected_by_code
+                            .entry(err.code().to_owned())
+                            .or_default() += 1;
+                        *stats
+                            .rejected_by_severity
+                            .entry(err.severity())
+                            .or_default() += 1;
+                        let conflicting =
+                            matches!(err, ProcessingError::ConflictingDuplicate { .. });
+                        match err {
+                            ProcessingError::DuplicateReplay => stats.duplicate_replays += 1,
+                            ProcessingError::ConflictingDuplicate { .. } => {
+                                stats.conflicting_duplicates += 1
+                            }
+                            _ => {}
+                        }
+                        if options.fail_fast
+                            || (conflicting && options.fail_on_conflicting_duplicate)
+                            || err.severity() == Severity::Fatal
+                        {
+                            options.error(format_args!(
+                                "Failed with error {}: {} for record: {:?}",
+                                err.code(),
+                                err,
+                                copy
+                            ));
+                            return stats;
+                        }
+                        options.warn(format_args!(
+                            "Ignoring error {}: {} for record: {:?}",
+                            err.code(),
+                            err,
+                            copy
+                        ));
+                    }
+                }
+                if let Some(warning) = processor.take_memory_warning() {
+                    options.warn(format_args!("{}", warning));
+                }
+                if let Some(err) = processor.spill_error() {
+                    options.error(format_args!("Failed with error: {}", err));
+                    return stats;
+                }
+            }
+            Err(row) => {
+                stats.malformed += 1;
+                match handle_malformed(&row, options) {
+                    MalformedOutcome::Ignored => {}
+                    MalformedOutcome::Quarantined => stats.quarantined += 1,
+                    MalformedOutcome::Failed => return stats,
+                }
+            }
+        }
+    }
+    if !flush_quarantine(options) {
+        return stats;
+    }
+    stats.completed = true;
+    stats
+}
+
+/// What became of a malformed row.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MalformedOutcome {
+    Ignored,
+    Quarantined,
+    /// The run has to stop.
+    Failed,
+}
+
+/// Applies the `MalformedRowPolicy` of the options to a row which could not
+/// be read as a record, `fail_fast` also stops at the skipped rows.
+pub(crate) fn handle_malformed(row: &MalformedRow, options: &RunOptions) -> MalformedOutcome {
+    match &options.on_malformed {
+        MalformedRowPolicy::Quarantine { writer } => {
+            let mut writer = writer.lock().unwrap_or_else(|err| err.into_inner());
+            if let Err(err) = writer.write_all(&row.raw) {
+                options.error(format_args!(
+                    "Failed with error {}, could not quarantine: {}",
+                    row.error, err
+                ));
+                return MalformedOutcome::Failed;
+            }
+            options.warn(format_args!("Quarantined row with error {}", row.error));
+            MalformedOutcome::Quarantined
+        }
+        MalformedRowPolicy::Abort => {
+            options.error(format_args!("Failed with error {}", row.error));
+            MalformedOutcome::Failed
+        }
+        MalformedRowPolicy::Skip if options.fail_fast => {
+            options.error(format_args!("Failed with error {}", row.error));
+            MalformedOutcome::Failed
+        }
+        MalformedRowPolicy::Skip => {
+            options.warn(format_args!("Ignoring error {}", row.error));
+            MalformedOutcome::Ignored
+        }
+    }
+}
+
+/// Flushes the quarantined rows at the end of a run, returns false when they
+/// could not be written.
+pub(crate) fn flush_quarantine(options: &RunOptions) -> bool {
+    if let MalformedRowPolicy::Quarantine { writer } = &options.on_malformed {
+        let mut writer = writer.lock().unwrap_or_else(|err| err.into_inner());
+        if let Err(err) = writer.flush() {
+            options.error(format_args!(
+                "Could not flush quarantined rows because of: {}",
+                err
+            ));
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{
+        OutputOptions, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE, PANIC_MARKER, PARTIAL_MARKER,
+    };
+    use crate::transaction_cache::SpillFailurePolicy;
+    use crate::type_defs::{Amount, ClientId};
+    use std::sync::{Arc, Mutex};
+
+    type Processor = TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>;
+
+    const INPUT: &str = "type,client,tx,amount
+deposit,1,1,2.0
+deposit,2,2,3.0
+dispute,1,1,
+withdrawal,2,3,5.0
+not a record
+";
+
+    // Test that every row is accounted for in the stats.
+    #[test]
+    fn test_run_stats() {
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, INPUT.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!(
+            stats,
+            ProcessingStats {
+                applied: 3,
+                applied_by_kind: BTreeMap::from([
+                    ("deposit".to_owned(), 2),
+                    ("dispute".to_owned(), 1)
+                ]),
+                rejected: 1,
+                rejected_by_code: BTreeMap::from([("E_INSUFFICIENT".to_owned(), 1)]),
+                rejected_by_severity: BTreeMap::from([(Severity::Warn, 1)]),
+                malformed: 1,
+                filtered: 0,
+                skipped: 0,
+                quarantined: 0,
+                idempotent_replays: 0,
+                duplicate_replays: 0,
+                conflicting_duplicates: 0,
+                first_record: Some(1),
+                last_record: Some(5),
+                completed: true,
+                cancelled: false,
+            }
+        );
+    }
+
+    // Test that a fatal error, here a cache dir which went away with the
+    // strict spill policy, stops the run even without fail_fast.
+    #[test]
+    fn test_run_fatal() {
+        let base_dir = tempdir::TempDir::new("runner_fatal").unwrap();
+        let mut processor = Processor::new()
+            .with_cache_dir(base_dir.path(), false)
+            .unwrap()
+            .with_spill_failure_policy(SpillFailurePolicy::Abort);
+        std::fs::remove_dir(processor.cache_dir().unwrap()).unwrap();
+        let stats = run(&mut processor, INPUT.as_bytes(), &RunOptions::default()).unwrap();
+        assert!(!stats.completed);
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(
+            stats.rejected_by_severity,
+            BTreeMap::from([(Severity::Fatal, 1)])
+        );
+    }
+
+    // Test that the caches are flushed after every chunk of records, leaving
+    // nothing in memory at the end of an input made of whole chunks, and that
+    // the flushed transactions are loaded back for the disputes.
+    #[test]
+    fn test_run_chunks() {
+        let base_dir = tempdir::TempDir::new("runner_chunks").unwrap();
+        let mut processor = Processor::new()
+            .with_cache_dir(base_dir.path(), false)
+            .unwrap();
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=9_990 {
+            input.push_str(&format!("deposit,{},{},1.0\n", tx % 10, tx));
+        }
+        for tx in 1..=10 {
+            input.push_str(&format!("dispute,{},{},\n", tx % 10, tx));
+        }
+        let options = RunOptions {
+            chunk_size: Some(1_000),
+            ..RunOptions::default()
+        };
+        let stats = run(&mut processor, input.as_bytes(), &options).unwrap();
+        assert!(stats.completed);
+        assert_eq!(stats.applied, 10_000);
+        let cache_stats = processor.cache_stats();
+        assert_eq!(cache_stats.flushes, 10);
+        assert_eq!(cache_stats.transactions, 0);
+        assert_eq!(cache_stats.disputes, 0);
+
+        let mut output = Vec::new();
+        processor
+            .serialize_to(&mut output, &Default::default())
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\n1,998.0,1.0,999.0,false\n"), "{}", output);
+    }
+
+    // Test that filtered records are counted and never reach the processor.
+    #[test]
+    fn test_run_filtered() {
+        let mut processor = Processor::new();
+        let options = RunOptions {
+            filter: RecordFilter::new().with_clients(vec![ClientId(1)]),
+            ..RunOptions::default()
+        };
+        let stats = run(&mut processor, INPUT.as_bytes(), &options).unwrap();
+        assert_eq!(stats.applied, 2);
+        assert_eq!(stats.filtered, 2);
+
+        let mut output = Vec::new();
+        processor
+            .serialize_to(&mut output, &Default::default())
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("1,0.0000,2.0,2.0,false"));
+        assert!(!output.contains("\n2,"));
+    }
+
+    // Test that a dispute is rejected when the type filter excludes the
+    // deposit it references.
+    #[test]
+    fn test_run_dispute_without_deposit() {
+        let mut processor = Processor::new();
+        let options = RunOptions {
+            filter: RecordFilter::new()
+                .with_clients(vec![ClientId(1)])
+                .with_types(vec!["dispute"]),
+            ..RunOptions::default()
+        };
+        let stats = run(&mut processor, INPUT.as_bytes(), &options).unwrap();
+        assert_eq!(stats.applied, 0);
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.filtered, 3);
+    }
+
+    // Test that the currency symbol is only stripped when configured.
+    #[test]
+    fn test_run_currency_symbol() {
+        let input = "deposit,1,1,$2.0\ndeposit,1,2,3.0$\n";
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, input.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!(stats.rejected, 2);
+
+        let mut processor = Processor::new();
+        let options = RunOptions {
+            currency_symbol: Some("$".to_owned()),
+            ..RunOptions::default()
+        };
+        let stats = run(&mut processor, input.as_bytes(), &options).unwrap();
+        assert_eq!(stats.applied, 2);
+    }
+
+    // Test that skip and take select a range of data records, the header
+    // being ignored when computing the positions.
+    #[test]
+    fn test_run_skip_take() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=10 {
+            input.push_str(&format!("deposit,1,{},{}\n", tx, tx));
+        }
+        let mut processor = Processor::new();
+        let options = RunOptions {
+            skip: 2,
+            take: Some(3),
+            ..RunOptions::default()
+        };
+        let stats = run(&mut processor, input.as_bytes(), &options).unwrap();
+        assert_eq!(stats.applied, 3);
+        assert_eq!(stats.skipped, 2);
+        assert_eq!((stats.first_record, stats.last_record), (Some(3), Some(5)));
+        assert!(stats.completed);
+        assert_eq!(stats.to_string().split(", ").last(), Some("records: 3-5"));
+
+        // 3 + 4 + 5
+        let mut output = Vec::new();
+        processor
+            .serialize_to(&mut output, &Default::default())
+            .unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("1,12,0.0000,12,false"));
+
+        // A take past the largest position reads every record after the skip.
+        let options = RunOptions {
+            skip: 8,
+            take: Some(u64::MAX),
+            ..RunOptions::default()
+        };
+        let stats = run(&mut Processor::new(), input.as_bytes(), &options).unwrap();
+        assert_eq!((stats.applied, stats.skipped), (2, 8));
+        assert!(stats.completed);
+    }
+
+    // Test that semicolon and tab separated inputs with comments give the same
+    // balances as the comma separated one.
+    #[test]
+    fn test_run_csv_dialects() {
+        let balances = |input: &str, csv: CsvOptions| {
+            let mut processor = Processor::new();
+            let options = RunOptions {
+                csv,
+                ..RunOptions::default()
+            };
+            let stats = run(&mut processor, input.as_bytes(), &options).unwrap();
+            assert_eq!(stats.applied, 3);
+            let mut output = Vec::new();
+            processor
+                .serialize_to(&mut output, &Default::default())
+                .unwrap();
+            let mut lines: Vec<String> = String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .map(str::to_owned)
+                .collect();
+            lines.sort();
+            lines
+        };
+        let semicolon = CsvOptions {
+            delimiter: b';',
+            comment: Some(b'#'),
+            columns: None,
+            strict_schema: false,
+        };
+        let tab = CsvOptions {
+            comment: Some(b'#'),
+            ..CsvOptions::tsv()
+        };
+        let baseline = balances(INPUT, CsvOptions::default());
+        assert_eq!(
+            balances(
+                "# semicolon\ntype;client;tx;amount\ndeposit;1;1;2.0\ndeposit;2;2;3.0\n\
+                 # comment\ndispute;1;1;\nwithdrawal;2;3;5.0\n",
+                semicolon
+            ),
+            baseline
+        );
+        assert_eq!(
+            balances(
+                "type\tclient\ttx\tamount\ndeposit\t1\t1\t2.0\n# comment\n\
+                 deposit\t2\t2\t3.0\ndispute\t1\t1\nwithdrawal\t2\t3\t5.0\n",
+                tab
+            ),
+            baseline
+        );
+    }
+
+    // Test that a wrong delimiter makes every row malformed instead of failing.
+    #[test]
+    fn test_run_wrong_delimiter() {
+        let mut processor = Processor::new();
+        let input = "deposit;1;1;2.0\ndeposit;2;2;3.0\n";
+        let stats = run(&mut processor, input.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!(stats.applied, 0);
+        assert_eq!(stats.malformed, 1);
+        assert!(stats.completed);
+    }
+
+    // Test that a headerless row with the memo column is read in both
+    // modes while a row with more fields than a record, or than the header, is
+    // only malformed with a strict schema, and that an unknown header name
+    // fails a strict run up front.
+    #[test]
+    fn test_run_strict_schema() {
+        let headerless = "deposit,1,1,2.0,rent\n\
+                          deposit,1,2,3.0,rent,drift\n";
+        let input = format!("type,client,tx,amount\n{}", headerless);
+        let stats = |input: &str, strict_schema: bool, columns: Option<ColumnMapping>| {
+            let options = RunOptions {
+                csv: CsvOptions {
+                    columns,
+                    strict_schema,
+                    ..CsvOptions::default()
+                },
+                ..RunOptions::default()
+            };
+            run(&mut Processor::new(), input.as_bytes(), &options)
+        };
+        let lenient = stats(headerless, false, None).unwrap();
+        assert_eq!((lenient.applied, lenient.malformed), (2, 0));
+        let strict = stats(headerless, true, None).unwrap();
+        assert_eq!((strict.applied, strict.malformed), (1, 1));
+
+        // Against the header, the rows have more fields than columns.
+        let lenient = stats(&input, false, None).unwrap();
+        assert_eq!((lenient.applied, lenient.malformed), (2, 0));
+        let strict = stats(&input, true, None).unwrap();
+        assert_eq!((strict.applied, strict.malformed), (0, 2));
+        let strict = stats(&input, true, Some(ColumnMapping::default())).unwrap();
+        assert_eq!((strict.applied, strict.malformed), (0, 2));
+
+        let input = "type,client,tx,amount,memo,region\ndeposit,1,1,2.0,rent,eu\n";
+        let options = |strict_schema| RunOptions {
+            csv: CsvOptions {
+                columns: Some(ColumnMapping::default()),
+                strict_schema,
+                ..CsvOptions::default()
+            },
+            ..RunOptions::default()
+        };
+        let lenient = run(&mut Processor::new(), input.as_bytes(), &options(false)).unwrap();
+        assert_eq!(lenient.applied, 1);
+        assert_eq!(
+            run(&mut Processor::new(), input.as_bytes(), &options(true)).err(),
+            Some(
+                "Unknown column region, expected the columns of type, client, tx, amount, \
+                 idempotency_key, timestamp, memo"
+                    .to_owned()
+            )
+        );
+    }
+
+    // Test that the transaction ids above u32::MAX are processed and that an
+    // id above u64::MAX is a malformed row rather than truncated.
+    #[test]
+    fn test_run_wide_tx_ids() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,4294967295,2.0\n\
+                     deposit,1,4294967296,3.0\n\
+                     deposit,1,18446744073709551615,4.0\n\
+                     deposit,1,18446744073709551616,5.0\n\
+                     dispute,1,4294967296,\n";
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, input.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!((stats.applied, stats.malformed), (4, 1));
+        let view = processor.client_view(ClientId(1)).unwrap();
+        assert_eq!(view.available, "6.0".parse::<Amount>().unwrap());
+        assert_eq!(view.held, "3.0".parse::<Amount>().unwrap());
+    }
+
+    // Test that the client ids above u16::MAX are processed and that an id
+    // above u32::MAX is a malformed row rather than truncated.
+    #[test]
+    fn test_run_wide_client_ids() {
+        let input = "type,client,tx,amount\n\
+                     deposit,100000,1,2.0\n\
+                     deposit,4294967295,2,3.0\n\
+                     deposit,4294967296,3,4.0\n";
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, input.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!((stats.applied, stats.malformed), (2, 1));
+        let view = processor.client_view(ClientId(100_000)).unwrap();
+        assert_eq!(view.total, "2.0".parse::<Amount>().unwrap());
+        assert!(processor.client_view(ClientId(u32::MAX)).is_some());
+        assert!(processor.client_view(ClientId(0)).is_none());
+    }
+
+    // Test that a cache dir which can not be written into, here a read-only
+    // file in its place so it fails even as root, only switches the caches to
+    // memory and every record is still processed.
+    #[test]
+    fn test_run_read_only_cache_dir() {
+        let base_dir = tempdir::TempDir::new("runner_read_only").unwrap();
+        let mut processor = Processor::new()
+            .with_cache_dir(base_dir.path(), false)
+            .unwrap();
+        let cache_dir = processor.cache_dir().unwrap().to_path_buf();
+        std::fs::remove_dir(&cache_dir).unwrap();
+        std::fs::write(&cache_dir, "").unwrap();
+        let mut permissions = std::fs::metadata(&cache_dir).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&cache_dir, permissions).unwrap();
+
+        let input = "type,client,tx,amount,idempotency_key\n\
+                     deposit,1,1,2.0,key-1\n\
+                     deposit,2,2,3.0,\n\
+                     dispute,1,1,,\n";
+        let stats = run(&mut processor, input.as_bytes(), &RunOptions::default()).unwrap();
+        assert!(stats.completed);
+        assert_eq!((stats.applied, stats.rejected), (3, 0));
+        assert!(processor.memory_only());
+        // The warning was taken and logged by the run.
+        assert_eq!(processor.take_memory_warning(), None);
+        let view = processor.client_view(ClientId(1)).unwrap();
+        assert_eq!(view.held, "2.0".parse::<Amount>().unwrap());
+    }
+
+    // Test that JSON lines go through the same processing as csv records and
+    // that blank lines are not counted.
+    #[test]
+    fn test_process_jsonl_reader() {
+        let input = r#"{"type":"deposit","client":1,"tx":1,"amount":"2.0","extra":true}
+
+{"type":"dispute","client":1,"tx":1}
+{"type":"deposit","client":1,"tx":2,"amount":2.0}
+{"type":"deposit","client":1,"tx":3,"amount": 12345678901234.5678 }
+{"type":"deposit","client":1,"tx":4,"amount":true}
+"#;
+        let mut processor = Processor::new();
+        let stats =
+            process_jsonl_reader(&mut processor, input.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!(stats.applied, 4);
+        assert_eq!(stats.malformed, 1);
+        assert_eq!(stats.last_record, Some(5));
+        let view = processor.client_view(ClientId(1)).unwrap();
+        assert_eq!(view.held, "2.0".parse::<Amount>().unwrap());
+        assert_eq!(
+            view.available,
+            "12345678901236.5678".parse::<Amount>().unwrap()
+        );
+        assert_eq!(InputFormat::from_path("input.ndjson"), InputFormat::Jsonl);
+        assert_eq!(InputFormat::from_path("input.csv"), InputFormat::Csv);
+        assert_eq!("jsonl".parse(), Ok(InputFormat::Jsonl));
+    }
+
+    // Test that malformed rows are quarantined verbatim and that replaying
+    // them once fixed gives the missing balances.
+    #[test]
+    fn test_run_quarantine() {
+        let input = "type,client,tx,amount\r\n\
+                     deposit,1,1,2.0\r\n\
+                     deposit, two ,2,\"3.0\"\r\n\
+                     # comment\r\n\
+                     deposit,3,3,1.0\r\n\
+                     withdrawal,1,4\r\n\
+                     deposit,1,\"5\"x,1.0";
+        let quarantined = Arc::new(Mutex::new(Vec::new()));
+        let options = RunOptions {
+            csv: CsvOptions {
+                comment: Some(b'#'),
+                ..CsvOptions::default()
+            },
+            ..RunOptions::default()
+        }
+        .with_malformed_row_policy(MalformedRowPolicy::Quarantine {
+            writer: quarantined.clone(),
+        });
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, input.as_bytes(), &options).unwrap();
+        assert_eq!(stats.applied, 2);
+        assert_eq!(stats.rejected, 1);
+        assert_eq!((stats.malformed, stats.quarantined), (2, 2));
+        assert!(stats.completed);
+
+        let quarantined = String::from_utf8(quarantined.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            quarantined,
+            "deposit, two ,2,\"3.0\"\r\ndeposit,1,\"5\"x,1.0"
+        );
+
+        let fixed = quarantined.replace("two", "2").replace("\"5\"x", "5");
+        let stats = run(&mut processor, fixed.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!(stats.applied, 2);
+        let mut output = Vec::new();
+        processor
+            .serialize_to(&mut output, &Default::default())
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("1,3.0,0.0000,3.0,false"));
+        assert!(output.contains("2,3.0,0.0000,3.0,false"));
+    }
+
+    // Test that the abort policy stops at the first malformed row, even
+    // without fail_fast.
+    #[test]
+    fn test_run_abort_on_malformed() {
+        let input = "deposit,1,1,2.0\ndeposit,x,2,1.0\ndeposit,1,3,1.0\n";
+        let options = RunOptions::default().with_malformed_row_policy(MalformedRowPolicy::Abort);
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, input.as_bytes(), &options).unwrap();
+        assert_eq!((stats.applied, stats.malformed), (1, 1));
+        assert!(!stats.completed);
+    }
+
+    // Test that replayed and conflicting duplicates are counted apart and
+    // that only the conflicting ones stop the run when asked to.
+    #[test]
+    fn test_run_duplicates() {
+        let input = "deposit,1,1,2.0\ndeposit,1,1,2.0\ndeposit,1,1,3.0\ndeposit,1,2,1.0\n";
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, input.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!((stats.applied, stats.rejected), (2, 2));
+        assert_eq!(
+            (stats.duplicate_replays, stats.conflicting_duplicates),
+            (1, 1)
+        );
+        assert!(stats.completed);
+
+        let options = RunOptions {
+            fail_on_conflicting_duplicate: true,
+            ..RunOptions::default()
+        };
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, input.as_bytes(), &options).unwrap();
+        assert_eq!((stats.applied, stats.rejected), (1, 2));
+        assert_eq!(stats.last_record, Some(3));
+        assert!(!stats.completed);
+    }
+
+    // Test that replaying an input with idempotency keys counts every record
+    // as an idempotent replay.
+    #[test]
+    fn test_run_idempotency_keys() {
+        let input = "type,client,tx,amount,idempotency_key\n\
+                     deposit,1,1,2.0,a\nwithdrawal,1,2,1.0,b\ndeposit,1,3,1.0\n";
+        let retry = "type,client,tx,amount,idempotency_key\n\
+                     deposit,1,7,2.0,a\nwithdrawal,1,8,1.0,b\n";
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, input.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!((stats.applied, stats.idempotent_replays), (3, 0));
+        let stats = run(&mut processor, retry.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!(
+            (stats.applied, stats.rejected, stats.idempotent_replays),
+            (0, 0, 2)
+        );
+        assert!(stats.to_string().contains("idempotent replays: 2"));
+    }
+
+    // Test that an extra column of a headerless input is never read as an
+    // idempotency key, only a column named after it is.
+    #[test]
+    fn test_run_extra_positional_column() {
+        let input = "deposit,1,1,2.0,batch-7\n\
+                     deposit,2,2,3.0,batch-7\n\
+                     deposit,3,3,4.0,batch-7\n";
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, input.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!((stats.applied, stats.idempotent_replays), (3, 0));
+        assert!(processor.client_view(ClientId(2)).is_some());
+
+        let input = format!("type,client,tx,amount,idempotency_key\n{}", input);
+        let mut processor = Processor::new();
+        let stats = run(&mut processor, input.as_bytes(), &RunOptions::default()).unwrap();
+        assert_eq!((stats.applied, stats.idempotent_replays), (1, 2));
+    }
+
+    // Test that malformed JSON lines are quarantined verbatim.
+    #[test]
+    fn test_process_jsonl_quarantine() {
+        let input = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"2.0\"}\n\
+                     {\"type\":\"deposit\",\"client\":\"2\",\"tx\":2}\r\n";
+        let quarantined = Arc::new(Mutex::new(Vec::new()));
+        let options =
+            RunOptions::default().with_malformed_row_policy(MalformedRowPolicy::Quarantine {
+                writer: quarantined.clone(),
+            });
+        let mut processor = Processor::new();
+        let stats = process_jsonl_reader(&mut processor, input.as_bytes(), &options).unwrap();
+        assert_eq!(stats.quarantined, 1);
+        assert_eq!(
+            *quarantined.lock().unwrap(),
+            b"{\"type\":\"deposit\",\"client\":\"2\",\"tx\":2}\r\n"
+        );
+    }
+
+    // Test that only the csv files of a directory are listed, in lexical
+    // order.
+    #[test]
+    fn test_input_files() {
+        let dir = tempdir::TempDir::new("runner_input_files").unwrap();
+        assert!(input_files(dir.path()).unwrap().is_empty());
+        for name in &[
+            "2021-01-02.csv",
+            "2021-01-01.csv",
+            "notes.txt",
+            "2021-01-10.csv",
+        ] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        fs::create_dir(dir.path().join("nested.csv")).unwrap();
+        let names: Vec<_> = input_files(dir.path())
+            .unwrap()
+            .iter()
+            .map(|file| file.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["2021-01-01.csv", "2021-01-02.csv", "2021-01-10.csv"]
+        );
+
+        let file = dir.path().join("notes.txt");
+        assert_eq!(input_files(&file).unwrap(), vec![file]);
+    }
+
+    /// Observer cancelling the token once the given number of deposits were
+    /// applied.
+    struct CancelAfter(CancellationToken, u32);
+
+    impl crate::observer::ProcessorObserver for CancelAfter {
+        fn on_deposit(&mut self, _: ClientId, _: crate::type_defs::TransactionId, _: Amount) {
+            self.1 = self.1.saturating_sub(1);
+            if self.1 == 0 {
+                self.0.cancel();
+            }
+        }
+    }
+
+    // Test that a cancelled run stops reading the input and that its balances
+    // are marked as partial.
+    #[test]
+    fn test_cancellation() {
+        let input = "deposit,1,1,1.0\ndeposit,2,2,2.0\ndeposit,3,3,3.0\ndeposit,4,4,4.0\n";
+        let token = CancellationToken::new().with_check_interval(1);
+        let mut processor = Processor::new().with_observer(Box::new(CancelAfter(token.clone(), 2)));
+        let options = RunOptions {
+            cancellation: Some(token),
+            ..RunOptions::default()
+        };
+        let stats = run(&mut processor, input.as_bytes(), &options).unwrap();
+        assert_eq!(stats.applied, 2);
+        assert_eq!(stats.last_record, Some(2));
+        assert!(stats.cancelled);
+        assert!(!stats.completed);
+        assert!(stats.to_string().ends_with(", cancelled"));
+
+        let mut output = Vec::new();
+        let output_options = OutputOptions {
+            partial: true,
+            ..OutputOptions::default()
+        };
+        processor
+            .serialize_to(&mut output, &output_options)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!(
+                "client,available,held,total,locked\n1,1.0,0.0000,1.0,false\n2,2.0,0.0000,2.0,false\n{}\n",
+                PARTIAL_MARKER
+            )
+        );
+        let mut output = Vec::new();
+        let output_options = OutputOptions {
+            partial: true,
+            panicked: true,
+            ..OutputOptions::default()
+        };
+        processor
+            .serialize_to(&mut output, &output_options)
+            .unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with(&format!("2,2.0,0.0000,2.0,false\n{}\n", PANIC_MARKER)));
+
+        // The token is only checked every check interval.
+        let token = CancellationToken::new().with_check_interval(3);
+        let mut processor = Processor::new().with_observer(Box::new(CancelAfter(token.clone(), 1)));
+        let options = RunOptions {
+            cancellation: Some(token),
+            ..RunOptions::default()
+        };
+        let stats = run(&mut processor, input.as_bytes(), &options).unwrap();
+        assert_eq!(stats.applied, 3);
+    }
+}
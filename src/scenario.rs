@@ -0,0 +1,351 @@
+use crate::type_defs::TransactionRecord;
+
+/// Weights of the kinds of records produced by a `Generator`, each record is
+/// of a kind with a probability proportional to its weight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperationMix {
+    pub deposit: u32,
+    pub withdrawal: u32,
+    pub dispute: u32,
+    pub resolve: u32,
+    pub chargeback: u32,
+}
+
+impl Default for OperationMix {
+    fn default() -> Self {
+        OperationMix {
+            deposit: 50,
+            withdrawal: 30,
+            dispute: 10,
+            resolve: 7,
+            chargeback: 3,
+        }
+    }
+}
+
+impl OperationMix {
+    fn total(&self) -> u64 {
+        [
+            self.deposit,
+            self.withdrawal,
+            self.dispute,
+            self.resolve,
+            self.chargeback,
+        ]
+        .iter()
+        .map(|weight| *weight as u64)
+        .sum()
+    }
+}
+
+/// splitmix64, small and good enough to draw scenarios, the crate does not
+/// depend on a random number generator.
+#[derive(Debug, Clone)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Number in 0..bound, bound must not be 0.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+/// Infinite sequence of records drawn from a seed, for property style tests
+/// over many scenarios, e.g. `Generator::new(7).with_clients(10).take(1000)`.
+///
+/// The same seed and settings always give the same records. The state of the
+/// generator is kept in vectors indexed by client, so nothing depends on the
+/// iteration order of a HashMap.
+///
+/// With referential validity, the default, disputes reference a deposit of
+/// the same client and resolves and chargebacks a deposit disputed by a
+/// previous record, a record of these kinds for a client without such a
+/// deposit is drawn as a deposit instead. The records may still be rejected,
+/// e.g. a withdrawal above the available funds or any record of a locked
+/// client. Without it, they reference any transaction id generated so far or
+/// the next one, of any client.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    rng: SplitMix64,
+    clients: u32,
+    mix: OperationMix,
+    referential_validity: bool,
+    next_tx: u64,
+    /// Deposits of each client which were not charged back, by client index.
+    deposits: Vec<Vec<u64>>,
+    /// Deposits of each client disputed and not yet resolved or charged back.
+    disputed: Vec<Vec<u64>>,
+}
+
+impl Generator {
+    /// Generator of records for 100 clients with the default mix.
+    pub fn new(seed: u64) -> Self {
+        Generator {
+            rng: SplitMix64(seed),
+            clients: 100,
+            mix: OperationMix::default(),
+            referential_validity: true,
+            next_tx: 1,
+            deposits: Vec::new(),
+            disputed: Vec::new(),
+        }
+    }
+
+    /// Sets the number of clients, with ids from 1 to clients.
+    pub fn with_clients(mut self, clients: u32) -> Self {
+        assert!(clients > 0, "a scenario needs at least one client");
+        self.clients = clients;
+        self
+    }
+
+    /// Sets the weights of the kinds of records.
+    pub fn with_mix(mut self, mix: OperationMix) -> Self {
+        assert!(mix.total() > 0, "the operation mix has no weight");
+        self.mix = mix;
+        self
+    }
+
+    /// Sets whether disputes, resolves and chargebacks reference the deposits
+    /// of their client, see `Generator`.
+    pub fn with_referential_validity(mut self, referential_validity: bool) -> Self {
+        self.referential_validity = referential_validity;
+        self
+    }
+
+    /// Amount between 0.0001 and max units, with 4 fractional digits.
+    fn amount(&mut self, max: u64) -> String {
+        let value = 1 + self.rng.below(max * 10_000);
+        format!("{}.{:04}", value / 10_000, value % 10_000)
+    }
+
+    fn new_tx(&mut self) -> u64 {
+        let tx = self.next_tx;
+        self.next_tx += 1;
+        tx
+    }
+
+    /// Transaction id referenced by a dispute, resolve or chargeback without
+    /// referential validity.
+    fn any_tx(&mut self) -> u64 {
+        1 + self.rng.below(self.next_tx)
+    }
+
+    fn record(
+        transaction_type: &str,
+        client: u32,
+        tx: u64,
+        amount: Option<String>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type: transaction_type.to_owned(),
+            client,
+            tx,
+            amount,
+            ..Default::default()
+        }
+    }
+
+    fn deposit(&mut self, index: usize) -> TransactionRecord {
+        let tx = self.new_tx();
+        self.deposits[index].push(tx);
+        let amount = self.amount(1_000);
+        Self::record("deposit", index as u32 + 1, tx, Some(amount))
+    }
+}
+
+impl Iterator for Generator {
+    type Item = TransactionRecord;
+
+    fn next(&mut self) -> Option<TransactionRecord> {
+        if self.deposits.is_empty() {
+            self.deposits = vec![Vec::new(); self.clients as usize];
+            self.disputed = vec![Vec::new(); self.clients as usize];
+        }
+        let index = self.rng.below(self.clients as u64) as usize;
+        let client = index as u32 + 1;
+        let mut draw = self.rng.below(self.mix.total());
+        let mut kind = 0;
+        for weight in [
+            self.mix.deposit,
+            self.mix.withdrawal,
+            self.mix.dispute,
+            self.mix.resolve,
+            self.mix.chargeback,
+        ] {
+            if draw < weight as u64 {
+                break;
+            }
+            draw -= weight as u64;
+            kind += 1;
+        }
+
+        let record = match kind {
+            0 => self.deposit(index),
+            1 => {
+                let tx = self.new_tx();
+                let amount = self.amount(500);
+                Self::record("withdrawal", client, tx, Some(amount))
+            }
+            2 if !self.referential_validity => {
+                let tx = self.any_tx();
+                Self::record("dispute", client, tx, None)
+            }
+            2 => {
+                if self.deposits[index].is_empty() {
+                    return Some(self.deposit(index));
+                }
+                let position = self.rng.below(self.deposits[index].len() as u64) as usize;
+                let tx = self.deposits[index][position];
+                if !self.disputed[index].contains(&tx) {
+                    self.disputed[index].push(tx);
+                }
+                Self::record("dispute", client, tx, None)
+            }
+            _ => {
+                let transaction_type = if kind == 3 { "resolve" } else { "chargeback" };
+                if !self.referential_validity {
+                    let tx = self.any_tx();
+                    return Some(Self::record(transaction_type, client, tx, None));
+                }
+                if self.disputed[index].is_empty() {
+                    return Some(self.deposit(index));
+                }
+                let position = self.rng.below(self.disputed[index].len() as u64) as usize;
+                let tx = self.disputed[index].remove(position);
+                if kind == 4 {
+                    self.deposits[index].retain(|deposit| *deposit != tx);
+                }
+                Self::record(transaction_type, client, tx, None)
+            }
+        };
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+    use crate::processor::{TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+    use crate::type_defs::{Amount, ClientId};
+
+    const RECORDS: usize = 100_000;
+
+    fn amount(record: &TransactionRecord) -> Amount {
+        record.amount.as_deref().unwrap().parse().unwrap()
+    }
+
+    // Test that a seed always gives the same records and another seed other
+    // records.
+    #[test]
+    fn test_deterministic() {
+        let records = |seed| -> Vec<(String, u32, u64, Option<String>)> {
+            Generator::new(seed)
+                .with_clients(20)
+                .take(1_000)
+                .map(|record| {
+                    (
+                        record.transaction_type,
+                        record.client,
+                        record.tx,
+                        record.amount,
+                    )
+                })
+                .collect()
+        };
+        assert_eq!(records(42), records(42));
+        assert_ne!(records(42), records(43));
+        let kinds: HashSet<String> = records(42).into_iter().map(|record| record.0).collect();
+        assert_eq!(kinds.len(), 5);
+    }
+
+    // Test that the total across all clients equals the applied deposits minus
+    // the applied withdrawals minus the deposits charged back.
+    #[test]
+    fn test_total_invariant() {
+        for (seed, referential_validity) in [(1, true), (2, false)] {
+            let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+            let mut deposits = HashMap::new();
+            let mut expected = Amount::new();
+            for record in Generator::new(seed)
+                .with_referential_validity(referential_validity)
+                .take(RECORDS)
+            {
+                let (transaction_type, tx) = (record.transaction_type.clone(), record.tx);
+                let value = record.amount.as_ref().map(|_| amount(&record));
+                if processor.process_transaction(record).is_err() {
+                    continue;
+                }
+                match transaction_type.as_str() {
+                    "deposit" => {
+                        expected += value.unwrap();
+                        deposits.insert(tx, value.unwrap());
+                    }
+                    "withdrawal" => expected -= value.unwrap(),
+                    "chargeback" => expected -= deposits[&tx],
+                    _ => {}
+                }
+            }
+            assert_eq!(processor.balance_summary().total, expected);
+        }
+    }
+
+    // Test that every client has its available and held funds adding up to its
+    // total, the held funds across clients being the deposits under dispute,
+    // and only the clients with a chargeback are locked.
+    #[test]
+    fn test_held_invariant() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let mut deposits = HashMap::new();
+        let mut disputed = HashSet::new();
+        let mut charged_back = HashSet::new();
+        for record in Generator::new(3).with_clients(50).take(RECORDS) {
+            let (transaction_type, client, tx) =
+                (record.transaction_type.clone(), record.client, record.tx);
+            let value = record.amount.as_ref().map(|_| amount(&record));
+            if processor.process_transaction(record).is_err() {
+                continue;
+            }
+            match transaction_type.as_str() {
+                "deposit" => {
+                    deposits.insert(tx, value.unwrap());
+                }
+                "dispute" => {
+                    disputed.insert(tx);
+                }
+                "resolve" => {
+                    disputed.remove(&tx);
+                }
+                "chargeback" => {
+                    disputed.remove(&tx);
+                    charged_back.insert(client);
+                }
+                _ => {}
+            }
+        }
+
+        let mut held = Amount::new();
+        for tx in &disputed {
+            held += deposits[tx];
+        }
+        let summary = processor.balance_summary();
+        assert_eq!(summary.held, held);
+        assert_eq!(summary.locked, charged_back.len() as u64);
+        for client in 1..=50 {
+            let view = processor.client_view(ClientId(client)).unwrap();
+            let mut total = view.available;
+            total += view.held;
+            assert_eq!(total, view.total);
+            assert_eq!(view.locked, charged_back.contains(&client));
+        }
+    }
+}
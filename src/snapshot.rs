@@ -0,0 +1,171 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::processor::{OutputOptions, TransactionProcessor};
+
+/// Writes the balances of a processor during a run into numbered files of a
+/// directory, e.g. from the callback of
+/// `TransactionProcessor::with_snapshot_every`.
+///
+/// Each snapshot is written into a temporary file renamed once complete, so a
+/// reader of the directory never sees a partial snapshot.
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    dir: PathBuf,
+    /// Number of snapshots kept, the older ones are removed after each
+    /// snapshot. Every snapshot is kept when unset.
+    keep: Option<usize>,
+    options: OutputOptions,
+}
+
+impl Snapshots {
+    /// Creates dir if needed, the snapshots are written with the given output
+    /// options, without their shards or pages.
+    pub fn create(
+        dir: &Path,
+        keep: Option<usize>,
+        options: &OutputOptions,
+    ) -> Result<Self, String> {
+        fs::create_dir_all(dir).map_err(|err| {
+            format!(
+                "Could not create snapshot dir {} because of: {}",
+                dir.display(),
+                err
+            )
+        })?;
+        Ok(Snapshots {
+            dir: dir.to_owned(),
+            keep,
+            options: OutputOptions {
+                shards: None,
+                pages: None,
+                partial: false,
+                panicked: false,
+                ..options.clone()
+            },
+        })
+    }
+
+    /// Name of the snapshot taken after the given number of records, zero
+    /// padded so the names sort in the order of the snapshots.
+    pub fn file_name(sequence: u64) -> String {
+        format!("balances-{:012}.csv", sequence)
+    }
+
+    /// Writes the balances of processor as the snapshot taken after sequence
+    /// records, then removes the snapshots which are not kept anymore.
+    pub fn write<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>(
+        &self,
+        processor: &TransactionProcessor<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>,
+        sequence: u64,
+    ) -> Result<PathBuf, String> {
+        let path = self.dir.join(Self::file_name(sequence));
+        let tmp_path = self.dir.join(format!(".{}.tmp", Self::file_name(sequence)));
+        let written = File::create(&tmp_path)
+            .map_err(|err| format!("Could not create {}: {}", tmp_path.display(), err))
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                processor.serialize_to(&mut writer, &self.options)?;
+                writer
+                    .into_inner()
+                    .map_err(|err| err.to_string())
+                    .and_then(|file| file.sync_all().map_err(|err| err.to_string()))
+                    .map_err(|err| format!("Could not write {}: {}", tmp_path.display(), err))
+            })
+            .and_then(|_| {
+                fs::rename(&tmp_path, &path)
+                    .map_err(|err| format!("Could not create {}: {}", path.display(), err))
+            });
+        if let Err(err) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+        self.prune()?;
+        Ok(path)
+    }
+
+    /// Removes the oldest snapshots beyond the number kept.
+    fn prune(&self) -> Result<(), String> {
+        let keep = match self.keep {
+            Some(keep) => keep,
+            None => return Ok(()),
+        };
+        let entries = fs::read_dir(&self.dir)
+            .map_err(|err| format!("Could not read {}: {}", self.dir.display(), err))?;
+        let mut snapshots: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("balances-") && name.ends_with(".csv"))
+            })
+            .collect();
+        snapshots.sort();
+        let stale = snapshots.len().saturating_sub(keep);
+        for path in &snapshots[..stale] {
+            fs::remove_file(path)
+                .map_err(|err| format!("Could not remove {}: {}", path.display(), err))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+    use crate::type_defs::TransactionRecord;
+    use tempdir::TempDir;
+
+    fn deposit(client: u32, tx: u64) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type: "deposit".to_owned(),
+            client,
+            tx,
+            amount: Some("1.0".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Test that every snapshot holds the balances at the time it was taken,
+    // without leaving temporary files, and only the last ones are kept.
+    #[test]
+    fn test_snapshots() {
+        let dir = TempDir::new("snapshots").unwrap();
+        let snapshot_dir = dir.path().join("out");
+        let snapshots =
+            Snapshots::create(&snapshot_dir, Some(2), &OutputOptions::default()).unwrap();
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_snapshot_every(
+                1,
+                Box::new(move |processor, processed| {
+                    snapshots.write(processor, processed).unwrap();
+                }),
+            );
+        processor.process_iter((1..=3).map(|tx| deposit(tx as u32, tx)));
+
+        assert_eq!(
+            file_names(&snapshot_dir),
+            vec!["balances-000000000002.csv", "balances-000000000003.csv"]
+        );
+        let clients = |name: &str| {
+            fs::read_to_string(snapshot_dir.join(name))
+                .unwrap()
+                .lines()
+                .count()
+                - 1
+        };
+        assert_eq!(clients("balances-000000000002.csv"), 2);
+        assert_eq!(clients("balances-000000000003.csv"), 3);
+    }
+}
@@ -0,0 +1,335 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use tempdir::TempDir;
+
+use crate::store::{StoreFamily, TransactionStore};
+use crate::transaction_cache::CacheKeyed;
+
+const GET: &str = "SELECT value FROM transactions WHERE key = ?1";
+const INSERT: &str = "INSERT OR REPLACE INTO transactions (key, value) VALUES (?1, ?2)";
+const REMOVE: &str = "DELETE FROM transactions WHERE key = ?1";
+const ALL: &str = "SELECT key, value FROM transactions";
+
+/// Type which stores transactions in the `transactions` table of a SQLite
+/// database, the keys and values being kept as JSON. The database can be
+/// reopened by a later run, or queried with SQL, e.g.
+/// `SELECT value FROM transactions WHERE key = '42'`.
+///
+/// The operations of `TransactionStore` can not fail, the first error met is
+/// kept and reported through `spill_error`, the operations after it read
+/// nothing and write nothing.
+pub struct SqliteStore<K, V> {
+    db: Connection,
+    /// Path of the database, None for an in-memory database.
+    path: Option<PathBuf>,
+    /// Last value read by `get`, which returns a reference to it.
+    loaded: Option<V>,
+    error: Option<String>,
+    _key: PhantomData<K>,
+}
+
+impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> SqliteStore<K, V> {
+    /// Opens the database at path, creating it and its table if needed, the
+    /// transactions stored by a previous run are kept.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let mut store = Self::open_db(&path.to_string_lossy(), Connection::open(path))?;
+        store.path = Some(path.to_path_buf());
+        Ok(store)
+    }
+
+    /// Opens a new database held in memory.
+    pub fn in_memory_db() -> Result<Self, String> {
+        Self::open_db(":memory:", Connection::open_in_memory())
+    }
+
+    fn open_db(filename: &str, db: rusqlite::Result<Connection>) -> Result<Self, String> {
+        let error = |err: rusqlite::Error| {
+            format!("Could not open database {} because of: {}", filename, err)
+        };
+        let db = db.map_err(error)?;
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS transactions (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+        )
+        .map_err(error)?;
+        // The statements are prepared up front so a broken database fails
+        // here rather than on the first transaction.
+        for sql in [GET, INSERT, REMOVE, ALL] {
+            db.prepare_cached(sql).map_err(error)?;
+        }
+        Ok(SqliteStore {
+            db,
+            path: None,
+            loaded: None,
+            error: None,
+            _key: PhantomData,
+        })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Keeps the first error met.
+    fn fail(&mut self, err: String) {
+        if self.error.is_none() {
+            self.error = Some(format!("SQLite store failed because of: {}", err));
+        }
+    }
+
+    fn encode<T: Serialize>(value: &T) -> Result<String, String> {
+        serde_json::to_string(value).map_err(|err| err.to_string())
+    }
+
+    fn decode<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+        serde_json::from_str(value).map_err(|err| err.to_string())
+    }
+
+    /// Reads the value stored for key.
+    fn load(&mut self, key: &K) -> Option<V> {
+        if self.error.is_some() {
+            return None;
+        }
+        let result = Self::encode(key).and_then(|key| {
+            self.db
+                .prepare_cached(GET)
+                .and_then(|mut get| {
+                    get.query_row(params![key], |row| row.get::<_, String>(0))
+                        .optional()
+                })
+                .map_err(|err| err.to_string())
+        });
+        match result.and_then(|value| value.as_deref().map(Self::decode).transpose()) {
+            Ok(value) => value,
+            Err(err) => {
+                self.fail(err);
+                None
+            }
+        }
+    }
+
+    /// Runs one of the statements which do not return rows.
+    fn execute<P: rusqlite::Params>(&mut self, sql: &str, params: P) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = self
+            .db
+            .prepare_cached(sql)
+            .and_then(|mut statement| statement.execute(params));
+        if let Err(err) = result {
+            self.fail(err.to_string());
+        }
+    }
+
+    /// Reads every entry of the table.
+    fn load_all(&mut self) -> Vec<(K, V)> {
+        if self.error.is_some() {
+            return Vec::new();
+        }
+        let rows = self
+            .db
+            .prepare_cached(ALL)
+            .and_then(|mut all| {
+                all.query_map([], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .map_err(|err| err.to_string());
+        let entries = rows.and_then(|rows| {
+            rows.iter()
+                .map(|(key, value)| Ok((Self::decode(key)?, Self::decode(value)?)))
+                .collect()
+        });
+        match entries {
+            Ok(entries) => entries,
+            Err(err) => {
+                self.fail(err);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for SqliteStore<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteStore")
+            .field("path", &self.path)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl<K: Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> TransactionStore<K, V>
+    for SqliteStore<K, V>
+{
+    fn get(&mut self, key: &K) -> Option<&V> {
+        self.loaded = self.load(key);
+        self.loaded.as_ref()
+    }
+
+    fn contains_key(&mut self, key: &K) -> bool {
+        self.load(key).is_some()
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let previous = self.load(&key);
+        match Self::encode(&key).and_then(|key| Ok((key, Self::encode(&value)?))) {
+            Ok((key, value)) => self.execute(INSERT, params![key, value]),
+            Err(err) => self.fail(err),
+        }
+        previous
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let previous = self.load(key);
+        if previous.is_some() {
+            match Self::encode(key) {
+                Ok(key) => self.execute(REMOVE, params![key]),
+                Err(err) => self.fail(err),
+            }
+        }
+        previous
+    }
+
+    fn for_each<F: FnMut(&K, &V)>(&mut self, mut f: F) {
+        for (key, value) in self.load_all() {
+            f(&key, &value);
+        }
+    }
+
+    fn entries<'a>(&'a mut self) -> Box<dyn Iterator<Item = (K, V)> + 'a>
+    where
+        K: Clone + 'a,
+        V: Clone + 'a,
+    {
+        Box::new(self.load_all().into_iter())
+    }
+
+    /// Only the last value read is held in memory.
+    fn in_memory(&self) -> u64 {
+        self.loaded.is_some() as u64
+    }
+
+    fn spill_error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+/// Stores the transactions in `SqliteStore`s, each in its own database held
+/// in memory, or in a new subdirectory of the cache dir when given. A
+/// database kept from a run can be reopened with `SqliteStore::open` and
+/// given to `Client::new_with_cache`.
+#[derive(Debug)]
+pub struct SqliteStores;
+
+impl StoreFamily for SqliteStores {
+    type Store<K: CacheKeyed + fmt::Debug, V: Serialize + DeserializeOwned + fmt::Debug> =
+        SqliteStore<K, V>;
+
+    fn create<K: CacheKeyed + fmt::Debug, V: Serialize + DeserializeOwned + fmt::Debug>(
+        cache_dir: Option<&Path>,
+    ) -> Result<Self::Store<K, V>, String> {
+        match cache_dir {
+            Some(cache_dir) => {
+                let dir = TempDir::new_in(cache_dir, "transaction_store").map_err(|err| {
+                    format!(
+                        "Could not create store dir in {} because of: {}",
+                        cache_dir.display(),
+                        err
+                    )
+                })?;
+                SqliteStore::open(&dir.into_path().join("transactions.sqlite3"))
+            }
+            None => SqliteStore::in_memory_db(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::store::MemoryStores;
+    use crate::type_defs::{Amount, ClientId, StoredTransaction, Transaction, TransactionId};
+
+    fn amount(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn stored(tx: u64, value: &str) -> StoredTransaction {
+        StoredTransaction {
+            transaction: Transaction::Deposit {
+                client: ClientId(1),
+                tx: TransactionId(tx),
+                amount: amount(value),
+            },
+            sequence: tx,
+        }
+    }
+
+    // Test that the transactions inserted are read back, replaced, removed
+    // and still there once the database is reopened.
+    #[test]
+    fn test_insert_get_remove() {
+        let dir = TempDir::new("sqlite_store").unwrap();
+        let path = dir.path().join("transactions.sqlite3");
+        let mut store = SqliteStore::<TransactionId, StoredTransaction>::open(&path).unwrap();
+        let deposit = stored(1, "1.5");
+        let withdrawal = stored(2, "0.5");
+
+        assert_eq!(store.insert(TransactionId(1), deposit), None);
+        assert_eq!(store.insert(TransactionId(2), withdrawal), None);
+        assert_eq!(store.get(&TransactionId(1)), Some(&deposit));
+        assert!(store.contains_key(&TransactionId(2)));
+        assert!(!store.contains_key(&TransactionId(3)));
+        assert_eq!(store.insert(TransactionId(2), deposit), Some(withdrawal));
+        assert_eq!(store.remove(&TransactionId(1)), Some(deposit));
+        assert_eq!(store.remove(&TransactionId(1)), None);
+        drop(store);
+
+        let mut store = SqliteStore::<TransactionId, StoredTransaction>::open(&path).unwrap();
+        let entries: Vec<_> = store.entries().collect();
+        assert_eq!(entries, vec![(TransactionId(2), deposit)]);
+        assert_eq!(store.spill_error(), None);
+    }
+
+    // Test that a client storing its transactions in SQLite gets the same
+    // balances as one keeping them in memory.
+    #[test]
+    fn test_client_balances() {
+        let dir = TempDir::new("sqlite_client").unwrap();
+        let mut sqlite = Client::<1024, 16, SqliteStores>::new_in(ClientId(1), dir.path()).unwrap();
+        let mut in_memory = Client::<1024, 16, MemoryStores>::new(ClientId(1)).unwrap();
+        for client in [&mut sqlite as &mut dyn Apply, &mut in_memory] {
+            client.apply();
+        }
+        assert_eq!(sqlite.view(), in_memory.view());
+        assert_eq!(sqlite.view().available, amount("3.5"));
+        assert_eq!(sqlite.view().held, amount("3.0"));
+        assert_eq!(sqlite.open_disputes(), in_memory.open_disputes());
+        let mut transactions: Vec<_> = sqlite.transactions().collect();
+        transactions.sort_by_key(|(tx, _)| tx.0);
+        assert_eq!(transactions.len(), 4);
+    }
+
+    trait Apply {
+        fn apply(&mut self);
+    }
+
+    impl<S: StoreFamily> Apply for Client<1024, 16, S> {
+        fn apply(&mut self) {
+            self.deposit(TransactionId(1), amount("3.0")).unwrap();
+            self.deposit(TransactionId(2), amount("4.0")).unwrap();
+            self.withdraw(TransactionId(3), amount("1.5")).unwrap();
+            self.dispute(&TransactionId(1)).unwrap();
+            self.deposit(TransactionId(4), amount("1.0")).unwrap();
+            self.dispute(&TransactionId(4)).unwrap();
+            self.resolve(&TransactionId(4)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,438 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::checksum::checksum;
+use crate::client::AccountStatus;
+use crate::processor::RecordedOutcome;
+use crate::type_defs::{
+    Amount, ClientId, StoredTransaction, Transaction, TransactionId, TransactionNotes,
+};
+
+/// Version of the format of the state snapshots, it has to be bumped whenever
+/// the serialized representation of the clients changes. Snapshots written
+/// in another version are rejected.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+pub struct StateFormatVersion(pub u32);
+
+impl StateFormatVersion {
+    pub const CURRENT: StateFormatVersion = StateFormatVersion(1);
+}
+
+/// State of a client in a snapshot: its balances and status, the counters
+/// the balances are audited with, and its stored transactions by increasing
+/// transaction id.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientState {
+    pub client_id: ClientId,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub status: AccountStatus,
+    /// Number of transactions applied to the client.
+    pub sequence: u64,
+    pub deposits_count: u64,
+    pub withdrawals_count: u64,
+    /// Cumulative change of the total by the handlers of custom types and
+    /// the fees.
+    pub adjustments: Amount,
+    pub charged_back: Amount,
+    pub chargebacks: Vec<TransactionId>,
+    pub ever_disputed: bool,
+    pub transactions: Vec<(TransactionId, StoredTransaction)>,
+    /// Deposits under dispute, with the amount they hold.
+    pub disputed: Vec<(TransactionId, Transaction)>,
+    pub notes: Vec<(TransactionId, TransactionNotes)>,
+    /// Checksum of the other fields, see `ClientState::compute_checksum`.
+    pub checksum: u64,
+}
+
+impl ClientState {
+    /// Returns the checksum of the serialized state with a zero checksum.
+    pub fn compute_checksum(&self) -> u64 {
+        let content = ClientState {
+            checksum: 0,
+            ..self.clone()
+        };
+        checksum(&serde_json::to_vec(&content).expect("Client state is always serializable"))
+    }
+
+    /// Sets the checksum of the state.
+    pub fn with_checksum(mut self) -> Self {
+        self.checksum = self.compute_checksum();
+        self
+    }
+}
+
+/// Snapshot of the clients of a processor, written by `--state-out` at the
+/// end of a run, also when it is interrupted, so the state can be checked
+/// without processing the input again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub version: StateFormatVersion,
+    /// Set when the run was interrupted before the end of its input.
+    pub partial: bool,
+    /// Clients by increasing id.
+    pub clients: Vec<ClientState>,
+    /// Outcomes of the records by idempotency key, by increasing key.
+    pub idempotency_keys: Vec<(String, RecordedOutcome)>,
+    /// Checksum of the idempotency keys, see `StateSnapshot::keys_checksum`.
+    pub idempotency_keys_checksum: u64,
+}
+
+impl StateSnapshot {
+    /// Returns the checksum of the serialized idempotency keys.
+    pub fn keys_checksum(&self) -> u64 {
+        checksum(
+            &serde_json::to_vec(&self.idempotency_keys)
+                .expect("Idempotency keys are always serializable"),
+        )
+    }
+
+    /// Writes the snapshot as JSON into a temporary file renamed to path once
+    /// complete, so path never holds a partial snapshot.
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| format!("Invalid state path {}", path.display()))?;
+        let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+        let written = File::create(&tmp_path)
+            .map_err(|err| format!("Could not create {}: {}", tmp_path.display(), err))
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                serde_json::to_writer(&mut writer, self)
+                    .map_err(|err| err.to_string())
+                    .and_then(|_| writer.flush().map_err(|err| err.to_string()))
+                    .and_then(|_| {
+                        writer
+                            .into_inner()
+                            .map_err(|err| err.to_string())
+                            .and_then(|file| file.sync_all().map_err(|err| err.to_string()))
+                    })
+                    .map_err(|err| format!("Could not write {}: {}", tmp_path.display(), err))
+            })
+            .and_then(|_| {
+                fs::rename(&tmp_path, path)
+                    .map_err(|err| format!("Could not create {}: {}", path.display(), err))
+            });
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        written
+    }
+
+    /// Reads a snapshot written by `StateSnapshot::write`, rejecting the
+    /// snapshots written in another format version.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let invalid = |err: serde_json::Error| format!("Invalid state {}: {}", path.display(), err);
+        let file = File::open(path)
+            .map_err(|err| format!("Could not open {}: {}", path.display(), err))?;
+        let value: serde_json::Value =
+            serde_json::from_reader(BufReader::new(file)).map_err(invalid)?;
+        let version = value
+            .get("version")
+            .cloned()
+            .map(serde_json::from_value::<StateFormatVersion>)
+            .transpose()
+            .map_err(invalid)?;
+        if version != Some(StateFormatVersion::CURRENT) {
+            return Err(format!(
+                "Invalid state {}: State format version {} is not supported, expected {}",
+                path.display(),
+                version.map_or("none".to_owned(), |version| version.0.to_string()),
+                StateFormatVersion::CURRENT.0
+            ));
+        }
+        serde_json::from_value(value).map_err(invalid)
+    }
+}
+
+/// Result of `StateSnapshot::check`: the failures of each client which does
+/// not pass the audit, and of the idempotency keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateCheck {
+    pub partial: bool,
+    /// Number of clients checked.
+    pub clients: usize,
+    /// Failures by client, by increasing client id.
+    pub client_failures: Vec<(ClientId, Vec<String>)>,
+    pub keys_failure: Option<String>,
+}
+
+impl StateCheck {
+    pub fn passed(&self) -> bool {
+        self.client_failures.is_empty() && self.keys_failure.is_none()
+    }
+
+    /// Writes one line per failure followed by the outcome of the check.
+    pub fn write_report<W: Write>(&self, mut writer: W) -> Result<(), String> {
+        let error =
+            |err: std::io::Error| format!("Could not write state report because of: {}", err);
+        for (client_id, failures) in &self.client_failures {
+            for failure in failures {
+                writeln!(writer, "client {}: {}", client_id, failure).map_err(error)?;
+            }
+        }
+        if let Some(failure) = &self.keys_failure {
+            writeln!(writer, "idempotency keys: {}", failure).map_err(error)?;
+        }
+        let partial = if self.partial {
+            " of an interrupted run"
+        } else {
+            ""
+        };
+        if self.passed() {
+            writeln!(
+                writer,
+                "pass: {} clients checked in the snapshot{}",
+                self.clients, partial
+            )
+        } else {
+            writeln!(
+                writer,
+                "fail: {} of {} clients failed in the snapshot{}",
+                self.client_failures.len(),
+                self.clients,
+                partial
+            )
+        }
+        .map_err(error)
+    }
+}
+
+impl ClientState {
+    /// Returns the failures of the audit of the client: its checksum, the
+    /// total being the available plus the held balance and the total
+    /// recomputed from its stored transactions, as `Client::recomputed_total`
+    /// does.
+    pub fn audit(&self) -> Vec<String> {
+        let mut failures = Vec::new();
+        if self.checksum != self.compute_checksum() {
+            failures.push(format!(
+                "checksum {} does not match its content {}",
+                self.checksum,
+                self.compute_checksum()
+            ));
+        }
+        if self.total - self.held != self.available {
+            failures.push(format!(
+                "total {} is not available {} plus held {}",
+                self.total, self.available, self.held
+            ));
+        }
+        let mut recomputed = self.adjustments - self.charged_back;
+        for (_, stored) in &self.transactions {
+            match stored.transaction {
+                Transaction::Deposit { amount, .. } => recomputed += amount,
+                Transaction::Withdrawal { amount, .. } => recomputed -= amount,
+                _ => {}
+            }
+        }
+        if recomputed != self.total {
+            failures.push(format!(
+                "total {} differs from {} recomputed from its transactions",
+                self.total, recomputed
+            ));
+        }
+        failures
+    }
+}
+
+impl StateSnapshot {
+    /// Audits every client of the snapshot and checks the checksum of the
+    /// idempotency keys, without restoring a processor.
+    pub fn check(&self) -> StateCheck {
+        let keys_checksum = self.keys_checksum();
+        StateCheck {
+            partial: self.partial,
+            clients: self.clients.len(),
+            client_failures: self
+                .clients
+                .iter()
+                .map(|client| (client.client_id, client.audit()))
+                .filter(|(_, failures)| !failures.is_empty())
+                .collect(),
+            keys_failure: (keys_checksum != self.idempotency_keys_checksum).then(|| {
+                format!(
+                    "checksum {} does not match their content {}",
+                    self.idempotency_keys_checksum, keys_checksum
+                )
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+    use crate::test_support::{Record, Scenario};
+    use tempdir::TempDir;
+
+    // Test that the snapshot of a processor holds the clients by increasing
+    // id with their transactions, is read back as written and that a change
+    // of a client no longer matches its checksum.
+    #[test]
+    fn test_state_round_trip() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        Scenario::new()
+            .then(Record::deposit(2, 1, "5.0"))
+            .then(Record::deposit(1, 2, "3.0").memo("rent"))
+            .then(Record::withdrawal(2, 3, "1.5"))
+            .then(Record::dispute(1, 2))
+            .run_on(&mut processor);
+        let snapshot = processor.state_snapshot(false);
+        assert_eq!(snapshot.version, StateFormatVersion::CURRENT);
+        let ids: Vec<u32> = snapshot
+            .clients
+            .iter()
+            .map(|client| client.client_id.0)
+            .collect();
+        assert_eq!(ids, vec![1, 2]);
+        let client = &snapshot.clients[0];
+        assert_eq!(client.held, "3.0".parse().unwrap());
+        assert_eq!(client.disputed.len(), 1);
+        assert_eq!(snapshot.clients[1].transactions.len(), 2);
+
+        let dir = TempDir::new("state").unwrap();
+        let path = dir.path().join("state.json");
+        snapshot.write(&path).unwrap();
+        let read = StateSnapshot::read(&path).unwrap();
+        assert_eq!(read, snapshot);
+        assert!(read
+            .clients
+            .iter()
+            .all(|client| client.checksum == client.compute_checksum()));
+
+        let mut changed = read.clients[1].clone();
+        changed.available = "100".parse().unwrap();
+        assert_ne!(changed.checksum, changed.compute_checksum());
+    }
+
+    // Test that the outcomes of the records by idempotency key are kept in the
+    // snapshot by increasing key, with their checksum.
+    #[test]
+    fn test_state_idempotency_keys() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        Scenario::new()
+            .then(Record::deposit(1, 1, "5.0").idempotency_key("batch-2"))
+            .then(Record::withdrawal(1, 2, "9.0").idempotency_key("batch-1"))
+            .then(Record::deposit(1, 3, "5.0").idempotency_key("batch-2"))
+            .run_on(&mut processor);
+        let snapshot = processor.state_snapshot(false);
+        let keys: Vec<&str> = snapshot
+            .idempotency_keys
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect();
+        assert_eq!(keys, vec!["batch-1", "batch-2"]);
+        assert!(matches!(
+            snapshot.idempotency_keys[0].1,
+            RecordedOutcome::Rejected(_)
+        ));
+        assert!(matches!(
+            snapshot.idempotency_keys[1].1,
+            RecordedOutcome::Applied(_)
+        ));
+        assert_eq!(snapshot.idempotency_keys_checksum, snapshot.keys_checksum());
+        assert_eq!(
+            snapshot.clients[0].available,
+            "5.0".parse::<Amount>().unwrap()
+        );
+
+        let dir = TempDir::new("state_keys").unwrap();
+        let path = dir.path().join("state.json");
+        snapshot.write(&path).unwrap();
+        assert_eq!(StateSnapshot::read(&path).unwrap(), snapshot);
+    }
+
+    // Test that transaction ids above u32::MAX are kept by the snapshots.
+    #[test]
+    fn test_state_wide_transaction_ids() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        let wide = u64::from(u32::MAX) + 1;
+        Scenario::new()
+            .then(Record::deposit(1, u64::from(u32::MAX), "1.0"))
+            .then(Record::deposit(1, wide, "2.0"))
+            .then(Record::dispute(1, wide))
+            .run_on(&mut processor);
+        let snapshot = processor.state_snapshot(false);
+
+        let dir = TempDir::new("state_wide_tx").unwrap();
+        let path = dir.path().join("state.json");
+        snapshot.write(&path).unwrap();
+        let read = StateSnapshot::read(&path).unwrap();
+        let ids: Vec<u64> = read.clients[0]
+            .transactions
+            .iter()
+            .map(|(tx, _)| tx.0)
+            .collect();
+        assert_eq!(ids, vec![u64::from(u32::MAX), wide]);
+        assert_eq!(read.clients[0].disputed[0].0, TransactionId(wide));
+        assert_eq!(read, snapshot);
+    }
+
+    // Test that a healthy snapshot passes the check and that the failures of
+    // a corrupted one are reported by client.
+    #[test]
+    fn test_state_check() {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        Scenario::new()
+            .then(Record::deposit(1, 1, "5.0").idempotency_key("a"))
+            .then(Record::deposit(2, 2, "3.0"))
+            .then(Record::dispute(2, 2))
+            .run_on(&mut processor);
+        let mut snapshot = processor.state_snapshot(false);
+        let check = snapshot.check();
+        assert!(check.passed());
+        let mut report = Vec::new();
+        check.write_report(&mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "pass: 2 clients checked in the snapshot\n"
+        );
+
+        // A total out of sync with the balances, kept under a valid checksum.
+        let client = &mut snapshot.clients[1];
+        client.total = "4.0".parse().unwrap();
+        *client = client.clone().with_checksum();
+        snapshot.idempotency_keys.clear();
+        snapshot.partial = true;
+        let check = snapshot.check();
+        assert!(!check.passed());
+        let mut report = Vec::new();
+        check.write_report(&mut report).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            format!(
+                "client 2: total 4.0 is not available 0.0000 plus held 3.0\n\
+                 client 2: total 4.0 differs from 3.0 recomputed from its transactions\n\
+                 idempotency keys: checksum {} does not match their content {}\n\
+                 fail: 1 of 2 clients failed in the snapshot of an interrupted run\n",
+                snapshot.idempotency_keys_checksum,
+                snapshot.keys_checksum()
+            )
+        );
+
+        snapshot.clients[0].available = "6.0".parse().unwrap();
+        let failures = snapshot.clients[0].audit();
+        assert_eq!(failures.len(), 2);
+        assert!(failures[0].starts_with("checksum "));
+    }
+
+    // Test that a snapshot of another format version is rejected.
+    #[test]
+    fn test_state_version() {
+        let dir = TempDir::new("state_version").unwrap();
+        let path = dir.path().join("state.json");
+        fs::write(&path, "{\"version\":0,\"partial\":false,\"clients\":[]}").unwrap();
+        assert_eq!(
+            StateSnapshot::read(&path).unwrap_err(),
+            format!(
+                "Invalid state {}: State format version 0 is not supported, expected 1",
+                path.display()
+            )
+        );
+    }
+}
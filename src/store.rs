@@ -0,0 +1,150 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::Path;
+
+use crate::transaction_cache::{CacheKeyed, SpillFailurePolicy, TransactionCache};
+
+/// Type which stores the transactions of a `Client`, keyed by transaction id
+/// or any other `CacheKeyed` key.
+///
+/// Reads take `&mut self` so a store can load entries lazily, as
+/// `TransactionCache` does with its spilled cache lines. Stores which never
+/// spill keep the default spill related methods.
+///
+/// Custom backends, e.g. a remote store, are plugged into a `Client` through
+/// their own `StoreFamily`. The operations can not fail, a store which could
+/// not complete one reports it through `spill_error`, which stops the
+/// `TransactionProcessor` like a cache which could not spill.
+pub trait TransactionStore<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V>;
+
+    fn contains_key(&mut self, key: &K) -> bool;
+
+    fn insert(&mut self, key: K, value: V) -> Option<V>;
+
+    fn remove(&mut self, key: &K) -> Option<V>;
+
+    /// Calls f with every entry of the store, in no particular order.
+    fn for_each<F: FnMut(&K, &V)>(&mut self, f: F);
+
+    /// Returns an iterator over clones of every entry of the store, in no
+    /// particular order.
+    fn entries<'a>(&'a mut self) -> Box<dyn Iterator<Item = (K, V)> + 'a>
+    where
+        K: Clone + 'a,
+        V: Clone + 'a;
+
+    /// Number of entries held in memory.
+    fn in_memory(&self) -> u64;
+
+    fn set_spill_policy(&mut self, _spill_policy: SpillFailurePolicy) {}
+
+    fn set_cache_limit(&mut self, _limit: u64) {}
+
+    /// Spills every entry held in memory, the stores which never spill keep
+    /// them.
+    fn flush(&mut self) {}
+
+    fn spill_error(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Kind of `TransactionStore` used by every store of a `Client`, which keeps
+/// several stores with different values.
+pub trait StoreFamily {
+    type Store<K: CacheKeyed + Debug, V: Serialize + DeserializeOwned + Debug>: TransactionStore<K, V>
+        + Debug;
+
+    /// Creates an empty store, the stores which spill to disk do so in a new
+    /// subdirectory of cache_dir when given.
+    fn create<K: CacheKeyed + Debug, V: Serialize + DeserializeOwned + Debug>(
+        cache_dir: Option<&Path>,
+    ) -> Result<Self::Store<K, V>, String>;
+
+    /// Creates an empty store which never touches the disk, for when the
+    /// stores can not be created on disk. The stores created without a cache
+    /// dir are used by default.
+    fn create_in_memory<K: CacheKeyed + Debug, V: Serialize + DeserializeOwned + Debug>(
+    ) -> Result<Self::Store<K, V>, String> {
+        Self::create(None)
+    }
+}
+
+/// Stores the transactions in `TransactionCache`s, which spill to disk.
+#[derive(Debug)]
+pub struct CacheStores<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>;
+
+impl<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32> StoreFamily
+    for CacheStores<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>
+{
+    type Store<K: CacheKeyed + Debug, V: Serialize + DeserializeOwned + Debug> =
+        TransactionCache<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE, K, V>;
+
+    fn create<K: CacheKeyed + Debug, V: Serialize + DeserializeOwned + Debug>(
+        cache_dir: Option<&Path>,
+    ) -> Result<Self::Store<K, V>, String> {
+        match cache_dir {
+            Some(cache_dir) => TransactionCache::new_in(cache_dir),
+            None => TransactionCache::new(),
+        }
+    }
+
+    fn create_in_memory<K: CacheKeyed + Debug, V: Serialize + DeserializeOwned + Debug>(
+    ) -> Result<Self::Store<K, V>, String> {
+        Ok(TransactionCache::in_memory_only())
+    }
+}
+
+/// Stores the transactions in `HashMap`s, without touching the filesystem,
+/// for the hosts which can keep every transaction in memory.
+#[derive(Debug)]
+pub struct MemoryStores;
+
+impl StoreFamily for MemoryStores {
+    type Store<K: CacheKeyed + Debug, V: Serialize + DeserializeOwned + Debug> = HashMap<K, V>;
+
+    fn create<K: CacheKeyed + Debug, V: Serialize + DeserializeOwned + Debug>(
+        _cache_dir: Option<&Path>,
+    ) -> Result<Self::Store<K, V>, String> {
+        Ok(HashMap::new())
+    }
+}
+
+impl<K: CacheKeyed, V> TransactionStore<K, V> for HashMap<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        HashMap::get(self, key)
+    }
+
+    fn contains_key(&mut self, key: &K) -> bool {
+        HashMap::contains_key(self, key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        HashMap::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        HashMap::remove(self, key)
+    }
+
+    fn for_each<F: FnMut(&K, &V)>(&mut self, mut f: F) {
+        for (key, value) in self.iter() {
+            f(key, value);
+        }
+    }
+
+    fn entries<'a>(&'a mut self) -> Box<dyn Iterator<Item = (K, V)> + 'a>
+    where
+        K: Clone + 'a,
+        V: Clone + 'a,
+    {
+        Box::new(self.iter().map(|(key, value)| (key.clone(), value.clone())))
+    }
+
+    fn in_memory(&self) -> u64 {
+        self.len() as u64
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::Write;
+
+use crate::type_defs::{Amount, ClientId, TransactionId};
+
+/// Thresholds above which a client is reported as suspicious, a threshold
+/// which is not set is never exceeded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SuspicionThresholds {
+    /// Maximum number of chargebacks.
+    pub max_chargebacks: Option<u64>,
+    /// Maximum cumulative charged back amount.
+    pub max_charged_back: Option<Amount>,
+}
+
+impl SuspicionThresholds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_chargebacks(mut self, max_chargebacks: u64) -> Self {
+        self.max_chargebacks = Some(max_chargebacks);
+        self
+    }
+
+    pub fn with_max_charged_back(mut self, max_charged_back: Amount) -> Self {
+        self.max_charged_back = Some(max_charged_back);
+        self
+    }
+
+    /// Returns true when either the count or the amount exceeds its threshold.
+    pub fn exceeded(&self, chargebacks: u64, charged_back: Amount) -> bool {
+        self.max_chargebacks.is_some_and(|max| chargebacks > max)
+            || self.max_charged_back.is_some_and(|max| charged_back > max)
+    }
+}
+
+/// Type which describes a client flagged by `TransactionProcessor::flag_suspicious`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuspicionReport {
+    pub client_id: ClientId,
+    pub chargebacks: u64,
+    pub charged_back: Amount,
+    /// Transactions which were charged back, in the order of the chargebacks.
+    pub transactions: Vec<TransactionId>,
+}
+
+/// Writes the reports as csv, the charged back transactions are separated by
+/// spaces.
+pub fn write_reports<W: Write>(reports: &[SuspicionReport], writer: W) -> Result<(), String> {
+    let mut writer = csv::Writer::from_writer(writer);
+    writer
+        .write_record(["client", "chargebacks", "charged_back", "transactions"])
+        .map_err(|err| format!("Could not write suspicious report because of: {}", err))?;
+    for report in reports {
+        let transactions: Vec<String> = report
+            .transactions
+            .iter()
+            .map(|transaction_id| transaction_id.to_string())
+            .collect();
+        writer
+            .write_record(&[
+                report.client_id.to_string(),
+                report.chargebacks.to_string(),
+                report.charged_back.to_string(),
+                transactions.join(" "),
+            ])
+            .map_err(|err| format!("Could not write suspicious report because of: {}", err))?;
+    }
+    writer
+        .flush()
+        .map_err(|err| format!("Could not write suspicious report because of: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that count-only, amount-only and combined thresholds are exceeded
+    // by either value.
+    #[test]
+    fn test_thresholds() {
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        let count_only = SuspicionThresholds::new().with_max_chargebacks(2);
+        assert!(!count_only.exceeded(2, amount("1000")));
+        assert!(count_only.exceeded(3, amount("0")));
+
+        let amount_only = SuspicionThresholds::new().with_max_charged_back(amount("10"));
+        assert!(!amount_only.exceeded(5, amount("10")));
+        assert!(amount_only.exceeded(1, amount("10.5")));
+
+        let both = count_only.with_max_charged_back(amount("10"));
+        assert!(both.exceeded(3, amount("1")));
+        assert!(both.exceeded(1, amount("11")));
+        assert!(!both.exceeded(2, amount("10")));
+
+        assert!(!SuspicionThresholds::new().exceeded(100, amount("100")));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::client::ClientView;
+use crate::error::ProcessingError;
+use crate::processor::{Applied, TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+use crate::type_defs::TransactionRecord;
+
+/// Builder of a `TransactionRecord`, e.g. `Record::deposit(1, 5, "2.0")`.
+/// The fields which are not given are left unset as in the inputs.
+#[derive(Debug, Clone)]
+pub struct Record {
+    record: TransactionRecord,
+}
+
+impl Record {
+    /// Record of any type, e.g. one applied by a registered handler.
+    pub fn new(transaction_type: &str, client: u32, tx: u64) -> Self {
+        Record {
+            record: TransactionRecord {
+                transaction_type: transaction_type.to_owned(),
+                client,
+                tx,
+                amount: None,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn deposit(client: u32, tx: u64, amount: &str) -> Self {
+        Self::new("deposit", client, tx).amount(amount)
+    }
+
+    pub fn withdrawal(client: u32, tx: u64, amount: &str) -> Self {
+        Self::new("withdrawal", client, tx).amount(amount)
+    }
+
+    /// Dispute of the whole deposit tx, give it an amount to dispute only a
+    /// portion of it.
+    pub fn dispute(client: u32, tx: u64) -> Self {
+        Self::new("dispute", client, tx)
+    }
+
+    pub fn resolve(client: u32, tx: u64) -> Self {
+        Self::new("resolve", client, tx)
+    }
+
+    pub fn chargeback(client: u32, tx: u64) -> Self {
+        Self::new("chargeback", client, tx)
+    }
+
+    pub fn amount(mut self, amount: &str) -> Self {
+        self.record.amount = Some(amount.to_owned());
+        self
+    }
+
+    pub fn idempotency_key(mut self, key: &str) -> Self {
+        self.record.idempotency_key = Some(key.to_owned());
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: &str) -> Self {
+        self.record.timestamp = Some(timestamp.to_owned());
+        self
+    }
+
+    pub fn memo(mut self, memo: &str) -> Self {
+        self.record.memo = Some(memo.to_owned());
+        self
+    }
+
+    pub fn build(self) -> TransactionRecord {
+        self.record
+    }
+}
+
+impl From<Record> for TransactionRecord {
+    fn from(record: Record) -> Self {
+        record.build()
+    }
+}
+
+/// Sequence of records run in order through a processor, e.g.
+/// `Scenario::new().then(Record::deposit(1, 1, "2.0")).run()`.
+#[derive(Debug, Default, Clone)]
+pub struct Scenario {
+    records: Vec<TransactionRecord>,
+}
+
+impl Scenario {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a record to the scenario.
+    pub fn then<R: Into<TransactionRecord>>(mut self, record: R) -> Self {
+        self.records.push(record.into());
+        self
+    }
+
+    /// Runs the scenario through a fresh processor and returns the balances
+    /// of its clients by increasing client id.
+    pub fn run(self) -> Vec<ClientView> {
+        self.run_with_outcomes().1
+    }
+
+    /// Same as `run`, also returning the outcome of each record.
+    pub fn run_with_outcomes(self) -> (Vec<Result<Applied, ProcessingError>>, Vec<ClientView>) {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+        self.run_on(&mut processor)
+    }
+
+    /// Runs the scenario through the given processor, e.g. one built with
+    /// other settings, and returns the outcome of each record with the
+    /// balances of the clients of the processor.
+    pub fn run_on<const L: u64, const S: u32>(
+        self,
+        processor: &mut TransactionProcessor<L, S>,
+    ) -> (Vec<Result<Applied, ProcessingError>>, Vec<ClientView>) {
+        let outcomes = processor.process_iter(self.records);
+        let mut client_ids: Vec<_> = processor.client_ids().collect();
+        client_ids.sort_by_key(|client_id| client_id.0);
+        let clients = client_ids
+            .into_iter()
+            .filter_map(|client_id| processor.client_view(client_id))
+            .collect();
+        (outcomes, clients)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::type_defs::{Amount, ClientId};
+
+    // Test that the builder sets the fields of the record.
+    #[test]
+    fn test_record_builder() {
+        let record = Record::withdrawal(3, 9, "1.5")
+            .memo("rent")
+            .idempotency_key("key-9")
+            .build();
+        assert_eq!(record.transaction_type, "withdrawal");
+        assert_eq!((record.client, record.tx), (3, 9));
+        assert_eq!(record.amount.as_deref(), Some("1.5"));
+        assert_eq!(record.memo.as_deref(), Some("rent"));
+        assert_eq!(record.idempotency_key.as_deref(), Some("key-9"));
+        assert_eq!(record.timestamp, None);
+        assert_eq!(Record::dispute(3, 9).build().amount, None);
+    }
+
+    // Test that a scenario returns the outcome of each record and the
+    // balances of every client by client id.
+    #[test]
+    fn test_scenario() {
+        let (outcomes, clients) = Scenario::new()
+            .then(Record::deposit(2, 1, "3.0"))
+            .then(Record::deposit(1, 2, "1.0"))
+            .then(Record::withdrawal(1, 3, "2.0"))
+            .then(Record::dispute(2, 1).amount("1.0"))
+            .run_with_outcomes();
+        assert_eq!(
+            outcomes.iter().map(Result::is_ok).collect::<Vec<_>>(),
+            vec![true, true, false, true]
+        );
+        let amount = |value: &str| -> Amount { value.parse().unwrap() };
+        assert_eq!(
+            clients,
+            vec![
+                ClientView {
+                    client_id: ClientId(1),
+                    available: amount("1"),
+                    held: Amount::new(),
+                    total: amount("1"),
+                    locked: false,
+                },
+                ClientView {
+                    client_id: ClientId(2),
+                    available: amount("2"),
+                    held: amount("1"),
+                    total: amount("3"),
+                    locked: false,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::ProcessingError;
+use crate::observer::ProcessorObserver;
+use crate::processor::Applied;
+use crate::type_defs::{Amount, ClientId, TransactionRecord};
+
+/// Log of the records of one traced client, with the balances the client had
+/// after the last applied record.
+struct ClientLog {
+    file: File,
+    /// Number of lines written so far.
+    lines: u64,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+/// Observer which appends every record of the traced clients, applied or
+/// rejected, to their own `client-<id>.log` file, one line per record with the
+/// balances of the client after it, e.g.
+/// `3: withdrawal tx 4 amount 10.0 rejected E_INSUFFICIENT (Insufficient funds), available 5.0, held 0.0000, total 5.0, locked false`.
+///
+/// The traces are a best effort, a log which can not be written is not
+/// written anymore and the run goes on.
+pub struct ClientTraces {
+    logs: HashMap<ClientId, ClientLog>,
+}
+
+impl ClientTraces {
+    /// Opens the log file of each client in dir, appending to the existing
+    /// ones.
+    pub fn create(dir: &Path, clients: &[ClientId]) -> Result<Self, String> {
+        let mut logs = HashMap::new();
+        for client_id in clients {
+            let path = dir.join(Self::file_name(*client_id));
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(|err| format!("Could not open {}: {}", path.display(), err))?;
+            logs.insert(
+                *client_id,
+                ClientLog {
+                    file,
+                    lines: 0,
+                    available: Amount::new(),
+                    held: Amount::new(),
+                    total: Amount::new(),
+                    locked: false,
+                },
+            );
+        }
+        Ok(ClientTraces { logs })
+    }
+
+    /// Name of the log file of a client.
+    pub fn file_name(client_id: ClientId) -> String {
+        format!("client-{}.log", client_id)
+    }
+}
+
+impl ProcessorObserver for ClientTraces {
+    fn on_processed(
+        &mut self,
+        record: &TransactionRecord,
+        result: &Result<Applied, ProcessingError>,
+    ) {
+        let client_id = ClientId(record.client);
+        let log = match self.logs.get_mut(&client_id) {
+            Some(log) => log,
+            None => return,
+        };
+        let outcome = match result {
+            Ok(applied) => {
+                log.available = applied.available;
+                log.held = applied.held;
+                log.total = applied.total;
+                log.locked = applied.locked;
+                "applied".to_owned()
+            }
+            Err(err) => format!("rejected {} ({})", err.code(), err),
+        };
+        let amount = match &record.amount {
+            Some(amount) if !amount.is_empty() => format!(" amount {}", amount),
+            _ => String::new(),
+        };
+        log.lines += 1;
+        let written = writeln!(
+            log.file,
+            "{}: {} tx {}{} {}, available {}, held {}, total {}, locked {}",
+            log.lines,
+            record.transaction_type,
+            record.tx,
+            amount,
+            outcome,
+            log.available,
+            log.held,
+            log.total,
+            log.locked
+        );
+        if written.is_err() {
+            self.logs.remove(&client_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+    use tempdir::TempDir;
+
+    fn record(
+        transaction_type: &str,
+        client: u32,
+        tx: u64,
+        amount: Option<&str>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type: transaction_type.to_owned(),
+            client,
+            tx,
+            amount: amount.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    // Test that every record of the traced client is logged in order with the
+    // balances after it, and the records of other clients are not.
+    #[test]
+    fn test_client_trace() {
+        let dir = TempDir::new("client_trace").unwrap();
+        let traces = ClientTraces::create(dir.path(), &[ClientId(812)]).unwrap();
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_observer(Box::new(traces));
+        processor.process_iter(vec![
+            record("deposit", 812, 1, Some("5.0")),
+            record("deposit", 7, 2, Some("1.0")),
+            record("dispute", 812, 1, None),
+            record("withdrawal", 812, 3, Some("1.0")),
+            record("resolve", 812, 1, None),
+            record("withdrawal", 812, 4, Some("1.0")),
+        ]);
+        drop(processor);
+
+        let log = std::fs::read_to_string(dir.path().join("client-812.log")).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "1: deposit tx 1 amount 5.0 applied, available 5.0, held 0.0000, total 5.0, locked false",
+                "2: dispute tx 1 applied, available 0.0000, held 5.0, total 5.0, locked false",
+                "3: withdrawal tx 3 amount 1.0 rejected E_INSUFFICIENT (Insufficient funds), available 0.0000, held 5.0, total 5.0, locked false",
+                "4: resolve tx 1 applied, available 5.0, held 0.0000, total 5.0, locked false",
+                "5: withdrawal tx 4 amount 1.0 applied, available 4.0, held 0.0000, total 4.0, locked false",
+            ]
+        );
+        assert!(!dir.path().join("client-7.log").exists());
+    }
+}
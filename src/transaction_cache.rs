@@ -1,118 +1,612 @@
 use fs::OpenOptions;
+use std::collections::hash_map::DefaultHasher;
 use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 use std::collections::HashMap;
 use tempdir::TempDir;
 
+use crate::checksum::checksum;
+use crate::store::TransactionStore;
 use crate::type_defs::{Transaction, TransactionId};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+/// Type which can be used as the key of a `TransactionCache`, keys are grouped
+/// into cache lines which are spilled to disk together.
+pub trait CacheKeyed: Eq + Hash + Clone + Serialize + DeserializeOwned {
+    /// Returns the number of the cache line holding the key. The keys which
+    /// are not numbered are spread over `cache_lines` lines.
+    fn cache_line(&self, cache_line_size: u32, cache_lines: u64) -> u64;
+}
+
+impl CacheKeyed for TransactionId {
+    fn cache_line(&self, cache_line_size: u32, _: u64) -> u64 {
+        self.0 / cache_line_size as u64
+    }
+}
+
+impl CacheKeyed for String {
+    /// Strings are spread over the cache lines by hash, the hasher is only
+    /// stable within a build which is fine as the cache files do not outlive
+    /// the run.
+    fn cache_line(&self, _: u32, cache_lines: u64) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish() % cache_lines
+    }
+}
+
+/// Version of the format of the spilled cache lines, it has to be bumped
+/// whenever the serialized representation of the cached values changes.
+///
+/// Version 3 widened the transaction ids and the cache line numbers to u64
+/// and version 4 the client ids to u32. Version 5 added the checksum of the
+/// cache line to its header. The cache lines spilled by earlier versions are
+/// rejected and have to be spilled again by processing the input again.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+pub struct CacheFormatVersion(pub u32);
+
+impl CacheFormatVersion {
+    pub const CURRENT: CacheFormatVersion = CacheFormatVersion(5);
+}
+
+/// Header written on the first line of every spilled cache line.
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFileHeader {
+    version: CacheFormatVersion,
+    /// `CACHE_LINE_SIZE` of the cache which spilled the line, the keys are
+    /// grouped into other cache lines under another size. It is missing from
+    /// the headers of the first format version.
+    #[serde(default)]
+    line_size: u32,
+    /// `checksum::checksum` of the rest of the file, missing from the headers
+    /// before format version 5.
+    #[serde(default)]
+    checksum: u64,
+}
+
+/// Returns the cache lines spilled into cache_dir by increasing number, the
+/// files which are not named after a cache line are ignored.
+pub fn spilled_lines(cache_dir: &Path) -> Result<Vec<u64>, String> {
+    let entries = fs::read_dir(cache_dir)
+        .map_err(|err| format!("Could not read {} because of: {}", cache_dir.display(), err))?;
+    let mut lines: Vec<u64> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    lines.sort_unstable();
+    Ok(lines)
+}
+
+/// Reads a spilled cache line file on its own, e.g. to inspect the cache dir
+/// left by a run, rejecting the files written in another format version.
+pub fn read_cache_file<K, V>(path: &Path) -> Result<HashMap<K, V>, String>
+where
+    K: Eq + Hash + DeserializeOwned,
+    V: DeserializeOwned,
+{
+    read_cache_line(open_cache_file(path)?, None)
+}
+
+fn open_cache_file(path: &Path) -> Result<BufReader<fs::File>, String> {
+    fs::File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("Could not open {} because of: {}", path.display(), err))
+}
+
+/// Reads the header of a spilled cache line, rejecting the ones written in
+/// another format version or, when given, under another cache line size.
+fn read_cache_header<R: BufRead>(
+    reader: &mut R,
+    line_size: Option<u32>,
+) -> Result<CacheFileHeader, String> {
+    let mut header = String::new();
+    reader
+        .read_line(&mut header)
+        .map_err(|err| format!("Could not read cache header because of: {}", err))?;
+    let header: CacheFileHeader = serde_json::from_str(&header)
+        .map_err(|err| format!("Invalid cache header because of: {}", err))?;
+    if header.version != CacheFormatVersion::CURRENT {
+        return Err(format!(
+            "Cache format version {} is not supported, expected {}",
+            header.version.0,
+            CacheFormatVersion::CURRENT.0
+        ));
+    }
+    match line_size {
+        Some(line_size) if line_size != header.line_size => Err(format!(
+            "Cache line size {} does not match the cache line size {} of this cache",
+            header.line_size, line_size
+        )),
+        _ => Ok(header),
+    }
+}
+
+/// Reads a spilled cache line, rejecting the ones written in another format
+/// version or, when given, under another cache line size, and the ones which
+/// do not match the checksum of their header, e.g. truncated ones.
+fn read_cache_line<K, V, R>(reader: R, line_size: Option<u32>) -> Result<HashMap<K, V>, String>
+where
+    K: Eq + Hash + DeserializeOwned,
+    V: DeserializeOwned,
+    R: BufRead,
+{
+    let content = read_cache_content(reader, line_size)?;
+    serde_json::from_slice(&content)
+        .map_err(|err| format!("Could not read cache line because of: {}", err))
+}
+
+/// Returns the content of a spilled cache line after its header, checked in
+/// the same way as `read_cache_line` but not deserialized.
+fn read_cache_content<R: BufRead>(
+    mut reader: R,
+    line_size: Option<u32>,
+) -> Result<Vec<u8>, String> {
+    let header = read_cache_header(&mut reader, line_size)?;
+    let mut content = Vec::new();
+    reader
+        .read_to_end(&mut content)
+        .map_err(|err| format!("Could not read cache line because of: {}", err))?;
+    if checksum(&content) != header.checksum {
+        return Err(format!(
+            "Cache line checksum {} does not match its content {}",
+            header.checksum,
+            checksum(&content)
+        ));
+    }
+    Ok(content)
+}
+
+/// What a cache does when a cache line can not be spilled, e.g. because the
+/// disk is full. The cache line is kept in memory in both cases.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SpillFailurePolicy {
+    /// Warn and keep every cache line in memory from then on.
+    #[default]
+    KeepInMemory,
+    /// Keep every cache line in memory and report the failure through
+    /// `TransactionCache::spill_error`, so the run can be stopped.
+    Abort,
+}
+
+/// Function creating the file a cache line is spilled into.
+pub(crate) type SpillFile = fn(&Path) -> io::Result<Box<dyn Write>>;
+
+fn create_spill_file(path: &Path) -> io::Result<Box<dyn Write>> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    Ok(Box::new(BufWriter::new(file)))
+}
+
 /// Type which represents a CacheKey identifier.
 #[derive(Debug, Default, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
-struct CacheKey<const CACHE_LINE_SIZE: u32>(u32);
+struct CacheKey<const CACHE_LINE_SIZE: u32>(u64);
 
-impl<const CACHE_LINE_SIZE: u32> From<TransactionId> for CacheKey<CACHE_LINE_SIZE> {
-    fn from(transaction_id: TransactionId) -> Self {
-        CacheKey(transaction_id.0 / CACHE_LINE_SIZE)
+impl<const CACHE_LINE_SIZE: u32> CacheKey<CACHE_LINE_SIZE> {
+    /// Returns the key of the cache line holding key in a cache of at most
+    /// cache_size_limit entries, which fits that many lines.
+    fn of<K: CacheKeyed>(key: &K, cache_size_limit: u64) -> Self {
+        let cache_lines = (cache_size_limit / CACHE_LINE_SIZE as u64).max(1);
+        CacheKey(key.cache_line(CACHE_LINE_SIZE, cache_lines))
     }
 }
 
 /// Type which represents a CacheLine
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
-struct CacheLine {
+#[derive(Debug, Clone)]
+struct CacheLine<K, V> {
     loaded: bool,
-    transactions: HashMap<TransactionId, Transaction>,
+    /// Value of the cache access counter the last time this line was used.
+    last_access: u64,
+    transactions: HashMap<K, V>,
+}
+
+impl<K, V> Default for CacheLine<K, V> {
+    fn default() -> Self {
+        CacheLine {
+            loaded: false,
+            last_access: 0,
+            transactions: HashMap::new(),
+        }
+    }
+}
+
+/// Iterator over the entries of a `TransactionCache` returned by
+/// `TransactionCache::entries`, the entries of a cache line are cloned when
+/// the line is reached so it can be spilled again right away.
+pub struct Entries<'a, const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32, K, V> {
+    cache: &'a mut TransactionCache<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE, K, V>,
+    lines: std::vec::IntoIter<u64>,
+    /// Entries of the current cache line not yielded yet.
+    line: std::vec::IntoIter<(K, V)>,
+}
+
+impl<'a, const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32, K, V> Iterator
+    for Entries<'a, CACHE_SIZE_LIMIT, CACHE_LINE_SIZE, K, V>
+where
+    K: CacheKeyed + Clone,
+    V: Serialize + DeserializeOwned + Clone,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.line.next() {
+                return Some(entry);
+            }
+            let line = self.lines.next()?;
+            let entries: Vec<(K, V)> = self
+                .cache
+                .load_line(line)
+                .transactions
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            self.line = entries.into_iter();
+            self.cache.store_cache();
+        }
+    }
 }
 
 /// Type which abstracts a cache of transactions it behaves exactly as a HashMap
 /// with the benefit that it tracks how many records are stored in memory and
 /// it goes beyond a certain threshold define by the CACHE_SIZE_LIMIT generic it
-/// serializes the caches into files on disk.
+/// serializes the least recently used cache lines into files on disk.
+///
+/// Transactions are stored by transaction id by default, any other `CacheKeyed`
+/// key and serializable value can be stored the same way.
 #[derive(Debug)]
-pub struct TransactionCache<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32> {
-    cache: HashMap<CacheKey<CACHE_LINE_SIZE>, CacheLine>,
+pub struct TransactionCache<
+    const CACHE_SIZE_LIMIT: u64,
+    const CACHE_LINE_SIZE: u32,
+    K = TransactionId,
+    V = Transaction,
+> {
+    cache: HashMap<CacheKey<CACHE_LINE_SIZE>, CacheLine<K, V>>,
     cache_size: u64,
-    cache_size_limit: u64,
-    cache_dir: TempDir,
+    /// Number of transactions in memory above which cache lines are spilled.
+    high_watermark: u64,
+    /// Number of transactions in memory left once cache lines are spilled.
+    low_watermark: u64,
+    access_counter: u64,
+    cache_dir: PathBuf,
+    /// Removes the cache dir when the cache is dropped, unset for the caches
+    /// created with `new_in`.
+    _temp_dir: Option<TempDir>,
+    spill_policy: SpillFailurePolicy,
+    /// Set once a cache line could not be spilled.
+    spill_disabled: bool,
+    /// Set for the caches without a cache dir, which never touch the disk.
+    memory_only: bool,
+    spill_error: Option<String>,
+    /// Creates the file a cache line is spilled into.
+    spill_file: SpillFile,
 }
 
-impl<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>
-    TransactionCache<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE>
+impl<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32, K, V>
+    TransactionCache<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE, K, V>
+where
+    K: CacheKeyed,
+    V: Serialize + DeserializeOwned,
 {
     pub fn new() -> Result<Self, String> {
+        Self::with_watermarks(CACHE_SIZE_LIMIT, CACHE_SIZE_LIMIT / 2)
+    }
+
+    /// Creates a cache which spills the least recently used cache lines to disk
+    /// once more than high_watermark transactions are held in memory, until at
+    /// most low_watermark transactions are left.
+    pub fn with_watermarks(high_watermark: u64, low_watermark: u64) -> Result<Self, String> {
         let tmp_dir = TempDir::new("transaction_cache")
             .map_err(|err| format!("Could not create cache dir because of: {}", err))?;
-        Ok(TransactionCache {
+        Ok(Self::with_dir(
+            tmp_dir.path().to_path_buf(),
+            Some(tmp_dir),
+            high_watermark,
+            low_watermark,
+        ))
+    }
+
+    /// Creates a cache which spills its cache lines into a new uniquely named
+    /// subdirectory of base_dir, so caches sharing the same base_dir never
+    /// collide. The subdirectory is left in place when the cache is dropped.
+    pub fn new_in(base_dir: &Path) -> Result<Self, String> {
+        let tmp_dir = TempDir::new_in(base_dir, "transaction_cache").map_err(|err| {
+            format!(
+                "Could not create cache dir in {} because of: {}",
+                base_dir.display(),
+                err
+            )
+        })?;
+        Ok(Self::with_dir(
+            tmp_dir.into_path(),
+            None,
+            CACHE_SIZE_LIMIT,
+            CACHE_SIZE_LIMIT / 2,
+        ))
+    }
+
+    /// Creates a cache which keeps every transaction in memory, e.g. when no
+    /// cache dir can be created. `cache_dir` is empty.
+    pub fn in_memory_only() -> Self {
+        let mut cache =
+            Self::with_dir(PathBuf::new(), None, CACHE_SIZE_LIMIT, CACHE_SIZE_LIMIT / 2);
+        cache.spill_disabled = true;
+        cache.memory_only = true;
+        cache
+    }
+
+    /// Attaches to the cache dir left by a previous cache, e.g. after a crash,
+    /// its spilled cache lines are only read once accessed. The dir is left in
+    /// place when the cache is dropped.
+    ///
+    /// The cache only holds what was spilled before the previous cache went
+    /// away: the transactions it still had in memory are lost and a spilled
+    /// cache line may be older than the last changes made to it. It is up to
+    /// the caller to replay the input from a point covering those changes.
+    ///
+    /// A dir holding cache lines of another format version or spilled under
+    /// another `CACHE_LINE_SIZE` is rejected, its keys would be looked up in
+    /// the wrong cache lines, as well as a dir holding a cache line which does
+    /// not match its checksum. The cache lines are spilled into a temporary
+    /// file renamed over the line, so a crash never leaves a torn one behind.
+    pub fn reopen(cache_dir: &Path) -> Result<Self, String> {
+        if !cache_dir.is_dir() {
+            return Err(format!(
+                "Could not reopen cache dir {}, it is not a directory",
+                cache_dir.display()
+            ));
+        }
+        for line in spilled_lines(cache_dir)? {
+            let path = cache_dir.join(line.to_string());
+            read_cache_content(open_cache_file(&path)?, Some(CACHE_LINE_SIZE)).map_err(|err| {
+                format!(
+                    "Could not reopen cache dir {}, cache line {}: {}",
+                    cache_dir.display(),
+                    line,
+                    err
+                )
+            })?;
+        }
+        Ok(Self::with_dir(
+            cache_dir.to_path_buf(),
+            None,
+            CACHE_SIZE_LIMIT,
+            CACHE_SIZE_LIMIT / 2,
+        ))
+    }
+
+    fn with_dir(
+        cache_dir: PathBuf,
+        temp_dir: Option<TempDir>,
+        high_watermark: u64,
+        low_watermark: u64,
+    ) -> Self {
+        TransactionCache {
             cache: HashMap::new(),
             cache_size: 0,
-            cache_size_limit: CACHE_SIZE_LIMIT,
-            cache_dir: tmp_dir,
-        })
+            high_watermark,
+            low_watermark: low_watermark.min(high_watermark),
+            access_counter: 0,
+            cache_dir,
+            _temp_dir: temp_dir,
+            spill_policy: SpillFailurePolicy::default(),
+            spill_disabled: false,
+            memory_only: false,
+            spill_error: None,
+            spill_file: create_spill_file,
+        }
     }
 
-    pub fn get(&mut self, transaction_id: &TransactionId) -> Option<&Transaction> {
-        let cache_key = CacheKey::from(*transaction_id);
-        let mut cache_line = self
-            .cache
-            .entry(cache_key)
-            .or_insert_with(CacheLine::default);
+    /// Directory holding the spilled cache lines.
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
 
-        self.cache_size += Self::load_cache(&self.cache_dir, cache_key, &mut cache_line);
-        cache_line.transactions.get(transaction_id)
+    /// Number of transactions held in memory, the spilled ones are not counted.
+    pub fn in_memory(&self) -> u64 {
+        self.cache_size
     }
 
-    pub fn contains_key(&mut self, transaction_id: &TransactionId) -> bool {
-        let cache_key = CacheKey::from(*transaction_id);
-        let mut cache_line = self
-            .cache
-            .entry(cache_key)
-            .or_insert_with(CacheLine::default);
+    pub fn get(&mut self, transaction_id: &K) -> Option<&V> {
+        self.cache_line(transaction_id)
+            .transactions
+            .get(transaction_id)
+    }
 
-        self.cache_size += Self::load_cache(&self.cache_dir, cache_key, &mut cache_line);
-        cache_line.transactions.contains_key(transaction_id)
+    pub fn contains_key(&mut self, transaction_id: &K) -> bool {
+        self.cache_line(transaction_id)
+            .transactions
+            .contains_key(transaction_id)
     }
 
-    pub fn remove(&mut self, transaction_id: &TransactionId) -> Option<Transaction> {
-        let cache_key = CacheKey::from(*transaction_id);
-        let cache_line = self
-            .cache
-            .entry(cache_key)
-            .or_insert_with(CacheLine::default);
-        self.cache_size += Self::load_cache(&self.cache_dir, cache_key, cache_line);
-        cache_line.transactions.remove(transaction_id)
+    pub fn remove(&mut self, transaction_id: &K) -> Option<V> {
+        let val = self
+            .cache_line(transaction_id)
+            .transactions
+            .remove(transaction_id);
+        if val.is_some() {
+            self.cache_size -= 1;
+        }
+        val
+    }
+
+    /// Calls f with every entry of the cache by increasing cache line, the
+    /// spilled cache lines are loaded one at a time and spilled again once
+    /// too many transactions are in memory.
+    pub fn for_each<F: FnMut(&K, &V)>(&mut self, mut f: F) {
+        for line in self.lines() {
+            for (key, value) in self.load_line(line).transactions.iter() {
+                f(key, value);
+            }
+            self.store_cache();
+        }
+    }
+
+    /// Returns an iterator over clones of every entry of the cache, loading
+    /// the spilled cache lines as they are reached in the same way as
+    /// `for_each`.
+    pub fn entries(&mut self) -> Entries<'_, CACHE_SIZE_LIMIT, CACHE_LINE_SIZE, K, V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        Entries {
+            lines: self.lines().into_iter(),
+            cache: self,
+            line: Vec::new().into_iter(),
+        }
     }
 
+    /// Returns the cache lines in memory or on disk by increasing line.
+    fn lines(&self) -> Vec<u64> {
+        let mut lines: Vec<u64> = self.cache.keys().map(|cache_key| cache_key.0).collect();
+        if !self.memory_only {
+            lines.extend(spilled_lines(&self.cache_dir).unwrap_or_default());
+        }
+        lines.sort_unstable();
+        lines.dedup();
+        lines
+    }
+
+    /// Returns the cache line, loading it from disk if needed, without
+    /// marking it as used.
+    fn load_line(&mut self, line: u64) -> &mut CacheLine<K, V> {
+        let cache_key = CacheKey(line);
+        let cache_line = self.cache.entry(cache_key).or_default();
+        if !self.memory_only {
+            match Self::load_cache(&self.cache_dir, cache_key, cache_line) {
+                Ok(loaded) => self.cache_size += loaded,
+                Err(err) => {
+                    self.spill_disabled = true;
+                    self.spill_error.get_or_insert(err);
+                }
+            }
+        }
+        cache_line
+    }
+
+    /// Returns the cache line holding the transaction, loading it from disk if
+    /// needed, and marks it as the most recently used one.
+    fn cache_line(&mut self, transaction_id: &K) -> &mut CacheLine<K, V> {
+        let cache_key = CacheKey::of(transaction_id, CACHE_SIZE_LIMIT);
+        let cache_line = self.cache.entry(cache_key).or_default();
+
+        self.access_counter += 1;
+        cache_line.last_access = self.access_counter;
+        if !self.memory_only {
+            match Self::load_cache(&self.cache_dir, cache_key, cache_line) {
+                Ok(loaded) => self.cache_size += loaded,
+                Err(err) => {
+                    self.spill_disabled = true;
+                    self.spill_error.get_or_insert(err);
+                }
+            }
+        }
+        cache_line
+    }
+
+    /// Loads the spilled cache line into cache_line unless it is loaded
+    /// already, returning the number of entries loaded. A cache line which can
+    /// not be read is left unloaded, so it is never spilled over the file.
     fn load_cache(
-        cache_dir: &TempDir,
+        cache_dir: &Path,
         cache_key: CacheKey<CACHE_LINE_SIZE>,
-        cache_line: &mut CacheLine,
-    ) -> u64 {
-        let cache_file_name = Self::cache_path(cache_dir.path().to_str().unwrap(), &cache_key);
+        cache_line: &mut CacheLine<K, V>,
+    ) -> Result<u64, String> {
+        let cache_file_name = Self::cache_path(cache_dir.to_str().unwrap(), &cache_key);
         let cache_file = std::path::Path::new(&cache_file_name);
         let mut num_loaded = 0;
         if !cache_line.loaded && cache_file.exists() {
-            let file = OpenOptions::new().read(true).open(cache_file).unwrap();
-
-            let stored_cache_lines: HashMap<TransactionId, Transaction> =
-                serde_json::from_reader(file).unwrap();
+            let stored_cache_lines: HashMap<K, V> = open_cache_file(cache_file)
+                .and_then(|file| read_cache_line(file, Some(CACHE_LINE_SIZE)))
+                .map_err(|err| format!("Could not load {}: {}", cache_file_name, err))?;
             num_loaded = stored_cache_lines.len();
             cache_line.transactions.extend(stored_cache_lines);
-            cache_line.loaded = true;
         }
-        num_loaded as u64
+        cache_line.loaded = true;
+        Ok(num_loaded as u64)
     }
 
+    /// Writes a cache line preceded by the header of the current format,
+    /// which holds the checksum of the cache line.
+    fn write_cache_line<W: Write>(
+        mut writer: W,
+        transactions: &HashMap<K, V>,
+    ) -> Result<(), String> {
+        let content = serde_json::to_vec(transactions)
+            .map_err(|err| format!("Could not write cache line because of: {}", err))?;
+        let header = CacheFileHeader {
+            version: CacheFormatVersion::CURRENT,
+            line_size: CACHE_LINE_SIZE,
+            checksum: checksum(&content),
+        };
+        serde_json::to_writer(&mut writer, &header)
+            .map_err(|err| format!("Could not write cache header because of: {}", err))?;
+        writer
+            .write_all(b"\n")
+            .map_err(|err| format!("Could not write cache header because of: {}", err))?;
+        writer
+            .write_all(&content)
+            .map_err(|err| format!("Could not write cache line because of: {}", err))
+    }
+
+    /// Spills the least recently used cache lines to disk until the number of
+    /// transactions in memory gets under the low watermark. Once a cache line
+    /// can not be spilled every cache line is kept in memory.
     fn store_cache(&mut self) {
-        if self.cache_size > self.cache_size_limit {
-            for (cache_key, cache_line) in self.cache.iter() {
-                Self::store_cache_line(
-                    self.cache_dir.path().to_str().unwrap(),
-                    cache_key,
-                    cache_line,
-                );
+        if self.cache_size <= self.high_watermark || self.spill_disabled {
+            return;
+        }
+        self.spill_lines(self.low_watermark);
+    }
+
+    /// Spills every cache line to disk, whatever the number of transactions
+    /// in memory, e.g. between two chunks of an input.
+    pub fn flush(&mut self) {
+        if !self.spill_disabled {
+            self.spill_lines(0);
+        }
+    }
+
+    /// Spills the least recently used cache lines until at most target
+    /// transactions are left in memory.
+    fn spill_lines(&mut self, target: u64) {
+        let mut lines_by_access: Vec<(u64, CacheKey<CACHE_LINE_SIZE>)> = self
+            .cache
+            .iter()
+            .map(|(cache_key, cache_line)| (cache_line.last_access, *cache_key))
+            .collect();
+        lines_by_access.sort_unstable_by_key(|(last_access, _)| *last_access);
+
+        for (_, cache_key) in lines_by_access {
+            if self.cache_size <= target {
+                break;
             }
-            self.cache.clear();
-            self.cache_size = 0;
+            let cache_line = &self.cache[&cache_key];
+            if let Err(err) = self.store_cache_line(&cache_key, cache_line) {
+                self.spill_failed(err);
+                return;
+            }
+            if let Some(cache_line) = self.cache.remove(&cache_key) {
+                self.cache_size -= cache_line.transactions.len() as u64;
+            }
+        }
+    }
+
+    fn spill_failed(&mut self, err: String) {
+        self.spill_disabled = true;
+        match self.spill_policy {
+            SpillFailurePolicy::KeepInMemory => eprintln!(
+                "Warning: {}, the cache in {} is kept in memory only from now on",
+                err,
+                self.cache_dir.display()
+            ),
+            SpillFailurePolicy::Abort => self.spill_error = Some(err),
         }
     }
 
@@ -121,33 +615,432 @@ impl<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32>
     }
 
     fn store_cache_line(
-        cache_save_prefix: &str,
+        &self,
         cache_key: &CacheKey<CACHE_LINE_SIZE>,
-        cache_line: &CacheLine,
-    ) {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(Self::cache_path(cache_save_prefix, cache_key))
-            .unwrap();
-        serde_json::to_writer(file, &cache_line.transactions).unwrap();
-    }
-
-    pub fn insert(
-        &mut self,
-        transaction_id: TransactionId,
-        transaction: Transaction,
-    ) -> Option<Transaction> {
+        cache_line: &CacheLine<K, V>,
+    ) -> Result<(), String> {
+        let cache_file_name = Self::cache_path(self.cache_dir.to_str().unwrap(), cache_key);
+        if cache_line.transactions.is_empty() {
+            // Nothing to keep, just make sure stale transactions are not loaded back.
+            let _ = fs::remove_file(cache_file_name);
+            return Ok(());
+        }
+        // The cache line is written aside and renamed over the previous one,
+        // so the file of the line is always complete.
+        let cache_file = Path::new(&cache_file_name);
+        let spilled_file = cache_file.with_extension("spill");
+        let written = (self.spill_file)(&spilled_file)
+            .map_err(|err| {
+                format!(
+                    "Could not create {} because of: {}",
+                    spilled_file.display(),
+                    err
+                )
+            })
+            .and_then(|mut file| {
+                Self::write_cache_line(&mut file, &cache_line.transactions)?;
+                file.flush()
+                    .map_err(|err| format!("Could not write cache line because of: {}", err))
+            })
+            .and_then(|()| {
+                fs::rename(&spilled_file, cache_file).map_err(|err| {
+                    format!(
+                        "Could not rename {} because of: {}",
+                        spilled_file.display(),
+                        err
+                    )
+                })
+            })
+            .map_err(|err| format!("Could not spill cache line {}: {}", cache_key.0, err));
+        if written.is_err() {
+            let _ = fs::remove_file(&spilled_file);
+        }
+        written
+    }
+
+    /// Spills the cache lines once more than limit transactions are held in
+    /// memory, until half of them are left, instead of CACHE_SIZE_LIMIT.
+    pub fn set_cache_limit(&mut self, limit: u64) {
+        self.high_watermark = limit;
+        self.low_watermark = limit / 2;
+        self.store_cache();
+    }
+
+    /// What the cache does when a cache line can not be spilled.
+    pub fn set_spill_policy(&mut self, spill_policy: SpillFailurePolicy) {
+        self.spill_policy = spill_policy;
+    }
+
+    /// Returns why a cache line could not be spilled, only set with
+    /// `SpillFailurePolicy::Abort`, or why a spilled cache line could not be
+    /// loaded back, whatever the policy.
+    pub fn spill_error(&self) -> Option<&str> {
+        self.spill_error.as_deref()
+    }
+
+    /// Spills the cache lines through the given function instead of files,
+    /// e.g. to simulate a full disk.
+    #[cfg(test)]
+    pub(crate) fn set_spill_file(&mut self, spill_file: SpillFile) {
+        self.spill_file = spill_file;
+    }
+
+    pub fn insert(&mut self, transaction_id: K, transaction: V) -> Option<V> {
         let val = self
-            .cache
-            .entry(CacheKey::from(transaction_id))
-            .or_insert_with(CacheLine::default)
+            .cache_line(&transaction_id)
             .transactions
             .insert(transaction_id, transaction);
-        self.cache_size += 1;
+        if val.is_none() {
+            self.cache_size += 1;
+        }
         self.store_cache();
         val
     }
 }
+
+impl<const CACHE_SIZE_LIMIT: u64, const CACHE_LINE_SIZE: u32, K, V> TransactionStore<K, V>
+    for TransactionCache<CACHE_SIZE_LIMIT, CACHE_LINE_SIZE, K, V>
+where
+    K: CacheKeyed,
+    V: Serialize + DeserializeOwned,
+{
+    fn get(&mut self, key: &K) -> Option<&V> {
+        TransactionCache::get(self, key)
+    }
+
+    fn contains_key(&mut self, key: &K) -> bool {
+        TransactionCache::contains_key(self, key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        TransactionCache::insert(self, key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        TransactionCache::remove(self, key)
+    }
+
+    fn for_each<F: FnMut(&K, &V)>(&mut self, f: F) {
+        TransactionCache::for_each(self, f)
+    }
+
+    fn entries<'a>(&'a mut self) -> Box<dyn Iterator<Item = (K, V)> + 'a>
+    where
+        K: Clone + 'a,
+        V: Clone + 'a,
+    {
+        Box::new(TransactionCache::entries(self))
+    }
+
+    fn in_memory(&self) -> u64 {
+        TransactionCache::in_memory(self)
+    }
+
+    fn set_spill_policy(&mut self, spill_policy: SpillFailurePolicy) {
+        TransactionCache::set_spill_policy(self, spill_policy)
+    }
+
+    fn set_cache_limit(&mut self, limit: u64) {
+        TransactionCache::set_cache_limit(self, limit)
+    }
+
+    fn flush(&mut self) {
+        TransactionCache::flush(self)
+    }
+
+    fn spill_error(&self) -> Option<&str> {
+        TransactionCache::spill_error(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::type_defs::ClientId;
+
+    type TestCache = TransactionCache<0, 10>;
+
+    fn deposit(tx: u64) -> Transaction {
+        Transaction::Deposit {
+            client: ClientId(1),
+            tx: TransactionId(tx),
+            amount: "1".parse().unwrap(),
+        }
+    }
+
+    fn resident_lines(cache: &TestCache) -> Vec<u64> {
+        let mut lines: Vec<u64> = cache.cache.keys().map(|cache_key| cache_key.0).collect();
+        lines.sort_unstable();
+        lines
+    }
+
+    // Test that only the least recently used cache lines are spilled and that
+    // spilled transactions are loaded back.
+    #[test]
+    fn test_lru_eviction() {
+        let mut cache = TestCache::with_watermarks(30, 25).unwrap();
+        for tx in 0..30 {
+            cache.insert(TransactionId(tx), deposit(tx));
+            // Keep the first cache line hot.
+            assert!(cache.contains_key(&TransactionId(0)));
+        }
+        assert_eq!(resident_lines(&cache), vec![0, 1, 2]);
+
+        // Going over the high watermark spills the coldest line only.
+        cache.insert(TransactionId(30), deposit(30));
+        assert_eq!(resident_lines(&cache), vec![0, 2, 3]);
+        assert_eq!(cache.cache_size, 21);
+        assert!(cache.cache_dir.join("1").exists());
+
+        // Spilled transactions are still available.
+        for tx in 0..31 {
+            assert!(cache.contains_key(&TransactionId(tx)));
+        }
+        assert_eq!(
+            cache.get(&TransactionId(15)).unwrap().tx_id(),
+            TransactionId(15)
+        );
+    }
+
+    // Test that transactions inserted into a spilled line do not overwrite the
+    // spilled ones.
+    #[test]
+    fn test_insert_into_spilled_line() {
+        let mut cache = TestCache::with_watermarks(10, 0).unwrap();
+        for tx in 0..11 {
+            cache.insert(TransactionId(tx), deposit(tx));
+        }
+        assert!(resident_lines(&cache).is_empty());
+
+        cache.insert(TransactionId(15), deposit(15));
+        assert_eq!(
+            cache.remove(&TransactionId(1)).unwrap().tx_id(),
+            TransactionId(1)
+        );
+        for tx in (0..11).filter(|tx| *tx != 1) {
+            assert!(cache.contains_key(&TransactionId(tx)));
+        }
+        assert!(!cache.contains_key(&TransactionId(1)));
+        assert_eq!(cache.cache_size, 11);
+    }
+
+    // Test that the ids around and above u32::MAX are bucketed into cache
+    // lines beyond the u32 range, spilled and loaded back.
+    #[test]
+    fn test_wide_ids() {
+        let wide = u32::MAX as u64;
+        assert_eq!(TransactionId(wide).cache_line(10, 1), 429_496_729);
+        assert_eq!(TransactionId(wide + 1).cache_line(10, 1), 429_496_729);
+        assert_eq!(TransactionId(u64::MAX).cache_line(10, 1), u64::MAX / 10);
+        assert_eq!(
+            TransactionId(u64::MAX).cache_line(u32::MAX, 1),
+            4_294_967_297
+        );
+
+        let mut cache = TestCache::with_watermarks(2, 0).unwrap();
+        let ids = [wide * 16, u64::MAX, wide - 1, wide, wide + 1];
+        for tx in ids {
+            cache.insert(TransactionId(tx), deposit(tx));
+        }
+        assert!(cache.cache_dir.join((u64::MAX / 10).to_string()).exists());
+        for tx in ids {
+            assert_eq!(cache.get(&TransactionId(tx)), Some(&deposit(tx)));
+        }
+    }
+
+    // Test that caches created in the same base dir get distinct
+    // subdirectories which are kept once the caches are dropped.
+    #[test]
+    fn test_new_in_distinct_subdirectories() {
+        let base_dir = TempDir::new("transaction_cache_base").unwrap();
+        let mut first = TestCache::new_in(base_dir.path()).unwrap();
+        let second = TestCache::new_in(base_dir.path()).unwrap();
+        assert_ne!(first.cache_dir(), second.cache_dir());
+        assert_eq!(first.cache_dir().parent(), Some(base_dir.path()));
+        assert_eq!(second.cache_dir().parent(), Some(base_dir.path()));
+
+        first.insert(TransactionId(0), deposit(0));
+        let first_dir = first.cache_dir().to_path_buf();
+        drop(first);
+        drop(second);
+        assert!(first_dir.exists());
+    }
+
+    // Test that values stored by string keys are spilled and loaded back.
+    #[test]
+    fn test_string_keys() {
+        let mut cache =
+            TransactionCache::<0, { u32::MAX / 4 }, String, u32>::with_watermarks(2, 0).unwrap();
+        for value in 0..10 {
+            cache.insert(format!("key-{}", value), value);
+        }
+        assert!(cache.cache_size <= 2);
+        for value in 0..10 {
+            assert_eq!(cache.get(&format!("key-{}", value)), Some(&value));
+        }
+        assert_eq!(cache.remove(&"key-3".to_owned()), Some(3));
+        assert!(!cache.contains_key(&"key-3".to_owned()));
+
+        // The keys are spread over CACHE_SIZE_LIMIT / CACHE_LINE_SIZE lines
+        // rather than spilled one file per key.
+        let mut cache = TransactionCache::<64, 8, String, u32>::with_watermarks(2, 0).unwrap();
+        for value in 0..1000 {
+            cache.insert(format!("key-{}", value), value);
+        }
+        cache.flush();
+        assert!(spilled_lines(cache.cache_dir()).unwrap().len() <= 8);
+        assert_eq!(cache.get(&"key-500".to_owned()), Some(&500));
+    }
+
+    // Test that a spilled cache line is read back with its header and that a
+    // cache line of another format version, or not matching its checksum, is
+    // rejected.
+    #[test]
+    fn test_cache_format_version() {
+        let transactions: HashMap<TransactionId, Transaction> =
+            (0..3).map(|tx| (TransactionId(tx), deposit(tx))).collect();
+        let mut written = Vec::new();
+        TestCache::write_cache_line(&mut written, &transactions).unwrap();
+        assert!(written.starts_with(b"{\"version\":5,\"line_size\":10,\"checksum\":"));
+        let read: HashMap<TransactionId, Transaction> =
+            read_cache_line(&written[..], Some(10)).unwrap();
+        assert_eq!(read, transactions);
+
+        let mut outdated = b"{\"version\":3,\"line_size\":10}\n".to_vec();
+        outdated.extend_from_slice(&written[written.iter().position(|b| *b == b'\n').unwrap()..]);
+        assert_eq!(
+            read_cache_line::<TransactionId, Transaction, _>(&outdated[..], None),
+            Err("Cache format version 3 is not supported, expected 5".to_owned())
+        );
+        let truncated = &written[..written.len() - 1];
+        assert!(
+            read_cache_line::<TransactionId, Transaction, _>(truncated, None)
+                .unwrap_err()
+                .starts_with("Cache line checksum ")
+        );
+        assert!(read_cache_line::<TransactionId, Transaction, _>(&b"{}"[..], None).is_err());
+        assert_eq!(
+            read_cache_line::<TransactionId, Transaction, _>(&written[..], Some(20)),
+            Err(
+                "Cache line size 10 does not match the cache line size 20 of this cache".to_owned()
+            )
+        );
+    }
+
+    // Test that a reopened cache dir gives back the spilled transactions and
+    // is kept once the cache is dropped.
+    #[test]
+    fn test_reopen() {
+        let base_dir = TempDir::new("transaction_cache_reopen").unwrap();
+        let mut cache = TestCache::new_in(base_dir.path()).unwrap();
+        for tx in 0..25 {
+            cache.insert(TransactionId(tx), deposit(tx));
+        }
+        let cache_dir = cache.cache_dir().to_path_buf();
+        drop(cache);
+
+        let mut reopened = TestCache::reopen(&cache_dir).unwrap();
+        assert_eq!(reopened.in_memory(), 0);
+        assert_eq!(reopened.get(&TransactionId(12)), Some(&deposit(12)));
+        let mut count = 0;
+        reopened.for_each(|_, _| count += 1);
+        assert_eq!(count, 25);
+        drop(reopened);
+        assert!(cache_dir.exists());
+
+        assert!(TestCache::reopen(&base_dir.path().join("missing")).is_err());
+    }
+
+    // Test that a cache dir spilled under one cache line size is rejected
+    // when reopened under another one instead of being misread.
+    #[test]
+    fn test_reopen_other_line_size() {
+        let base_dir = TempDir::new("transaction_cache_line_size").unwrap();
+        let mut cache = TestCache::new_in(base_dir.path()).unwrap();
+        for tx in 0..25 {
+            cache.insert(TransactionId(tx), deposit(tx));
+        }
+        let cache_dir = cache.cache_dir().to_path_buf();
+        drop(cache);
+
+        let err = TransactionCache::<0, 20>::reopen(&cache_dir).unwrap_err();
+        assert!(err.contains("Cache line size 10 does not match the cache line size 20"));
+        assert!(TestCache::reopen(&cache_dir).is_ok());
+    }
+
+    // Test that the cache lines are spilled without leaving a temporary file,
+    // that a cache line not matching its checksum is rejected by reopen and
+    // that loading it is reported through spill_error instead of panicking.
+    #[test]
+    fn test_corrupt_cache_line() {
+        let base_dir = TempDir::new("transaction_cache_corrupt").unwrap();
+        let mut cache = TestCache::new_in(base_dir.path()).unwrap();
+        for tx in 0..25 {
+            cache.insert(TransactionId(tx), deposit(tx));
+        }
+        let cache_dir = cache.cache_dir().to_path_buf();
+        let files: Vec<String> = fs::read_dir(&cache_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert!(files.iter().all(|file| file.parse::<u64>().is_ok()));
+
+        let line = cache_dir.join("1");
+        let mut content = fs::read(&line).unwrap();
+        content.truncate(content.len() - 1);
+        fs::write(&line, content).unwrap();
+        let err = TestCache::reopen(&cache_dir).unwrap_err();
+        assert!(err.contains("cache line 1: Cache line checksum"));
+
+        assert_eq!(cache.get(&TransactionId(12)), None);
+        assert!(cache.spill_error().unwrap().starts_with("Could not load "));
+        assert_eq!(cache.get(&TransactionId(3)), Some(&deposit(3)));
+    }
+
+    /// Writer of a full disk.
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::StorageFull, "No space left"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn full_disk(path: &Path) -> io::Result<Box<dyn Write>> {
+        fs::File::create(path)?;
+        Ok(Box::new(FullDisk))
+    }
+
+    // Test that a cache which can not spill keeps every transaction in memory
+    // without leaving a partial cache line behind, and only reports the
+    // failure with the abort policy.
+    #[test]
+    fn test_spill_failure() {
+        for policy in [SpillFailurePolicy::KeepInMemory, SpillFailurePolicy::Abort] {
+            let mut cache = TestCache::with_watermarks(10, 0).unwrap();
+            cache.set_spill_policy(policy);
+            cache.set_spill_file(full_disk);
+            for tx in 0..25 {
+                cache.insert(TransactionId(tx), deposit(tx));
+            }
+            assert_eq!(cache.in_memory(), 25);
+            assert_eq!(resident_lines(&cache), vec![0, 1, 2]);
+            assert!(!cache.cache_dir.join("0").exists());
+            assert!(!cache.cache_dir.join("0.spill").exists());
+            for tx in 0..25 {
+                assert_eq!(cache.get(&TransactionId(tx)), Some(&deposit(tx)));
+            }
+            match policy {
+                SpillFailurePolicy::KeepInMemory => assert_eq!(cache.spill_error(), None),
+                SpillFailurePolicy::Abort => assert!(cache
+                    .spill_error()
+                    .unwrap()
+                    .contains("Could not spill cache line 0")),
+            }
+        }
+    }
+}
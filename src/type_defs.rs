@@ -1,29 +1,68 @@
 use serde::{Deserialize, Serialize};
 
+use crate::error::ProcessingError;
+use crate::money::MoneyContext;
+
 use fmt::Display;
+use rust_decimal::prelude::RoundingStrategy;
 use rust_decimal::Decimal;
+use std::convert::TryFrom;
 use std::fmt;
-use std::ops::{AddAssign, SubAssign};
+use std::ops::{AddAssign, Neg, Sub, SubAssign};
 use std::str::FromStr;
 
 /// Type to represent a client Id
 #[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
-pub struct ClientId(pub u16);
+pub struct ClientId(pub u32);
+
+impl Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for ClientId {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .parse()
+            .map(ClientId)
+            .map_err(|err| format!("Invalid client id {}: {}", value, err))
+    }
+}
 
 /// Type to represent a transaction Id
 #[derive(Debug, PartialEq, Eq, Hash, Copy, Clone, Serialize, Deserialize)]
-pub struct TransactionId(pub u32);
+pub struct TransactionId(pub u64);
+
+impl Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for TransactionId {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .parse()
+            .map(TransactionId)
+            .map_err(|err| format!("Invalid transaction id {}: {}", value, err))
+    }
+}
 
 /// Decimal precision level
-const PRECISION: u32 = 4;
+pub const PRECISION: u32 = 4;
 
 /// Type to represent the amount held by a client account
-#[derive(Copy, Debug, Clone, PartialOrd, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Copy, Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Amount(Decimal);
 
 impl Display for Amount {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0.to_string())
+        write!(f, "{}", self.0)
     }
 }
 
@@ -32,70 +71,628 @@ impl Amount {
         Amount(Decimal::new(0, 4))
     }
 
-    pub fn from_str(fixed_value: String) -> Result<Self, String> {
-        let decimal = Decimal::from_str(&fixed_value).unwrap();
-        if decimal.scale() > PRECISION {
-            return Err("Invalid precision".to_owned());
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// Wraps a decimal as is, e.g. as parsed by a `MoneyContext`.
+    pub(crate) fn from_decimal(decimal: Decimal) -> Self {
+        Amount(decimal)
+    }
+
+    pub(crate) fn decimal(&self) -> Decimal {
+        self.0
+    }
+
+    /// Wraps the result of an arithmetic operation, a zero result is the same
+    /// as `Amount::new()` whatever its scale or sign, so a balance going back
+    /// to zero is written as the balance of a fresh account.
+    pub(crate) fn canonical(decimal: Decimal) -> Self {
+        if decimal.is_zero() {
+            Amount::new()
+        } else {
+            Amount(decimal)
         }
+    }
+
+    /// Returns the amount as an integer number of minor units, e.g. cents for
+    /// a precision of 2. Digits beyond the precision are rounded half away
+    /// from zero.
+    pub fn to_minor_units(&self, precision: u32) -> i128 {
+        let scaled = self.0 * Decimal::from(10i64.pow(precision));
+        scaled
+            .round_dp_with_strategy(0, RoundingStrategy::MidpointAwayFromZero)
+            .mantissa()
+    }
+
+    /// Parses an amount which may carry the given currency symbol right before
+    /// or right after the digits, e.g. "$10.00" or "10.00$", with the default
+    /// `MoneyContext`.
+    pub fn parse_with_symbol(value: &str, symbol: &str) -> Result<Self, ProcessingError> {
+        MoneyContext::default().parse_with_symbol(value, symbol)
+    }
+}
+
+/// Removes a leading or trailing currency symbol from an amount, any other
+/// symbol is left in place so the amount fails to parse.
+pub fn strip_currency_symbol<'a>(value: &'a str, symbol: &str) -> &'a str {
+    if symbol.is_empty() {
+        return value;
+    }
+    let value = value.trim();
+    value
+        .strip_prefix(symbol)
+        .or_else(|| value.strip_suffix(symbol))
+        .map(str::trim)
+        .unwrap_or(value)
+}
+
+impl Default for Amount {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ProcessingError;
+
+    /// Parses an amount with the default `MoneyContext`, see
+    /// `MoneyContext::parse`.
+    fn from_str(fixed_value: &str) -> Result<Self, Self::Err> {
+        MoneyContext::default().parse(fixed_value)
+    }
+}
+
+impl TryFrom<&str> for Amount {
+    type Error = ProcessingError;
 
-        Ok(Amount(decimal))
+    fn try_from(fixed_value: &str) -> Result<Self, Self::Error> {
+        fixed_value.parse()
     }
 }
 
+/// The operators panic when the result does not fit, the balances are
+/// combined through a `MoneyContext` instead.
 impl AddAssign for Amount {
     fn add_assign(&mut self, other: Self) {
-        self.0 += other.0;
+        *self = Amount::canonical(self.0 + other.0);
     }
 }
 
 impl SubAssign for Amount {
     fn sub_assign(&mut self, other: Self) {
-        self.0 -= other.0;
+        *self = Amount::canonical(self.0 - other.0);
+    }
+}
+
+/// The difference of two amounts might be negative, e.g. when reporting the
+/// net change of a client.
+impl Sub for Amount {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Amount::canonical(self.0 - other.0)
+    }
+}
+
+impl Neg for Amount {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Amount::canonical(-self.0)
     }
 }
 
 /// Type which holds a transaction information as read from the csv file.
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
 pub struct TransactionRecord {
     #[serde(rename = "type")]
     pub transaction_type: String,
-    pub client: u16,
-    pub tx: u32,
+    pub client: u32,
+    pub tx: u64,
     #[serde(default)]
     pub amount: Option<String>,
+    /// Key under which the outcome of the record is remembered, a record
+    /// with a known key is not applied again but gets the remembered outcome.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Time of the transaction as given in the input, it does not affect the
+    /// balances but is kept with deposits and withdrawals for the exports.
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    /// Free text memo of the transaction, it does not affect the balances. It
+    /// is written in the reports and kept with deposits and withdrawals only
+    /// when the processor is configured to.
+    #[serde(default)]
+    pub memo: Option<String>,
+}
+
+/// Free text kept with a processed transaction, outside of the balance logic.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionNotes {
+    pub timestamp: Option<String>,
+    pub memo: Option<String>,
+}
+
+impl TransactionNotes {
+    pub fn is_empty(&self) -> bool {
+        self.timestamp.is_none() && self.memo.is_none()
+    }
 }
 
-/// Type to represent a transaction.
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+impl From<&Transaction> for TransactionRecord {
+    /// Converts a transaction back into a record, transactions of a custom
+    /// type are written with the `extension` type.
+    fn from(transaction: &Transaction) -> Self {
+        TransactionRecord {
+            transaction_type: transaction.kind().to_string(),
+            client: transaction.client_id().0,
+            tx: transaction.tx_id().0,
+            amount: transaction.amount().map(|amount| amount.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Names of the transaction types read from the records.
+pub const TRANSACTION_TYPES: [&str; 5] =
+    ["deposit", "withdrawal", "dispute", "resolve", "chargeback"];
+
+/// Type to represent the kind of a transaction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TransactionKind {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    ChargeBack,
+    /// A transaction type applied by a handler registered on the processor.
+    Extension,
+}
+
+impl Display for TransactionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TransactionKind::Deposit => "deposit",
+            TransactionKind::Withdrawal => "withdrawal",
+            TransactionKind::Dispute => "dispute",
+            TransactionKind::Resolve => "resolve",
+            TransactionKind::ChargeBack => "chargeback",
+            TransactionKind::Extension => "extension",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Type to represent a transaction. It is spilled to disk by the
+/// `TransactionCache`, so any change to its serialized representation needs a
+/// new `CacheFormatVersion`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum Transaction {
-    Deposit(ClientId, TransactionId, Amount),
-    Withdrawal(ClientId, TransactionId, Amount),
-    Dispute(ClientId, TransactionId),
-    Resolve(ClientId, TransactionId),
-    ChargeBack(ClientId, TransactionId),
-    Unknown,
+    Deposit {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Amount,
+    },
+    Withdrawal {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Amount,
+    },
+    Dispute {
+        client: ClientId,
+        tx: TransactionId,
+        /// Disputed portion of the deposit, the whole deposit when not set.
+        amount: Option<Amount>,
+    },
+    Resolve {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    ChargeBack {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    /// Transaction of a custom type, applied by a handler registered on the
+    /// processor. It is only recorded so the transaction id is not reused.
+    Extension {
+        client: ClientId,
+        tx: TransactionId,
+    },
+}
+
+/// Type which holds a processed transaction together with its position in
+/// the sequence of the transactions applied to the client.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredTransaction {
+    pub transaction: Transaction,
+    pub sequence: u64,
+}
+
+impl TryFrom<TransactionRecord> for Transaction {
+    type Error = ProcessingError;
+
+    fn try_from(record: TransactionRecord) -> Result<Self, Self::Error> {
+        Self::try_from(&record)
+    }
+}
+
+impl TryFrom<&TransactionRecord> for Transaction {
+    type Error = ProcessingError;
+
+    /// Converts a record into a transaction with the default `MoneyContext`,
+    /// see `Transaction::from_record`.
+    fn try_from(record: &TransactionRecord) -> Result<Self, Self::Error> {
+        Self::from_record(record, &MoneyContext::default())
+    }
 }
 
 impl Transaction {
-    pub fn from_record(record: TransactionRecord) -> Result<Self, String> {
+    /// Converts a record into a transaction, deposits and withdrawals must carry
+    /// a non blank amount, disputes may carry one for the disputed portion while
+    /// for resolves and chargebacks the amount column is ignored. The amounts
+    /// are parsed with money.
+    pub fn from_record(
+        record: &TransactionRecord,
+        money: &MoneyContext,
+    ) -> Result<Self, ProcessingError> {
+        let client = ClientId(record.client);
+        let tx = TransactionId(record.tx);
+        let optional_amount = record
+            .amount
+            .as_deref()
+            .map(str::trim)
+            .filter(|amount| !amount.is_empty());
+        let amount = |kind: TransactionKind| -> Result<Amount, ProcessingError> {
+            money.parse(optional_amount.ok_or(ProcessingError::MissingAmount(kind))?)
+        };
         let transaction = match record.transaction_type.as_str() {
-            "deposit" => Transaction::Deposit(
-                ClientId(record.client),
-                TransactionId(record.tx),
-                Amount::from_str(record.amount.unwrap_or_else(|| "0.0".to_owned()))?,
+            "deposit" => Transaction::Deposit {
+                client,
+                tx,
+                amount: amount(TransactionKind::Deposit)?,
+            },
+            "withdrawal" => Transaction::Withdrawal {
+                client,
+                tx,
+                amount: amount(TransactionKind::Withdrawal)?,
+            },
+            "dispute" => Transaction::Dispute {
+                client,
+                tx,
+                amount: optional_amount
+                    .map(|amount| money.parse(amount))
+                    .transpose()?,
+            },
+            "resolve" => Transaction::Resolve { client, tx },
+            "chargeback" => Transaction::ChargeBack { client, tx },
+            unknown => return Err(ProcessingError::UnknownTransactionType(unknown.to_owned())),
+        };
+        Ok(transaction)
+    }
+}
+
+impl Transaction {
+    /// Returns the id of the client referenced by the transaction.
+    pub fn client_id(&self) -> ClientId {
+        match *self {
+            Transaction::Deposit { client, .. }
+            | Transaction::Withdrawal { client, .. }
+            | Transaction::Dispute { client, .. }
+            | Transaction::Resolve { client, .. }
+            | Transaction::ChargeBack { client, .. }
+            | Transaction::Extension { client, .. } => client,
+        }
+    }
+
+    /// Returns the id of the transaction, for dispute, resolve and chargeback
+    /// this is the id of the referenced transaction.
+    pub fn tx_id(&self) -> TransactionId {
+        match *self {
+            Transaction::Deposit { tx, .. }
+            | Transaction::Withdrawal { tx, .. }
+            | Transaction::Dispute { tx, .. }
+            | Transaction::Resolve { tx, .. }
+            | Transaction::ChargeBack { tx, .. }
+            | Transaction::Extension { tx, .. } => tx,
+        }
+    }
+
+    /// Returns the amount carried by the transaction, if any.
+    pub fn amount(&self) -> Option<Amount> {
+        match *self {
+            Transaction::Deposit { amount, .. } | Transaction::Withdrawal { amount, .. } => {
+                Some(amount)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> TransactionKind {
+        match self {
+            Transaction::Deposit { .. } => TransactionKind::Deposit,
+            Transaction::Withdrawal { .. } => TransactionKind::Withdrawal,
+            Transaction::Dispute { .. } => TransactionKind::Dispute,
+            Transaction::Resolve { .. } => TransactionKind::Resolve,
+            Transaction::ChargeBack { .. } => TransactionKind::ChargeBack,
+            Transaction::Extension { .. } => TransactionKind::Extension,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test that amounts parse through the standard traits and keep the
+    // precision validation.
+    #[test]
+    fn test_amount_parse() {
+        assert_eq!("1.5".parse::<Amount>().unwrap().to_string(), "1.5");
+        assert_eq!(
+            Amount::try_from("1.2345").unwrap(),
+            "1.2345".parse::<Amount>().unwrap()
+        );
+        assert_eq!(
+            "1.23456".parse::<Amount>(),
+            Err(ProcessingError::InvalidPrecision)
+        );
+        assert!(Amount::try_from("abc").is_err());
+    }
+
+    // Test that scientific notation is rejected rather than parsed as 100 or
+    // 1500.
+    #[test]
+    fn test_amount_scientific_notation() {
+        for value in ["1e2", "1.5E3", "2e-1"] {
+            assert_eq!(
+                value.parse::<Amount>(),
+                Err(ProcessingError::InvalidAmount(format!(
+                    "{}: scientific notation is not supported",
+                    value
+                )))
+            );
+        }
+    }
+
+    // Test that a configured currency symbol is stripped on either side of the
+    // amount while other symbols are still rejected.
+    #[test]
+    fn test_amount_currency_symbol() {
+        let expected = Amount::from_str("10.00").unwrap();
+        assert_eq!(Amount::parse_with_symbol("$10.00", "$"), Ok(expected));
+        assert_eq!(Amount::parse_with_symbol("10.00$", "$"), Ok(expected));
+        assert_eq!(Amount::parse_with_symbol("10.00", "$"), Ok(expected));
+        assert_eq!(Amount::parse_with_symbol("€10.00", "€"), Ok(expected));
+
+        assert!(Amount::parse_with_symbol("€10.00", "$").is_err());
+        assert!(Amount::parse_with_symbol("10$.00", "$").is_err());
+        assert!(Amount::parse_with_symbol("$10.00$", "$").is_err());
+        assert!(Amount::from_str("$10.00").is_err());
+    }
+
+    // Test that subtracting and negating amounts leaves the operands untouched
+    // and may give negative amounts.
+    #[test]
+    fn test_amount_sub_neg() {
+        let small = Amount::from_str("1.5").unwrap();
+        let large = Amount::from_str("4.25").unwrap();
+        assert_eq!(large - small, Amount::from_str("2.75").unwrap());
+        assert_eq!(small - large, Amount::from_str("-2.75").unwrap());
+        assert_eq!((small - large).to_string(), "-2.75");
+        assert_eq!(-(small - large), Amount::from_str("2.75").unwrap());
+        assert_eq!(-small, Amount::from_str("-1.5").unwrap());
+        assert_eq!(-Amount::new(), Amount::new());
+        assert_eq!(small, Amount::from_str("1.5").unwrap());
+        assert_eq!(large, Amount::from_str("4.25").unwrap());
+    }
+
+    // Test that amounts are converted exactly to integer minor units.
+    #[test]
+    fn test_amount_minor_units() {
+        let amount = |value: &str| Amount::from_str(value).unwrap();
+        assert_eq!(amount("10.50").to_minor_units(2), 1050);
+        assert_eq!(amount("10.5").to_minor_units(2), 1050);
+        assert_eq!(amount("1.2345").to_minor_units(4), 12345);
+        assert_eq!(amount("1").to_minor_units(4), 10000);
+        assert_eq!(amount("-2.5").to_minor_units(2), -250);
+        assert_eq!(Amount::new().to_minor_units(PRECISION), 0);
+        assert_eq!(amount("1.005").to_minor_units(2), 101);
+    }
+
+    // Test that amount comparisons do not depend on the scale used to write them.
+    #[test]
+    fn test_amount_compare_mixed_scales() {
+        let one: Amount = "1".parse().unwrap();
+        assert_eq!(one, "1.0000".parse().unwrap());
+        assert!(one <= "1.0000".parse().unwrap());
+        assert!(one >= "1.0".parse().unwrap());
+        assert!(one < "1.0001".parse().unwrap());
+        assert!(one > "0.9999".parse().unwrap());
+        assert_eq!(Amount::new(), "0".parse().unwrap());
+    }
+
+    // Test that ids can be parsed from and formatted to strings.
+    #[test]
+    fn test_id_parse_display() {
+        assert_eq!("42".parse::<ClientId>(), Ok(ClientId(42)));
+        assert_eq!(ClientId(42).to_string(), "42");
+        assert_eq!("100000".parse::<ClientId>(), Ok(ClientId(100_000)));
+        assert!("4294967296".parse::<ClientId>().is_err());
+
+        assert_eq!("7".parse::<TransactionId>(), Ok(TransactionId(7)));
+        assert_eq!(TransactionId(7).to_string(), "7");
+        assert!("-1".parse::<TransactionId>().is_err());
+    }
+
+    // Test the accessors of a transaction built from a record.
+    #[test]
+    fn test_transaction_accessors() {
+        let transaction = Transaction::try_from(TransactionRecord {
+            transaction_type: "withdrawal".to_owned(),
+            client: 3,
+            tx: 9,
+            amount: Some("2.5".to_owned()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(transaction.kind(), TransactionKind::Withdrawal);
+        assert_eq!(transaction.client_id(), ClientId(3));
+        assert_eq!(transaction.tx_id(), TransactionId(9));
+        assert_eq!(transaction.amount(), Some("2.5".parse().unwrap()));
+
+        let transaction = Transaction::try_from(TransactionRecord {
+            transaction_type: "chargeback".to_owned(),
+            client: 3,
+            tx: 9,
+            amount: None,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(transaction.kind(), TransactionKind::ChargeBack);
+        assert_eq!(transaction.amount(), None);
+
+        assert!(Transaction::try_from(TransactionRecord {
+            transaction_type: "bonus".to_owned(),
+            client: 3,
+            tx: 9,
+            amount: None,
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    fn record(transaction_type: &str, amount: Option<&str>) -> TransactionRecord {
+        TransactionRecord {
+            transaction_type: transaction_type.to_owned(),
+            client: 1,
+            tx: 5,
+            amount: amount.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    // Test that deposits and withdrawals are rejected without an amount instead
+    // of defaulting to zero.
+    #[test]
+    fn test_amount_required() {
+        for transaction_type in &["deposit", "withdrawal"] {
+            let transaction = Transaction::try_from(record(transaction_type, Some("2.0"))).unwrap();
+            assert_eq!(transaction.amount(), Some("2.0".parse().unwrap()));
+
+            assert_eq!(
+                Transaction::try_from(record(transaction_type, None))
+                    .unwrap_err()
+                    .to_string(),
+                format!("Missing amount for {}", transaction_type)
+            );
+        }
+    }
+
+    // Test that empty or blank amounts are treated as missing instead of being
+    // parsed.
+    #[test]
+    fn test_amount_blank() {
+        for amount in &[None, Some(""), Some("   ")] {
+            assert_eq!(
+                Transaction::try_from(record("deposit", *amount)).unwrap_err(),
+                ProcessingError::MissingAmount(TransactionKind::Deposit)
+            );
+        }
+        assert_eq!(
+            Transaction::try_from(record("withdrawal", Some(" "))).unwrap_err(),
+            ProcessingError::MissingAmount(TransactionKind::Withdrawal)
+        );
+    }
+
+    // Test that the amount column is ignored for resolves and chargebacks.
+    #[test]
+    fn test_amount_ignored() {
+        for transaction_type in &["resolve", "chargeback"] {
+            let transaction = Transaction::try_from(record(transaction_type, None)).unwrap();
+            assert_eq!(transaction.amount(), None);
+
+            let transaction = Transaction::try_from(record(transaction_type, Some("2.0"))).unwrap();
+            assert_eq!(transaction.amount(), None);
+            assert_eq!(transaction.tx_id(), TransactionId(5));
+        }
+    }
+
+    // Test that a dispute carries its amount column as the disputed portion,
+    // when it is not blank.
+    #[test]
+    fn test_dispute_amount() {
+        let portion = |amount: Option<&str>| match Transaction::try_from(record("dispute", amount))
+        {
+            Ok(Transaction::Dispute { amount, .. }) => amount,
+            other => panic!("Unexpected {:?}", other),
+        };
+        assert_eq!(portion(None), None);
+        assert_eq!(portion(Some(" ")), None);
+        assert_eq!(portion(Some(" 2.5 ")), Some("2.5".parse().unwrap()));
+        assert!(Transaction::try_from(record("dispute", Some("x"))).is_err());
+    }
+
+    // Test that every variant keeps its tagged JSON representation and is
+    // read back unchanged, as the spilled cache lines rely on it.
+    #[test]
+    fn test_transaction_serde_round_trip() {
+        let client = ClientId(2);
+        let tx = TransactionId(9);
+        let amount: Amount = "1.5".parse().unwrap();
+        let cases = vec![
+            (
+                Transaction::Deposit { client, tx, amount },
+                r#"{"type":"deposit","client":2,"tx":9,"amount":"1.5"}"#,
             ),
-            "withdrawal" => Transaction::Withdrawal(
-                ClientId(record.client),
-                TransactionId(record.tx),
-                Amount::from_str(record.amount.unwrap_or_else(|| "0.0".to_owned()))?,
+            (
+                Transaction::Withdrawal { client, tx, amount },
+                r#"{"type":"withdrawal","client":2,"tx":9,"amount":"1.5"}"#,
             ),
-            "dispute" => Transaction::Dispute(ClientId(record.client), TransactionId(record.tx)),
-            "resolve" => Transaction::Resolve(ClientId(record.client), TransactionId(record.tx)),
-            "chargeback" => {
-                Transaction::ChargeBack(ClientId(record.client), TransactionId(record.tx))
-            }
-            _ => Transaction::Unknown,
+            (
+                Transaction::Dispute {
+                    client,
+                    tx,
+                    amount: Some(amount),
+                },
+                r#"{"type":"dispute","client":2,"tx":9,"amount":"1.5"}"#,
+            ),
+            (
+                Transaction::Dispute {
+                    client,
+                    tx,
+                    amount: None,
+                },
+                r#"{"type":"dispute","client":2,"tx":9,"amount":null}"#,
+            ),
+            (
+                Transaction::Resolve { client, tx },
+                r#"{"type":"resolve","client":2,"tx":9}"#,
+            ),
+            (
+                Transaction::ChargeBack { client, tx },
+                r#"{"type":"charge_back","client":2,"tx":9}"#,
+            ),
+            (
+                Transaction::Extension { client, tx },
+                r#"{"type":"extension","client":2,"tx":9}"#,
+            ),
+        ];
+        for (transaction, json) in cases {
+            assert_eq!(serde_json::to_string(&transaction).unwrap(), json);
+            let read: Transaction = serde_json::from_str(json).unwrap();
+            assert_eq!(read, transaction);
+        }
+
+        let stored = StoredTransaction {
+            transaction: Transaction::Deposit { client, tx, amount },
+            sequence: 4,
         };
-        Ok(transaction)
+        let json = serde_json::to_string(&stored).unwrap();
+        assert_eq!(
+            serde_json::from_str::<StoredTransaction>(&json).unwrap(),
+            stored
+        );
     }
 }
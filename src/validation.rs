@@ -0,0 +1,203 @@
+use std::fmt;
+
+use crate::error::ProcessingError;
+use crate::type_defs::{Amount, TransactionRecord};
+
+/// Rule of a `RecordValidator` which a record can break.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValidationRule {
+    /// The record is for client 0, used upstream for an unknown client.
+    ZeroClient,
+    /// The record has transaction id 0, used upstream for an unknown
+    /// transaction.
+    ZeroTransaction,
+    /// The amount of the record is above the ceiling.
+    AmountCeiling,
+    /// A deposit without an amount.
+    DepositAmount,
+}
+
+impl fmt::Display for ValidationRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ValidationRule::ZeroClient => "zero_client",
+            ValidationRule::ZeroTransaction => "zero_tx",
+            ValidationRule::AmountCeiling => "amount_ceiling",
+            ValidationRule::DepositAmount => "deposit_amount",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Rules checked on every record before it is converted into a transaction,
+/// each one enabled on its own. None is enabled by default, so the records are
+/// rejected as without a validator.
+///
+/// A record breaking a rule is rejected with `ProcessingError::Validation`
+/// naming the rule, like any other rejected record.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RecordValidator {
+    /// Reject the records of client 0.
+    pub reject_zero_client: bool,
+    /// Reject the records with transaction id 0.
+    pub reject_zero_tx: bool,
+    /// Reject the records whose amount is above it, whatever their type.
+    pub amount_ceiling: Option<Amount>,
+    /// Reject the deposits without an amount under this rule. They are
+    /// rejected as missing their amount without it.
+    pub require_deposit_amount: bool,
+}
+
+impl RecordValidator {
+    pub fn with_zero_client_rejected(mut self) -> Self {
+        self.reject_zero_client = true;
+        self
+    }
+
+    pub fn with_zero_tx_rejected(mut self) -> Self {
+        self.reject_zero_tx = true;
+        self
+    }
+
+    pub fn with_amount_ceiling(mut self, ceiling: Amount) -> Self {
+        self.amount_ceiling = Some(ceiling);
+        self
+    }
+
+    pub fn with_deposit_amount_required(mut self) -> Self {
+        self.require_deposit_amount = true;
+        self
+    }
+
+    /// Checks the record against the enabled rules, in the order of the
+    /// fields. An amount which can not be parsed is left to the conversion of
+    /// the record to reject.
+    pub fn validate(&self, record: &TransactionRecord) -> Result<(), ProcessingError> {
+        let violation = |rule, reason: String| Err(ProcessingError::Validation { rule, reason });
+        if self.reject_zero_client && record.client == 0 {
+            return violation(ValidationRule::ZeroClient, "client id 0".to_owned());
+        }
+        if self.reject_zero_tx && record.tx == 0 {
+            return violation(
+                ValidationRule::ZeroTransaction,
+                "transaction id 0".to_owned(),
+            );
+        }
+        let amount = record
+            .amount
+            .as_deref()
+            .map(str::trim)
+            .filter(|amount| !amount.is_empty());
+        if let (Some(ceiling), Some(amount)) = (self.amount_ceiling, amount) {
+            match amount.parse::<Amount>() {
+                Ok(amount) if amount > ceiling => {
+                    return violation(
+                        ValidationRule::AmountCeiling,
+                        format!("amount {} above {}", amount, ceiling),
+                    );
+                }
+                _ => {}
+            }
+        }
+        if self.require_deposit_amount && record.transaction_type == "deposit" && amount.is_none() {
+            return violation(
+                ValidationRule::DepositAmount,
+                "deposit without amount".to_owned(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+    use crate::test_support::Record;
+
+    fn records() -> Vec<TransactionRecord> {
+        vec![
+            Record::deposit(1, 1, "5.0").build(),
+            Record::deposit(0, 2, "1.0").build(),
+            Record::deposit(2, 0, "1.0").build(),
+            Record::deposit(3, 3, "5000.0").build(),
+            Record::new("deposit", 4, 4).build(),
+            Record::withdrawal(1, 5, "1.0").build(),
+        ]
+    }
+
+    /// Returns the error code and rule of every rejected record by index.
+    fn rejected(validator: RecordValidator) -> Vec<(usize, &'static str, Option<ValidationRule>)> {
+        let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new()
+            .with_validator(validator);
+        processor
+            .process_iter(records())
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, result)| {
+                let err = result.err()?;
+                let rule = match &err {
+                    ProcessingError::Validation { rule, .. } => Some(*rule),
+                    _ => None,
+                };
+                Some((index, err.code(), rule))
+            })
+            .collect()
+    }
+
+    // Test that no rule is enabled by default, the records are only rejected
+    // as without a validator.
+    #[test]
+    fn test_default_rules() {
+        assert_eq!(
+            rejected(RecordValidator::default()),
+            vec![(4, "E_MISSING_AMOUNT", None)]
+        );
+    }
+
+    // Test that each rule only rejects the records breaking it, with the name
+    // of the rule in the message.
+    #[test]
+    fn test_each_rule() {
+        let missing_amount = (4, "E_MISSING_AMOUNT", None);
+        let cases = [
+            (
+                RecordValidator::default().with_zero_client_rejected(),
+                vec![
+                    (1, "E_VALIDATION", Some(ValidationRule::ZeroClient)),
+                    missing_amount,
+                ],
+            ),
+            (
+                RecordValidator::default().with_zero_tx_rejected(),
+                vec![
+                    (2, "E_VALIDATION", Some(ValidationRule::ZeroTransaction)),
+                    missing_amount,
+                ],
+            ),
+            (
+                RecordValidator::default().with_amount_ceiling("1000".parse().unwrap()),
+                vec![
+                    (3, "E_VALIDATION", Some(ValidationRule::AmountCeiling)),
+                    missing_amount,
+                ],
+            ),
+            (
+                RecordValidator::default().with_deposit_amount_required(),
+                vec![(4, "E_VALIDATION", Some(ValidationRule::DepositAmount))],
+            ),
+        ];
+        for (validator, expected) in cases {
+            assert_eq!(rejected(validator), expected);
+        }
+
+        let err = RecordValidator::default()
+            .with_zero_client_rejected()
+            .validate(&records()[1])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Validation rule zero_client failed: client id 0"
+        );
+    }
+}
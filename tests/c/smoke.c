@@ -0,0 +1,54 @@
+/* Drives the processor through include/exchange.h, built and run by tests/ffi.rs. */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "exchange.h"
+
+static int fail(const char *message) {
+    fprintf(stderr, "%s\n", message);
+    return 1;
+}
+
+int main(void) {
+    const char *expected = "client,available,held,total,locked\n1,0.0000,2.5,2.5,false\n";
+    char small[10];
+    char buf[128];
+    intptr_t needed;
+    TpProcessor *handle = tp_new();
+    if (handle == NULL) {
+        return fail("tp_new returned null");
+    }
+    if (tp_process_record(handle, "deposit", 1, 1, "2.5") != TP_OK) {
+        return fail("deposit was rejected");
+    }
+    if (tp_process_record(handle, "withdrawal", 1, 2, "10") <= TP_OK) {
+        return fail("withdrawal beyond the balance was not rejected with its error code");
+    }
+    if (tp_process_record(handle, "dispute", 1, 1, NULL) != TP_OK) {
+        return fail("dispute was rejected");
+    }
+    if (tp_process_record(handle, NULL, 1, 3, NULL) != TP_ERR_INVALID_ARGUMENT) {
+        return fail("null type was not an invalid argument");
+    }
+
+    needed = tp_serialize_csv(handle, NULL, 0);
+    if (needed != (intptr_t)strlen(expected)) {
+        return fail("tp_serialize_csv did not size the balances");
+    }
+    memset(small, 0, sizeof(small));
+    if (tp_serialize_csv(handle, (uint8_t *)small, sizeof(small)) != needed || small[0] != 0) {
+        return fail("tp_serialize_csv wrote into a buffer too small");
+    }
+    if (tp_serialize_csv(handle, (uint8_t *)buf, sizeof(buf)) != needed) {
+        return fail("tp_serialize_csv did not write the balances");
+    }
+    if (memcmp(buf, expected, (size_t)needed) != 0) {
+        return fail("tp_serialize_csv wrote other balances");
+    }
+
+    tp_free(handle);
+    tp_free(NULL);
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,1831 @@
+use std::process::{Command, Output};
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_exchange"))
+        .args(args)
+        .output()
+        .expect("Could not run the exchange binary")
+}
+
+// Test that without --fail-fast the error is ignored and every record is processed.
+#[test]
+fn test_second_line_error_is_ignored() {
+    let output = run(&["tests/inputs/samplecode_second_line_error.csv"]);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("1,1.0,0.0000,1.0,false"));
+    assert!(stdout.contains("2,2.0,0.0000,2.0,false"));
+}
+
+// Test that --fail-fast stops at the failing record but still writes what was
+// processed so far.
+#[test]
+fn test_fail_fast_writes_partial_output() {
+    let output = run(&[
+        "--fail-fast",
+        "tests/inputs/samplecode_second_line_error.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("client,available,held,total,locked"));
+    assert!(stdout.contains("1,1.0,0.0000,1.0,false"));
+    assert!(!stdout.contains("2,2.0,0.0000,2.0,false"));
+}
+
+// Test that --no-partial-output skips the output when failing fast.
+#[test]
+fn test_fail_fast_without_partial_output() {
+    let output = run(&[
+        "--fail-fast",
+        "--no-partial-output",
+        "tests/inputs/samplecode_second_line_error.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}
+
+// Test that the header is not considered a failure in --fail-fast mode.
+#[test]
+fn test_fail_fast_accepts_header() {
+    let output = run(&[
+        "--fail-fast",
+        "tests/inputs/samplecode_dispute_resolve_all.csv",
+    ]);
+    assert!(output.status.success());
+}
+
+fn sorted_lines(output: Output) -> Vec<String> {
+    let mut lines: Vec<String> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(str::to_owned)
+        .collect();
+    lines.sort();
+    lines
+}
+
+// Test that reading the input through a memory mapping produces the same
+// balances as the buffered reader.
+#[cfg(unix)]
+#[test]
+fn test_mmap_matches_buffered_read() {
+    for input in &[
+        "tests/inputs/samplecode_test.csv",
+        "tests/inputs/samplecode_dispute_chargeback_all.csv",
+        "tests/inputs/samplecode_dispute_resolve_all.csv",
+    ] {
+        let buffered = run(&[input]);
+        let mapped = run(&["--mmap", input]);
+        assert!(buffered.status.success());
+        assert!(mapped.status.success());
+        assert_eq!(sorted_lines(buffered), sorted_lines(mapped));
+    }
+}
+
+// Test that --with-lock-reason appends the chargeback which locked the account.
+#[test]
+fn test_with_lock_reason_column() {
+    let output = run(&[
+        "--with-lock-reason",
+        "tests/inputs/samplecode_dispute_chargeback_all.csv",
+    ]);
+    assert!(output.status.success());
+
+    let lines = sorted_lines(output);
+    assert!(lines.contains(&"client,available,held,total,locked,lock_reason".to_owned()));
+    assert!(lines.contains(&"7,-12600,12593,-7,true,7".to_owned()));
+}
+
+// Test that --with-dispute-flag marks the clients with a disputed deposit,
+// whether it was resolved or charged back.
+#[test]
+fn test_with_dispute_flag() {
+    let output = run(&[
+        "--with-dispute-flag",
+        "tests/inputs/samplecode_chargebacks.csv",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,5.0,0.0000,5.0,false,false",
+            "2,1.0,0.0000,1.0,true,true",
+            "3,1.0,0.0000,1.0,false,true",
+            "client,available,held,total,locked,ever_disputed",
+        ]
+    );
+}
+
+// Test that --client and --types skip the records which do not match and that
+// the skipped records are reported with --stats.
+#[test]
+fn test_client_and_type_filters() {
+    let output = run(&[
+        "--client",
+        "1",
+        "--types",
+        "deposit",
+        "--stats",
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("applied: 2, rejected: 0, malformed: 0, filtered: 3"));
+    let lines = sorted_lines(output);
+    assert_eq!(
+        lines,
+        vec![
+            "1,3.0,0.0000,3.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+}
+
+// Test that an invalid client id given to --client is reported.
+#[test]
+fn test_invalid_client_filter() {
+    let output = run(&["--client", "1,x", "tests/inputs/samplecode_test.csv"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Invalid client id x"));
+}
+
+// Test that an unknown transaction type given to --types is reported with the
+// valid ones rather than filtering out every record.
+#[test]
+fn test_invalid_type_filter() {
+    let output = run(&[
+        "--types",
+        "deposit,withdrawl",
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr).unwrap().contains(
+        "Unknown transaction type withdrawl given to --types, expected one of deposit, withdrawal, dispute, \
+         resolve, chargeback"
+    ));
+}
+
+// Test that the client ids above u16::MAX are processed and written in full,
+// the row of a client id above u32::MAX being skipped as malformed.
+#[test]
+fn test_wide_client_ids() {
+    let input = "tests/inputs/samplecode_wide_clients.csv";
+    let expected = vec![
+        "100000,1.5,0.0000,1.5,false",
+        "4294967295,3.0,0.0000,3.0,false",
+        "7,1.5,0.0000,1.5,false",
+        "client,available,held,total,locked",
+    ];
+    let output = run(&[input]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("number too large to fit in target type"));
+    assert_eq!(sorted_lines(output), expected);
+    assert_eq!(
+        sorted_lines(run(&["--parallel-chunks", "2", input])),
+        expected
+    );
+
+    let output = run(&[
+        "--client",
+        "100000",
+        "--columns",
+        "client_id=customer,total",
+        "--minor-units",
+        input,
+    ]);
+    assert_eq!(sorted_lines(output), vec!["100000,150", "customer,total"]);
+}
+
+// Test that --skip and --take only apply the selected data records.
+#[test]
+fn test_skip_take() {
+    let output = run(&[
+        "--skip",
+        "2",
+        "--take",
+        "3",
+        "--stats",
+        "tests/inputs/samplecode_ten_rows.csv",
+    ]);
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("skipped: 2, records: 3-5"));
+    // Rows 3 to 5: deposit 1.5 and withdrawal 0.5 for client 1, deposit 2.25
+    // for client 2.
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,1.0,0.0000,1.0,false",
+            "2,2.25,0.0000,2.25,false",
+            "client,available,held,total,locked",
+        ]
+    );
+}
+
+// Test that flushing the caches every few records with --chunk-size gives the
+// same balances, the disputed deposits being loaded back from disk.
+#[test]
+fn test_chunk_size() {
+    let input = "tests/inputs/samplecode_chargebacks.csv";
+    let output = run(&["--chunk-size", "2", input]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, run(&[input]).stdout);
+
+    let output = run(&["--chunk-size", "0", input]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+}
+
+// Test that semicolon and tab separated inputs with comments give the same
+// balances as the comma separated one.
+#[test]
+fn test_csv_dialects() {
+    let baseline = sorted_lines(run(&["tests/inputs/samplecode_test.csv"]));
+    let semicolon = run(&[
+        "--delimiter",
+        ";",
+        "--comment",
+        "#",
+        "tests/inputs/samplecode_test_semicolon.csv",
+    ]);
+    assert_eq!(sorted_lines(semicolon), baseline);
+    let tab = run(&[
+        "--tsv",
+        "--comment",
+        "#",
+        "tests/inputs/samplecode_test_tab.tsv",
+    ]);
+    assert_eq!(sorted_lines(tab), baseline);
+}
+
+// Test that --cache-dir creates a subdirectory for the run which is removed
+// with --clean-cache-on-exit.
+#[test]
+fn test_cache_dir() {
+    let base_dir = std::env::temp_dir().join(format!("exchange_cli_cache_{}", std::process::id()));
+    std::fs::create_dir_all(&base_dir).unwrap();
+    let base = base_dir.to_str().unwrap();
+    let input = "tests/inputs/samplecode_test.csv";
+
+    assert!(run(&["--cache-dir", base, input]).status.success());
+    assert_eq!(std::fs::read_dir(&base_dir).unwrap().count(), 1);
+    assert!(run(&["--cache-dir", base, "--clean-cache-on-exit", input])
+        .status
+        .success());
+    assert_eq!(std::fs::read_dir(&base_dir).unwrap().count(), 1);
+
+    std::fs::remove_dir_all(&base_dir).unwrap();
+}
+
+// Test that a JSON lines input gives the same balances as the equivalent csv.
+#[test]
+fn test_jsonl_input() {
+    let baseline = sorted_lines(run(&["tests/inputs/samplecode_test.csv"]));
+    let jsonl = run(&["tests/inputs/samplecode_test.jsonl"]);
+    assert!(jsonl.status.success());
+    assert_eq!(sorted_lines(jsonl), baseline);
+
+    let explicit = run(&[
+        "--input-format",
+        "jsonl",
+        "tests/inputs/samplecode_test.jsonl",
+    ]);
+    assert_eq!(sorted_lines(explicit), baseline);
+}
+
+// Test that a JSON line with a string client id is rejected on its own.
+#[test]
+fn test_jsonl_string_client_rejected() {
+    let output = run(&["--stats", "tests/inputs/samplecode_string_client.jsonl"]);
+    assert!(output.status.success());
+
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("Ignoring error line 2: invalid type: string \"2\""));
+    assert!(stderr.contains("applied: 2, rejected: 0, malformed: 1"));
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,1.0,0.0000,1.0,false",
+            "3,3.0,0.0000,3.0,false",
+            "client,available,held,total,locked",
+        ]
+    );
+}
+
+// Test that converting csv to JSON lines and back processes into the same
+// balances and that malformed rows are counted the same from either format.
+#[test]
+fn test_convert() {
+    let dir = tempdir::TempDir::new("exchange_convert").unwrap();
+    let jsonl = dir.path().join("converted.jsonl");
+    let csv = dir.path().join("converted.csv");
+    let output = run(&[
+        "convert",
+        "tests/inputs/samplecode_test.csv",
+        jsonl.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let output = run(&[
+        "convert",
+        "--from",
+        "jsonl",
+        "--to",
+        "csv",
+        jsonl.to_str().unwrap(),
+        csv.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(run(&[csv.to_str().unwrap()])),
+        sorted_lines(run(&["tests/inputs/samplecode_test.csv"]))
+    );
+
+    let output = run(&[
+        "convert",
+        "tests/inputs/samplecode_malformed.csv",
+        jsonl.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("converted: 2, invalid: 0, malformed: 1"));
+    let output = run(&["convert", jsonl.to_str().unwrap(), csv.to_str().unwrap()]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("converted: 2, invalid: 0, malformed: 0"));
+
+    let output = run(&[
+        "convert",
+        "--on-malformed",
+        "abort",
+        "tests/inputs/samplecode_malformed.csv",
+        jsonl.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+// Test that remapped columns give the same balances as the standard headers.
+#[test]
+fn test_column_mapping() {
+    let baseline = sorted_lines(run(&["tests/inputs/samplecode_test.csv"]));
+    let mapped = run(&[
+        "--map",
+        "type=txn_type",
+        "--map",
+        "client=customer",
+        "--map",
+        "tx=reference",
+        "--map",
+        "amount=value",
+        "tests/inputs/samplecode_partner_columns.csv",
+    ]);
+    assert!(mapped.status.success());
+    assert_eq!(sorted_lines(mapped), baseline);
+}
+
+// Test that an input with headers naming the amount column value gives the
+// same balances, with --amount-column or once read by header name.
+#[test]
+fn test_amount_column() {
+    let baseline = sorted_lines(run(&["tests/inputs/samplecode_test.csv"]));
+    let input = "tests/inputs/samplecode_value_column.csv";
+    let named = run(&["--amount-column", "value", input]);
+    assert!(named.status.success());
+    assert_eq!(sorted_lines(named), baseline);
+    let aliased = run(&["--map", "client=client", input]);
+    assert!(aliased.status.success());
+    assert_eq!(sorted_lines(aliased), baseline);
+}
+
+// Test that --strict-schema turns the rows with extra fields into malformed
+// rows and an unknown header column into a usage error.
+#[test]
+fn test_strict_schema() {
+    let dir = tempdir::TempDir::new("cli_strict_schema").unwrap();
+    let input = dir.path().join("drift.csv");
+    std::fs::write(
+        &input,
+        "deposit,1,1,2.0,rent\n\
+         deposit,1,2,3.0,rent,eu\n",
+    )
+    .unwrap();
+    let input = input.to_str().unwrap();
+    let lenient = run(&[input]);
+    assert_eq!(
+        sorted_lines(lenient),
+        vec![
+            "1,5.0,0.0000,5.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+    let strict = run(&["--strict-schema", input]);
+    assert!(String::from_utf8_lossy(&strict.stderr)
+        .contains("Row has 6 fields, at most 5 are expected"));
+    assert_eq!(
+        sorted_lines(strict),
+        vec![
+            "1,2.0,0.0000,2.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+
+    let header = run(&[
+        "--strict-schema",
+        "--amount-column",
+        "value",
+        "tests/inputs/samplecode_value_column.csv",
+    ]);
+    assert!(header.status.success());
+    let drift = dir.path().join("region.csv");
+    std::fs::write(&drift, "type,client,tx,amount,region\ndeposit,1,1,2.0,eu\n").unwrap();
+    let drift = run(&[
+        "--strict-schema",
+        "--map",
+        "client=client",
+        drift.to_str().unwrap(),
+    ]);
+    assert_eq!(drift.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&drift.stderr).contains("Unknown column region"));
+}
+
+// Test that the amount column can be left out of dispute only inputs while a
+// missing mapped column is reported upfront.
+#[test]
+fn test_column_mapping_without_amount() {
+    let mapping = [
+        "--map",
+        "type=txn_type",
+        "--map",
+        "client=customer",
+        "--map",
+        "tx=reference",
+    ];
+    let disputes = run(&[
+        &mapping[..],
+        &["tests/inputs/samplecode_partner_disputes.csv"],
+    ]
+    .concat());
+    assert!(disputes.status.success());
+    let stderr = String::from_utf8(disputes.stderr).unwrap();
+    assert_eq!(
+        stderr
+            .matches("Transaction not found for this client")
+            .count(),
+        2
+    );
+
+    let missing = run(&[
+        &mapping[..],
+        &[
+            "--map",
+            "amount=value",
+            "tests/inputs/samplecode_partner_disputes.csv",
+        ],
+    ]
+    .concat());
+    assert_eq!(missing.status.code(), Some(2));
+    assert!(missing.stdout.is_empty());
+    assert!(String::from_utf8(missing.stderr)
+        .unwrap()
+        .contains("Missing column value for field amount"));
+}
+
+// Test that --minor-units writes the amounts as integers of cents, or of the
+// minor unit given by --minor-unit-digits.
+#[test]
+fn test_minor_units() {
+    let output = run(&["--minor-units", "tests/inputs/samplecode_test.csv"]);
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,150,0,150,false",
+            "2,200,0,200,false",
+            "client,available,held,total,locked",
+        ]
+    );
+
+    let output = run(&[
+        "--minor-unit-digits",
+        "4",
+        "--minor-units",
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,15000,0,15000,false",
+            "2,20000,0,20000,false",
+            "client,available,held,total,locked",
+        ]
+    );
+
+    let output = run(&[
+        "--minor-units",
+        "--minor-unit-digits",
+        "5",
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("--minor-unit-digits needs at most 4 digits"));
+
+    let output = run(&[
+        "--minor-units",
+        "--minor-unit-digits",
+        "4294967298",
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Invalid value 4294967298 for --minor-unit-digits"));
+}
+
+// Test that a leading byte order mark does not change the balances.
+#[test]
+fn test_bom_input() {
+    let clean = run(&["tests/inputs/samplecode_test_no_headers.csv"]);
+    let bom = run(&["tests/inputs/samplecode_test_no_headers_bom.csv"]);
+    assert!(bom.status.success());
+    assert_eq!(clean.stderr, bom.stderr);
+    assert_eq!(sorted_lines(bom), sorted_lines(clean));
+}
+
+// Test that latin-1 inputs are processed with --input-encoding latin1, or its
+// --encoding alias, and that the error points at the encoding without it.
+#[test]
+fn test_latin1_input() {
+    let baseline = sorted_lines(run(&["tests/inputs/samplecode_test.csv"]));
+    let latin1 = run(&[
+        "--input-encoding",
+        "latin1",
+        "tests/inputs/samplecode_latin1.csv",
+    ]);
+    assert_eq!(sorted_lines(latin1), baseline);
+    let alias = run(&["--encoding", "latin1", "tests/inputs/samplecode_latin1.csv"]);
+    assert_eq!(sorted_lines(alias), baseline);
+
+    let utf8 = run(&["tests/inputs/samplecode_latin1.csv"]);
+    let stderr = String::from_utf8(utf8.stderr).unwrap();
+    assert_eq!(stderr.matches("need --input-encoding latin1").count(), 2);
+}
+
+// Test that the windows-1252 memos are transcoded, e.g. the euro sign which
+// latin-1 reads as a control character.
+#[test]
+fn test_windows1252_input() {
+    let dir = tempdir::TempDir::new("cli_windows1252").unwrap();
+    let rejected = dir.path().join("rejected.csv");
+    let output = run(&[
+        "--input-encoding",
+        "windows-1252",
+        "--rejected-out",
+        rejected.to_str().unwrap(),
+        "tests/inputs/samplecode_windows1252.csv",
+    ]);
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,5.0,0.0000,5.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+    let rejected = std::fs::read_to_string(rejected).unwrap();
+    assert!(rejected.contains(",1,2,10.0,\u{201C}loyer\u{201D} 10\u{20AC},E_INSUFFICIENT,"));
+}
+
+// Test that a rejected records report which can not be written fails the run
+// after the balances are written.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_rejected_out_write_error() {
+    let output = run(&[
+        "--rejected-out",
+        "/dev/full",
+        "tests/inputs/samplecode_severities.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Could not write rejected records"));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("client,"));
+}
+
+// Test that malformed rows are copied verbatim into the quarantine file and
+// that --on-malformed abort stops at them.
+#[test]
+fn test_on_malformed() {
+    let quarantine =
+        std::env::temp_dir().join(format!("exchange_quarantine_{}.csv", std::process::id()));
+    let policy = format!("quarantine={}", quarantine.display());
+    let output = run(&[
+        "--on-malformed",
+        &policy,
+        "tests/inputs/samplecode_malformed.csv",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&quarantine).unwrap(),
+        "deposit,two,2,3.0\n"
+    );
+    std::fs::remove_file(&quarantine).unwrap();
+
+    let output = run(&[
+        "--on-malformed",
+        "abort",
+        "tests/inputs/samplecode_malformed.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,2.0,0.0000,2.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+}
+
+// Test that the csv files of a directory are processed in lexical order into
+// the same balances and that an empty directory gives an empty output.
+#[test]
+fn test_directory_input() {
+    let dir = tempdir::TempDir::new("exchange_cli_dir").unwrap();
+    // The withdrawal only succeeds after the deposit of the first day and the
+    // dispute has to come after both.
+    let days = [
+        ("2021-01-03.csv", "type,client,tx,amount\ndispute,1,1,\n"),
+        ("2021-01-01.csv", "type,client,tx,amount\ndeposit,1,1,5.0\n"),
+        (
+            "2021-01-02.csv",
+            "type,client,tx,amount\nwithdrawal,1,2,3.0\n",
+        ),
+        ("readme.txt", "deposit,1,3,100.0\n"),
+    ];
+    for (name, content) in days.iter() {
+        std::fs::write(dir.path().join(name), content).unwrap();
+    }
+    let output = run(&["--stats", dir.path().to_str().unwrap()]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("applied: 3, rejected: 0"));
+    assert_eq!(
+        sorted_lines(output),
+        vec!["1,-3.0,5.0,2.0,false", "client,available,held,total,locked"]
+    );
+
+    let empty = tempdir::TempDir::new("exchange_cli_empty_dir").unwrap();
+    let output = run(&[empty.path().to_str().unwrap()]);
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(output),
+        vec!["client,available,held,total,locked"]
+    );
+}
+
+// Test that replayed and conflicting duplicates are counted apart and that
+// --fail-on-conflicting-duplicate stops at the conflicting one.
+#[test]
+fn test_conflicting_duplicates() {
+    let output = run(&["--stats", "tests/inputs/samplecode_duplicates.csv"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("duplicate replays: 1, conflicting duplicates: 1"));
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,3.0,0.0000,3.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+
+    let output = run(&[
+        "--fail-on-conflicting-duplicate",
+        "tests/inputs/samplecode_duplicates.csv",
+    ]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains(
+        "Failed with error E_DUP_CONFLICT: Transaction already processed with amount 2.0 instead of 3.0"
+    ));
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,2.0,0.0000,2.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+}
+
+// Test that --verify finds the balances consistent with the transactions and
+// leaves the output unchanged.
+#[test]
+fn test_verify() {
+    let baseline = run(&["tests/inputs/samplecode_chargebacks.csv"]);
+    let output = run(&["--verify", "tests/inputs/samplecode_chargebacks.csv"]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    assert_eq!(output.stdout, baseline.stdout);
+}
+
+// Test that --fail-on exits with code 4 once a record is rejected with the
+// given severity or a higher one, after processing every record. The fixture
+// has a replay (info), a withdrawal over the funds (warn) and a conflicting
+// duplicate (error), fatal errors always stop the run.
+#[test]
+fn test_fail_on() {
+    let input = "tests/inputs/samplecode_severities.csv";
+    let baseline = run(&[input]);
+    assert!(baseline.status.success());
+    for (fail_on, code) in [("info", 4), ("warn", 4), ("error", 4), ("fatal", 0)] {
+        let output = run(&["--fail-on", fail_on, input]);
+        assert_eq!(output.status.code(), Some(code), "--fail-on {}", fail_on);
+        assert_eq!(output.stdout, baseline.stdout);
+    }
+    // Only the replay is left among the deposits.
+    for (fail_on, code) in [("info", 4), ("warn", 0)] {
+        let output = run(&["--fail-on", fail_on, "--types", "deposit", input]);
+        assert_eq!(output.status.code(), Some(code), "--fail-on {}", fail_on);
+    }
+    assert_eq!(
+        run(&["--fail-on", "critical", input]).status.code(),
+        Some(2)
+    );
+
+    let output = run(&["--summary-only", input]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(
+        "rejected: 3\n  \
+           severity info: 1\n  \
+           severity warn: 1\n  \
+           severity error: 1\n  \
+           E_DUP_CONFLICT: 1\n  \
+           E_DUP_REPLAY: 1\n  \
+           E_INSUFFICIENT: 1\n"
+    ));
+}
+
+// Test that each traced client gets its own log and the balances are not
+// changed by tracing.
+#[test]
+fn test_trace_client() {
+    let dir = tempdir::TempDir::new("cli_trace_client").unwrap();
+    let input = "tests/inputs/samplecode_chargebacks.csv";
+    let output = run(&[
+        "--trace-client",
+        "2",
+        "--trace-client",
+        "3",
+        "--trace-dir",
+        dir.path().to_str().unwrap(),
+        input,
+    ]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, run(&[input]).stdout);
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("client-3.log")).unwrap(),
+        "1: deposit tx 4 amount 1.0 applied, available 1.0, held 0.0000, total 1.0, locked false\n\
+         2: dispute tx 4 applied, available 0.0000, held 1.0, total 1.0, locked false\n\
+         3: resolve tx 4 applied, available 1.0, held 0.0000, total 1.0, locked false\n"
+    );
+    let client_2 = std::fs::read_to_string(dir.path().join("client-2.log")).unwrap();
+    assert_eq!(client_2.lines().count(), 4);
+    assert!(client_2.ends_with("locked true\n"));
+    assert!(!dir.path().join("client-1.log").exists());
+}
+
+// Test that --snapshot-every writes the balances every few records into the
+// --snapshot-dir directory, keeping the last --snapshot-keep snapshots.
+#[test]
+fn test_snapshot_every() {
+    let dir = tempdir::TempDir::new("cli_snapshot_every").unwrap();
+    let snapshot_dir = dir.path().join("out");
+    let input = "tests/inputs/samplecode_chargebacks.csv";
+    let output = run(&[
+        "--snapshot-every",
+        "3",
+        "--snapshot-dir",
+        snapshot_dir.to_str().unwrap(),
+        "--snapshot-keep",
+        "1",
+        input,
+    ]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, run(&[input]).stdout);
+    let snapshots: Vec<String> = std::fs::read_dir(&snapshot_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert_eq!(snapshots, vec!["balances-000000000006.csv"]);
+    let snapshot = std::fs::read_to_string(snapshot_dir.join(&snapshots[0])).unwrap();
+    assert_eq!(
+        snapshot.lines().collect::<Vec<_>>(),
+        vec![
+            "client,available,held,total,locked",
+            "1,5.0,0.0000,5.0,false",
+            "2,1.0,0.0000,1.0,true",
+            "3,1.0,0.0000,1.0,false",
+        ]
+    );
+}
+
+// Test that --suspicious-out reports the clients with chargebacks above the
+// thresholds, every client with a chargeback by default.
+#[test]
+fn test_suspicious_out() {
+    let dir = tempdir::TempDir::new("cli_suspicious_out").unwrap();
+    let report = dir.path().join("suspicious.csv");
+    let output = run(&[
+        "--suspicious-out",
+        report.to_str().unwrap(),
+        "tests/inputs/samplecode_chargebacks.csv",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&report).unwrap(),
+        "client,chargebacks,charged_back,transactions\n2,1,3.0,2\n"
+    );
+
+    let output = run(&[
+        "--suspicious-out",
+        report.to_str().unwrap(),
+        "--max-charged-back",
+        "3",
+        "tests/inputs/samplecode_chargebacks.csv",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&report).unwrap(),
+        "client,chargebacks,charged_back,transactions\n"
+    );
+}
+
+// Test that --max-tx-per-client rejects the transactions of a client beyond
+// the limit.
+#[test]
+fn test_max_tx_per_client() {
+    let output = run(&[
+        "--max-tx-per-client",
+        "2",
+        "tests/inputs/samplecode_ten_rows.csv",
+    ]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("Client transaction limit exceeded"));
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,11.5,0.0000,11.5,false",
+            "2,22.25,0.0000,22.25,false",
+            "3,7.0,0.0000,7.0,false",
+            "client,available,held,total,locked",
+        ]
+    );
+}
+
+// Test that --dispute-window rejects the disputes of older deposits.
+#[test]
+fn test_dispute_window() {
+    let output = run(&[
+        "--dispute-window",
+        "1",
+        "tests/inputs/samplecode_ten_rows.csv",
+    ]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("Transaction too old to be disputed"));
+}
+
+// Test that the memos are written in the rejected records and open disputes
+// reports without changing the balances.
+#[test]
+fn test_memo_reports() {
+    let dir = tempdir::TempDir::new("cli_memo_reports").unwrap();
+    let rejected = dir.path().join("rejected.csv");
+    let disputes = dir.path().join("disputes.csv");
+    let balances = run(&["tests/inputs/samplecode_memos.csv"]);
+    let output = run(&[
+        "--rejected-out",
+        rejected.to_str().unwrap(),
+        "--disputes-out",
+        disputes.to_str().unwrap(),
+        "--keep-memos",
+        "tests/inputs/samplecode_memos.csv",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, balances.stdout);
+    assert_eq!(
+        std::fs::read_to_string(&rejected).unwrap(),
+        "type,client,tx,amount,memo,code,error\n\
+         withdrawal,1,3,50.0,rent,E_INSUFFICIENT,Insufficient funds\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&disputes).unwrap(),
+        "client,tx,amount,memo\n1,2,2.0,\"gift, birthday\"\n"
+    );
+}
+
+// Test that --held-detail writes the amount held by each open dispute, which
+// sum to the held balance of the client.
+#[test]
+fn test_held_detail() {
+    let dir = tempdir::TempDir::new("cli_held_detail").unwrap();
+    let detail = dir.path().join("held.csv");
+    let input = "tests/inputs/samplecode_open_disputes.csv";
+    let output = run(&["--held-detail", detail.to_str().unwrap(), input]);
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,0.0000,3.5,3.5,false",
+            "2,4.0,0.0000,4.0,false",
+            "client,available,held,total,locked",
+        ]
+    );
+    assert_eq!(
+        std::fs::read_to_string(&detail).unwrap(),
+        "client,tx,held\n1,1,1.5\n1,2,2.0\n"
+    );
+}
+
+// Test that --disputes-report writes every dispute event with the final state
+// of its deposit, whether resolved, charged back or still disputed.
+#[test]
+fn test_disputes_report() {
+    let dir = tempdir::TempDir::new("cli_disputes_report").unwrap();
+    let report = dir.path().join("disputes.csv");
+    let input = "tests/inputs/samplecode_dispute_lifecycle.csv";
+    let output = run(&["--disputes-report", report.to_str().unwrap(), input]);
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,1.5,2.0,3.5,false",
+            "2,0.0000,0.0000,0.0000,true",
+            "client,available,held,total,locked",
+        ]
+    );
+    assert_eq!(
+        std::fs::read_to_string(&report).unwrap(),
+        "client,tx,amount,event,final_state\n\
+         1,1,1.5,disputed,resolved\n\
+         1,1,1.5,resolved,resolved\n\
+         1,3,2.0,disputed,disputed\n\
+         2,2,4.0,disputed,charged_back\n\
+         2,2,4.0,charged_back,charged_back\n"
+    );
+}
+
+// Test that the lines starting with the comment character are only ignored
+// once it is given, the rows starting with # being read as records otherwise.
+#[test]
+fn test_comment_lines() {
+    let expected = vec![
+        "1,2.0,0.0000,2.0,false",
+        "2,2.5,0.0000,2.5,false",
+        "client,available,held,total,locked",
+    ];
+    let output = run(&[
+        "--stats",
+        "--comment-char",
+        "#",
+        "tests/inputs/samplecode_comments.csv",
+    ]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("applied: 4, rejected: 0, malformed: 0"));
+    assert_eq!(sorted_lines(output), expected);
+
+    let output = run(&["tests/inputs/samplecode_comments.csv"]);
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("Unknown transaction type #dispute"));
+
+    let output = run(&[
+        "--comment-char",
+        ";",
+        "tests/inputs/samplecode_comments.csv",
+    ]);
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert!(stderr.contains("Unknown transaction type #dispute"));
+}
+
+// Test that --columns writes the selected columns in the given order and that
+// an unknown column is reported before processing.
+#[test]
+fn test_columns() {
+    let output = run(&[
+        "--columns",
+        "total,client,locked=frozen",
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(output),
+        vec!["1.5,1,false", "2.0,2,false", "total,client,frozen"]
+    );
+
+    let output = run(&[
+        "--columns",
+        "client,totl",
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("Unknown column totl"));
+}
+
+// Test that --extended-output appends the counts of the applied transactions
+// and the status of the account.
+#[test]
+fn test_extended_output() {
+    let output = run(&["--extended-output", "tests/inputs/samplecode_test.csv"]);
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,1.5,0.0000,1.5,false,2,1,0,active",
+            "2,2.0,0.0000,2.0,false,1,0,0,active",
+            "client,available,held,total,locked,deposits_count,withdrawals_count,open_disputes_count,status"
+        ]
+    );
+}
+
+// Test that --output-shards writes the balances into the shard files instead
+// of stdout.
+#[test]
+fn test_output_shards() {
+    let dir = tempdir::TempDir::new("cli_shards").unwrap();
+    let shards = format!("{}:2", dir.path().display());
+    let output = run(&[
+        "--output-shards",
+        &shards,
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("part-00000.csv")).unwrap(),
+        "client,available,held,total,locked\n2,2.0,0.0000,2.0,false\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("part-00001.csv")).unwrap(),
+        "client,available,held,total,locked\n1,1.5,0.0000,1.5,false\n"
+    );
+    assert!(dir.path().join("_manifest.json").exists());
+
+    let shards = format!("{}:0", dir.path().display());
+    let output = run(&[
+        "--output-shards",
+        &shards,
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Invalid output shards"));
+}
+
+// Test that --page-size splits the sorted balances into pages and can not be
+// combined with --output-shards.
+#[test]
+fn test_page_size() {
+    let dir = tempdir::TempDir::new("cli_pages").unwrap();
+    let prefix = dir.path().join("balances");
+    let output = run(&[
+        "--page-size",
+        "1",
+        "--page-prefix",
+        prefix.to_str().unwrap(),
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("balances.0.csv")).unwrap(),
+        "client,available,held,total,locked\n1,1.5,0.0000,1.5,false\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.path().join("balances.1.csv")).unwrap(),
+        "client,available,held,total,locked\n2,2.0,0.0000,2.0,false\n"
+    );
+    assert!(!dir.path().join("balances.2.csv").exists());
+
+    let shards = format!("{}:2", dir.path().display());
+    let output = run(&[
+        "--page-size",
+        "1",
+        "--output-shards",
+        &shards,
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+// Test that --allow-overdraft lets the withdrawals go below zero up to the
+// limit, withdrawals beyond it being rejected as without the option.
+#[test]
+fn test_allow_overdraft() {
+    let input = "tests/inputs/samplecode_severities.csv";
+    let output = run(&["--allow-overdraft", "6", input]);
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,-4.0,0.0000,-4.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+    let output = run(&["--allow-overdraft", "4.5", input]);
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,6.0,0.0000,6.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+    let output = run(&[
+        "--allow-overdraft",
+        "6",
+        "--deny-withdrawal-overdraft",
+        input,
+    ]);
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,6.0,0.0000,6.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+    assert_eq!(
+        run(&["--allow-overdraft", "-1", input]).status.code(),
+        Some(2)
+    );
+}
+
+// Test that --min-deposit and --min-withdrawal reject the amounts below them
+// and accept the amounts at them, the withdrawal of tx 3 going through once its
+// deposit is rejected.
+#[test]
+fn test_minimum_amounts() {
+    let input = "tests/inputs/samplecode_severities.csv";
+    let output = run(&["--min-deposit", "5", input]);
+    assert!(output.status.success());
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,4.0,0.0000,4.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+    let output = run(&["--min-deposit", "5.0001", input]);
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,0.0000,0.0000,0.0000,false",
+            "client,available,held,total,locked"
+        ]
+    );
+    let output = run(&[
+        "--allow-overdraft",
+        "6",
+        "--min-withdrawal",
+        "10.0001",
+        input,
+    ]);
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,6.0,0.0000,6.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+    let output = run(&["--allow-overdraft", "6", "--min-withdrawal", "10", input]);
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,-4.0,0.0000,-4.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+    assert_eq!(run(&["--min-deposit", "-1", input]).status.code(), Some(2));
+}
+
+// Test that each validation rule only rejects the records breaking it, whether
+// enabled by its option or by the config file. The deposit without amount is
+// rejected either way, only under its rule with --require-deposit-amount.
+#[test]
+fn test_validation_rules() {
+    let dir = tempdir::TempDir::new("cli_validation").unwrap();
+    let input = dir.path().join("input.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\n\
+         deposit,0,1,1.0\n\
+         deposit,1,0,2.0\n\
+         deposit,2,2,5000.0\n\
+         deposit,3,3,\n\
+         deposit,4,4,3.0\n",
+    )
+    .unwrap();
+    let input = input.to_str().unwrap();
+    let clients = |args: &[&str]| -> Vec<String> {
+        sorted_lines(run(args))
+            .into_iter()
+            .filter_map(|line| line.split(',').next().map(str::to_owned))
+            .filter(|client| client != "client")
+            .collect()
+    };
+    assert_eq!(clients(&[input]), vec!["0", "1", "2", "4"]);
+    assert_eq!(
+        clients(&["--reject-zero-client", input]),
+        vec!["1", "2", "4"]
+    );
+    assert_eq!(clients(&["--reject-zero-tx", input]), vec!["0", "2", "4"]);
+    assert_eq!(
+        clients(&["--amount-ceiling", "1000", input]),
+        vec!["0", "1", "4"]
+    );
+
+    let rejected = dir.path().join("rejected.csv");
+    let output = run(&[
+        "--require-deposit-amount",
+        "--rejected-out",
+        rejected.to_str().unwrap(),
+        input,
+    ]);
+    assert!(output.status.success());
+    let rejected = std::fs::read_to_string(&rejected).unwrap();
+    assert_eq!(rejected.lines().count(), 2);
+    assert!(rejected.contains("Validation rule deposit_amount failed: deposit without amount"));
+
+    let config = dir.path().join("run.toml");
+    std::fs::write(
+        &config,
+        "[processor]\nreject_zero_client = true\namount_ceiling = \"1000\"\n",
+    )
+    .unwrap();
+    let config = config.to_str().unwrap();
+    assert_eq!(clients(&["--config", config, input]), vec!["1", "4"]);
+    assert_eq!(
+        clients(&["--config", config, "--reject-zero-tx", input]),
+        vec!["4"]
+    );
+    assert_eq!(
+        run(&["--amount-ceiling", "-1", input]).status.code(),
+        Some(2)
+    );
+}
+
+// Test that --parallel-chunks gives the same balances as a sequential run.
+#[test]
+fn test_parallel_chunks() {
+    let dir = tempdir::TempDir::new("cli_parallel").unwrap();
+    let path = dir.path().join("grouped.csv");
+    let mut input = String::new();
+    for client in 1..=20 {
+        for deposit in 0..client {
+            input.push_str(&format!(
+                "deposit,{},{},1.25\n",
+                client,
+                client * 100 + deposit
+            ));
+        }
+        input.push_str(&format!(
+            "withdrawal,{},{},3.0\n",
+            client,
+            client * 100 + 99
+        ));
+    }
+    std::fs::write(&path, input).unwrap();
+    let path = path.to_str().unwrap();
+
+    let sequential = run(&[path]);
+    let parallel = run(&["--parallel-chunks", "4", path]);
+    assert!(parallel.status.success());
+    assert_eq!(sorted_lines(parallel), sorted_lines(sequential));
+
+    for chunks in &["0", "1025", "100000000000", "18446744073709551616"] {
+        let output = run(&["--parallel-chunks", chunks, path]);
+        assert_eq!(output.status.code(), Some(2), "{}", chunks);
+    }
+    let output = run(&[
+        "--parallel-chunks",
+        "1024",
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert!(output.status.success());
+}
+
+// Test that inspect-cache summarizes a spilled cache dir and prints a single
+// stored transaction.
+#[test]
+fn test_inspect_cache() {
+    use exchange::transaction_cache::TransactionCache;
+    use exchange::type_defs::{ClientId, Transaction, TransactionId};
+
+    let base_dir = tempdir::TempDir::new("cli_inspect").unwrap();
+    // Without room in memory every insert is spilled right away.
+    let mut cache = TransactionCache::<0, 10>::new_in(base_dir.path()).unwrap();
+    for tx in 1..=12 {
+        let transaction = Transaction::Deposit {
+            client: ClientId(4),
+            tx: TransactionId(tx),
+            amount: "2.25".parse().unwrap(),
+        };
+        cache.insert(TransactionId(tx), transaction);
+    }
+    let cache_dir = cache.cache_dir().to_str().unwrap().to_owned();
+    drop(cache);
+
+    let output = run(&["inspect-cache", &cache_dir]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "line 0: 9 entries, tx 1..=9, deposited 20.25\n\
+         line 1: 3 entries, tx 10..=12, deposited 6.75\n\
+         2 lines, 12 entries, deposited 27.00\n"
+    );
+
+    let output = run(&["inspect-cache", "--tx", "11", &cache_dir]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("\"type\": \"deposit\""));
+    assert!(stdout.contains("\"tx\": 11"));
+
+    assert_eq!(
+        run(&["inspect-cache", "--tx", "13", &cache_dir])
+            .status
+            .code(),
+        Some(2)
+    );
+    assert_eq!(run(&["inspect-cache"]).status.code(), Some(2));
+}
+
+// Test that --summary-only prints the aggregate balances and stats instead of
+// the balances of each client.
+#[test]
+fn test_summary_only() {
+    let output = run(&["--summary-only", "tests/inputs/samplecode_chargebacks.csv"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "clients: 3\n\
+         locked clients: 1\n\
+         available: 7.0\n\
+         held: 0.0000\n\
+         total: 7.0\n\
+         applied: 8\n  \
+           chargeback: 1\n  \
+           deposit: 4\n  \
+           dispute: 2\n  \
+           resolve: 1\n\
+         rejected: 0\n  \
+           duplicate replays: 0\n  \
+           conflicting duplicates: 0\n\
+         malformed: 0\n\
+         filtered: 0\n\
+         skipped: 0\n\
+         idempotent replays: 0\n"
+    );
+}
+
+// Test that the help lists the commands and that each command prints its own
+// usage on stdout.
+#[test]
+fn test_help() {
+    for args in [&["--help"][..], &["help"][..]] {
+        let output = run(args);
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        assert!(stdout.starts_with("Usage: exchange [<command>] [<options>] <input>\n"));
+        assert!(stdout.contains("\n  process "));
+        assert!(stdout.contains("\n  inspect-cache "));
+    }
+
+    for args in [
+        &["process", "--help"][..],
+        &["help", "process"][..],
+        &["--fail-fast", "-h"][..],
+    ] {
+        let output = run(args);
+        assert!(output.status.success());
+        assert!(String::from_utf8(output.stdout)
+            .unwrap()
+            .starts_with("Usage: exchange [process] [--fail-fast]"));
+    }
+
+    let output = run(&["inspect-cache", "--help"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Usage: exchange inspect-cache [--tx <id>] <cache dir>\n"
+    );
+}
+
+// Test that a bare input path still runs the process command.
+#[test]
+fn test_process_command() {
+    let input = "tests/inputs/samplecode_test.csv";
+    let bare = run(&[input]);
+    let process = run(&["process", input]);
+    assert!(process.status.success());
+    assert_eq!(sorted_lines(process), sorted_lines(bare));
+}
+
+// Test that an unknown command or a missing input prints the help on stderr
+// and exits with code 2.
+#[test]
+fn test_invalid_usage() {
+    for args in [&["frobnicate"][..], &[][..], &["help", "frobnicate"][..]] {
+        let output = run(args);
+        assert_eq!(output.status.code(), Some(2));
+        assert!(output.stdout.is_empty());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("Usage: exchange [<command>]"));
+    }
+    let stderr = String::from_utf8(run(&["frobnicate"]).stderr).unwrap();
+    assert!(stderr.starts_with("Unknown command frobnicate\n"));
+
+    let output = run(&["process", "--bogus", "input.csv"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("Unknown option --bogus\nUsage: exchange [process]"));
+}
+
+// Test that the options override the settings of the config file, which
+// override the defaults.
+#[test]
+fn test_config_file() {
+    let dir = tempdir::TempDir::new("cli_config").unwrap();
+    let config = dir.path().join("run.toml");
+    std::fs::write(
+        &config,
+        "[processor]\n\
+         cache_limit = 1_000\n\
+         dispute_window = 10\n\
+         strict_spill = true\n\
+         \n\
+         [run]\n\
+         fail_fast = true\n",
+    )
+    .unwrap();
+    let config = config.to_str().unwrap();
+
+    let output = run(&["print-config", "--config", config, "--dispute-window", "5"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with(
+        "[processor]\n\
+         held_policy = \"allow\"\n\
+         dispute_window = 5\n\
+         reject_zero_client = false\n\
+         reject_zero_tx = false\n\
+         require_deposit_amount = false\n\
+         keep_memos = false\n\
+         cache_limit = 1000\n\
+         clean_cache_on_exit = false\n\
+         strict_spill = true\n"
+    ));
+    assert!(stdout.contains("[run]\nfail_fast = true\n"));
+    assert!(stdout.contains(
+        "[output]\n\
+         sort_by = \"client\"\n\
+         columns = \"client_id=client,available,held,total,locked\"\n"
+    ));
+
+    // The config makes the run fail fast.
+    let output = run(&[
+        "--config",
+        config,
+        "tests/inputs/samplecode_second_line_error.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+// Test that a misspelled key of the config file is reported with its line.
+#[test]
+fn test_config_file_unknown_key() {
+    let dir = tempdir::TempDir::new("cli_config_typo").unwrap();
+    let config = dir.path().join("run.toml");
+    std::fs::write(&config, "[processor]\ndispute_windw = 10\n").unwrap();
+
+    let output = run(&[
+        "--config",
+        config.to_str().unwrap(),
+        "tests/inputs/samplecode_test.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("line 2, column 1\n"));
+    assert!(stderr.contains("unknown field `dispute_windw`"));
+}
+
+// Test that --config is only taken where an option is, not as the value of
+// another option.
+#[test]
+fn test_config_option_value() {
+    let output = run(&["print-config", "--cache-dir", "--config"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("cache_dir = \"--config\"\n"));
+
+    let output = run(&["print-config", "--config"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("Missing value for --config"));
+}
+
+/// Runs the binary with the given environment variables, which are only set
+/// for that run.
+fn run_with_env(args: &[&str], vars: &[(&str, &str)]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_exchange"))
+        .args(args)
+        .envs(vars.iter().copied())
+        .output()
+        .expect("Could not run the exchange binary")
+}
+
+// Test that the environment variables override the config file and are
+// overridden by the options.
+#[test]
+fn test_env_config() {
+    use exchange::processor::TRANSACTION_SIZE;
+
+    let dir = tempdir::TempDir::new("cli_env").unwrap();
+    let config = dir.path().join("run.toml");
+    std::fs::write(
+        &config,
+        "[processor]\n\
+         cache_limit = 1000\n\
+         dispute_window = 10\n\
+         \n\
+         [run]\n\
+         fail_fast = false\n\
+         log_level = \"warn\"\n",
+    )
+    .unwrap();
+    let limit = (TRANSACTION_SIZE * 500).to_string();
+    let vars = [
+        ("TRANSACTION_CACHE_DIR", "/var/cache/exchange"),
+        ("TRANSACTION_CACHE_LIMIT_BYTES", limit.as_str()),
+        ("TRANSACTION_LOG_LEVEL", "error"),
+        ("TRANSACTION_STRICT", "true"),
+    ];
+
+    let output = run_with_env(
+        &[
+            "print-config",
+            "--config",
+            config.to_str().unwrap(),
+            "--log-level",
+            "off",
+        ],
+        &vars,
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(
+        "dispute_window = 10\n\
+         reject_zero_client = false\n\
+         reject_zero_tx = false\n\
+         require_deposit_amount = false\n\
+         keep_memos = false\n\
+         cache_limit = 500\n\
+         cache_dir = \"/var/cache/exchange\"\n"
+    ));
+    assert!(stdout.contains("[run]\nfail_fast = true\n"));
+    assert!(stdout.contains("log_level = \"off\"\n"));
+
+    // Only the error which stops the run is logged.
+    let output = run_with_env(
+        &["tests/inputs/samplecode_second_line_error.csv"],
+        &[("TRANSACTION_LOG_LEVEL", "error")],
+    );
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+    let output = run_with_env(
+        &["tests/inputs/samplecode_second_line_error.csv"],
+        &[("TRANSACTION_STRICT", "1")],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("Failed with error"));
+}
+
+// Test that an environment variable which can not be parsed stops the run
+// before processing, naming the variable.
+#[test]
+fn test_invalid_env_config() {
+    let output = run_with_env(
+        &["tests/inputs/samplecode_test.csv"],
+        &[("TRANSACTION_CACHE_LIMIT_BYTES", "lots")],
+    );
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Invalid TRANSACTION_CACHE_LIMIT_BYTES: lots is not a number of bytes\n"
+    );
+}
+
+// Test that without a usable temporary directory the records are processed in
+// memory after a single warning, instead of each one failing, which is only
+// logged at the warn level.
+#[test]
+fn test_unusable_temp_dir() {
+    let dir = tempdir::TempDir::new("cli_temp_dir").unwrap();
+    let not_a_dir = dir.path().join("tmp");
+    std::fs::write(&not_a_dir, "").unwrap();
+    let output = run_with_env(
+        &["tests/inputs/samplecode_test.csv"],
+        &[("TMPDIR", not_a_dir.to_str().unwrap())],
+    );
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    assert_eq!(stderr.matches("kept in memory only").count(), 1);
+    assert!(!stderr.contains("E_IO"));
+    assert_eq!(
+        sorted_lines(output),
+        vec![
+            "1,1.5,0.0000,1.5,false",
+            "2,2.0,0.0000,2.0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+
+    let output = run_with_env(
+        &["--log-level", "error", "tests/inputs/samplecode_test.csv"],
+        &[("TMPDIR", not_a_dir.to_str().unwrap())],
+    );
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty());
+}
+
+// Test that --state-out writes the state of every client at the end of a
+// complete run.
+#[test]
+fn test_state_out() {
+    use exchange::state::StateSnapshot;
+
+    let dir = tempdir::TempDir::new("cli_state_out").unwrap();
+    let state = dir.path().join("state.json");
+    let output = run(&[
+        "--state-out",
+        state.to_str().unwrap(),
+        "tests/inputs/samplecode_dispute_chargeback_all.csv",
+    ]);
+    assert!(output.status.success());
+    let snapshot = StateSnapshot::read(&state).unwrap();
+    assert!(!snapshot.partial);
+    assert!(!snapshot.clients.is_empty());
+    for client in &snapshot.clients {
+        assert_eq!(client.checksum, client.compute_checksum());
+        assert_eq!(client.total - client.held, client.available);
+    }
+}
+
+// Test that a client id above u16::MAX is processed end to end, into the
+// output and through the state snapshot.
+#[test]
+fn test_state_out_wide_client() {
+    use exchange::state::StateSnapshot;
+
+    let dir = tempdir::TempDir::new("cli_state_wide").unwrap();
+    let input = dir.path().join("input.csv");
+    std::fs::write(
+        &input,
+        "type,client,tx,amount\ndeposit,100000,4294967296,2.5\ndispute,100000,4294967296,\n",
+    )
+    .unwrap();
+    let state = dir.path().join("state.json");
+    let output = run(&[
+        "--state-out",
+        state.to_str().unwrap(),
+        input.to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n100000,0.0000,2.5,2.5,false\n"
+    );
+    let snapshot = StateSnapshot::read(&state).unwrap();
+    assert_eq!(snapshot.clients.len(), 1);
+    assert_eq!(snapshot.clients[0].client_id.0, 100_000);
+    assert_eq!(snapshot.clients[0].disputed[0].0 .0, 4_294_967_296);
+}
+
+// Test that ctrl-c during processing still writes the state and the partial
+// balances, and that a second ctrl-c once processing is over kills the run.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_interrupt_writes_state() {
+    use exchange::state::StateSnapshot;
+    use std::ffi::CString;
+    use std::io::{Read, Write};
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Stdio;
+
+    let dir = tempdir::TempDir::new("cli_interrupt").unwrap();
+    let input = dir.path().join("input.csv");
+    let path = CString::new(input.to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+    let state = dir.path().join("state.json");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_exchange"))
+        .args([
+            "--state-out",
+            state.to_str().unwrap(),
+            input.to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // Opening the fifo waits for the run to open it, after the ctrl-c handler
+    // is installed. The rows written after ctrl-c are not all read anymore.
+    let mut writer = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&input)
+        .unwrap();
+    let rows = |clients: std::ops::Range<u32>| -> String {
+        clients
+            .map(|client| format!("deposit,{},{},1.0\n", client, client))
+            .collect()
+    };
+    writer.write_all(b"type,client,tx,amount\n").unwrap();
+    writer
+        .write_all(rows(1_000_000..1_004_000).as_bytes())
+        .unwrap();
+    // Wait for the run to read the rows out of the fifo.
+    let mut unread: libc::c_int = 1;
+    while unread > 0 {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        let fd = std::os::unix::io::AsRawFd::as_raw_fd(&writer);
+        assert_eq!(unsafe { libc::ioctl(fd, libc::FIONREAD, &mut unread) }, 0);
+    }
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let _ = writer.write_all(rows(1_004_000..1_020_000).as_bytes());
+    drop(writer);
+
+    // The state is written before the balances, which do not fit into the
+    // pipe, so the run is still writing them when interrupted again.
+    let mut stdout = child.stdout.take().unwrap();
+    let mut start = [0u8; 4096];
+    stdout.read_exact(&mut start).unwrap();
+    assert!(start.starts_with(b"client,available,held,total,locked\n"));
+    let snapshot = StateSnapshot::read(&state).unwrap();
+    assert!(snapshot.partial);
+    assert!((3000..20000).contains(&snapshot.clients.len()));
+
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+    let status = child.wait().unwrap();
+    assert_eq!(status.signal(), Some(libc::SIGINT));
+}
+
+/// Copies the state snapshot at source into target with the available
+/// balance of its first client changed, leaving its checksum as it was.
+fn corrupt_state(source: &str, target: &std::path::Path) {
+    let mut state: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(source).unwrap()).unwrap();
+    state["clients"][0]["available"] = serde_json::Value::from("9.5");
+    std::fs::write(target, state.to_string()).unwrap();
+}
+
+// Test that check-state passes a healthy snapshot and reports the failures of
+// each client of a corrupted one with exit code 1.
+#[test]
+fn test_check_state() {
+    let output = run(&["check-state", "tests/inputs/state_healthy.json"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "pass: 2 clients checked in the snapshot\n"
+    );
+
+    let dir = tempdir::TempDir::new("cli_check_state").unwrap();
+    let corrupted = dir.path().join("state.json");
+    corrupt_state("tests/inputs/state_healthy.json", &corrupted);
+    let output = run(&["check-state", corrupted.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let report = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("client 1: checksum "));
+    assert_eq!(
+        lines[1],
+        "client 1: total 3.5 is not available 9.5 plus held 2.0"
+    );
+    assert_eq!(lines[2], "fail: 1 of 2 clients failed in the snapshot");
+
+    let output = run(&["check-state", "tests/inputs/missing_state.json"]);
+    assert_eq!(output.status.code(), Some(2));
+}
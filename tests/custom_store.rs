@@ -0,0 +1,120 @@
+use exchange::client::Client;
+use exchange::error::ProcessingError;
+use exchange::store::{StoreFamily, TransactionStore};
+use exchange::transaction_cache::CacheKeyed;
+use exchange::type_defs::{Amount, ClientId, TransactionId};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::Path;
+
+/// Store keeping its entries in a `HashMap` which fails every insert once
+/// full, the way a remote store would once unreachable.
+#[derive(Debug)]
+struct BoundedStore<K, V> {
+    entries: HashMap<K, V>,
+    capacity: usize,
+    error: Option<String>,
+}
+
+impl<K: CacheKeyed, V> TransactionStore<K, V> for BoundedStore<K, V> {
+    fn get(&mut self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    fn contains_key(&mut self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if self.entries.len() >= self.capacity {
+            self.error = Some(format!("store full at {} entries", self.capacity));
+            return None;
+        }
+        self.entries.insert(key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.entries.remove(key)
+    }
+
+    fn for_each<F: FnMut(&K, &V)>(&mut self, mut f: F) {
+        for (key, value) in self.entries.iter() {
+            f(key, value);
+        }
+    }
+
+    fn entries<'a>(&'a mut self) -> Box<dyn Iterator<Item = (K, V)> + 'a>
+    where
+        K: Clone + 'a,
+        V: Clone + 'a,
+    {
+        Box::new(
+            self.entries
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone())),
+        )
+    }
+
+    fn in_memory(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    fn spill_error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+#[derive(Debug)]
+struct BoundedStores;
+
+impl StoreFamily for BoundedStores {
+    type Store<K: CacheKeyed + Debug, V: Serialize + DeserializeOwned + Debug> = BoundedStore<K, V>;
+
+    fn create<K: CacheKeyed + Debug, V: Serialize + DeserializeOwned + Debug>(
+        _cache_dir: Option<&Path>,
+    ) -> Result<Self::Store<K, V>, String> {
+        Ok(BoundedStore {
+            entries: HashMap::new(),
+            capacity: 3,
+            error: None,
+        })
+    }
+}
+
+fn amount(amount: &str) -> Amount {
+    amount.parse().unwrap()
+}
+
+// Test that a client works on top of a store defined outside of the crate
+// and surfaces the errors of the store.
+#[test]
+fn test_custom_store() {
+    let mut client = Client::<0, 0, BoundedStores>::new(ClientId(1)).unwrap();
+    client.deposit(TransactionId(1), amount("10")).unwrap();
+    client.deposit(TransactionId(2), amount("5")).unwrap();
+    client.withdraw(TransactionId(3), amount("3")).unwrap();
+    client.dispute(&TransactionId(2)).unwrap();
+    assert_eq!(
+        client.withdraw(TransactionId(1), amount("1")),
+        Err(ProcessingError::ConflictingDuplicate {
+            stored_amount: None,
+            new_amount: amount("1"),
+        })
+    );
+    assert_eq!(client.available(), amount("7"));
+    assert_eq!(client.held(), amount("5"));
+    assert_eq!(client.transactions().count(), 3);
+    assert_eq!(client.spill_error(), None);
+
+    client.chargeback(&TransactionId(2)).unwrap();
+    assert_eq!(client.total(), amount("7"));
+    assert!(client.locked());
+
+    let mut client = Client::<0, 0, BoundedStores>::new(ClientId(2)).unwrap();
+    for tx in 1..=4 {
+        client.deposit(TransactionId(tx), amount("1")).unwrap();
+    }
+    assert_eq!(client.spill_error(), Some("store full at 3 entries"));
+}
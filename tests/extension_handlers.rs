@@ -0,0 +1,51 @@
+use exchange::error::ProcessingError;
+use exchange::processor::{OutputOptions, TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+use exchange::type_defs::TransactionRecord;
+
+// Test that registered handlers apply custom transaction types, deduplicate
+// their transaction ids and that unregistered types are still rejected.
+#[test]
+fn test_bonus_handler() {
+    let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+    processor.register_handler(
+        "bonus",
+        Box::new(|record, client| {
+            let amount = record
+                .amount
+                .as_deref()
+                .ok_or_else(|| ProcessingError::InvalidAmount("missing bonus".to_owned()))?;
+            client.credit(amount.parse()?)
+        }),
+    );
+
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .has_headers(true)
+        .from_path("tests/inputs/samplecode_bonus.csv")
+        .unwrap();
+    let results: Vec<Result<(), ProcessingError>> = rdr
+        .deserialize::<TransactionRecord>()
+        .map(|record| processor.process_transaction(record.unwrap()).map(|_| ()))
+        .collect();
+    assert_eq!(
+        results,
+        vec![
+            Ok(()),
+            Ok(()),
+            Ok(()),
+            Err(ProcessingError::DuplicateTransaction),
+            Err(ProcessingError::UnknownTransactionType(
+                "adjustment".to_owned()
+            )),
+            Ok(()),
+        ]
+    );
+
+    let mut output = Vec::new();
+    processor
+        .serialize_to(&mut output, &OutputOptions::default())
+        .unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("\n1,0.0000,0.0000,0.0000,false\n"));
+    assert!(output.contains("\n2,2.0,0.0000,2.0,false\n"));
+}
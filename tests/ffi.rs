@@ -0,0 +1,57 @@
+#![cfg(all(feature = "ffi", target_os = "linux"))]
+
+use std::path::Path;
+use std::process::Command;
+
+// Test that a C program compiled against the checked-in header and linked
+// with the static library drives the processor.
+#[test]
+fn test_c_smoke() {
+    // The library of target/debug is the one of the last build, whatever its
+    // features, so the one with only `ffi` is built into a dir of its own.
+    let target_dir = Path::new(env!("CARGO_BIN_EXE_exchange"))
+        .parent()
+        .unwrap()
+        .join("ffi-smoke");
+    let built = Command::new(env!("CARGO"))
+        .args([
+            "build",
+            "--lib",
+            "--offline",
+            "--features",
+            "ffi",
+            "--target-dir",
+        ])
+        .arg(&target_dir)
+        .output()
+        .expect("Could not run cargo");
+    assert!(
+        built.status.success(),
+        "{}",
+        String::from_utf8_lossy(&built.stderr)
+    );
+
+    let dir = tempdir::TempDir::new("ffi_c_smoke").unwrap();
+    let program = dir.path().join("smoke");
+    let compiled = Command::new("gcc")
+        .args(["-Wall", "-Werror", "-Iinclude", "tests/c/smoke.c"])
+        .arg(target_dir.join("debug").join("libexchange.a"))
+        .arg("-o")
+        .arg(&program)
+        .args(["-lpthread", "-ldl", "-lm"])
+        .output()
+        .expect("Could not run gcc");
+    assert!(
+        compiled.status.success(),
+        "{}",
+        String::from_utf8_lossy(&compiled.stderr)
+    );
+
+    let output = Command::new(&program).output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "ok\n");
+}
@@ -0,0 +1,130 @@
+//! Runs every `<name>.input.csv` of `tests/fixtures` through the library and
+//! compares the balances with `<name>.expected.csv`. Adding a scenario is a
+//! matter of dropping both files in the directory.
+
+use exchange::processor::{OutputOptions, TransactionProcessor, CACHE_SIZE_LIMIT, CACHE_SIZE_LINE};
+use exchange::runner::{self, LogLevel, RunOptions};
+use exchange::type_defs::Amount;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+const FIXTURES: &str = "tests/fixtures";
+
+/// Rows of a balances csv, the header first then the clients by increasing id.
+fn sorted_rows(balances: &str) -> Vec<Vec<String>> {
+    let mut lines = balances.lines().filter(|line| !line.is_empty());
+    let header = lines.next().unwrap_or_default();
+    let split = |line: &str| {
+        line.split(',')
+            .map(|field| field.trim().to_owned())
+            .collect()
+    };
+    let mut rows: Vec<Vec<String>> = lines.map(split).collect();
+    rows.sort_by_key(|row: &Vec<String>| row[0].parse::<u64>().unwrap_or(u64::MAX));
+    rows.insert(0, split(header));
+    rows
+}
+
+/// Fields are equal when they are the same amount, e.g. `1.5` and `1.5000`,
+/// or else the same text.
+fn same_field(actual: &str, expected: &str) -> bool {
+    match (actual.parse::<Amount>(), expected.parse::<Amount>()) {
+        (Ok(actual), Ok(expected)) => actual == expected,
+        _ => actual == expected,
+    }
+}
+
+/// Runs input through a fresh processor and returns its balances.
+fn process(input: &Path) -> String {
+    let mut processor = TransactionProcessor::<CACHE_SIZE_LIMIT, CACHE_SIZE_LINE>::new();
+    let options = RunOptions {
+        log_level: LogLevel::Off,
+        ..RunOptions::default()
+    };
+    let file = File::open(input).unwrap();
+    runner::run(&mut processor, file, &options).unwrap();
+    let mut output = Vec::new();
+    processor
+        .serialize_to(&mut output, &OutputOptions::default())
+        .unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+/// Returns a line diff of the rows when they differ, `-` for the expected
+/// rows and `+` for the produced ones.
+fn diff(actual: &[Vec<String>], expected: &[Vec<String>]) -> Option<String> {
+    let mut lines = Vec::new();
+    for index in 0..actual.len().max(expected.len()) {
+        let (actual, expected) = (actual.get(index), expected.get(index));
+        let same = match (actual, expected) {
+            (Some(actual), Some(expected)) => {
+                actual.len() == expected.len()
+                    && actual
+                        .iter()
+                        .zip(expected)
+                        .all(|(actual, expected)| same_field(actual, expected))
+            }
+            _ => false,
+        };
+        if same {
+            lines.push(format!("  {}", actual.unwrap().join(",")));
+            continue;
+        }
+        if let Some(expected) = expected {
+            lines.push(format!("- {}", expected.join(",")));
+        }
+        if let Some(actual) = actual {
+            lines.push(format!("+ {}", actual.join(",")));
+        }
+    }
+    if lines.iter().all(|line| line.starts_with("  ")) {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
+
+fn fixtures() -> Vec<(String, PathBuf, PathBuf)> {
+    let mut fixtures: Vec<_> = fs::read_dir(FIXTURES)
+        .unwrap()
+        .filter_map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path
+                .file_name()?
+                .to_str()?
+                .strip_suffix(".input.csv")?
+                .to_owned();
+            let expected = path.with_file_name(format!("{}.expected.csv", name));
+            Some((name, path, expected))
+        })
+        .collect();
+    fixtures.sort();
+    fixtures
+}
+
+// Test that the balances of every fixture are the expected ones, reporting
+// the diff of each failing fixture.
+#[test]
+fn test_golden_files() {
+    let fixtures = fixtures();
+    assert!(!fixtures.is_empty(), "no fixture in {}", FIXTURES);
+    let mut failures = Vec::new();
+    for (name, input, expected) in fixtures {
+        let expected = match fs::read_to_string(&expected) {
+            Ok(expected) => expected,
+            Err(err) => {
+                failures.push(format!("{}: {}: {}", name, expected.display(), err));
+                continue;
+            }
+        };
+        let actual = process(&input);
+        if let Some(diff) = diff(&sorted_rows(&actual), &sorted_rows(&expected)) {
+            failures.push(format!("{}:\n{}", name, diff));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "golden files differ:\n{}",
+        failures.join("\n\n")
+    );
+}